@@ -0,0 +1,35 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use kvs::{KvStore, KvStoreOptions, KvsEngine};
+use tempfile::TempDir;
+
+const SETS: usize = 1000;
+
+/// Sets `SETS` tiny keys in a store opened with `options`.
+fn tiny_sets(c: &mut Criterion, name: &str, options: fn() -> KvStoreOptions) {
+    c.bench_function(name, move |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = KvStore::open_with_options(temp_dir.path(), options()).unwrap();
+
+        b.iter(|| {
+            for set in 0..SETS {
+                store.set(format!("key{}", set), "v".to_owned()).unwrap();
+            }
+            store.flush().unwrap();
+        })
+    });
+}
+
+fn coalescing(c: &mut Criterion) {
+    tiny_sets(c, "write per set", KvStoreOptions::default);
+    tiny_sets(c, "coalesced sets", || KvStoreOptions {
+        coalesce_bytes: Some(4096),
+        ..KvStoreOptions::default()
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = coalescing
+}
+criterion_main!(benches);
@@ -0,0 +1,81 @@
+use criterion::{
+    criterion_group, criterion_main, Bencher, Criterion, ParameterizedBenchmark, Throughput,
+};
+use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use std::path::Path;
+use tempfile::TempDir;
+
+const VALUE_SIZES: [usize; 3] = [16, 1024, 16 * 1024];
+const KEYS: usize = 100;
+
+fn open_kvs(path: &Path) -> KvStore {
+    KvStore::open(path).unwrap()
+}
+
+fn open_sled(path: &Path) -> SledKvsEngine {
+    SledKvsEngine::new(sled::Db::start_default(path).unwrap())
+}
+
+/// Overwrites `KEYS` keys in turn with values of `value_size` bytes.
+fn write_heavy<E: KvsEngine>(b: &mut Bencher, value_size: usize, open: fn(&Path) -> E) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = open(temp_dir.path());
+    let value = "v".repeat(value_size);
+    let mut iter = 0;
+
+    b.iter(|| {
+        engine
+            .set(format!("key{}", iter % KEYS), value.to_owned())
+            .unwrap();
+        iter += 1;
+    });
+}
+
+/// Reads `KEYS` keys in turn, each holding a value of `value_size` bytes.
+fn read_heavy<E: KvsEngine>(b: &mut Bencher, value_size: usize, open: fn(&Path) -> E) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut engine = open(temp_dir.path());
+    let value = "v".repeat(value_size);
+    for key in 0..KEYS {
+        engine.set(format!("key{}", key), value.to_owned()).unwrap();
+    }
+    let mut iter = 0;
+
+    b.iter(|| {
+        let value = engine.get(format!("key{}", iter % KEYS)).unwrap();
+        assert!(value.is_some());
+        iter += 1;
+    });
+}
+
+// Each iteration is a single operation, so throughput reads as ops/sec.
+fn set_bench(c: &mut Criterion) {
+    let benchmark = ParameterizedBenchmark::new(
+        "kvs",
+        |b, &size| write_heavy(b, size, open_kvs),
+        VALUE_SIZES.to_vec(),
+    )
+    .with_function("sled", |b, &size| write_heavy(b, size, open_sled))
+    .throughput(|_| Throughput::Elements(1));
+
+    c.bench("set", benchmark);
+}
+
+fn get_bench(c: &mut Criterion) {
+    let benchmark = ParameterizedBenchmark::new(
+        "kvs",
+        |b, &size| read_heavy(b, size, open_kvs),
+        VALUE_SIZES.to_vec(),
+    )
+    .with_function("sled", |b, &size| read_heavy(b, size, open_sled))
+    .throughput(|_| Throughput::Elements(1));
+
+    c.bench("get", benchmark);
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = set_bench, get_bench
+}
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use kvs::{GroupCommit, KvStore, KvStoreOptions, KvsEngine};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const WRITERS: usize = 8;
+const WRITES_PER_WRITER: usize = 10;
+
+/// Runs `WRITERS` threads each setting `WRITES_PER_WRITER` keys through `set`.
+fn concurrent_sets<F>(set: F)
+where
+    F: Fn(String, String) + Clone + Send + 'static,
+{
+    let handles: Vec<_> = (0..WRITERS)
+        .map(|writer| {
+            let set = set.clone();
+            thread::spawn(move || {
+                for write in 0..WRITES_PER_WRITER {
+                    set(format!("key{}_{}", writer, write), "value".to_owned());
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+fn sync_writes(c: &mut Criterion) {
+    c.bench_function("fsync per write", |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let options = KvStoreOptions {
+            sync_writes: true,
+            ..KvStoreOptions::default()
+        };
+        let store = Arc::new(Mutex::new(
+            KvStore::open_with_options(temp_dir.path(), options).unwrap(),
+        ));
+
+        b.iter(|| {
+            let store = store.clone();
+            concurrent_sets(move |key, value| store.lock().unwrap().set(key, value).unwrap())
+        })
+    });
+
+    c.bench_function("group commit", |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let store = GroupCommit::open(
+            temp_dir.path(),
+            KvStoreOptions::default(),
+            Duration::from_micros(100),
+        )
+        .unwrap();
+
+        b.iter(|| {
+            let store = store.clone();
+            concurrent_sets(move |key, value| store.clone().set(key, value).unwrap())
+        })
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = sync_writes
+}
+criterion_main!(benches);
@@ -1,8 +1,15 @@
 extern crate structopt;
+use serde::de::{self, DeserializeSeed, MapAccess, Visitor};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::str::FromStr;
 use structopt::StructOpt;
 
-use kvs::{KvsClient, Result};
+use kvs::{KvsClient, KvsError, Result};
 use std::net::SocketAddr;
 
 #[derive(Debug, StructOpt)]
@@ -43,6 +50,84 @@ enum CommandOption {
     Get {
         #[structopt(name = "KEY")]
         key: String,
+        #[structopt(
+            long,
+            help = "Prints the value without a trailing newline, exiting with a non-zero code if the key is not found"
+        )]
+        raw: bool,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "scan")]
+    /// Prints the keys from START, included, to END, excluded, along with their values (scan <START> <END>)
+    Scan {
+        #[structopt(name = "START")]
+        start: String,
+        #[structopt(name = "END")]
+        end: String,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "compact")]
+    /// Compacts the storage of the server right away (compact)
+    Compact {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "import")]
+    /// Sets the key/value pairs of a JSON object or of a CSV file of KEY,VALUE rows (import <FILE>)
+    Import {
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+        #[structopt(
+            long,
+            help = "Sets the format of the file, guessed from its extension by default",
+            value_name = "json|csv",
+            parse(try_from_str)
+        )]
+        format: Option<ImportFormat>,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "info")]
+    /// Prints statistics of the server (info)
+    Info {
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "config")]
+    /// Prints how the server is configured (config)
+    Config {
         #[structopt(
             long,
             help = "Sets the server address",
@@ -64,13 +149,14 @@ fn main() {
 
 fn run(command_option: CommandOption) -> Result<()> {
     match command_option {
-        CommandOption::Get { key, addr } => {
+        CommandOption::Get { key, raw, addr } => {
             let mut client = KvsClient::connect(addr)?;
 
-            if let Some(value) = client.get(key)? {
-                println!("{}", value);
-            } else {
-                println!("Key not found");
+            match client.get(key)? {
+                Some(value) if raw => print!("{}", value),
+                Some(value) => println!("{}", value),
+                None if raw => exit(1),
+                None => println!("Key not found"),
             }
         }
         CommandOption::Set { key, value, addr } => {
@@ -81,7 +167,355 @@ fn run(command_option: CommandOption) -> Result<()> {
             let mut client = KvsClient::connect(addr)?;
             client.remove(key)?;
         }
+        CommandOption::Scan { start, end, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            scan(&mut client, start, end)?;
+        }
+        CommandOption::Compact { addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            let report = client.compact()?;
+
+            println!(
+                "Reclaimed {} bytes, removed {} log files",
+                report.bytes_reclaimed(),
+                report.files_removed
+            );
+        }
+        CommandOption::Import { file, format, addr } => {
+            let format = match format {
+                Some(format) => format,
+                None => ImportFormat::guess(&file)?,
+            };
+            let client = KvsClient::connect(addr)?.with_auto_flush(false);
+            let (imported, failed) = import(client, &file, format)?;
+
+            println!("Imported {} pairs, {} failed", imported, failed);
+            if failed > 0 {
+                exit(1);
+            }
+        }
+        CommandOption::Info { addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            let info = client.info()?;
+
+            println!("version: {}", info.version);
+            println!("uptime: {}s", info.uptime_secs);
+            println!("keys: {}", info.key_count);
+            println!("uncompacted bytes: {}", info.uncompacted_bytes);
+            if let Some(threshold) = info.compaction_threshold {
+                println!("compaction threshold: {} bytes", threshold);
+            }
+            println!("connections: {}", info.connections);
+            println!("health: {}", info.health);
+        }
+        CommandOption::Config { addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            let config = client.config()?;
+
+            println!("engine: {}", config.engine);
+            println!("threads: {}", config.threads);
+            if let Some(threshold) = config.compaction_threshold {
+                println!("compaction threshold: {} bytes", threshold);
+            }
+            println!("read-only: {}", config.read_only);
+            println!("protocol version: {}", config.protocol_version);
+            if let Some(requests_per_sec) = config.rate_limit {
+                println!("rate limit: {} requests per second", requests_per_sec);
+            }
+            println!("max request size: {} bytes", config.max_request_bytes);
+            println!("write retries: {}", config.write_retries);
+        }
     }
 
     Ok(())
 }
+
+/// Keys fetched per request while scanning.
+const SCAN_PAGE_SIZE: usize = 100;
+
+/// Prints every key in `start..end` with its value, one per line separated by a tab,
+/// as pages of keys come in.
+fn scan(client: &mut KvsClient, start: String, end: String) -> Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    // Listed keys come after the cursor, so the start itself is fetched on its own.
+    if start < end {
+        if let Some(value) = client.get(start.to_owned())? {
+            writeln!(out, "{}\t{}", start, value)?;
+        }
+    }
+
+    let mut cursor = start;
+    loop {
+        let keys = client.scan_keys_page(Some(cursor), SCAN_PAGE_SIZE)?;
+        let exhausted = keys.len() < SCAN_PAGE_SIZE;
+
+        for key in &keys {
+            if *key >= end {
+                return Ok(());
+            }
+            // The key might have been removed since it was listed.
+            if let Some(value) = client.get(key.to_owned())? {
+                writeln!(out, "{}\t{}", key, value)?;
+            }
+        }
+        out.flush()?;
+
+        match keys.last() {
+            Some(last) if !exhausted => cursor = last.to_owned(),
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Layout of a file read by `kvs-client import`.
+#[derive(Debug, Clone, Copy)]
+enum ImportFormat {
+    /// A single object mapping keys to their values.
+    Json,
+    /// A KEY,VALUE row per pair, a first `key,value` row being skipped as a header.
+    Csv,
+}
+
+impl ImportFormat {
+    fn guess(file: &Path) -> Result<ImportFormat> {
+        match file.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) => extension.parse().map_err(KvsError::MessageError),
+            None => Err(KvsError::MessageError(
+                "Unknown import format, set one with --format".to_owned(),
+            )),
+        }
+    }
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> std::result::Result<ImportFormat, String> {
+        match format.to_ascii_lowercase().as_str() {
+            "json" => Ok(ImportFormat::Json),
+            "csv" => Ok(ImportFormat::Csv),
+            _ => Err(format!(
+                "Unknown import format {}, expected json or csv",
+                format
+            )),
+        }
+    }
+}
+
+/// Requests sent ahead of their responses while importing.
+const IMPORT_WINDOW: usize = 100;
+
+/// Pairs imported between two progress reports.
+const IMPORT_PROGRESS_EVERY: u64 = 10_000;
+
+/// Sets every pair of `file`, streaming it, returning how many pairs got imported
+/// and how many failed. Failures are reported along the way on stderr.
+fn import(client: KvsClient, file: &Path, format: ImportFormat) -> Result<(u64, u64)> {
+    let mut reader = BufReader::new(File::open(file)?);
+    let mut importer = Importer {
+        client,
+        in_flight: VecDeque::new(),
+        imported: 0,
+        failed: 0,
+    };
+
+    match format {
+        ImportFormat::Json => import_json(&mut importer, reader)?,
+        ImportFormat::Csv => import_csv(&mut importer, &mut reader)?,
+    }
+
+    importer.finish()
+}
+
+/// Sets pairs through pipelined requests, keeping up to `IMPORT_WINDOW` of them
+/// in flight.
+struct Importer {
+    client: KvsClient,
+    /// Keys of the requests whose responses are still to be received.
+    in_flight: VecDeque<String>,
+    imported: u64,
+    failed: u64,
+}
+
+impl Importer {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        if self.in_flight.len() >= IMPORT_WINDOW {
+            self.recv()?;
+        }
+        self.client.send_set(key.to_owned(), value)?;
+        self.in_flight.push_back(key);
+
+        Ok(())
+    }
+
+    /// Receives the response of the oldest request in flight. Only a broken
+    /// connection is an error, a refused pair is counted as a failure.
+    fn recv(&mut self) -> Result<()> {
+        let key = match self.in_flight.pop_front() {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        match self.client.recv() {
+            Ok(_) => {
+                self.imported += 1;
+                if self.imported.is_multiple_of(IMPORT_PROGRESS_EVERY) {
+                    eprintln!("Imported {} pairs", self.imported);
+                }
+            }
+            Err(e @ KvsError::Io(_)) | Err(e @ KvsError::Serde(_)) => return Err(e),
+            Err(e) => self.fail(format!("Failed to set {}: {}", key, e)),
+        }
+
+        Ok(())
+    }
+
+    fn fail(&mut self, reason: String) {
+        eprintln!("{}", reason);
+        self.failed += 1;
+    }
+
+    fn finish(mut self) -> Result<(u64, u64)> {
+        while !self.in_flight.is_empty() {
+            self.recv()?;
+        }
+
+        Ok((self.imported, self.failed))
+    }
+}
+
+fn import_json(importer: &mut Importer, reader: impl io::Read) -> Result<()> {
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let mut aborted = None;
+    let pairs = JsonPairs {
+        importer,
+        aborted: &mut aborted,
+    };
+    let parsed = pairs.deserialize(&mut deserializer);
+    if let Some(e) = aborted {
+        return Err(e);
+    }
+    parsed?;
+    deserializer.end()?;
+
+    Ok(())
+}
+
+/// Sets the entries of a JSON object as they get parsed, never holding more than
+/// one of them in memory.
+struct JsonPairs<'a> {
+    importer: &'a mut Importer,
+    /// The error which aborted the import, serde only carrying messages.
+    aborted: &'a mut Option<KvsError>,
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for JsonPairs<'a> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for JsonPairs<'a> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an object mapping keys to values")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            let value = match map.next_value::<serde_json::Value>()? {
+                serde_json::Value::String(value) => value,
+                value @ serde_json::Value::Number(_) | value @ serde_json::Value::Bool(_) => {
+                    value.to_string()
+                }
+                _ => {
+                    self.importer
+                        .fail(format!("Failed to set {}: value is not a scalar", key));
+                    continue;
+                }
+            };
+            if let Err(e) = self.importer.set(key, value) {
+                *self.aborted = Some(e);
+                return Err(de::Error::custom("import aborted"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn import_csv(importer: &mut Importer, reader: &mut impl BufRead) -> Result<()> {
+    let mut line_number = 0;
+    let mut record = String::new();
+    loop {
+        record.clear();
+        let first_line = line_number + 1;
+        // A quoted field may span lines, the record goes on until its quote closes.
+        let fields = loop {
+            if reader.read_line(&mut record)? == 0 {
+                break None;
+            }
+            line_number += 1;
+            if let Some(fields) = parse_csv_record(&record) {
+                break Some(fields);
+            }
+        };
+        let mut fields = match fields {
+            Some(fields) => fields,
+            None if record.is_empty() => return Ok(()),
+            None => {
+                importer.fail(format!("Line {}: unterminated quoted field", first_line));
+                return Ok(());
+            }
+        };
+
+        match fields.len() {
+            1 if fields[0].is_empty() => (),
+            2 if first_line == 1 && fields[0] == "key" && fields[1] == "value" => (),
+            2 => {
+                let value = fields.pop().unwrap_or_default();
+                let key = fields.pop().unwrap_or_default();
+                importer.set(key, value)?;
+            }
+            len => importer.fail(format!(
+                "Line {}: expected 2 fields, got {}",
+                first_line, len
+            )),
+        }
+    }
+}
+
+/// Splits a CSV record, its line ending excluded, into its fields, `None` if a
+/// quoted field is still open at its end.
+fn parse_csv_record(record: &str) -> Option<Vec<String>> {
+    let record = record.strip_suffix('\n').unwrap_or(record);
+    let record = record.strip_suffix('\r').unwrap_or(record);
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = record.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return None;
+    }
+
+    fields.push(field);
+    Some(fields)
+}
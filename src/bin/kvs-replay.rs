@@ -0,0 +1,86 @@
+extern crate structopt;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::exit;
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+use kvs::{KvsClient, KvsError, RecordedRequest, Result};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "kvs-replay")]
+/// Sends the requests recorded by a server with --request-log to another one
+struct ReplayOption {
+    #[structopt(name = "FILE", parse(from_os_str))]
+    file: PathBuf,
+    #[structopt(
+        long,
+        help = "Sets the server address",
+        value_name = "IP:PORT",
+        default_value = "127.0.0.1:4000",
+        parse(try_from_str)
+    )]
+    addr: SocketAddr,
+    #[structopt(
+        long,
+        help = "Limits the replay to a number of requests per second",
+        value_name = "REQUESTS"
+    )]
+    rate: Option<u32>,
+}
+
+fn main() {
+    let replay_option = ReplayOption::from_args();
+    if let Err(e) = run(replay_option) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(options: ReplayOption) -> Result<()> {
+    let interval = match options.rate {
+        Some(0) => {
+            return Err(KvsError::MessageError(
+                "The rate must be at least one request per second".to_owned(),
+            ))
+        }
+        Some(rate) => Some(Duration::from_secs(1) / rate),
+        None => None,
+    };
+    let log = BufReader::new(File::open(&options.file)?);
+    let mut client = KvsClient::connect(options.addr)?;
+
+    let started_at = Instant::now();
+    let mut replayed = 0;
+    let mut failed = 0;
+    for (line, record) in RecordedRequest::read_log(log).enumerate() {
+        if let Some(interval) = interval {
+            // Requests are paced from the start so slow ones don't lower the rate.
+            let due = started_at + interval * replayed;
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                thread::sleep(wait);
+            }
+        }
+
+        // The answered errors were part of the recorded traffic, only the I/O
+        // and protocol ones stop the replay.
+        match client.replay(&record?) {
+            Ok(()) => {}
+            Err(e @ KvsError::Io(_)) | Err(e @ KvsError::Serde(_)) => return Err(e),
+            Err(e) => {
+                eprintln!("Request {}: {}", line + 1, e);
+                failed += 1;
+            }
+        }
+        replayed += 1;
+    }
+
+    println!(
+        "Replayed {} requests, {} answered with an error",
+        replayed, failed
+    );
+    Ok(())
+}
@@ -4,16 +4,23 @@ extern crate structopt;
 
 use clap::arg_enum;
 use env_logger::Env;
-use kvs::{KvStore, KvsServer, Result};
+use kvs::{KvStore, KvsEngine, KvsError, KvsServer, Result, SledKvsEngine};
 use std::env::current_dir;
+use std::fs::{self, OpenOptions};
+use std::io;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use structopt::StructOpt;
 
+/// Records the engine a directory was served with, see `check_engine`.
+const ENGINE_FILE: &str = "engine";
+
 arg_enum! {
     #[derive(Copy, Clone, PartialEq, Debug)]
     enum Engine {
         Kvs,
+        Sled,
     }
 }
 
@@ -36,6 +43,35 @@ struct ServerOption {
         case_insensitive = true
     )]
     engine: Engine,
+    #[structopt(
+        long = "read-only",
+        help = "Refuses every write while still serving reads"
+    )]
+    read_only: bool,
+    #[structopt(
+        long = "rate-limit",
+        help = "Limits every connection to a number of requests per second",
+        value_name = "REQUESTS"
+    )]
+    rate_limit: Option<u32>,
+    #[structopt(
+        long = "compression",
+        help = "Compresses the large messages of the clients asking for it"
+    )]
+    compression: bool,
+    #[structopt(
+        long = "request-log",
+        help = "Records every request received to a file, see kvs-replay",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    request_log: Option<PathBuf>,
+    #[cfg(feature = "http")]
+    #[structopt(
+        long = "http",
+        help = "Serves HTTP requests instead of the kvs protocol"
+    )]
+    http: bool,
 }
 
 fn main() {
@@ -50,11 +86,80 @@ fn main() {
 }
 
 fn run(options: ServerOption) -> Result<()> {
-    let kvs_engine = KvStore::open(current_dir()?)?;
-
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
-    info!("Storage engine: Kvs");
+    info!("Storage engine: {}", options.engine);
+
+    let dir = current_dir()?;
+    check_engine(&dir, options.engine)?;
+    match options.engine {
+        Engine::Kvs => run_with_engine(KvStore::open(&dir)?, &options),
+        Engine::Sled => {
+            run_with_engine(SledKvsEngine::new(sled::Db::start_default(&dir)?), &options)
+        }
+    }
+}
+
+/// Fails if `dir` was served with another engine than `engine`, whose files it
+/// can't read, recording `engine` on the first start.
+fn check_engine(dir: &Path, engine: Engine) -> Result<()> {
+    let path = dir.join(ENGINE_FILE);
+    let recorded = match fs::read_to_string(&path) {
+        Ok(recorded) => recorded,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            fs::write(&path, engine.to_string())?;
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    match recorded.trim().parse::<Engine>() {
+        Ok(recorded) if recorded == engine => Ok(()),
+        Ok(recorded) => Err(KvsError::MessageError(format!(
+            "The directory was served with the {} engine, not {}",
+            recorded, engine
+        ))),
+        Err(_) => Err(KvsError::MessageError(format!(
+            "Unknown engine {:?} recorded in {}",
+            recorded.trim(),
+            path.display()
+        ))),
+    }
+}
+
+fn run_with_engine<E: KvsEngine>(engine: E, options: &ServerOption) -> Result<()> {
+    if options.read_only {
+        info!("Serving in read-only mode");
+    }
+    let mut server = KvsServer::new(engine)
+        .with_read_only(options.read_only)
+        .with_compression(options.compression);
+    if let Some(requests_per_sec) = options.rate_limit {
+        info!(
+            "Limiting connections to {} requests per second",
+            requests_per_sec
+        );
+        server = server.with_rate_limit(requests_per_sec);
+    }
+    if let Some(path) = &options.request_log {
+        info!("Recording requests to {}", path.display());
+        let log = OpenOptions::new().create(true).append(true).open(path)?;
+        server = server.with_request_log(log);
+    }
+    #[cfg(feature = "http")]
+    {
+        if options.http {
+            info!("Serving over HTTP");
+        }
+        server = server.with_http(options.http);
+    }
+    let shutdown = server.shutdown_handle();
+
+    // SIGINT and SIGTERM flush the engine and release its files before exiting.
+    ctrlc::set_handler(move || {
+        info!("Shutdown signal received");
+        shutdown.shutdown();
+    })
+    .map_err(|e| KvsError::MessageError(format!("Failed to set signal handler: {}", e)))?;
 
-    let server = KvsServer::new(kvs_engine);
     server.run(options.addr)
 }
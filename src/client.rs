@@ -1,63 +1,460 @@
-use crate::{KvsError, Result};
+use crate::{CompactionReport, KvsError, RecordedRequest, Result, ServerConfig, ServerInfo};
 
-use crate::protocol::{GetResponse, Protocol, RemoveResponse, SetResponse};
-use serde::Deserialize;
-use serde_json::de::IoRead;
-use serde_json::Deserializer;
+use crate::protocol::{
+    buffer_message, read_message, write_message, AnyResponse, CompactResponse, ConfigResponse,
+    GetPatternResponse, GetResponse, GetStreamResponse, HandshakeResponse, IncrementResponse,
+    InfoResponse, LenResponse, Protocol, RemoveIfResponse, RemoveResponse, ScanKeysResponse,
+    SetResponse, SyncResponse, JSON_STREAM_VERSION, LENGTH_PREFIXED_VERSION, PROTOCOL_VERSION,
+};
+use serde::de::DeserializeOwned;
+use std::collections::VecDeque;
 use std::io::{BufReader, BufWriter, Write};
 use std::net::{SocketAddr, TcpStream};
 
+/// Identifies a request sent through `KvsClient::send_get` and alike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestToken(u64);
+
+/// Response to a request sent without waiting, tagged with its token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// The value fetched by a GET request.
+    Get(RequestToken, Option<String>),
+    /// Acknowledgement of a SET request.
+    Set(RequestToken),
+    /// Acknowledgement of a REMOVE request.
+    Remove(RequestToken),
+}
+
+/// Iterator over the keys of a server fetched in pages, see `KvsClient::scan_keys`.
+pub struct KeyScan<'a> {
+    client: &'a mut KvsClient,
+    page_size: usize,
+    page: VecDeque<String>,
+    cursor: Option<String>,
+    exhausted: bool,
+}
+
+impl<'a> Iterator for KeyScan<'a> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        if let Some(key) = self.page.pop_front() {
+            return Some(Ok(key));
+        }
+        if self.exhausted {
+            return None;
+        }
+
+        match self
+            .client
+            .scan_keys_page(self.cursor.take(), self.page_size)
+        {
+            Ok(keys) => {
+                // A short page means there is nothing left to fetch.
+                self.exhausted = keys.len() < self.page_size;
+                self.cursor = keys.last().cloned();
+                self.page = keys.into();
+                self.page.pop_front().map(Ok)
+            }
+            Err(e) => {
+                self.exhausted = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Kind of a request whose response hasn't been received yet.
+enum PendingRequest {
+    Get,
+    Set,
+    Remove,
+}
+
 /// The client of our key-value that connects to `KvsServer`.
 pub struct KvsClient {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    reader: BufReader<TcpStream>,
     writer: BufWriter<TcpStream>,
+    version: u32,
+    namespace: Option<String>,
+    pending: VecDeque<(RequestToken, PendingRequest)>,
+    next_token: u64,
+    auto_flush: bool,
 }
 
 impl KvsClient {
     /// Open the connection with the server and returns a KvsClient struct.
+    ///
+    /// The protocol version is negotiated right away, falling back to the
+    /// original JSON stream when the server doesn't support a handshake.
     pub fn connect(addr: SocketAddr) -> Result<Self> {
+        KvsClient::connect_with_version(addr, LENGTH_PREFIXED_VERSION)
+    }
+
+    /// Opens the connection like `KvsClient::connect`, also negotiating the
+    /// compression of the messages larger than a kilobyte in both directions.
+    ///
+    /// Messages stay uncompressed if the server doesn't enable compression, see
+    /// `KvsServer::with_compression`.
+    pub fn connect_compressed(addr: SocketAddr) -> Result<Self> {
+        KvsClient::connect_with_version(addr, PROTOCOL_VERSION)
+    }
+
+    /// Opens the connection, negotiating up to `version`.
+    fn connect_with_version(addr: SocketAddr, version: u32) -> Result<Self> {
         let reader = TcpStream::connect(addr)?;
         let writer = reader.try_clone()?;
 
-        Ok(KvsClient {
-            reader: Deserializer::from_reader(BufReader::new(reader)),
+        let mut client = KvsClient {
+            reader: BufReader::new(reader),
             writer: BufWriter::new(writer),
-        })
+            version: JSON_STREAM_VERSION,
+            namespace: None,
+            pending: VecDeque::new(),
+            next_token: 0,
+            auto_flush: true,
+        };
+
+        let handshake = Protocol::Handshake { version };
+        if let HandshakeResponse::Ok(version) = client.request(&handshake)? {
+            client.version = version;
+        }
+
+        Ok(client)
+    }
+
+    /// Scopes every following request to the keyspace of `namespace`, isolating
+    /// its keys from the ones of other namespaces.
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    /// Controls whether requests sent without waiting are flushed to the server right
+    /// away, which is the default.
+    ///
+    /// Without auto-flush they pile up in the write buffer, as long as it has room,
+    /// until `KvsClient::flush` sends the whole batch at once. Waiting for a response
+    /// flushes them too.
+    pub fn with_auto_flush(mut self, auto_flush: bool) -> Self {
+        self.auto_flush = auto_flush;
+        self
+    }
+
+    /// Sends every request still sitting in the write buffer.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
     }
 
     /// Sends a GET request and parses the response.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, &Protocol::Get { key })?;
-        self.writer.flush()?;
+        let namespace = self.namespace.to_owned();
+        match self.request(&Protocol::Get { namespace, key })? {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(e) => Err(e.into()),
+        }
+    }
 
-        // https://docs.serde.rs/serde/trait.Deserialize.html#tymethod.deserialize
-        match GetResponse::deserialize(&mut self.reader)? {
+    /// Sends a GET_STREAM request, writing the value to `writer` chunk by chunk as
+    /// they arrive instead of holding all of it in memory. Returns whether the key
+    /// was found, nothing being written otherwise.
+    ///
+    /// The value might be partially written when an error is returned.
+    pub fn get_stream(&mut self, key: String, mut writer: impl Write) -> Result<bool> {
+        let namespace = self.namespace.to_owned();
+        let mut response = self.request(&Protocol::GetStream { namespace, key })?;
+
+        loop {
+            match response {
+                GetStreamResponse::Chunk(chunk) => writer.write_all(chunk.as_bytes())?,
+                GetStreamResponse::End(found) => return Ok(found),
+                GetStreamResponse::Err(e) => return Err(e.into()),
+            }
+            response = read_message(&mut self.reader, self.version)?;
+        }
+    }
+
+    /// Sends a GET_RANGE request, fetching only the bytes `start..end` of the value.
+    pub fn get_range(&mut self, key: String, start: usize, end: usize) -> Result<Option<String>> {
+        let namespace = self.namespace.to_owned();
+        match self.request(&Protocol::GetRange {
+            namespace,
+            key,
+            start,
+            end,
+        })? {
             GetResponse::Ok(value) => Ok(value),
-            GetResponse::Err(e) => Err(KvsError::MessageError(e)),
+            GetResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sends a GET_PATTERN request, fetching every key matching the glob `pattern`
+    /// along with its value, in ascending order of keys.
+    ///
+    /// The pattern must match whole keys: `*` matches any sequence of characters,
+    /// possibly empty, and `?` exactly one, so `user:*` matches `user:1` but not
+    /// `admin:1`. Other characters match themselves, there is no escaping.
+    pub fn get_pattern(&mut self, pattern: String) -> Result<Vec<(String, String)>> {
+        let namespace = self.namespace.to_owned();
+        match self.request(&Protocol::GetPattern { namespace, pattern })? {
+            GetPatternResponse::Ok(pairs) => Ok(pairs),
+            GetPatternResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sends a LEN request, returning the length in bytes of the value of the key.
+    pub fn len(&mut self, key: String) -> Result<Option<u64>> {
+        let namespace = self.namespace.to_owned();
+        match self.request(&Protocol::Len { namespace, key })? {
+            LenResponse::Ok(len) => Ok(len),
+            LenResponse::Err(e) => Err(e.into()),
         }
     }
 
     /// Sends a SET request and parses the response.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Protocol::Set { key, value })?;
-        self.writer.flush()?;
-
-        // https://docs.serde.rs/serde/trait.Deserialize.html#tymethod.deserialize
-        match SetResponse::deserialize(&mut self.reader)? {
+        let namespace = self.namespace.to_owned();
+        match self.request(&Protocol::Set {
+            namespace,
+            key,
+            value,
+        })? {
             SetResponse::Ok(_) => Ok(()),
-            SetResponse::Err(e) => Err(KvsError::MessageError(e)),
+            SetResponse::Err(e) => Err(e.into()),
         }
     }
 
     /// Sends a REMOVE request and parses the response.
     pub fn remove(&mut self, key: String) -> Result<()> {
-        serde_json::to_writer(&mut self.writer, &Protocol::Remove { key })?;
+        let namespace = self.namespace.to_owned();
+        match self.request(&Protocol::Remove { namespace, key })? {
+            RemoveResponse::Ok(_) => Ok(()),
+            RemoveResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sends a REMOVE_IF request, removing the key only if its value is still
+    /// `expected`, and returns whether it was removed.
+    pub fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        let namespace = self.namespace.to_owned();
+        match self.request(&Protocol::RemoveIf {
+            namespace,
+            key,
+            expected,
+        })? {
+            RemoveIfResponse::Ok(removed) => Ok(removed),
+            RemoveIfResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sends an INCREMENT request, adding `delta` to the integer of the key, and
+    /// returns the result.
+    pub fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.increment_request(key, delta, None)
+    }
+
+    /// Sends an INCREMENT request identified by `op_id`, which must be unique to
+    /// this operation. Sending it again with the same `op_id`, such as when retrying
+    /// after a timeout, returns the same result without incrementing twice as long
+    /// as the server still remembers the operation.
+    pub fn increment_once(&mut self, key: String, delta: i64, op_id: u64) -> Result<i64> {
+        self.increment_request(key, delta, Some(op_id))
+    }
+
+    fn increment_request(&mut self, key: String, delta: i64, op_id: Option<u64>) -> Result<i64> {
+        let namespace = self.namespace.to_owned();
+        match self.request(&Protocol::Increment {
+            namespace,
+            key,
+            delta,
+            op_id,
+        })? {
+            IncrementResponse::Ok(value) => Ok(value),
+            IncrementResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sends a SCAN_KEYS request, listing up to `limit` keys greater than `after`
+    /// in ascending order.
+    pub fn scan_keys_page(&mut self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let namespace = self.namespace.to_owned();
+        match self.request(&Protocol::ScanKeys {
+            namespace,
+            after,
+            limit,
+        })? {
+            ScanKeysResponse::Ok(keys) => Ok(keys),
+            ScanKeysResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Iterates over every key in ascending order, fetching them `page_size` at a
+    /// time as the iteration goes.
+    ///
+    /// Keys set or removed meanwhile might or might not be listed, depending on
+    /// whether their page was already fetched.
+    pub fn scan_keys(&mut self, page_size: usize) -> KeyScan<'_> {
+        KeyScan {
+            client: self,
+            page_size,
+            page: VecDeque::new(),
+            cursor: None,
+            exhausted: false,
+        }
+    }
+
+    /// Sends a COMPACT request and parses the resulting report.
+    pub fn compact(&mut self) -> Result<CompactionReport> {
+        match self.request(&Protocol::Compact)? {
+            CompactResponse::Ok(report) => Ok(report),
+            CompactResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sends a SYNC request, returning once every write acknowledged so far by the
+    /// server is durable on disk.
+    ///
+    /// Writes sent without waiting must have their responses received first.
+    pub fn sync(&mut self) -> Result<()> {
+        match self.request(&Protocol::Sync)? {
+            SyncResponse::Ok(_) => Ok(()),
+            SyncResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sends an INFO request and parses the statistics of the server.
+    pub fn info(&mut self) -> Result<ServerInfo> {
+        match self.request(&Protocol::Info)? {
+            InfoResponse::Ok(info) => Ok(info),
+            InfoResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sends a CONFIG request and parses how the server is configured.
+    pub fn config(&mut self) -> Result<ServerConfig> {
+        match self.request(&Protocol::Config)? {
+            ConfigResponse::Ok(config) => Ok(config),
+            ConfigResponse::Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sends a request recorded by a server again, see `KvsServer::with_request_log`,
+    /// discarding the value answered.
+    ///
+    /// Handshakes are skipped since the connection already negotiated its version,
+    /// the namespace of the client is ignored in favor of the recorded one.
+    ///
+    /// # Errors
+    ///
+    /// It returns the error answered by the server, or `KvsError::Serde` if the
+    /// recorded request isn't one of the kvs protocol.
+    pub fn replay(&mut self, record: &RecordedRequest) -> Result<()> {
+        let request: Protocol = serde_json::from_value(record.request.to_owned())?;
+        match request {
+            Protocol::Handshake { .. } => Ok(()),
+            Protocol::GetStream { .. } => {
+                let mut response = self.request(&request)?;
+                loop {
+                    match response {
+                        GetStreamResponse::Chunk(_) => {}
+                        GetStreamResponse::End(_) => return Ok(()),
+                        GetStreamResponse::Err(e) => return Err(e.into()),
+                    }
+                    response = read_message(&mut self.reader, self.version)?;
+                }
+            }
+            _ => match self.request(&request)? {
+                AnyResponse::Ok(_) => Ok(()),
+                AnyResponse::Err(e) => Err(e.into()),
+            },
+        }
+    }
+
+    /// Sends a GET request without waiting for its response, see `KvsClient::recv`.
+    pub fn send_get(&mut self, key: String) -> Result<RequestToken> {
+        let namespace = self.namespace.to_owned();
+        self.send(&Protocol::Get { namespace, key }, PendingRequest::Get)
+    }
+
+    /// Sends a SET request without waiting for its response, see `KvsClient::recv`.
+    pub fn send_set(&mut self, key: String, value: String) -> Result<RequestToken> {
+        let namespace = self.namespace.to_owned();
+        let request = Protocol::Set {
+            namespace,
+            key,
+            value,
+        };
+        self.send(&request, PendingRequest::Set)
+    }
+
+    /// Sends a REMOVE request without waiting for its response, see `KvsClient::recv`.
+    pub fn send_remove(&mut self, key: String) -> Result<RequestToken> {
+        let namespace = self.namespace.to_owned();
+        self.send(&Protocol::Remove { namespace, key }, PendingRequest::Remove)
+    }
+
+    /// Waits for the response of the oldest request sent without waiting.
+    ///
+    /// Responses come back in the order their requests were sent. An error returned
+    /// by the server still consumes the response of its request.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::MessageError` if no request is pending.
+    pub fn recv(&mut self) -> Result<Response> {
+        let (token, pending) = self
+            .pending
+            .pop_front()
+            .ok_or_else(|| KvsError::MessageError("No pending request".to_owned()))?;
+        // The request might still be buffered, its response would never come.
         self.writer.flush()?;
 
-        // https://docs.serde.rs/serde/trait.Deserialize.html#tymethod.deserialize
-        match RemoveResponse::deserialize(&mut self.reader)? {
-            RemoveResponse::Ok(_) => Ok(()),
-            RemoveResponse::Err(e) => Err(KvsError::MessageError(e)),
+        match pending {
+            PendingRequest::Get => match read_message(&mut self.reader, self.version)? {
+                GetResponse::Ok(value) => Ok(Response::Get(token, value)),
+                GetResponse::Err(e) => Err(e.into()),
+            },
+            PendingRequest::Set => match read_message(&mut self.reader, self.version)? {
+                SetResponse::Ok(_) => Ok(Response::Set(token)),
+                SetResponse::Err(e) => Err(e.into()),
+            },
+            PendingRequest::Remove => match read_message(&mut self.reader, self.version)? {
+                RemoveResponse::Ok(_) => Ok(Response::Remove(token)),
+                RemoveResponse::Err(e) => Err(e.into()),
+            },
+        }
+    }
+
+    /// Sends a request, queueing its response to be read by `KvsClient::recv`.
+    fn send(&mut self, request: &Protocol, pending: PendingRequest) -> Result<RequestToken> {
+        if self.auto_flush {
+            write_message(&mut self.writer, self.version, request)?;
+        } else {
+            buffer_message(&mut self.writer, self.version, request)?;
+        }
+
+        let token = RequestToken(self.next_token);
+        self.next_token += 1;
+        self.pending.push_back((token, pending));
+        Ok(token)
+    }
+
+    /// Sends a request and waits for its response.
+    ///
+    /// Responses still pending would be mistaken for this one, so they must be
+    /// received first.
+    fn request<R: DeserializeOwned>(&mut self, request: &Protocol) -> Result<R> {
+        if !self.pending.is_empty() {
+            return Err(KvsError::MessageError(
+                "Responses of requests sent without waiting are still pending".to_owned(),
+            ));
         }
+
+        write_message(&mut self.writer, self.version, request)?;
+
+        // https://docs.serde.rs/serde/trait.Deserialize.html#tymethod.deserialize
+        read_message(&mut self.reader, self.version)
     }
 }
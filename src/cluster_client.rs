@@ -0,0 +1,102 @@
+use crate::{KvsClient, KvsError, Result};
+
+use std::net::SocketAddr;
+
+/// A client spreading reads across replica servers while routing writes to a primary.
+///
+/// Connections are opened lazily and dropped whenever a node fails, so they are
+/// reestablished on the next request sent to that node.
+pub struct KvsClusterClient {
+    primary: Node,
+    replicas: Vec<Node>,
+    next_replica: usize,
+}
+
+/// A server of a cluster along with the connection to it, if any.
+pub(crate) struct Node {
+    addr: SocketAddr,
+    client: Option<KvsClient>,
+}
+
+impl Node {
+    pub(crate) fn new(addr: SocketAddr) -> Self {
+        Node { addr, client: None }
+    }
+
+    /// Runs `op` over the connection to this node, connecting first if needed.
+    /// Connection level failures drop the connection so it can be reestablished later.
+    pub(crate) fn call<T>(&mut self, op: impl FnOnce(&mut KvsClient) -> Result<T>) -> Result<T> {
+        if self.client.is_none() {
+            self.client = Some(KvsClient::connect(self.addr)?);
+        }
+
+        let client = self.client.as_mut().expect("client was just connected");
+        let result = op(client);
+
+        if let Err(KvsError::Io(_)) | Err(KvsError::Serde(_)) = result {
+            warn!("Dropping connection to {}", self.addr);
+            self.client = None;
+        }
+
+        result
+    }
+}
+
+impl KvsClusterClient {
+    /// Creates a client for a cluster formed by a primary and its read replicas.
+    ///
+    /// Reads are served by the primary when no replicas are given.
+    pub fn new(primary: SocketAddr, replicas: Vec<SocketAddr>) -> Self {
+        KvsClusterClient {
+            primary: Node::new(primary),
+            replicas: replicas.into_iter().map(Node::new).collect(),
+            next_replica: 0,
+        }
+    }
+
+    /// Sends a GET request to the next replica in a round-robin fashion.
+    ///
+    /// Unreachable replicas are skipped, the request only fails once every
+    /// replica failed to answer it.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        if self.replicas.is_empty() {
+            return self.primary.call(|client| client.get(key));
+        }
+
+        let mut last_error = None;
+        for _ in 0..self.replicas.len() {
+            let index = self.next_replica;
+            self.next_replica = (index + 1) % self.replicas.len();
+            let replica = &mut self.replicas[index];
+
+            match replica.call(|client| client.get(key.to_owned())) {
+                Err(e @ KvsError::Io(_)) | Err(e @ KvsError::Serde(_)) => last_error = Some(e),
+                result => return result,
+            }
+        }
+
+        Err(last_error.expect("at least one replica was tried"))
+    }
+
+    /// Sends a SET request to the primary.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.primary.call(|client| client.set(key, value))
+    }
+
+    /// Sends a REMOVE request to the primary.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.primary.call(|client| client.remove(key))
+    }
+
+    /// Sends a REMOVE_IF request to the primary.
+    pub fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        self.primary.call(|client| client.remove_if(key, expected))
+    }
+
+    /// Sends an INCREMENT request identified by `op_id` to the primary, see
+    /// `KvsClient::increment_once`.
+    pub fn increment_once(&mut self, key: String, delta: i64, op_id: u64) -> Result<i64> {
+        self.primary
+            .call(|client| client.increment_once(key, delta, op_id))
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+/// Keys a filter of a log file holds before the next one is added to it.
+const INITIAL_CAPACITY: usize = 1024;
+
+/// Bits of a filter per key it holds.
+const BITS_PER_KEY: usize = 10;
+
+/// Bits set per key, giving about 1% of false positives with `BITS_PER_KEY` bits.
+const HASHES: u64 = 7;
+
+/// A bloom filter of the keys each log file sets, telling without reading them that
+/// no log file knows of a key.
+///
+/// Filters only ever err on the side of a key being there: a file keeps answering
+/// for the keys it set once they are removed, until the filters are rebuilt.
+#[derive(Default)]
+pub(crate) struct LogBlooms {
+    files: HashMap<u64, ScalableBloom>,
+}
+
+impl LogBlooms {
+    /// Notes that the log file `file_index` sets `key`.
+    pub(crate) fn insert(&mut self, file_index: u64, key: &str) {
+        self.files.entry(file_index).or_default().insert(key);
+    }
+
+    /// Returns `false` if no log file sets `key`.
+    pub(crate) fn may_contain(&self, key: &str) -> bool {
+        let hashes = KeyHashes::new(key);
+        self.files.values().any(|bloom| bloom.contains(&hashes))
+    }
+}
+
+/// A sequence of bloom filters, each one twice as large as the previous, so a
+/// filter keeps its false positive rate however many keys it ends up holding.
+#[derive(Default)]
+struct ScalableBloom {
+    filters: Vec<Bloom>,
+}
+
+impl ScalableBloom {
+    fn insert(&mut self, key: &str) {
+        let hashes = KeyHashes::new(key);
+        if self.contains(&hashes) {
+            return;
+        }
+
+        let full = self
+            .filters
+            .last()
+            .is_none_or(|filter| filter.len >= filter.capacity);
+        if full {
+            let capacity = self
+                .filters
+                .last()
+                .map_or(INITIAL_CAPACITY, |filter| filter.capacity * 2);
+            self.filters.push(Bloom::new(capacity));
+        }
+        if let Some(filter) = self.filters.last_mut() {
+            filter.insert(&hashes);
+        }
+    }
+
+    fn contains(&self, hashes: &KeyHashes) -> bool {
+        self.filters.iter().any(|filter| filter.contains(hashes))
+    }
+}
+
+struct Bloom {
+    bits: Vec<u64>,
+    capacity: usize,
+    len: usize,
+}
+
+impl Bloom {
+    fn new(capacity: usize) -> Self {
+        Bloom {
+            bits: vec![0; (capacity * BITS_PER_KEY).div_ceil(64)],
+            capacity,
+            len: 0,
+        }
+    }
+
+    fn insert(&mut self, hashes: &KeyHashes) {
+        let bit_count = self.bits.len() as u64 * 64;
+        for bit in hashes.bits(bit_count) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    fn contains(&self, hashes: &KeyHashes) -> bool {
+        let bit_count = self.bits.len() as u64 * 64;
+        hashes
+            .bits(bit_count)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+}
+
+/// The two hashes of a key the bits of every filter are derived from, hashing
+/// the key once however many filters are probed.
+struct KeyHashes(u64, u64);
+
+impl KeyHashes {
+    fn new(key: &str) -> Self {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let first = hasher.finish();
+        first.hash(&mut hasher);
+        // A zero step would probe the same bit over and over.
+        KeyHashes(first, hasher.finish() | 1)
+    }
+
+    fn bits(&self, bit_count: u64) -> impl Iterator<Item = u64> + '_ {
+        (0..HASHES).map(move |i| self.0.wrapping_add(i.wrapping_mul(self.1)) % bit_count)
+    }
+}
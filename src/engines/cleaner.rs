@@ -0,0 +1,99 @@
+use super::io_stats::IoCounters;
+use super::log_file::sync_dir;
+
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// A stale log file along with its reader, if it was open.
+type StaleLog = (Option<BufReader<File>>, PathBuf);
+
+/// Closes and removes stale log files off the hot path of a store.
+///
+/// A background thread is spawned on the first removal. Dropping the cleaner waits
+/// for every queued removal, so a store reopened afterwards never replays them.
+pub(crate) struct Cleaner {
+    sender: Option<Sender<StaleLog>>,
+    handle: Option<JoinHandle<()>>,
+    // Set when the directory gets synced after removing files, counting the syncs.
+    sync_dir: Option<Arc<IoCounters>>,
+}
+
+impl Cleaner {
+    /// Creates a cleaner, spawning no thread until something gets removed.
+    ///
+    /// With `sync_dir`, the directory of the removed files is synced once the queue
+    /// runs empty, the syncs being counted there.
+    pub(crate) fn new(sync_dir: Option<Arc<IoCounters>>) -> Self {
+        Cleaner {
+            sender: None,
+            handle: None,
+            sync_dir,
+        }
+    }
+
+    /// Queues the log file at `path` to be closed and removed.
+    pub(crate) fn remove(&mut self, reader: Option<BufReader<File>>, path: PathBuf) {
+        let sender = match self.sender.as_ref() {
+            Some(sender) => sender,
+            None => self.spawn(),
+        };
+
+        // The thread only goes away on a panic, leaving the removal to us.
+        if let Err(mpsc::SendError(stale)) = sender.send((reader, path)) {
+            let dir = stale.1.parent().map(PathBuf::from);
+            remove_log(stale);
+            sync_removals(dir, self.sync_dir.as_deref());
+        }
+    }
+
+    fn spawn(&mut self) -> &Sender<StaleLog> {
+        let (sender, receiver) = mpsc::channel::<StaleLog>();
+        let sync = self.sync_dir.clone();
+
+        self.handle = Some(thread::spawn(move || {
+            while let Ok(stale) = receiver.recv() {
+                // Removals queued together share a single sync.
+                let dir = stale.1.parent().map(PathBuf::from);
+                remove_log(stale);
+                while let Ok(stale) = receiver.try_recv() {
+                    remove_log(stale);
+                }
+                sync_removals(dir, sync.as_deref());
+            }
+        }));
+        self.sender.get_or_insert(sender)
+    }
+}
+
+impl Drop for Cleaner {
+    fn drop(&mut self) {
+        // Closing the channel lets the thread run out of removals and exit.
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn sync_removals(dir: Option<PathBuf>, io: Option<&IoCounters>) {
+    if let (Some(dir), Some(io)) = (dir, io) {
+        match sync_dir(&dir) {
+            Ok(()) => io.dir_synced(),
+            Err(e) => error!("Failed to sync {:?}: {}", dir, e),
+        }
+    }
+}
+
+fn remove_log((reader, path): StaleLog) {
+    drop(reader);
+    match fs::remove_file(&path) {
+        // Such as the writer log file removed from under the store.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => error!("Failed to remove stale log {:?}: {}", path, e),
+        Ok(()) => (),
+    }
+}
@@ -0,0 +1,19 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the current time for a store, driving key expiry and audit timestamps.
+pub trait Clock: Send + Sync {
+    /// Milliseconds elapsed since the Unix epoch.
+    fn now_millis(&self) -> u64;
+}
+
+/// The clock of the operating system, used unless another one is configured.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64)
+    }
+}
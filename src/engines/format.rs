@@ -0,0 +1,159 @@
+use super::Patch;
+use crate::Result;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::io::{self, Read, Write};
+
+/// A mutation recorded in the log files.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Associates `value` with `key`.
+    Set {
+        /// The key being set.
+        key: String,
+        /// The value being set.
+        value: String,
+        /// Milliseconds since the Unix epoch after which the key expires.
+        ///
+        /// Logs written before expiry support lack this field, their keys never expire.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires_at: Option<u64>,
+        /// Milliseconds since the Unix epoch at which the key was set, see
+        /// `KvStore::modified_at`.
+        ///
+        /// Logs written before timestamps lack this field.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        modified_at: Option<u64>,
+        /// Version the key gets, see `KvStore::get_versioned`.
+        ///
+        /// Logs written before versions were recorded lack this field, their versions
+        /// being counted again on replay.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
+    },
+    /// Associates the integer `value` with `key`, see `KvStore::set_int`.
+    SetInt {
+        /// The key being set.
+        key: String,
+        /// The integer being set.
+        value: i64,
+        /// Milliseconds since the Unix epoch at which the key was set.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        modified_at: Option<u64>,
+        /// Version the key gets, like the one of `Command::Set`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<u64>,
+    },
+    /// Edits the string value of `key` in place, see `KvStore::patch`.
+    Patch {
+        /// The key being patched.
+        key: String,
+        /// The edits applied to its value.
+        patch: Patch,
+        /// Milliseconds since the Unix epoch at which the key was patched.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        modified_at: Option<u64>,
+    },
+    /// Removes `key` and its value.
+    Remove {
+        /// The key being removed.
+        key: String,
+        /// Milliseconds since the Unix epoch at which the key was soft deleted, see
+        /// `KvStoreOptions::soft_delete_grace`. `None` for a plain removal.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        removed_at: Option<u64>,
+    },
+}
+
+impl Command {
+    /// Length in bytes of the string value a `Command::Set` associates.
+    pub(crate) fn value_len(&self) -> Option<u64> {
+        match self {
+            Command::Set { value, .. } => Some(value.len() as u64),
+            Command::SetInt { .. } | Command::Patch { .. } | Command::Remove { .. } => None,
+        }
+    }
+
+    /// Milliseconds since the Unix epoch at which the command was written, if recorded.
+    pub(crate) fn modified_at(&self) -> Option<u64> {
+        match self {
+            Command::Set { modified_at, .. }
+            | Command::SetInt { modified_at, .. }
+            | Command::Patch { modified_at, .. } => *modified_at,
+            Command::Remove { .. } => None,
+        }
+    }
+
+    /// Version a `Command::Set` or `Command::SetInt` gives its key, if recorded.
+    pub(crate) fn version(&self) -> Option<u64> {
+        match self {
+            Command::Set { version, .. } | Command::SetInt { version, .. } => *version,
+            Command::Patch { .. } | Command::Remove { .. } => None,
+        }
+    }
+}
+
+/// Iterator over the commands of a log file, along with the offset right after each of them.
+pub type CommandStream<'a> = Box<dyn Iterator<Item = Result<(Command, u64)>> + 'a>;
+
+/// How commands are laid out in the log files.
+///
+/// Records must be self-delimiting: the store keeps the offset and length of each
+/// record and copies them around verbatim during compactions.
+///
+/// Formats are shared with the snapshots of a store, hence `Sync`.
+pub trait LogFormat: Send + Sync {
+    /// Identifies the format in the header of the log files, a store refuses the
+    /// log files written in another one.
+    fn name(&self) -> &str;
+
+    /// Serializes a single `command` into `writer`.
+    fn write_command(&self, writer: &mut dyn Write, command: &Command) -> Result<()>;
+
+    /// Deserializes the single command held by `reader`.
+    fn read_command(&self, reader: &mut dyn Read) -> Result<Command>;
+
+    /// Deserializes every command of a log file, from its start.
+    ///
+    /// A command cut short by the end of `reader` must be reported as a
+    /// `KvsError::Io` of kind `UnexpectedEof`, letting the store recover from a
+    /// crash in the middle of a write. The stream is not resumed after an error.
+    fn stream<'a>(&self, reader: &'a mut dyn Read) -> CommandStream<'a>;
+}
+
+/// Commands as concatenated JSON objects, the default format.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFormat;
+
+impl LogFormat for JsonFormat {
+    fn name(&self) -> &str {
+        "json"
+    }
+
+    fn write_command(&self, writer: &mut dyn Write, command: &Command) -> Result<()> {
+        serde_json::to_writer(writer, command)?;
+        Ok(())
+    }
+
+    fn read_command(&self, reader: &mut dyn Read) -> Result<Command> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    fn stream<'a>(&self, reader: &'a mut dyn Read) -> CommandStream<'a> {
+        let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+
+        Box::new(std::iter::from_fn(move || {
+            let command = match stream.next()? {
+                Ok(command) => command,
+                Err(e) if e.is_eof() => {
+                    return Some(Err(io::Error::new(io::ErrorKind::UnexpectedEof, e).into()))
+                }
+                Err(e) if e.is_io() => return Some(Err(io::Error::from(e).into())),
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            Some(Ok((command, stream.byte_offset() as u64)))
+        }))
+    }
+}
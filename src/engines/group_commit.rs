@@ -0,0 +1,192 @@
+use super::{CompactionReport, KvStore, KvStoreOptions, KvsEngine};
+use crate::Result;
+
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// A `KvStore` shared between threads, making every mutation durable before
+/// returning while batching the fsyncs of concurrent writers.
+///
+/// Writers append their commands and then wait for a sync covering them. The first
+/// one waiting becomes the leader of the batch: it lingers for a short window so other
+/// writers can join, then syncs the writer log file once for all of them.
+///
+/// Clones share the same store, so each thread can own one.
+///
+/// ```
+/// use kvs::{GroupCommit, KvStoreOptions, KvsEngine};
+/// use std::thread;
+/// use std::time::Duration;
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let store = GroupCommit::open(
+///     temp_dir.path(),
+///     KvStoreOptions::default(),
+///     Duration::from_millis(1),
+/// )
+/// .unwrap();
+///
+/// let mut thread_store = store.clone();
+/// thread::spawn(move || thread_store.set("foo".to_owned(), "bar".to_owned()))
+///     .join()
+///     .unwrap()
+///     .unwrap();
+/// ```
+#[derive(Clone)]
+pub struct GroupCommit {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    store: Mutex<KvStore>,
+    commits: Mutex<Commits>,
+    synced: Condvar,
+    window: Duration,
+}
+
+/// Sequence numbers of the writes appended and of the ones known to be on disk.
+#[derive(Default)]
+struct Commits {
+    written: u64,
+    synced: u64,
+    syncing: bool,
+}
+
+impl GroupCommit {
+    /// Opens the store like `KvStore::open_with_options`, each batch of writes
+    /// waiting up to `window` for more writers to join it.
+    ///
+    /// `options.sync_writes` and `options.coalesce_bytes` are ignored since syncing
+    /// and batching are handled by the batches.
+    pub fn open(
+        dir_path: impl Into<PathBuf>,
+        options: KvStoreOptions,
+        window: Duration,
+    ) -> Result<GroupCommit> {
+        let options = KvStoreOptions {
+            sync_writes: false,
+            coalesce_bytes: None,
+            ..options
+        };
+
+        Ok(GroupCommit {
+            shared: Arc::new(Shared {
+                store: Mutex::new(KvStore::open_with_options(dir_path, options)?),
+                commits: Mutex::new(Commits::default()),
+                synced: Condvar::new(),
+                window,
+            }),
+        })
+    }
+
+    /// Applies `mutation` to the store and waits for it to be synced.
+    fn write<T, F>(&self, mutation: F) -> Result<T>
+    where
+        F: FnOnce(&mut KvStore) -> Result<T>,
+    {
+        let (output, sequence) = {
+            let mut store = self.shared.store.lock().unwrap();
+            let output = mutation(&mut store)?;
+
+            let mut commits = self.shared.commits.lock().unwrap();
+            commits.written += 1;
+            (output, commits.written)
+        };
+
+        let mut commits = self.shared.commits.lock().unwrap();
+        while commits.synced < sequence {
+            if commits.syncing {
+                commits = self.shared.synced.wait(commits).unwrap();
+                continue;
+            }
+
+            commits.syncing = true;
+            drop(commits);
+            let result = self.sync_batch();
+
+            commits = self.shared.commits.lock().unwrap();
+            commits.syncing = false;
+            self.shared.synced.notify_all();
+            // A follower takes over as the leader of the next batch on failure.
+            commits.synced = commits.synced.max(result?);
+        }
+
+        Ok(output)
+    }
+
+    /// Syncs the writer log file, returning the sequence of the last write covered.
+    fn sync_batch(&self) -> Result<u64> {
+        thread::sleep(self.shared.window);
+
+        // Writes are sequenced while holding the store, so every write up to `covered`
+        // is either in the current writer log file or in one it replaced, which the
+        // store synced before rolling the writer over.
+        let (file, covered) = {
+            let store = self.shared.store.lock().unwrap();
+            let covered = self.shared.commits.lock().unwrap().written;
+            (store.writer_file()?, covered)
+        };
+        file.sync_data()?;
+
+        Ok(covered)
+    }
+}
+
+impl KvsEngine for GroupCommit {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.write(|store| store.set(key, value))
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.shared.store.lock().unwrap().get(key)
+    }
+
+    fn value_len(&mut self, key: String) -> Result<Option<u64>> {
+        self.shared.store.lock().unwrap().value_len(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.write(|store| store.remove(key))
+    }
+
+    /// Compares and removes while holding the store, so no other clone can write
+    /// the key in between.
+    fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        self.write(|store| store.remove_if(key, expected))
+    }
+
+    fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.write(|store| store.increment(key, delta))
+    }
+
+    fn scan_keys(&mut self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        self.shared.store.lock().unwrap().scan_keys(after, limit)
+    }
+
+    fn force_compact(&mut self) -> Result<CompactionReport> {
+        self.shared.store.lock().unwrap().compact()
+    }
+
+    fn key_count(&self) -> u64 {
+        self.shared.store.lock().unwrap().key_count()
+    }
+
+    fn uncompacted_bytes(&self) -> u64 {
+        self.shared.store.lock().unwrap().uncompacted_bytes()
+    }
+
+    fn compaction_threshold(&self) -> Option<u64> {
+        self.shared.store.lock().unwrap().compaction_threshold()
+    }
+
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.shared.store.lock().unwrap().flush()
+    }
+}
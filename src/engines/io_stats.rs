@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counts of the I/O operations a `KvStore` issued on its log files, see
+/// `KvStore::stats`.
+///
+/// Replaying the log files while opening the store isn't accounted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IoStats {
+    /// Flushes of the writer buffer, each of them writing to the log file.
+    pub flushes: u64,
+    /// Seeks of the readers and of the writer.
+    pub seeks: u64,
+    /// Bytes written to the log files.
+    pub bytes_written: u64,
+    /// Bytes read from the log files.
+    pub bytes_read: u64,
+    /// Syncs of the directory, see `KvStoreOptions::sync_directory`.
+    pub dir_syncs: u64,
+}
+
+/// The counters behind `IoStats`, shared between a store and its snapshots.
+#[derive(Debug, Default)]
+pub(crate) struct IoCounters {
+    flushes: AtomicU64,
+    seeks: AtomicU64,
+    bytes_written: AtomicU64,
+    bytes_read: AtomicU64,
+    dir_syncs: AtomicU64,
+}
+
+impl IoCounters {
+    pub(crate) fn flushed(&self) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn seeked(&self) {
+        self.seeks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn wrote(&self, bytes: u64) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn read(&self, bytes: u64) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dir_synced(&self) {
+        self.dir_syncs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> IoStats {
+        IoStats {
+            flushes: self.flushes.load(Ordering::Relaxed),
+            seeks: self.seeks.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            dir_syncs: self.dir_syncs.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use super::kvs::CommandMetadata;
+use crate::{KvsError, Result};
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+/// Iterator over the keys of an index along with their metadata.
+pub(crate) type Entries<'a> = Box<dyn Iterator<Item = (&'a String, &'a CommandMetadata)> + 'a>;
+
+/// Which structure a `KvStore` indexes its keys with, see `KvStoreOptions::key_index`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyIndexKind {
+    /// A `BTreeMap`, keeping the keys ordered for range scans.
+    #[default]
+    Ordered,
+    /// A `HashMap`, with faster point lookups but no range scans: they fail with
+    /// `KvsError::UnorderedIndex`.
+    Hashed,
+}
+
+impl KeyIndexKind {
+    /// Creates an empty index of this kind.
+    pub(crate) fn create(self) -> Box<dyn KeyIndex> {
+        match self {
+            KeyIndexKind::Ordered => Box::<BTreeMap<String, CommandMetadata>>::default(),
+            KeyIndexKind::Hashed => Box::<HashMap<String, CommandMetadata>>::default(),
+        }
+    }
+}
+
+/// The in-memory index of a store, mapping every key to its latest command.
+pub(crate) trait KeyIndex: Send + Sync {
+    fn get(&self, key: &str) -> Option<&CommandMetadata>;
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut CommandMetadata>;
+
+    /// Indexes `metadata` under `key`, returning the metadata it replaces.
+    fn insert(&mut self, key: String, metadata: CommandMetadata) -> Option<CommandMetadata>;
+
+    fn remove(&mut self, key: &str) -> Option<CommandMetadata>;
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&String, &mut CommandMetadata) -> bool);
+
+    /// Iterates over every key, in ascending order if the index is ordered.
+    fn iter(&self) -> Entries<'_>;
+
+    fn values_mut(&mut self) -> Box<dyn Iterator<Item = &mut CommandMetadata> + '_>;
+
+    /// Iterates over the keys coming after `after` in ascending order, failing with
+    /// `KvsError::UnorderedIndex` if the index doesn't keep its keys ordered.
+    fn range_after(&self, after: Option<String>) -> Result<Entries<'_>>;
+
+    /// Returns `true` if `KeyIndex::iter` yields the keys in ascending order.
+    fn is_ordered(&self) -> bool;
+
+    fn clone_box(&self) -> Box<dyn KeyIndex>;
+}
+
+impl KeyIndex for BTreeMap<String, CommandMetadata> {
+    fn get(&self, key: &str) -> Option<&CommandMetadata> {
+        BTreeMap::get(self, key)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut CommandMetadata> {
+        BTreeMap::get_mut(self, key)
+    }
+
+    fn insert(&mut self, key: String, metadata: CommandMetadata) -> Option<CommandMetadata> {
+        BTreeMap::insert(self, key, metadata)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<CommandMetadata> {
+        BTreeMap::remove(self, key)
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&String, &mut CommandMetadata) -> bool) {
+        BTreeMap::retain(self, keep)
+    }
+
+    fn iter(&self) -> Entries<'_> {
+        Box::new(BTreeMap::iter(self))
+    }
+
+    fn values_mut(&mut self) -> Box<dyn Iterator<Item = &mut CommandMetadata> + '_> {
+        Box::new(BTreeMap::values_mut(self))
+    }
+
+    fn range_after(&self, after: Option<String>) -> Result<Entries<'_>> {
+        let lower = after.map_or(Bound::Unbounded, Bound::Excluded);
+        Ok(Box::new(self.range((lower, Bound::Unbounded))))
+    }
+
+    fn is_ordered(&self) -> bool {
+        true
+    }
+
+    fn clone_box(&self) -> Box<dyn KeyIndex> {
+        Box::new(self.clone())
+    }
+}
+
+impl KeyIndex for HashMap<String, CommandMetadata> {
+    fn get(&self, key: &str) -> Option<&CommandMetadata> {
+        HashMap::get(self, key)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut CommandMetadata> {
+        HashMap::get_mut(self, key)
+    }
+
+    fn insert(&mut self, key: String, metadata: CommandMetadata) -> Option<CommandMetadata> {
+        HashMap::insert(self, key, metadata)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<CommandMetadata> {
+        HashMap::remove(self, key)
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&String, &mut CommandMetadata) -> bool) {
+        HashMap::retain(self, keep)
+    }
+
+    fn iter(&self) -> Entries<'_> {
+        Box::new(HashMap::iter(self))
+    }
+
+    fn values_mut(&mut self) -> Box<dyn Iterator<Item = &mut CommandMetadata> + '_> {
+        Box::new(HashMap::values_mut(self))
+    }
+
+    fn range_after(&self, _after: Option<String>) -> Result<Entries<'_>> {
+        Err(KvsError::UnorderedIndex)
+    }
+
+    fn is_ordered(&self) -> bool {
+        false
+    }
+
+    fn clone_box(&self) -> Box<dyn KeyIndex> {
+        Box::new(self.clone())
+    }
+}
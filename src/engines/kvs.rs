@@ -1,185 +1,3451 @@
-use super::KvsEngine;
+use super::bloom::LogBlooms;
+use super::cleaner::Cleaner;
+use super::clock::{Clock, SystemClock};
+use super::format::{Command, JsonFormat, LogFormat};
+use super::io_stats::{IoCounters, IoStats};
+use super::key_index::{KeyIndex, KeyIndexKind};
+use super::log_file::{sync_dir, LogFile};
+use super::log_header::LogHeader;
+use super::patch::Patch;
+use super::reader_cache::ReaderCache;
+use super::value_cache::ValueCache;
+#[cfg(feature = "crash-test")]
+use super::CrashTest;
+use super::{value_range, CompactionReport, KvsEngine};
 use crate::{KvsError, Result};
 
-use std::collections::{BTreeMap, HashMap};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsStr;
-use std::fs::{File, OpenOptions};
+use std::fs::{File, OpenOptions, TryLockError};
 use std::io::prelude::*;
-use std::io::{BufReader, BufWriter, SeekFrom};
-use std::path::PathBuf;
+use std::io::{self, BufReader, BufWriter, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
+use serde::Serialize;
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// Longest time a set stays buffered, see `KvStoreOptions::coalesce_bytes`.
+const DEFAULT_COALESCE_WINDOW: Duration = Duration::from_millis(10);
+const REPLAY_PROGRESS_INTERVAL: u64 = 1024 * 1024;
+/// File of the store directory locked by the `KvStore` writing to it.
+const LOCK_FILE: &str = "kvs.lock";
 
-#[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+/// Line of `KvStore::export_jsonl`, a live key along with its value.
+#[derive(Serialize)]
+struct ExportRecord<'a> {
+    key: &'a str,
+    value: ExportedValue,
 }
 
-#[derive(Debug)]
+/// Value of an `ExportRecord`, integers being exported as JSON numbers.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum ExportedValue {
+    String(String),
+    Int(i64),
+}
+
+/// Entry of the audit log, a mutation and when it was applied.
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    /// Milliseconds since the Unix epoch.
+    timestamp: u64,
+    command: &'a Command,
+}
+
+#[derive(Debug, Clone)]
 pub struct CommandMetadata {
     file_index: u64,
     position: u64,
     length: u64,
+    /// Bytes taken by the commands of the same key superseded by this one.
+    stale: u64,
+    /// Milliseconds since the Unix epoch after which the key expires.
+    expires_at: Option<u64>,
+    /// Amount of times the key was set, the version of its current value.
+    version: u64,
+    /// Length in bytes of the value, known from the command when it got indexed.
+    /// `None` for integers, whose length is only learnt by reading them.
+    value_len: Option<u64>,
+    /// Milliseconds since the Unix epoch at which the key was last written, `None`
+    /// for commands predating timestamps.
+    modified_at: Option<u64>,
+    /// The `Command::Patch` records applied on top of the value since, in order.
+    patches: Vec<PatchRecord>,
+}
+
+/// Where a `Command::Patch` lies in the log files.
+#[derive(Debug, Clone, Copy)]
+struct PatchRecord {
+    file_index: u64,
+    position: u64,
+    length: u64,
+}
+
+impl CommandMetadata {
+    /// Returns `true` if the key expired by `now`, in milliseconds since the Unix epoch.
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+
+    /// Version of the key while this is its latest command, `0` once it expired.
+    fn live_version(&self, now: u64) -> u64 {
+        if self.is_expired(now) {
+            0
+        } else {
+            self.version
+        }
+    }
+
+    /// Bytes taken by this command along with its patches.
+    fn record_len(&self) -> u64 {
+        self.length + self.patches.iter().map(|patch| patch.length).sum::<u64>()
+    }
+
+    /// Bytes of the key left stale once this command gets superseded.
+    fn stale_once_superseded(&self) -> u64 {
+        self.stale + self.record_len()
+    }
+}
+
+/// A key removed while soft deletes are enabled, which `KvStore::undelete` restores
+/// until its grace period elapses.
+struct SoftDeleted {
+    /// The command the key held when removed.
+    set: CommandMetadata,
+    // Where its tombstone lies, compactions carry both commands over meanwhile.
+    tombstone_file_index: u64,
+    tombstone_position: u64,
+    tombstone_length: u64,
+    /// Milliseconds since the Unix epoch at which the key was removed.
+    removed_at: u64,
+}
+
+impl SoftDeleted {
+    /// Returns `true` if the key can still be restored at `now`, in milliseconds since
+    /// the Unix epoch.
+    fn within(&self, grace: Duration, now: u64) -> bool {
+        now < self.removed_at.saturating_add(grace.as_millis() as u64)
+    }
+}
+
+/// Progress of an incremental compaction, see `KvStoreOptions::compaction_step`.
+struct IncrementalCompaction {
+    index: u64,
+    writer: File,
+    position: u64,
+    // Keys indexed when the compaction started, not copied yet.
+    keys: std::vec::IntoIter<String>,
+    bytes_before: u64,
+    umcompacted_at_start: u64,
+}
+
+/// The keys indexed while replaying the log files.
+struct Index {
+    map: Box<dyn KeyIndex>,
+    soft_deleted: BTreeMap<String, SoftDeleted>,
+    blooms: LogBlooms,
+    max_version: u64,
+}
+
+impl Index {
+    fn new(kind: KeyIndexKind) -> Self {
+        Index {
+            map: kind.create(),
+            soft_deleted: BTreeMap::new(),
+            blooms: LogBlooms::default(),
+            max_version: 0,
+        }
+    }
+}
+
+/// Options tweaking how a `KvStore` is opened and behaves.
+#[derive(Default)]
+pub struct KvStoreOptions {
+    /// Makes writes to the log files fail on demand to simulate crashes.
+    #[cfg(feature = "crash-test")]
+    pub crash_test: Option<CrashTest>,
+    /// Compacts the log files periodically, on top of the COMPACTION_THRESHOLD.
+    ///
+    /// A background timer flags the compaction as due once the interval elapses, it
+    /// then runs during the next operation on the store as long as there are stale
+    /// commands to reclaim.
+    pub compaction_interval: Option<Duration>,
+    /// Spreads compactions over several calls of `KvStore::compact`, each of them
+    /// copying about this many bytes of live commands, `None` copying them at once.
+    ///
+    /// Writes go to a new log file meanwhile, and each automatic compaction check
+    /// performs the next step until the stale log files are removed. A crash in
+    /// between leaves the stale log files behind for the next compaction. Incremental
+    /// compactions always allocate indexes as with `IndexPolicy::Monotonic`.
+    pub compaction_step: Option<u64>,
+    /// Bounds how many log files are kept open for reading, `None` keeps all of them open.
+    ///
+    /// The least recently read log file is closed once the limit is reached and
+    /// reopened on demand, trading some reads for a bounded amount of file descriptors.
+    pub max_open_readers: Option<usize>,
+    /// Receives a record of every mutation, one JSON object per line.
+    ///
+    /// Unlike the log files it is never compacted, preserving the whole history of
+    /// the store. Each record carries the command applied and its timestamp in
+    /// milliseconds since the Unix epoch.
+    pub audit_log: Option<Box<dyn Write + Send>>,
+    /// Waits for every mutation to reach the disk, through an fsync, before returning.
+    ///
+    /// Concurrent writers should prefer `GroupCommit`, sharing a single fsync per batch.
+    pub sync_writes: bool,
+    /// Syncs the directory after creating, renaming or removing log files, without
+    /// which a crash might lose the files themselves, whatever was synced to them.
+    ///
+    /// Implied by `sync_writes`. Only supported on Unix platforms.
+    pub sync_directory: bool,
+    /// Keeps the values of up to this many recently read keys in memory, trading memory
+    /// for faster repeated gets. Setting or removing a key invalidates its cached value.
+    pub value_cache_capacity: Option<usize>,
+    /// High-water mark of uncompacted bytes above which writes block on a compaction.
+    ///
+    /// This bounds the disk usage when stale commands pile up faster than they are
+    /// compacted away, at the cost of slower writes. It should sit well above the
+    /// COMPACTION_THRESHOLD, otherwise every write past it pays for a compaction.
+    pub max_uncompacted_bytes: Option<u64>,
+    /// Largest key accepted by a set, in bytes.
+    pub max_key_size: Option<usize>,
+    /// Largest value accepted by a set, in bytes.
+    pub max_value_size: Option<usize>,
+    /// Size in bytes past which the writer rolls over to a new log file, keeping every
+    /// log file it writes manageable between compactions.
+    ///
+    /// The output of a compaction isn't split, it might go past the limit.
+    pub max_log_size: Option<u64>,
+    /// Compacts once the store holds more than this many log files, however few bytes
+    /// there are to reclaim, bounding the files to open and replay when many small
+    /// writes keep rolling over `max_log_size`.
+    ///
+    /// A compaction leaves two log files behind, its output and the writer one, so
+    /// smaller limits are raised to two.
+    pub max_log_files: Option<usize>,
+    /// How compactions pick the indexes of the log files they create.
+    pub index_policy: IndexPolicy,
+    /// What gets do when the command of a key doesn't read back.
+    pub corruption_policy: CorruptionPolicy,
+    /// What gets do when the log file of a key is missing.
+    pub missing_log_policy: MissingLogPolicy,
+    /// Which keys sets accept.
+    pub key_policy: KeyPolicy,
+    /// What swaps do when one of the keys is absent.
+    pub swap_policy: SwapPolicy,
+    /// Tells the time for key expiry and audit records, `SystemClock` when `None`.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Called while opening with the amount of log bytes replayed so far and the total
+    /// to replay, making slow opens observable.
+    ///
+    /// It is called every REPLAY_PROGRESS_INTERVAL bytes and after each log file,
+    /// the last call reporting every byte as replayed.
+    pub replay_progress: Option<Box<dyn FnMut(u64, u64) + Send>>,
+    /// Truncates a log file at its first corrupt command while opening, dropping it
+    /// along with every command following it in that file, instead of failing.
+    ///
+    /// Incomplete commands at the end of a log file, left by a crash, are dropped
+    /// regardless. Anything else unreadable is only dropped through this flag, so
+    /// data isn't lost silently. What got dropped is logged.
+    pub repair: bool,
+    /// Skips replaying the log files while opening, leaving the index to be built by
+    /// the first operation needing it, such as a get or a remove.
+    ///
+    /// Sets append to a fresh log file right away, making opens fast for write-only
+    /// ingestion whatever the size of the store. Until the index gets built, methods
+    /// taking `&self` see no keys and no compaction runs, see `KvStore::build_index`.
+    pub lazy_index: bool,
+    /// Recovers from the removal of the writer log file from under the store, through
+    /// a compaction copying every live command still readable to new log files,
+    /// instead of failing writes with `KvsError::WriterLost`.
+    pub recreate_lost_writer: bool,
+    /// Reads back every live command before compactions copy it, failing with
+    /// `KvsError::CorruptRecord` instead of carrying a command which doesn't parse as
+    /// a set of its key over to the compacted log file. Nothing is copied by a full
+    /// compaction once one is found.
+    ///
+    /// Only `KvStore::compact` reports it, automatic compactions log it and stop
+    /// until an explicit one succeeds, once the key was overwritten or removed.
+    ///
+    /// Commands hold no checksum, so corruption leaving a valid command of the same
+    /// key goes unnoticed.
+    pub verify_compaction: bool,
+    /// Buffers the commands of sets in memory until this many bytes are pending, or
+    /// until `coalesce_window` elapsed since the oldest of them, writing them to the
+    /// writer log file at once. `None` writes every command right away.
+    ///
+    /// The writer allocates a buffer of this many bytes, a set larger than it being
+    /// written right away.
+    ///
+    /// Buffered sets are indexed right away and served by reads like written ones,
+    /// which write them first when needed. They only reach the disk once written:
+    /// `KvsEngine::flush` writes and syncs them, like every other mutation writes
+    /// them along with its own command.
+    ///
+    /// Buffered sets are acknowledged before reaching the disk, so it can't be
+    /// combined with `sync_writes`.
+    pub coalesce_bytes: Option<u64>,
+    /// Longest time a set stays buffered under `coalesce_bytes`, 10 milliseconds when
+    /// `None`. It is checked by every read and mutation: the sets buffered by a store
+    /// left idle wait for the next one, or for `KvsEngine::flush`.
+    pub coalesce_window: Option<Duration>,
+    /// Keeps removed keys around for this long, letting `KvStore::undelete` restore
+    /// them, `None` removes them for good.
+    ///
+    /// A removed key is hidden from gets right away, but compactions carry its last
+    /// command over until the grace period elapses.
+    pub soft_delete_grace: Option<Duration>,
+    /// Structure of the in-memory index of the keys.
+    pub key_index: KeyIndexKind,
+    /// Layout of the commands in the log files, `JsonFormat` when `None`.
+    ///
+    /// A store must always be opened with the format it was written with.
+    pub format: Option<Box<dyn LogFormat>>,
+}
+
+/// Allocation of log file indexes, which also define the order commands are replayed in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexPolicy {
+    /// Every new log file takes the next index, which grows for the whole life of
+    /// the store.
+    #[default]
+    Monotonic,
+    /// The output of a compaction is renamed to the index 0 and the writer reuses
+    /// the index 1, keeping the names of the log files deterministic.
+    ///
+    /// Stale log files get removed right away, as their names are reused.
+    ReuseLowest,
+}
+
+/// How `KvStore` gets handle a key whose command is corrupt, see `KvStore::verify`
+/// to look for them upfront.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    /// The get fails with the error the command was read with.
+    #[default]
+    Error,
+    /// The get logs a warning and returns `None`, as if the key was missing, so
+    /// a single bad key doesn't make the rest of the store unusable.
+    Skip,
+}
+
+/// How `KvStore` gets handle a key whose log file is missing, such as when deleted
+/// from under the store.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissingLogPolicy {
+    /// The get fails with `KvsError::MissingLogFile`.
+    #[default]
+    Error,
+    /// The get logs a warning, drops the key from the index and returns `None`.
+    DropKey,
+}
+
+/// Which value wins when `KvStore::merge_from` copies a key both stores hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// The value of the store merged into is kept.
+    KeepSelf,
+    /// The value of the store merged from overwrites it.
+    Overwrite,
+}
+
+/// What `KvStore::swap` does when one of the keys is absent.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SwapPolicy {
+    /// The swap fails with `KvsError::KeyNotFound`, writing nothing.
+    #[default]
+    RequireBoth,
+    /// The absence is swapped like a value: the present key moves to the absent one.
+    MoveMissing,
+}
+
+/// Which keys a `KvStore` accepts to set, the ones already stored being readable
+/// whatever the policy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyPolicy {
+    /// Any string is a valid key.
+    #[default]
+    Lenient,
+    /// Keys containing control characters or whitespace are refused, keeping them
+    /// printable on a single line.
+    Strict,
+}
+
+/// Outcome of `KvStore::verify`, listing what couldn't be read back.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Amount of indexed keys whose latest command reads back fine.
+    pub ok: u64,
+    /// Indexed keys whose latest command is unreadable or belongs to another key.
+    pub corrupt_keys: Vec<String>,
+    /// Indexes of the log files that don't parse as a sequence of commands.
+    pub corrupt_files: Vec<u64>,
+}
+
+impl VerifyReport {
+    /// Returns `true` if nothing corrupt was found.
+    pub fn is_healthy(&self) -> bool {
+        self.corrupt_keys.is_empty() && self.corrupt_files.is_empty()
+    }
+}
+
+/// An indexed key whose command on disk doesn't match, see `KvStore::audit_index`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexDiscrepancy {
+    /// The key as indexed.
+    pub key: String,
+    /// Index of the log file the index points the key to.
+    pub file_index: u64,
+    /// Offset in that log file the index points the key to.
+    pub position: u64,
+    /// The key set by the command found there, `None` if it isn't a readable set.
+    pub found: Option<String>,
 }
 
 /// A struct representing our key-value store mechanism.
 pub struct KvStore {
     path: PathBuf,
-    readers: HashMap<u64, BufReader<File>>,
-    writer: BufWriter<File>,
-    map: BTreeMap<String, CommandMetadata>,
+    readers: ReaderCache,
+    // `None` when the store was opened in read-only mode.
+    writer: Option<BufWriter<LogFile>>,
+    map: Box<dyn KeyIndex>,
+    soft_deleted: BTreeMap<String, SoftDeleted>,
+    soft_delete_grace: Option<Duration>,
+    // Highest version handed out, which the keys set once missing start past.
+    max_version: u64,
     current_index: u64,
+    writer_pos: u64,
     umcompacted_bytes: u64,
+    compaction_due: Option<Arc<AtomicBool>>,
+    compaction_step: Option<u64>,
+    compaction: Option<IncrementalCompaction>,
+    // `false` until the log files are replayed for a store opened with `lazy_index`.
+    index_built: bool,
+    repair: bool,
+    recreate_lost_writer: bool,
+    verify_compaction: bool,
+    // The key of the corrupt record an automatic compaction stopped at, holding off
+    // the next ones until an explicit compaction succeeds.
+    corrupt_record: Option<String>,
+    coalesce_bytes: Option<u64>,
+    coalesce_window: Duration,
+    // When the oldest command buffered by the writer was appended.
+    buffered_since: Option<Instant>,
+    audit_log: Option<Box<dyn Write + Send>>,
+    sync_writes: bool,
+    sync_directory: bool,
+    max_uncompacted_bytes: Option<u64>,
+    max_key_size: Option<usize>,
+    max_value_size: Option<usize>,
+    max_log_size: Option<u64>,
+    max_log_files: Option<usize>,
+    index_policy: IndexPolicy,
+    corruption_policy: CorruptionPolicy,
+    missing_log_policy: MissingLogPolicy,
+    key_policy: KeyPolicy,
+    swap_policy: SwapPolicy,
+    format: Arc<dyn LogFormat>,
+    // Written at the start of every log file created.
+    header: Vec<u8>,
+    value_cache: Option<ValueCache>,
+    // The keys of each log file, answering gets of missing keys upfront.
+    blooms: LogBlooms,
+    disk_reads: u64,
+    bloom_rejections: u64,
+    io: Arc<IoCounters>,
+    cleaner: Cleaner,
+    generation: Arc<Generation>,
+    clock: Arc<dyn Clock>,
+    paused: bool,
+    /// Holds the exclusive lock of the directory for as long as the store is open,
+    /// `None` for read-only stores.
+    _lock: Option<File>,
 }
 
-impl KvStore {
-    /// Initializes our key-value store with an empty state.
-    pub fn new(
-        path: PathBuf,
-        readers: HashMap<u64, BufReader<File>>,
-        writer: BufWriter<File>,
-        map: BTreeMap<String, CommandMetadata>,
-        current_index: u64,
-        umcompacted_bytes: u64,
-    ) -> Self {
-        KvStore {
-            path,
-            readers,
-            writer,
-            map,
-            current_index,
-            umcompacted_bytes,
+/// The set of log files a store reads from, a new generation starting with every
+/// compaction.
+///
+/// Snapshots hold on to the generation they were taken in, the stale log files of a
+/// compaction are only removed once no snapshot of an older generation is left.
+struct Generation {
+    number: u64,
+    // Stale log files still read by snapshots, removed along with the last of them.
+    stale_logs: Mutex<Vec<(u64, PathBuf)>>,
+    // Indexes of the stale log files of every generation still alive, shared by all
+    // of them so their names aren't reused before they are removed.
+    pinned_logs: Arc<Mutex<BTreeSet<u64>>>,
+}
+
+impl Generation {
+    fn new(number: u64, pinned_logs: Arc<Mutex<BTreeSet<u64>>>) -> Arc<Generation> {
+        Arc::new(Generation {
+            number,
+            stale_logs: Mutex::new(Vec::new()),
+            pinned_logs,
+        })
+    }
+
+    /// Starts the generation following this one.
+    fn next(&self) -> Arc<Generation> {
+        Generation::new(self.number + 1, Arc::clone(&self.pinned_logs))
+    }
+
+    /// Returns `true` if a snapshot of an older generation still reads stale log files.
+    fn has_pinned_logs(&self) -> bool {
+        !self.pinned_logs.lock().unwrap().is_empty()
+    }
+}
+
+impl Drop for Generation {
+    fn drop(&mut self) {
+        let stale_logs = self
+            .stale_logs
+            .get_mut()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let mut pinned_logs = self
+            .pinned_logs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for (index, path) in stale_logs.drain(..) {
+            if let Err(e) = std::fs::remove_file(&path) {
+                error!("Failed to remove stale log {:?}: {}", path, e);
+            }
+            pinned_logs.remove(&index);
+        }
+    }
+}
+
+/// A read-only view of a `KvStore` frozen when `KvStore::snapshot` was called.
+///
+/// Reads keep succeeding whatever happens to the store afterwards: the log files
+/// of the snapshot are never removed while it is alive, compactions included.
+pub struct Snapshot {
+    path: PathBuf,
+    map: Box<dyn KeyIndex>,
+    format: Arc<dyn LogFormat>,
+    generation: Arc<Generation>,
+    clock: Arc<dyn Clock>,
+    io: Arc<IoCounters>,
+    /// The commands still buffered under `coalesce_bytes` when it was taken.
+    buffered: Option<BufferedCommands>,
+}
+
+/// Bytes buffered for the log file `file_index`, starting at offset `start`.
+struct BufferedCommands {
+    file_index: u64,
+    start: u64,
+    bytes: Vec<u8>,
+}
+
+impl Snapshot {
+    /// Gets the value the key had when the snapshot was taken.
+    ///
+    /// Returns `None` if the key didn't exist back then, or expired since.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let metadata = match self.map.get(key) {
+            Some(metadata) if !metadata.is_expired(self.clock.now_millis()) => metadata,
+            _ => return Ok(None),
+        };
+
+        let read = |file_index: u64, position: u64, length: u64| -> Result<Command> {
+            match &self.buffered {
+                Some(buffered)
+                    if buffered.file_index == file_index && position >= buffered.start =>
+                {
+                    let reader = io::Cursor::new(&buffered.bytes);
+                    read_at(
+                        reader,
+                        position - buffered.start,
+                        length,
+                        &*self.format,
+                        &self.io,
+                    )
+                }
+                _ => {
+                    let file_path = self.path.join(format!("{}.log", file_index));
+                    let reader = BufReader::new(File::open(file_path)?);
+                    read_at(reader, position, length, &*self.format, &self.io)
+                }
+            }
+        };
+        let mut command = read(metadata.file_index, metadata.position, metadata.length)?;
+        for patch in &metadata.patches {
+            let patch = read(patch.file_index, patch.position, patch.length)?;
+            apply_patch(&mut command, patch)?;
         }
+
+        match command {
+            Command::Set { value, .. } => Ok(Some(value)),
+            Command::SetInt { .. } => Err(KvsError::WrongType),
+            Command::Patch { .. } | Command::Remove { .. } => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    /// Generation of the store the snapshot was taken in, see `KvStore::generation`.
+    pub fn generation(&self) -> u64 {
+        self.generation.number
     }
+}
 
+impl KvStore {
     /// Opens each log file and reconstructs the key/value store in memory.
     /// Keys are stored in a BTreeMap pointing to positions in their respective log file.
     ///
-    /// A new log file is always generated in this step to serve as the writer file.
+    /// The most recent log file is reused as the writer file while it is smaller than
+    /// the COMPACTION_THRESHOLD, otherwise a new log file is generated for it.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Locked` while another `KvStore` has the directory open,
+    /// which stays locked until the store is dropped.
     ///
     /// ```
     /// use crate::kvs::KvsEngine;
     /// use self::kvs::KvStore;
-    /// use std::env::current_dir;
+    /// use tempfile::TempDir;
     ///
-    /// let store = KvStore::open(current_dir().unwrap()).unwrap();
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
     /// ```
     pub fn open(dir_path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(dir_path, KvStoreOptions::default())
+    }
+
+    /// Opens the store like `KvStore::open`, which it predates.
+    #[deprecated(since = "0.2.0", note = "use `KvStore::open` instead")]
+    pub fn new(dir_path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open(dir_path)
+    }
+
+    /// Opens the store like `KvStore::open`, customized by `options`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::MessageError` for `sync_writes` combined with
+    /// `coalesce_bytes`, besides the errors of `KvStore::open`.
+    ///
+    /// ```
+    /// use self::kvs::{KvStore, KvStoreOptions};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions::default())
+    ///     .unwrap();
+    /// ```
+    pub fn open_with_options(
+        dir_path: impl Into<PathBuf>,
+        mut options: KvStoreOptions,
+    ) -> Result<KvStore> {
+        if options.sync_writes && options.coalesce_bytes.is_some() {
+            return Err(KvsError::MessageError(
+                "Sets buffered under coalesce_bytes can't be synced as they are written".to_owned(),
+            ));
+        }
+        let dir_path = dir_path.into();
+        let lock = lock_dir(&dir_path)?;
+        let mut readers = ReaderCache::new(dir_path.to_owned(), options.max_open_readers);
+        let mut index = Index::new(options.key_index);
+        let format = options
+            .format
+            .take()
+            .unwrap_or_else(|| Box::new(JsonFormat));
+
+        let file_indexes = fetch_file_indexes(dir_path.to_owned())?;
+        let file_indexes = remove_empty_logs(&dir_path, file_indexes)?;
+        let (total_umcompacted_bytes, writer_index) = if options.lazy_index {
+            // A fresh log file, as the last one might end with a torn command.
+            let writer_index = file_indexes.last().map_or(1, |last_index| last_index + 1);
+            (0, writer_index)
+        } else {
+            let (total_umcompacted_bytes, _) = load_files(
+                dir_path.to_owned(),
+                &file_indexes,
+                &mut readers,
+                &mut index,
+                &*format,
+                if options.repair {
+                    Recovery::Repair
+                } else {
+                    Recovery::TornTails
+                },
+                options.replay_progress.as_mut(),
+            )?;
+            let writer_index = writer_index(&dir_path, &file_indexes)?;
+            (total_umcompacted_bytes, writer_index)
+        };
+        let format = Arc::from(format);
+
+        let writer_path = dir_path.to_owned().join(format!("{}.log", writer_index));
+
+        // Appending mode always writes at the end of the file, so reusing an
+        // existing log is safe.
+        let mut writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&writer_path)?;
+        let header = LogHeader::new(&*format).encode()?;
+        if writer.metadata()?.len() == 0 {
+            writer.write_all(&header)?;
+        }
+
+        if file_indexes.last() != Some(&writer_index) {
+            readers.insert(writer_index, BufReader::new(File::open(&writer_path)?));
+        }
+        let writer_pos = writer.metadata()?.len();
+        let sync_directory = options.sync_directory || options.sync_writes;
+        let io = Arc::<IoCounters>::default();
+        let mut store = KvStore {
+            path: dir_path,
+            readers,
+            writer: Some(LogFile::new(writer, &options).into_writer()),
+            map: index.map,
+            soft_deleted: index.soft_deleted,
+            soft_delete_grace: options.soft_delete_grace,
+            max_version: index.max_version,
+            current_index: writer_index,
+            writer_pos,
+            umcompacted_bytes: total_umcompacted_bytes,
+            compaction_due: None,
+            compaction_step: options.compaction_step,
+            compaction: None,
+            index_built: !options.lazy_index,
+            repair: options.repair,
+            recreate_lost_writer: options.recreate_lost_writer,
+            verify_compaction: options.verify_compaction,
+            corrupt_record: None,
+            coalesce_bytes: options.coalesce_bytes,
+            coalesce_window: options.coalesce_window.unwrap_or(DEFAULT_COALESCE_WINDOW),
+            buffered_since: None,
+            audit_log: options.audit_log,
+            sync_writes: options.sync_writes,
+            sync_directory,
+            max_uncompacted_bytes: options.max_uncompacted_bytes,
+            max_key_size: options.max_key_size,
+            max_value_size: options.max_value_size,
+            max_log_size: options.max_log_size,
+            max_log_files: options.max_log_files.map(|max| max.max(2)),
+            index_policy: options.index_policy,
+            corruption_policy: options.corruption_policy,
+            missing_log_policy: options.missing_log_policy,
+            key_policy: options.key_policy,
+            swap_policy: options.swap_policy,
+            format,
+            header,
+            value_cache: options.value_cache_capacity.map(ValueCache::new),
+            blooms: index.blooms,
+            disk_reads: 0,
+            bloom_rejections: 0,
+            io: Arc::clone(&io),
+            cleaner: Cleaner::new(sync_directory.then_some(io)),
+            generation: Generation::new(0, Arc::default()),
+            clock: options.clock.unwrap_or_else(|| Arc::new(SystemClock)),
+            paused: false,
+            _lock: Some(lock),
+        };
+        store.umcompacted_bytes = store
+            .umcompacted_bytes
+            .saturating_sub(store.restorable_bytes());
+        // Empty logs might have been removed and the writer log file created.
+        store.sync_dir()?;
+        if let Some(interval) = options.compaction_interval {
+            store.compaction_due = Some(spawn_compaction_timer(interval));
+        }
+        store.maybe_compact()?;
+
+        Ok(store)
+    }
+
+    /// Opens the store without a writer, leaving the log files untouched.
+    ///
+    /// This is meant to inspect a snapshot of the directory of a live store. Replay stops
+    /// at the last complete command of each log file, ignoring a command that was being
+    /// appended when the snapshot was taken. Mutating the store fails with
+    /// `KvsError::ReadOnly`.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open_read_only(temp_dir.path()).unwrap();
+    /// println!("{:?}", store.get("foo".to_owned()));
+    /// ```
+    pub fn open_read_only(dir_path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_read_only_with_options(dir_path, KvStoreOptions::default())
+    }
+
+    /// Opens the store read-only like `KvStore::open_read_only`, reading the log files
+    /// in `options.format`, indexing keys with `options.key_index` and keeping up to
+    /// `options.max_open_readers` log files open. The options about writing are ignored.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::IncompatibleLog` if a log file was written in another
+    /// format, besides the errors of `KvStore::open_read_only`.
+    ///
+    /// ```
+    /// use self::kvs::{KvStore, KvStoreOptions};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store =
+    ///     KvStore::open_read_only_with_options(temp_dir.path(), KvStoreOptions::default())
+    ///         .unwrap();
+    /// ```
+    pub fn open_read_only_with_options(
+        dir_path: impl Into<PathBuf>,
+        mut options: KvStoreOptions,
+    ) -> Result<KvStore> {
         let dir_path = dir_path.into();
-        let mut readers: HashMap<u64, BufReader<File>> = HashMap::new();
-        let mut map: BTreeMap<String, CommandMetadata> = BTreeMap::new();
+        let mut readers = ReaderCache::new(dir_path.to_owned(), options.max_open_readers);
+        let mut index = Index::new(options.key_index);
+        let format: Arc<dyn LogFormat> = Arc::from(
+            options
+                .format
+                .take()
+                .unwrap_or_else(|| Box::new(JsonFormat)),
+        );
+
+        let file_indexes = fetch_file_indexes(dir_path.to_owned())?;
+        // A torn command at the end is left out of the position, letting
+        // `KvStore::refresh` read it once complete.
+        let (total_umcompacted_bytes, writer_pos) = load_files(
+            dir_path.to_owned(),
+            &file_indexes,
+            &mut readers,
+            &mut index,
+            &*format,
+            Recovery::Untouched,
+            None,
+        )?;
+
+        let current_index = file_indexes.last().cloned().unwrap_or(0);
+
+        Ok(KvStore {
+            path: dir_path,
+            readers,
+            writer: None,
+            map: index.map,
+            soft_deleted: index.soft_deleted,
+            soft_delete_grace: None,
+            max_version: index.max_version,
+            current_index,
+            writer_pos,
+            umcompacted_bytes: total_umcompacted_bytes,
+            compaction_due: None,
+            compaction_step: None,
+            compaction: None,
+            index_built: true,
+            repair: false,
+            recreate_lost_writer: false,
+            verify_compaction: false,
+            corrupt_record: None,
+            coalesce_bytes: None,
+            coalesce_window: DEFAULT_COALESCE_WINDOW,
+            buffered_since: None,
+            audit_log: None,
+            sync_writes: false,
+            sync_directory: false,
+            max_uncompacted_bytes: None,
+            max_key_size: None,
+            max_value_size: None,
+            max_log_size: None,
+            max_log_files: None,
+            index_policy: IndexPolicy::default(),
+            corruption_policy: CorruptionPolicy::default(),
+            missing_log_policy: MissingLogPolicy::default(),
+            key_policy: KeyPolicy::default(),
+            swap_policy: SwapPolicy::default(),
+            header: LogHeader::new(&*format).encode()?,
+            format,
+            value_cache: None,
+            blooms: index.blooms,
+            disk_reads: 0,
+            bloom_rejections: 0,
+            io: Arc::default(),
+            cleaner: Cleaner::new(None),
+            generation: Generation::new(0, Arc::default()),
+            clock: options.clock.unwrap_or_else(|| Arc::new(SystemClock)),
+            paused: false,
+            _lock: None,
+        })
+    }
+
+    /// Folds the commands appended to the log files since the store was opened or
+    /// last refreshed into the index, and returns how many were applied.
+    ///
+    /// It lets a store opened with `KvStore::open_read_only` follow a directory
+    /// written by another process: new commands of the last log file are read, as
+    /// are the log files created after it. A command still being written is left
+    /// for the next refresh. Compactions done meanwhile require reopening the store.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::MessageError` for a store which isn't read-only, since
+    /// it is the only writer of its directory.
+    pub fn refresh(&mut self) -> Result<usize> {
+        if self.writer.is_some() {
+            return Err(KvsError::MessageError(
+                "Only read-only stores can be refreshed".to_owned(),
+            ));
+        }
+
+        let mut applied = 0;
+        let last_index = self.readers.file_indexes().last();
+        if last_index == Some(self.current_index) {
+            applied += self.replay_from(self.current_index, self.writer_pos)?;
+        }
+
+        for file_index in fetch_file_indexes(&self.path)? {
+            if last_index.is_some_and(|last_index| file_index <= last_index) {
+                continue;
+            }
+            let file_path = self.path.join(format!("{}.log", file_index));
+            let reader = BufReader::new(OpenOptions::new().read(true).open(file_path)?);
+            self.readers.insert(file_index, reader);
+            self.current_index = file_index;
+            applied += self.replay_from(file_index, 0)?;
+        }
+
+        Ok(applied)
+    }
+
+    /// Indexes the complete commands of the log file `file_index` starting at offset
+    /// `from`, moving the position of the store past them, and returns their amount.
+    fn replay_from(&mut self, file_index: u64, from: u64) -> Result<usize> {
+        let file_path = self.path.join(format!("{}.log", file_index));
+        let mut reader = BufReader::new(OpenOptions::new().read(true).open(&file_path)?);
+        let start = if from > 0 {
+            from
+        } else {
+            match LogHeader::read(&mut reader) {
+                Ok((Some(header), start)) => {
+                    header.check(&file_path, &*self.format)?;
+                    self.max_version = self.max_version.max(header.max_version.unwrap_or(0));
+                    start
+                }
+                Ok((None, _)) => 0,
+                Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    self.writer_pos = 0;
+                    return Ok(0);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        reader.seek(SeekFrom::Start(start))?;
+
+        let mut pos = start;
+        let mut applied = 0;
+        for command_result in self.format.stream(&mut reader) {
+            let (command, next_pos) = match command_result {
+                Ok((command, offset)) => (command, start + offset),
+                Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+
+            note_key(&mut self.blooms, file_index, &command);
+            self.umcompacted_bytes += load_command(
+                &mut *self.map,
+                &mut self.soft_deleted,
+                &mut self.max_version,
+                command,
+                file_index,
+                pos,
+                next_pos,
+            );
+            pos = next_pos;
+            applied += 1;
+        }
+        self.writer_pos = pos;
+
+        Ok(applied)
+    }
+
+    /// Rewrites every live command into a fresh log file and removes the stale ones.
+    ///
+    /// This happens automatically once the total amount of umcompacted bytes surpasses
+    /// the COMPACTION_THRESHOLD, but it can also be triggered manually.
+    ///
+    /// With `KvStoreOptions::verify_compaction` set, it fails with
+    /// `KvsError::CorruptRecord` naming the first key whose command is corrupt.
+    /// Automatic compactions stop once one of them finds it, until this succeeds.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// let report = store.compact().unwrap();
+    /// println!("{} bytes reclaimed", report.bytes_reclaimed());
+    /// ```
+    pub fn compact(&mut self) -> Result<CompactionReport> {
+        let report = self.compact_logs()?;
+        // Getting through means the corrupt record which stopped them is gone.
+        self.corrupt_record = None;
+
+        Ok(report)
+    }
+
+    fn compact_logs(&mut self) -> Result<CompactionReport> {
+        self.check_writable()?;
+        self.build_index()?;
+        self.write_buffered()?;
+        if let Some(step) = self.compaction_step {
+            return self.compact_step(step);
+        }
+        if self.verify_compaction {
+            // Checked upfront, a compaction failing halfway would leave the writer
+            // behind its output.
+            self.check_live_records()?;
+        }
+
+        let bytes_before = self.log_files_size()?;
+        let compaction_index = self.current_index + 1;
+        self.current_index += 2;
+
+        let (mut compaction_writer, mut compaction_writer_pos) =
+            self.create_compaction_log(compaction_index)?;
+        // Expired keys are dropped along with the stale log files.
+        let now = self.clock.now_millis();
+        self.map
+            .retain(&mut |_, metadata| !metadata.is_expired(now));
+
+        for cmd_metadata in self.map.values_mut() {
+            let (len, patches) = copy_patched_command(
+                &mut self.readers,
+                &self.io,
+                &*self.format,
+                cmd_metadata,
+                compaction_index,
+                &mut compaction_writer,
+            )?;
+            *cmd_metadata = CommandMetadata {
+                file_index: compaction_index,
+                position: compaction_writer_pos,
+                length: len,
+                stale: 0,
+                expires_at: cmd_metadata.expires_at,
+                version: cmd_metadata.version,
+                value_len: cmd_metadata.value_len,
+                modified_at: cmd_metadata.modified_at,
+                patches,
+            };
+            compaction_writer_pos += len;
+        }
+
+        self.carry_soft_deleted(
+            compaction_index,
+            &mut compaction_writer,
+            &mut compaction_writer_pos,
+            now,
+        )?;
+
+        compaction_writer.flush()?;
+        self.io.flushed();
+        // Stale logs are only removed once the commands copied from them are durable.
+        compaction_writer.sync_data()?;
+        self.sync_dir()?;
+        let stale_log_indexes: Vec<u64> = self
+            .readers
+            .file_indexes()
+            .filter(|index| *index < compaction_index)
+            .collect();
+
+        let files_removed = stale_log_indexes.len() as u64;
+        // Only the store holds the generation when no snapshot is alive, snapshots
+        // can't be taken meanwhile as they borrow the store.
+        let snapshotted = Arc::strong_count(&self.generation) > 1;
+        match self.index_policy {
+            // Names can't be reused while snapshots still read the stale log files,
+            // those taken in older generations included.
+            IndexPolicy::ReuseLowest if !snapshotted && !self.generation.has_pinned_logs() => {
+                self.renumber_compacted_log(compaction_index, stale_log_indexes)?
+            }
+            _ => self.remove_stale_logs(stale_log_indexes, snapshotted),
+        }
+        self.generation = self.generation.next();
+        self.rebuild_blooms();
+
+        self.open_writer()?;
+        self.umcompacted_bytes = 0;
+
+        self.compaction_report(bytes_before, files_removed)
+    }
+
+    /// Replays the log files into the index of a store opened with `lazy_index`,
+    /// commands appended since it was opened included. It does nothing once built.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::{KvStore, KvStoreOptions};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let options = KvStoreOptions {
+    ///     lazy_index: true,
+    ///     ..KvStoreOptions::default()
+    /// };
+    /// let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// store.build_index().unwrap();
+    /// assert_eq!(store.key_count(), 1);
+    /// ```
+    pub fn build_index(&mut self) -> Result<()> {
+        if self.index_built {
+            return Ok(());
+        }
+        self.write_buffered()?;
+
+        // The keys set meanwhile are indexed again as their commands get replayed.
+        self.map.retain(&mut |_, _| false);
+        let mut index = Index {
+            map: std::mem::replace(&mut self.map, KeyIndexKind::default().create()),
+            soft_deleted: BTreeMap::new(),
+            blooms: LogBlooms::default(),
+            max_version: 0,
+        };
+        let file_indexes = fetch_file_indexes(&self.path)?;
+        let (umcompacted_bytes, _) = load_files(
+            &self.path,
+            &file_indexes,
+            &mut self.readers,
+            &mut index,
+            &*self.format,
+            if self.repair {
+                Recovery::Repair
+            } else {
+                Recovery::TornTails
+            },
+            None,
+        )?;
+        self.map = index.map;
+        self.soft_deleted = index.soft_deleted;
+        self.blooms = index.blooms;
+        self.max_version = index.max_version;
+        self.umcompacted_bytes = umcompacted_bytes.saturating_sub(self.restorable_bytes());
+        self.index_built = true;
+
+        Ok(())
+    }
+
+    /// Returns `true` while an incremental compaction is under way, see
+    /// `KvStoreOptions::compaction_step`.
+    pub fn is_compacting(&self) -> bool {
+        self.compaction.is_some()
+    }
+
+    /// Fails with `KvsError::CorruptRecord` if the command of a key a compaction would
+    /// copy is corrupt, see `KvStoreOptions::verify_compaction`.
+    fn check_live_records(&mut self) -> Result<()> {
+        let now = self.clock.now_millis();
+        let grace = self.soft_delete_grace;
+        let live = self
+            .map
+            .iter()
+            .filter(|(_, metadata)| !metadata.is_expired(now));
+        let soft_deleted = self
+            .soft_deleted
+            .iter()
+            .filter(|(_, deleted)| grace.is_some_and(|grace| deleted.within(grace, now)))
+            .map(|(key, deleted)| (key, &deleted.set));
+        for (key, metadata) in live.chain(soft_deleted) {
+            check_record(&mut self.readers, &*self.format, &self.io, key, metadata)?;
+        }
+
+        Ok(())
+    }
+
+    /// Performs the next step of an incremental compaction, starting one if none is
+    /// under way, copying live commands until `step` bytes were copied.
+    fn compact_step(&mut self, step: u64) -> Result<CompactionReport> {
+        let mut compaction = match self.compaction.take() {
+            Some(compaction) => compaction,
+            None => self.start_incremental_compaction()?,
+        };
+
+        let now = self.clock.now_millis();
+        let mut copied = 0;
+        while copied < step {
+            let key = match compaction.keys.next() {
+                Some(key) => key,
+                None => break,
+            };
+            let metadata = match self.map.get(&key) {
+                // Keys written since the compaction started already live past it, the
+                // commands they superseded go away along with the stale log files.
+                Some(metadata) if metadata.file_index >= compaction.index => {
+                    if let Some(metadata) = self.map.get_mut(&key) {
+                        metadata.stale = 0;
+                    }
+                    continue;
+                }
+                Some(metadata) if metadata.is_expired(now) => {
+                    self.map.remove(&key);
+                    continue;
+                }
+                Some(metadata) => metadata,
+                None => continue,
+            };
+
+            if self.verify_compaction {
+                check_record(&mut self.readers, &*self.format, &self.io, &key, metadata)?;
+            }
+            // Patches written since the compaction started get replayed after its
+            // output, they are left out of the value copied.
+            let (len, patches) = copy_patched_command(
+                &mut self.readers,
+                &self.io,
+                &*self.format,
+                metadata,
+                compaction.index,
+                &mut compaction.writer,
+            )?;
+            let metadata = CommandMetadata {
+                file_index: compaction.index,
+                position: compaction.position,
+                length: len,
+                stale: 0,
+                patches,
+                ..metadata.clone()
+            };
+            self.blooms.insert(compaction.index, &key);
+            self.map.insert(key, metadata);
+            compaction.position += len;
+            copied += len;
+        }
+
+        if compaction.keys.len() > 0 {
+            self.compaction = Some(compaction);
+            let bytes = self.log_files_size()?;
+            return Ok(CompactionReport {
+                bytes_before: bytes,
+                bytes_after: bytes,
+                files_removed: 0,
+            });
+        }
+
+        let compaction_index = compaction.index;
+        self.carry_soft_deleted(
+            compaction_index,
+            &mut compaction.writer,
+            &mut compaction.position,
+            now,
+        )?;
+        // Whatever still lives in the stale log files expired before getting copied.
+        self.map
+            .retain(&mut |_, metadata| metadata.file_index >= compaction_index);
+
+        compaction.writer.flush()?;
+        self.io.flushed();
+        compaction.writer.sync_data()?;
+        self.sync_dir()?;
+        let stale_log_indexes: Vec<u64> = self
+            .readers
+            .file_indexes()
+            .filter(|index| *index < compaction_index)
+            .collect();
+        let files_removed = stale_log_indexes.len() as u64;
+        let snapshotted = Arc::strong_count(&self.generation) > 1;
+        self.remove_stale_logs(stale_log_indexes, snapshotted);
+        self.generation = self.generation.next();
+        self.rebuild_blooms();
+
+        // Only what got superseded since the compaction started is left to reclaim.
+        self.umcompacted_bytes = self
+            .umcompacted_bytes
+            .saturating_sub(compaction.umcompacted_at_start);
+
+        self.compaction_report(compaction.bytes_before, files_removed)
+    }
+
+    /// Creates the output of a compaction and points the writer past it, so writes
+    /// made between the steps don't go to a log file about to be removed.
+    fn start_incremental_compaction(&mut self) -> Result<IncrementalCompaction> {
+        let bytes_before = self.log_files_size()?;
+        let index = self.current_index + 1;
+        self.current_index += 2;
+
+        let (writer, position) = self.create_compaction_log(index)?;
+        self.open_writer()?;
+
+        let keys: Vec<String> = self.map.iter().map(|(key, _)| key.to_owned()).collect();
+        Ok(IncrementalCompaction {
+            index,
+            writer,
+            position,
+            keys: keys.into_iter(),
+            bytes_before,
+            umcompacted_at_start: self.umcompacted_bytes,
+        })
+    }
+
+    /// Creates the log file at `compaction_index` receiving the output of a compaction,
+    /// returning it along with the offset its first command goes at.
+    ///
+    /// Its header records the highest version handed out so far, which the commands
+    /// of the removed keys dropped by the compaction might have held.
+    fn create_compaction_log(&mut self, compaction_index: u64) -> Result<(File, u64)> {
+        let compaction_path = self
+            .path
+            .to_owned()
+            .join(format!("{}.log", compaction_index));
+        let mut compaction_writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&compaction_path)?;
+        let header = LogHeader {
+            max_version: Some(self.max_version),
+            ..LogHeader::new(&*self.format)
+        }
+        .encode()?;
+        compaction_writer.write_all(&header)?;
+        self.io.wrote(header.len() as u64);
+
+        self.readers.insert(
+            compaction_index,
+            BufReader::new(File::open(&compaction_path)?),
+        );
+
+        Ok((compaction_writer, header.len() as u64))
+    }
+
+    /// Bytes taken by the soft deleted keys still within their grace period, along with
+    /// their tombstones, which compactions carry over instead of reclaiming.
+    fn restorable_bytes(&self) -> u64 {
+        let grace = match self.soft_delete_grace {
+            Some(grace) => grace,
+            None => return 0,
+        };
+        let now = self.clock.now_millis();
+        self.soft_deleted
+            .values()
+            .filter(|deleted| deleted.within(grace, now))
+            .map(|deleted| deleted.set.record_len() + deleted.tombstone_length)
+            .sum()
+    }
+
+    /// Copies the commands of the soft deleted keys still within their grace period
+    /// which live before `compaction_index` to the output of a compaction, dropping
+    /// the other ones.
+    fn carry_soft_deleted(
+        &mut self,
+        compaction_index: u64,
+        writer: &mut File,
+        position: &mut u64,
+        now: u64,
+    ) -> Result<()> {
+        // Soft deleted keys keep both their last command and its tombstone, in that
+        // order, until their grace period elapses.
+        let grace = self.soft_delete_grace;
+        self.soft_deleted
+            .retain(|_, deleted| grace.is_some_and(|grace| deleted.within(grace, now)));
+        for (key, deleted) in self.soft_deleted.iter_mut() {
+            if deleted.set.file_index < compaction_index {
+                if self.verify_compaction {
+                    check_record(
+                        &mut self.readers,
+                        &*self.format,
+                        &self.io,
+                        key,
+                        &deleted.set,
+                    )?;
+                }
+                let (len, patches) = copy_patched_command(
+                    &mut self.readers,
+                    &self.io,
+                    &*self.format,
+                    &deleted.set,
+                    compaction_index,
+                    writer,
+                )?;
+                self.blooms.insert(compaction_index, key);
+                deleted.set = CommandMetadata {
+                    file_index: compaction_index,
+                    position: *position,
+                    length: len,
+                    stale: 0,
+                    patches,
+                    ..deleted.set.clone()
+                };
+                *position += len;
+            }
+
+            if deleted.tombstone_file_index < compaction_index {
+                let len = copy_command(
+                    &mut self.readers,
+                    &self.io,
+                    deleted.tombstone_file_index,
+                    deleted.tombstone_position,
+                    deleted.tombstone_length,
+                    writer,
+                )?;
+                deleted.tombstone_file_index = compaction_index;
+                deleted.tombstone_position = *position;
+                *position += len;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the log files a compaction made stale, or hands them over to the
+    /// snapshots still reading them.
+    fn remove_stale_logs(&mut self, stale_log_indexes: Vec<u64>, snapshotted: bool) {
+        for stale_log_index in stale_log_indexes {
+            let reader = self.readers.remove(stale_log_index);
+            let stale_path = self
+                .path
+                .to_owned()
+                .join(format!("{}.log", stale_log_index));
+
+            if snapshotted {
+                self.generation
+                    .pinned_logs
+                    .lock()
+                    .unwrap()
+                    .insert(stale_log_index);
+                let mut stale_logs = self.generation.stale_logs.lock().unwrap();
+                stale_logs.push((stale_log_index, stale_path));
+            } else {
+                // Closing and removing files is left to the cleaner, so compacting
+                // many of them at once doesn't hold up the operation that
+                // triggered it.
+                self.cleaner.remove(reader, stale_path);
+            }
+        }
+    }
+
+    /// Rebuilds the bloom filters from the index once a compaction moved the keys
+    /// to its output, forgetting the log files it removed.
+    fn rebuild_blooms(&mut self) {
+        let mut blooms = LogBlooms::default();
+        for (key, metadata) in self.map.iter() {
+            blooms.insert(metadata.file_index, key);
+        }
+        for (key, deleted) in &self.soft_deleted {
+            blooms.insert(deleted.set.file_index, key);
+        }
+        self.blooms = blooms;
+    }
+
+    fn compaction_report(&self, bytes_before: u64, files_removed: u64) -> Result<CompactionReport> {
+        let report = CompactionReport {
+            bytes_before,
+            bytes_after: self.log_files_size()?,
+            files_removed,
+        };
+        info!(
+            "Compaction reclaimed {} bytes, removing {} log files",
+            report.bytes_reclaimed(),
+            report.files_removed
+        );
+
+        Ok(report)
+    }
+
+    /// Moves the output of a compaction to the index 0, removing the stale log files
+    /// whose names get reused, and points the next writer to the index 1.
+    ///
+    /// The compacted log file holds every live command until it is renamed, so a crash
+    /// in between leaves nothing behind but stale log files or a higher index.
+    fn renumber_compacted_log(
+        &mut self,
+        compaction_index: u64,
+        stale_log_indexes: Vec<u64>,
+    ) -> Result<()> {
+        for stale_log_index in stale_log_indexes {
+            self.readers.remove(stale_log_index);
+            let stale_path = self
+                .path
+                .to_owned()
+                .join(format!("{}.log", stale_log_index));
+            match std::fs::remove_file(stale_path) {
+                // Such as the writer log file removed from under the store.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                res => res?,
+            }
+        }
+
+        let compaction_path = self.path.join(format!("{}.log", compaction_index));
+        let renumbered_path = self.path.join("0.log");
+        std::fs::rename(&compaction_path, &renumbered_path)?;
+
+        // An open reader keeps pointing to the file once renamed.
+        let reader = match self.readers.remove(compaction_index) {
+            Some(reader) => reader,
+            None => BufReader::new(File::open(&renumbered_path)?),
+        };
+        self.readers.insert(0, reader);
+        for cmd_metadata in self.map.values_mut() {
+            cmd_metadata.file_index = 0;
+        }
+        for deleted in self.soft_deleted.values_mut() {
+            deleted.set.file_index = 0;
+            deleted.tombstone_file_index = 0;
+        }
+        self.current_index = 1;
+
+        Ok(())
+    }
+
+    /// Sets the value of a key like `KvsEngine::set`, the key expiring once `ttl` elapses.
+    ///
+    /// Expired keys behave as if they were removed, their commands are dropped by the
+    /// next compaction.
+    ///
+    /// ```
+    /// use self::kvs::KvStore;
+    /// use std::time::Duration;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set_with_ttl("foo".to_owned(), "bar".to_owned(), Duration::from_secs(60)).unwrap();
+    /// ```
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = self
+            .clock
+            .now_millis()
+            .saturating_add(ttl.as_millis() as u64);
+        self.append_set(key, value, Some(expires_at)).map(|_| ())
+    }
+
+    /// Sets the value of a key like `KvsEngine::set` as long as it isn't present yet,
+    /// its integer included, expired keys counting as absent.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyExists` without writing anything if the key is
+    /// present, its value being left intact.
+    ///
+    /// ```
+    /// use self::kvs::{KvStore, KvsError};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.insert("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// assert!(matches!(
+    ///     store.insert("foo".to_owned(), "baz".to_owned()),
+    ///     Err(KvsError::KeyExists)
+    /// ));
+    /// ```
+    pub fn insert(&mut self, key: String, value: String) -> Result<()> {
+        self.build_index()?;
+        let now = self.clock.now_millis();
+        if self
+            .map
+            .get(&key)
+            .is_some_and(|metadata| !metadata.is_expired(now))
+        {
+            return Err(KvsError::KeyExists);
+        }
+
+        self.append_set(key, value, None).map(|_| ())
+    }
+
+    /// Restores a key removed less than `KvStoreOptions::soft_delete_grace` ago to the
+    /// value it held, its expiry included.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the key wasn't removed, if its grace
+    /// period elapsed or if soft deletes are disabled.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::{KvStore, KvStoreOptions};
+    /// use std::time::Duration;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let options = KvStoreOptions {
+    ///     soft_delete_grace: Some(Duration::from_secs(60)),
+    ///     ..KvStoreOptions::default()
+    /// };
+    /// let mut store = KvStore::open_with_options(temp_dir.path(), options).unwrap();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// store.remove("foo".to_owned()).unwrap();
+    /// store.undelete("foo".to_owned()).unwrap();
+    /// assert_eq!(store.get("foo".to_owned()).unwrap(), Some("bar".to_owned()));
+    /// ```
+    pub fn undelete(&mut self, key: String) -> Result<()> {
+        self.build_index()?;
+        self.check_writable()?;
+        self.write_buffered()?;
+        let grace = self.soft_delete_grace.ok_or(KvsError::KeyNotFound)?;
+        let now = self.clock.now_millis();
+        let set = match self.soft_deleted.get(&key) {
+            Some(deleted) if deleted.within(grace, now) => deleted.set.clone(),
+            _ => return Err(KvsError::KeyNotFound),
+        };
+
+        let reader = self.readers.get_mut(set.file_index)?;
+        self.disk_reads += 1;
+        let command = read_command(reader, &set, &*self.format, &self.io)?;
+        match patch_command(
+            &mut self.readers,
+            command,
+            &set.patches,
+            &*self.format,
+            &self.io,
+        )? {
+            Command::Set {
+                value, expires_at, ..
+            } => self.append_set(key, value, expires_at).map(|_| ()),
+            Command::SetInt { value, .. } => self.set_int(key, value),
+            Command::Patch { .. } | Command::Remove { .. } => Err(KvsError::UnexpectedCommand),
+        }
+    }
+
+    /// Runs the checks of `KvsEngine::set` without writing anything, telling whether
+    /// setting `key` to `value` would be accepted.
+    ///
+    /// A set might still fail on I/O errors, such as the disk running out of space,
+    /// which can't be foreseen.
+    ///
+    /// # Errors
+    ///
+    /// It returns the error the set would fail with: `KvsError::ReadOnly` for a
+    /// read-only store, `KvsError::Paused` while writes are paused,
+    /// `KvsError::KeyTooLarge` and `KvsError::ValueTooLarge` past the size limits of
+    /// `KvStoreOptions` and `KvsError::InvalidKey` for a key refused by its key policy.
+    ///
+    /// ```
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.try_set("foo", "bar").unwrap();
+    /// ```
+    pub fn try_set(&self, key: &str, value: &str) -> Result<()> {
+        self.check_writable()?;
+        self.check_key(key)?;
+        match self.max_value_size {
+            Some(max) if value.len() > max => return Err(KvsError::ValueTooLarge(max)),
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// Sets `key` to `value` like `KvsEngine::set`, taking anything convertible to a
+    /// `String` such as a `&str`.
+    ///
+    /// ```
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo", "bar").unwrap();
+    /// ```
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> Result<()> {
+        self.append_set(key.into(), value.into(), None).map(|_| ())
+    }
+
+    /// Sets `key` to the UTF-8 value read from `reader`, streaming it into the log
+    /// file chunk by chunk instead of holding the whole value in memory.
+    ///
+    /// Stores with an audit log, or whose log files aren't in the JSON format, read
+    /// the value into memory first as their commands can't be streamed. Like any set,
+    /// it compacts the log files once the value it supersedes is past the
+    /// COMPACTION_THRESHOLD.
+    ///
+    /// # Errors
+    ///
+    /// It returns the errors of `KvsEngine::set`, an `io::ErrorKind::InvalidData`
+    /// error if the value isn't UTF-8 and the errors of `reader` itself. The log file
+    /// is left as it was before the call on failure.
+    ///
+    /// ```
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set_stream("foo".to_owned(), "bar".as_bytes()).unwrap();
+    /// assert_eq!(store.get("foo").unwrap(), Some("bar".to_owned()));
+    /// ```
+    pub fn set_stream(&mut self, key: String, mut reader: impl Read) -> Result<()> {
+        self.try_set(&key, "")?;
+        if self.audit_log.is_some() || self.format.name() != JsonFormat.name() {
+            let mut value = String::new();
+            reader.read_to_string(&mut value)?;
+            return self.set(key, value);
+        }
+
+        self.apply_backpressure()?;
+        // Buffered commands go first, the value being written straight to the file.
+        self.write_buffered()?;
+        self.roll_writer_if_full(0)?;
+        let modified_at = self.clock.now_millis();
+        let version = self.next_version(&key);
+        let max_value_size = self.max_value_size;
+
+        let writer = self.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        let pos = writer.seek(SeekFrom::End(0))?;
+        self.io.seeked();
+        let streamed =
+            write_streamed_set(writer, &key, reader, modified_at, version, max_value_size)
+                .and_then(|value_len| {
+                    writer.flush()?;
+                    Ok(value_len)
+                });
+        let value_len = match streamed {
+            Ok(value_len) => value_len,
+            Err(e) => {
+                self.truncate_writer(pos)?;
+                return Err(e);
+            }
+        };
+        if self.sync_writes {
+            writer.get_ref().sync_data()?;
+        }
+        let new_pos = writer.seek(SeekFrom::End(0))?;
+        self.io.wrote(new_pos - pos);
+        self.io.flushed();
+        self.io.seeked();
+        self.writer_pos = new_pos;
+        if let Some(cache) = self.value_cache.as_mut() {
+            cache.invalidate(&key);
+        }
+
+        let metadata = CommandMetadata {
+            file_index: self.current_index,
+            position: pos,
+            length: new_pos - pos,
+            stale: 0,
+            expires_at: None,
+            version: version.unwrap_or(0),
+            value_len: Some(value_len),
+            modified_at: Some(modified_at),
+            patches: Vec::new(),
+        };
+        // Compacts when due, like the sets going through `append_key_command`.
+        self.index_command(key, metadata).map(|_| ())
+    }
+
+    /// Gets the value of `key` like `KvsEngine::get`, borrowing the key so looking it
+    /// up doesn't allocate.
+    ///
+    /// ```
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo", "bar").unwrap();
+    /// assert_eq!(store.get("foo").unwrap(), Some("bar".to_owned()));
+    /// ```
+    pub fn get<'k>(&mut self, key: impl Into<Cow<'k, str>>) -> Result<Option<String>> {
+        let key = key.into();
+        let key: &str = &key;
+        self.build_index()?;
+        self.maybe_compact()?;
+        self.write_buffered_for(key)?;
+
+        if !self.blooms.may_contain(key) {
+            self.bloom_rejections += 1;
+            return Ok(None);
+        }
+        let metadata = match self.map.get(key) {
+            Some(metadata) if !metadata.is_expired(self.clock.now_millis()) => metadata,
+            _ => return Ok(None),
+        };
+
+        if let Some(value) = self.value_cache.as_mut().and_then(|cache| cache.get(key)) {
+            return Ok(Some(value));
+        }
+
+        let reader = match self.readers.get_mut(metadata.file_index) {
+            Ok(reader) => reader,
+            Err(e) => return self.apply_missing_log_policy(key, e),
+        };
+        self.disk_reads += 1;
+        let command = match read_command(reader, metadata, &*self.format, &self.io) {
+            Ok(command) => patch_command(
+                &mut self.readers,
+                command,
+                &metadata.patches,
+                &*self.format,
+                &self.io,
+            ),
+            Err(e) => Err(e),
+        };
+
+        match self.apply_corruption_policy(key, command)? {
+            None => Ok(None),
+            Some(Command::Set { value, .. }) => {
+                if let Some(cache) = self.value_cache.as_mut() {
+                    cache.insert(key.to_owned(), value.to_owned());
+                }
+                Ok(Some(value))
+            }
+            Some(Command::SetInt { .. }) => Err(KvsError::WrongType),
+            Some(Command::Patch { .. }) | Some(Command::Remove { .. }) => {
+                Err(KvsError::UnexpectedCommand)
+            }
+        }
+    }
+
+    /// Removes `key` like `KvsEngine::remove`, borrowing the key.
+    pub fn remove<'k>(&mut self, key: impl Into<Cow<'k, str>>) -> Result<()> {
+        let key = key.into();
+        let key: &str = &key;
+        self.build_index()?;
+        self.check_writable()?;
+        match self.map.get(key) {
+            Some(metadata) if !metadata.is_expired(self.clock.now_millis()) => (),
+            _ => return Err(KvsError::KeyNotFound),
+        }
+        self.apply_backpressure()?;
+        let removed = self.map.remove(key).ok_or(KvsError::KeyNotFound)?;
+        if let Some(cache) = self.value_cache.as_mut() {
+            cache.invalidate(key);
+        }
+
+        let removed_at = self.soft_delete_grace.map(|_| self.clock.now_millis());
+        let cmd = Command::Remove {
+            key: key.to_owned(),
+            removed_at,
+        };
+        let mut bytes = Vec::new();
+        self.format.write_command(&mut bytes, &cmd)?;
+        self.roll_writer_if_full(bytes.len() as u64)?;
+        let pos = self.write_commands(&[&bytes])?;
+        self.audit(&cmd)?;
+
+        // The tombstone itself is dropped by the next compaction, along with the
+        // command it removes, unless compactions carry both over for an undelete.
+        if removed_at.is_none() {
+            self.umcompacted_bytes += removed.record_len() + bytes.len() as u64;
+        }
+        if let Some(removed_at) = removed_at {
+            self.soft_deleted.insert(
+                key.to_owned(),
+                SoftDeleted {
+                    set: removed,
+                    tombstone_file_index: self.current_index,
+                    tombstone_position: pos,
+                    tombstone_length: bytes.len() as u64,
+                    removed_at,
+                },
+            );
+        }
+        self.maybe_compact()?;
+
+        Ok(())
+    }
+
+    /// Sets `key` to the integer `value`, stored natively so `KvStore::increment`
+    /// doesn't go through strings. Integer keys never expire.
+    ///
+    /// ```
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set_int("foo".to_owned(), 41).unwrap();
+    /// assert_eq!(store.increment("foo".to_owned(), 1).unwrap(), 42);
+    /// ```
+    pub fn set_int(&mut self, key: String, value: i64) -> Result<()> {
+        self.check_writable()?;
+        self.check_key(&key)?;
+
+        let cmd = Command::SetInt {
+            key: key.to_owned(),
+            value,
+            modified_at: Some(self.clock.now_millis()),
+            version: self.next_version(&key),
+        };
+        self.append_key_command(key, cmd, None).map(|_| ())
+    }
+
+    /// Gets the integer set through `KvStore::set_int`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::WrongType` if the key holds a string.
+    pub fn get_int(&mut self, key: String) -> Result<Option<i64>> {
+        self.build_index()?;
+        self.maybe_compact()?;
+        self.write_buffered_for(&key)?;
+
+        let metadata = match self.map.get(&key) {
+            Some(metadata) if !metadata.is_expired(self.clock.now_millis()) => metadata,
+            _ => return Ok(None),
+        };
+
+        let reader = match self.readers.get_mut(metadata.file_index) {
+            Ok(reader) => reader,
+            Err(e) => return self.apply_missing_log_policy(&key, e),
+        };
+        self.disk_reads += 1;
+        let command = read_command(reader, metadata, &*self.format, &self.io);
+
+        match self.apply_corruption_policy(&key, command)? {
+            None => Ok(None),
+            Some(Command::SetInt { value, .. }) => Ok(Some(value)),
+            Some(Command::Set { .. }) => Err(KvsError::WrongType),
+            Some(Command::Patch { .. }) | Some(Command::Remove { .. }) => {
+                Err(KvsError::UnexpectedCommand)
+            }
+        }
+    }
+
+    /// Adds `delta` to the integer of `key`, a missing key counting as 0, and returns
+    /// the result.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::WrongType` if the key holds a string and
+    /// `KvsError::IntegerOverflow` if the result doesn't fit an `i64`, nothing being
+    /// written in both cases.
+    pub fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.build_index()?;
+        let current = self.get_int(key.to_owned())?.unwrap_or(0);
+        let value = current
+            .checked_add(delta)
+            .ok_or(KvsError::IntegerOverflow)?;
+
+        self.set_int(key, value)?;
+        Ok(value)
+    }
+
+    /// Gets the value of a key like `KvsEngine::get` along with its version.
+    ///
+    /// Every set of a key increments its version, which allows conditional writes
+    /// through `KvStore::set_if_version`. A key set while missing starts past every
+    /// version handed out so far, so that a version never comes back once removed.
+    /// Versions are recorded along with the sets in the log files, surviving
+    /// compactions and reopens.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// let version = store.set_versioned("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// assert_eq!(
+    ///     store.get_versioned("foo".to_owned()).unwrap(),
+    ///     Some(("bar".to_owned(), version))
+    /// );
+    /// ```
+    pub fn get_versioned(&mut self, key: String) -> Result<Option<(String, u64)>> {
+        self.build_index()?;
+        let value = self.get(&key)?;
+        let version = self.map.get(&key).map_or(0, |metadata| metadata.version);
+
+        Ok(value.map(|value| (value, version)))
+    }
+
+    /// Sets the value of a key like `KvsEngine::set`, returning its new version.
+    pub fn set_versioned(&mut self, key: String, value: String) -> Result<u64> {
+        self.build_index()?;
+        self.append_set(key, value, None)
+    }
+
+    /// Sets the value of a key only if its current version is `expected_version`,
+    /// returning its new version.
+    ///
+    /// A missing or expired key is at version 0, so it can be created only once by
+    /// expecting that version.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::VersionMismatch` with the current version of the key
+    /// if it doesn't match the expected one, nothing being written.
+    ///
+    /// ```
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// let version = store.set_versioned("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// store.set_if_version("foo".to_owned(), "baz".to_owned(), version).unwrap();
+    /// assert!(store.set_if_version("foo".to_owned(), "qux".to_owned(), version).is_err());
+    /// ```
+    pub fn set_if_version(
+        &mut self,
+        key: String,
+        value: String,
+        expected_version: u64,
+    ) -> Result<u64> {
+        self.build_index()?;
+        let now = self.clock.now_millis();
+        let current_version = self
+            .map
+            .get(&key)
+            .map_or(0, |metadata| metadata.live_version(now));
+
+        if current_version != expected_version {
+            return Err(KvsError::VersionMismatch(current_version));
+        }
+
+        self.append_set(key, value, None)
+    }
+
+    /// Applies the edits of `patch` to the string value of `key`, appending only them
+    /// to the writer log file instead of the whole value.
+    ///
+    /// Reads apply the patches recorded since the last full value on top of it, and
+    /// compactions write the patched value back in full.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found,
+    /// `KvsError::WrongType` if it holds an integer and `KvsError::InvalidRange` if
+    /// an edit doesn't fit the value, nothing being written then.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::{KvStore, Patch};
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// store.patch("foo".to_owned(), Patch::new().replace(2..3, "z")).unwrap();
+    /// assert_eq!(store.get("foo".to_owned()).unwrap(), Some("baz".to_owned()));
+    /// ```
+    pub fn patch(&mut self, key: String, patch: Patch) -> Result<()> {
+        self.build_index()?;
+        self.check_writable()?;
+        let mut value = self.get(&key)?.ok_or(KvsError::KeyNotFound)?;
+        patch.apply(&mut value)?;
+        self.try_set(&key, &value)?;
+
+        let modified_at = Some(self.clock.now_millis());
+        let cmd = Command::Patch {
+            key: key.to_owned(),
+            patch,
+            modified_at,
+        };
+        let (pos, new_pos) = self.append_command(&key, &cmd)?;
+        let file_index = self.current_index;
+        let metadata = self.map.get_mut(&key).ok_or(KvsError::KeyNotFound)?;
+        metadata.patches.push(PatchRecord {
+            file_index,
+            position: pos,
+            length: new_pos - pos,
+        });
+        metadata.version += 1;
+        self.max_version = self.max_version.max(metadata.version);
+        metadata.value_len = Some(value.len() as u64);
+        metadata.modified_at = modified_at;
+        if let Some(cache) = self.value_cache.as_mut() {
+            cache.insert(key, value);
+        }
+
+        self.maybe_compact()?;
+
+        Ok(())
+    }
+
+    /// Moves the value associated with `from` to the `to` key in one logical operation,
+    /// the value keeping its type and expiry.
+    ///
+    /// A Command::Set of `to` and a Command::Remove of `from` are appended to the
+    /// writer log file and flushed together.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if `from` is not found.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// store.rename("foo".to_owned(), "baz".to_owned()).unwrap();
+    /// ```
+    pub fn rename(&mut self, from: String, to: String) -> Result<()> {
+        self.build_index()?;
+        self.check_writable()?;
+        let command = self
+            .read_live_command(&from)?
+            .ok_or(KvsError::KeyNotFound)?;
+        if from == to {
+            return Ok(());
+        }
+        match &command {
+            Command::Set { value, .. } => self.try_set(&to, value)?,
+            _ => self.check_key(&to)?,
+        }
+        self.apply_backpressure()?;
+
+        // Serializing upfront lets us write both commands before a single flush,
+        // seeking the BufWriter in between would flush it.
+        let now = self.clock.now_millis();
+        let version = self.next_version(&to);
+        let set_cmd = swapped_command(Some(command), to.to_owned(), now, version, None)?;
+        let value_len = set_cmd.value_len();
+        let modified_at = Some(now);
+        let expires_at = match &set_cmd {
+            Command::Set { expires_at, .. } => *expires_at,
+            _ => None,
+        };
+        let removed_at = self.soft_delete_grace.map(|_| now);
+        let remove_cmd = Command::Remove {
+            key: from.to_owned(),
+            removed_at,
+        };
+        let mut set_bytes = Vec::new();
+        self.format.write_command(&mut set_bytes, &set_cmd)?;
+        let mut remove_bytes = Vec::new();
+        self.format.write_command(&mut remove_bytes, &remove_cmd)?;
+        self.roll_writer_if_full((set_bytes.len() + remove_bytes.len()) as u64)?;
+
+        let pos = self.write_commands(&[&set_bytes, &remove_bytes])?;
+        if let Some(cache) = self.value_cache.as_mut() {
+            cache.invalidate(&from);
+            cache.invalidate(&to);
+        }
+        self.audit(&set_cmd)?;
+        self.audit(&remove_cmd)?;
+
+        let stale = self
+            .map
+            .get(&to)
+            .map_or(0, |metadata| metadata.stale_once_superseded());
+        self.soft_deleted.remove(&to);
+        self.blooms.insert(self.current_index, &to);
+        let old_to_metadata = self.map.insert(
+            to,
+            CommandMetadata {
+                file_index: self.current_index,
+                position: pos,
+                length: set_bytes.len() as u64,
+                stale,
+                expires_at,
+                version: version.unwrap_or(0),
+                value_len,
+                modified_at,
+                patches: Vec::new(),
+            },
+        );
+        let from_metadata = self.map.remove(&from).ok_or(KvsError::KeyNotFound)?;
+
+        // The old key can be restored like a removed one.
+        match removed_at {
+            Some(removed_at) => {
+                self.soft_deleted.insert(
+                    from,
+                    SoftDeleted {
+                        set: from_metadata,
+                        tombstone_file_index: self.current_index,
+                        tombstone_position: pos + set_bytes.len() as u64,
+                        tombstone_length: remove_bytes.len() as u64,
+                        removed_at,
+                    },
+                );
+            }
+            None => {
+                self.umcompacted_bytes += from_metadata.record_len() + remove_bytes.len() as u64
+            }
+        }
+        self.umcompacted_bytes += match old_to_metadata {
+            Some(metadata) => metadata.record_len(),
+            None => 0,
+        };
+
+        self.maybe_compact()?;
+
+        Ok(())
+    }
+
+    /// Exchanges the values associated with `a` and `b` in one logical operation, the
+    /// values keeping their type and expiry.
+    ///
+    /// Both commands are appended to the writer log file and flushed together. A key
+    /// left without a value under `SwapPolicy::MoveMissing` gets a Command::Remove.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if either key is not found, unless the
+    /// store was opened with `SwapPolicy::MoveMissing`, then only if both are missing.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// store.set("baz".to_owned(), "qux".to_owned()).unwrap();
+    /// store.swap("foo".to_owned(), "baz".to_owned()).unwrap();
+    /// assert_eq!(store.get("foo".to_owned()).unwrap(), Some("qux".to_owned()));
+    /// ```
+    pub fn swap(&mut self, a: String, b: String) -> Result<()> {
+        self.build_index()?;
+        self.check_writable()?;
+        let a_command = self.read_live_command(&a)?;
+        let b_command = self.read_live_command(&b)?;
+        match (&a_command, &b_command) {
+            (None, None) => return Err(KvsError::KeyNotFound),
+            (None, _) | (_, None) if self.swap_policy == SwapPolicy::RequireBoth => {
+                return Err(KvsError::KeyNotFound)
+            }
+            _ if a == b => return Ok(()),
+            _ => (),
+        }
+        self.apply_backpressure()?;
+
+        let now = self.clock.now_millis();
+        let removed_at = self.soft_delete_grace.map(|_| now);
+        let a_version = self.next_version(&a);
+        let b_version = self.next_version(&b);
+        let commands = [
+            swapped_command(b_command, a.to_owned(), now, a_version, removed_at)?,
+            swapped_command(a_command, b.to_owned(), now, b_version, removed_at)?,
+        ];
+        let mut encoded = Vec::with_capacity(commands.len());
+        for command in &commands {
+            let mut bytes = Vec::new();
+            self.format.write_command(&mut bytes, command)?;
+            encoded.push(bytes);
+        }
+        let total_len: usize = encoded.iter().map(Vec::len).sum();
+        self.roll_writer_if_full(total_len as u64)?;
+
+        // Both commands go through a single flush, like a rename.
+        let slices: Vec<&[u8]> = encoded.iter().map(Vec::as_slice).collect();
+        let mut pos = self.write_commands(&slices)?;
+
+        for (command, bytes) in commands.iter().zip(&encoded) {
+            self.audit(command)?;
+            let length = bytes.len() as u64;
+            let (key, expires_at) = match command {
+                Command::Set {
+                    key, expires_at, ..
+                } => (key, *expires_at),
+                Command::SetInt { key, .. } => (key, None),
+                Command::Patch { .. } => return Err(KvsError::UnexpectedCommand),
+                Command::Remove { key, removed_at } => {
+                    if let Some(cache) = self.value_cache.as_mut() {
+                        cache.invalidate(key);
+                    }
+                    let removed = self.map.remove(key);
+                    if removed_at.is_none() {
+                        self.umcompacted_bytes +=
+                            removed.as_ref().map_or(0, |metadata| metadata.record_len()) + length;
+                    }
+                    if let (Some(set), Some(removed_at)) = (removed, *removed_at) {
+                        self.soft_deleted.insert(
+                            key.to_owned(),
+                            SoftDeleted {
+                                set,
+                                tombstone_file_index: self.current_index,
+                                tombstone_position: pos,
+                                tombstone_length: length,
+                                removed_at,
+                            },
+                        );
+                    }
+                    pos += length;
+                    continue;
+                }
+            };
+            if let Some(cache) = self.value_cache.as_mut() {
+                cache.invalidate(key);
+            }
+
+            let stale = self
+                .map
+                .get(key)
+                .map_or(0, |metadata| metadata.stale_once_superseded());
+            self.soft_deleted.remove(key);
+            self.blooms.insert(self.current_index, key);
+            let old_metadata = self.map.insert(
+                key.to_owned(),
+                CommandMetadata {
+                    file_index: self.current_index,
+                    position: pos,
+                    length,
+                    stale,
+                    expires_at,
+                    version: command.version().unwrap_or(0),
+                    value_len: command.value_len(),
+                    modified_at: command.modified_at(),
+                    patches: Vec::new(),
+                },
+            );
+            self.umcompacted_bytes += old_metadata.map_or(0, |metadata| metadata.record_len());
+            pos += length;
+        }
+
+        self.maybe_compact()?;
+
+        Ok(())
+    }
+
+    /// Rewrites the latest command of `key` to the writer log file, reclaiming the
+    /// bytes of its older commands without going through a full compaction.
+    ///
+    /// This is meant for hot keys overwritten many times between compactions.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// store.set("foo".to_owned(), "baz".to_owned()).unwrap();
+    /// store.compact_key("foo").unwrap();
+    /// ```
+    pub fn compact_key(&mut self, key: &str) -> Result<()> {
+        self.build_index()?;
+        self.check_writable()?;
+        self.write_buffered()?;
+        let length = self.map.get(key).ok_or(KvsError::KeyNotFound)?.record_len();
+        self.roll_writer_if_full(length)?;
+
+        let writer = self.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        let metadata = self.map.get_mut(key).ok_or(KvsError::KeyNotFound)?;
+        let reader = self.readers.get_mut(metadata.file_index)?;
+
+        let mut cmd = Vec::with_capacity(metadata.length as usize);
+        if metadata.patches.is_empty() {
+            reader.seek(SeekFrom::Start(metadata.position))?;
+            reader.take(metadata.length).read_to_end(&mut cmd)?;
+        } else {
+            // The value is rewritten with its patches applied.
+            let command = read_command(reader, metadata, &*self.format, &self.io)?;
+            let patches = &metadata.patches;
+            let command =
+                patch_command(&mut self.readers, command, patches, &*self.format, &self.io)?;
+            self.format.write_command(&mut cmd, &command)?;
+        }
+
+        let pos = writer.seek(SeekFrom::End(0))?;
+        writer.write_all(&cmd)?;
+        writer.flush()?;
+        self.io.seeked();
+        self.io.read(cmd.len() as u64);
+        self.io.seeked();
+        self.io.wrote(cmd.len() as u64);
+        self.io.flushed();
+        if self.sync_writes {
+            writer.get_ref().sync_data()?;
+        }
+        self.writer_pos = pos + cmd.len() as u64;
+        self.blooms.insert(self.current_index, key);
+
+        // Older commands are accounted as reclaimed while the previous copy
+        // of the latest one becomes stale.
+        self.umcompacted_bytes = self
+            .umcompacted_bytes
+            .saturating_sub(metadata.stale)
+            .saturating_add(metadata.record_len());
+        *metadata = CommandMetadata {
+            file_index: self.current_index,
+            position: pos,
+            length: cmd.len() as u64,
+            stale: metadata.record_len(),
+            expires_at: metadata.expires_at,
+            version: metadata.version,
+            value_len: metadata.value_len,
+            modified_at: metadata.modified_at,
+            patches: Vec::new(),
+        };
+
+        Ok(())
+    }
+
+    /// Copies every live key of `other` into this store, `policy` settling the keys
+    /// both of them hold. Expiry times and integers are carried over as they are.
+    ///
+    /// Keys are copied one at a time, a failure leaves the ones copied so far.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::{KvStore, MergePolicy};
+    /// # let (dir, other_dir) = (tempfile::TempDir::new().unwrap(), tempfile::TempDir::new().unwrap());
+    ///
+    /// let mut store = KvStore::open(dir.path()).unwrap();
+    /// let mut other = KvStore::open(other_dir.path()).unwrap();
+    /// other.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// store.merge_from(&mut other, MergePolicy::KeepSelf).unwrap();
+    /// assert_eq!(store.get("foo".to_owned()).unwrap(), Some("bar".to_owned()));
+    /// ```
+    pub fn merge_from(&mut self, other: &mut KvStore, policy: MergePolicy) -> Result<()> {
+        self.build_index()?;
+        other.build_index()?;
+        self.check_writable()?;
+        other.write_buffered()?;
+
+        let now = other.clock.now_millis();
+        let keys: Vec<String> = other
+            .map
+            .iter()
+            .filter(|(_, metadata)| !metadata.is_expired(now))
+            .map(|(key, _)| key.to_owned())
+            .collect();
+
+        for key in keys {
+            let held = self
+                .map
+                .get(&key)
+                .is_some_and(|metadata| !metadata.is_expired(self.clock.now_millis()));
+            if held && policy == MergePolicy::KeepSelf {
+                continue;
+            }
+
+            let metadata = match other.map.get(&key) {
+                Some(metadata) => metadata,
+                None => continue,
+            };
+            let reader = other.readers.get_mut(metadata.file_index)?;
+            let command = read_command(reader, metadata, &*other.format, &other.io)?;
+            let patches = &metadata.patches;
+            match patch_command(
+                &mut other.readers,
+                command,
+                patches,
+                &*other.format,
+                &other.io,
+            )? {
+                Command::Set {
+                    value, expires_at, ..
+                } => self.append_set(key, value, expires_at).map(|_| ())?,
+                Command::SetInt { value, .. } => self.set_int(key, value)?,
+                Command::Patch { .. } | Command::Remove { .. } => {
+                    return Err(KvsError::UnexpectedCommand)
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Takes a read-only view of every key as of now, unaffected by later writes and
+    /// compactions. The index of the store is copied, taking time and memory in
+    /// proportion to the amount of keys.
+    ///
+    /// The stale log files of the compactions running while it is alive are only
+    /// removed once it is dropped, which means a store reopened meanwhile replays them.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// let snapshot = store.snapshot();
+    /// store.set("foo".to_owned(), "baz".to_owned()).unwrap();
+    /// store.compact().unwrap();
+    /// assert_eq!(snapshot.get("foo").unwrap(), Some("bar".to_owned()));
+    /// ```
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            path: self.path.to_owned(),
+            map: self.map.clone_box(),
+            format: Arc::clone(&self.format),
+            generation: Arc::clone(&self.generation),
+            clock: Arc::clone(&self.clock),
+            io: Arc::clone(&self.io),
+            buffered: self
+                .writer
+                .as_ref()
+                .filter(|writer| !writer.buffer().is_empty())
+                .map(|writer| BufferedCommands {
+                    file_index: self.current_index,
+                    start: self.writer_pos - writer.buffer().len() as u64,
+                    bytes: writer.buffer().to_vec(),
+                }),
+        }
+    }
+
+    /// Freezes the log files until `KvStore::resume_writes`, so they can be copied
+    /// as a consistent whole while reads keep being served.
+    ///
+    /// Everything written so far is synced to disk first. Meanwhile sets, removes
+    /// and compactions fail with `KvsError::Paused`, automatic compactions are
+    /// held back.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.pause_writes().unwrap();
+    /// assert!(store.set("foo".to_owned(), "bar".to_owned()).is_err());
+    /// store.resume_writes();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// ```
+    pub fn pause_writes(&mut self) -> Result<()> {
+        self.flush()?;
+        self.paused = true;
+        Ok(())
+    }
+
+    /// Accepts writes again after `KvStore::pause_writes`.
+    pub fn resume_writes(&mut self) {
+        self.paused = false;
+    }
+
+    /// Amount of compactions run since the store was opened, each of them replacing
+    /// the log files reads go to.
+    pub fn generation(&self) -> u64 {
+        self.generation.number
+    }
+
+    /// Returns the index of the writer log file and the offset where the next command
+    /// will be appended to it, a cursor over everything written so far.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// let (_, before) = store.current_position();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// assert!(store.current_position().1 > before);
+    /// ```
+    pub fn current_position(&self) -> (u64, u64) {
+        (self.current_index, self.writer_pos)
+    }
+
+    /// Applies every command of a log, such as a log file of another store, as if
+    /// they were issued in order and returns how many were applied.
+    ///
+    /// The log must be written in the format of this store, with or without a
+    /// header. Removes of keys this store doesn't hold are applied as no-ops.
+    /// A failure leaves the commands applied so far.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use std::fs::File;
+    /// use tempfile::TempDir;
+    ///
+    /// let source_dir = TempDir::new().unwrap();
+    /// let mut source = KvStore::open(source_dir.path()).unwrap();
+    /// source.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// drop(source);
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// let log = File::open(source_dir.path().join("1.log")).unwrap();
+    /// assert_eq!(store.apply_log(log).unwrap(), 1);
+    /// assert_eq!(store.get("foo".to_owned()).unwrap(), Some("bar".to_owned()));
+    /// ```
+    pub fn apply_log(&mut self, reader: impl Read) -> Result<usize> {
+        self.build_index()?;
+        self.check_writable()?;
+
+        let (header, mut commands) = LogHeader::split(BufReader::new(reader))?;
+        if let Some(header) = header {
+            header.check("the applied log", &*self.format)?;
+        }
+
+        let format = Arc::clone(&self.format);
+        let mut applied = 0;
+        for command in format.stream(&mut commands) {
+            match command?.0 {
+                Command::Set {
+                    key,
+                    value,
+                    expires_at,
+                    ..
+                } => {
+                    self.append_set(key, value, expires_at)?;
+                }
+                Command::SetInt { key, value, .. } => self.set_int(key, value)?,
+                Command::Patch { key, patch, .. } => self.patch(key, patch)?,
+                Command::Remove { key, .. } => match self.remove(key) {
+                    Ok(()) | Err(KvsError::KeyNotFound) => (),
+                    Err(e) => return Err(e),
+                },
+            }
+            applied += 1;
+        }
+
+        Ok(applied)
+    }
+
+    /// Writes every live key to `writer` in ascending order, one JSON object
+    /// `{"key":...,"value":...}` per line, and returns how many were written.
+    ///
+    /// Unlike the log files, the output only holds the current values and doesn't
+    /// depend on the format of the store, for tools such as `jq`. Integers are
+    /// written as JSON numbers.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// let mut output = Vec::new();
+    /// store.export_jsonl(&mut output).unwrap();
+    /// assert_eq!(output, b"{\"key\":\"foo\",\"value\":\"bar\"}\n");
+    /// ```
+    pub fn export_jsonl(&mut self, writer: impl Write) -> Result<usize> {
+        self.build_index()?;
+
+        let mut writer = BufWriter::new(writer);
+        let mut exported = 0;
+        for key in &self.keys_matching(|_| true) {
+            let value = match self.read_live_command(key)? {
+                Some(Command::Set { value, .. }) => ExportedValue::String(value),
+                Some(Command::SetInt { value, .. }) => ExportedValue::Int(value),
+                Some(Command::Patch { .. }) | Some(Command::Remove { .. }) => {
+                    return Err(KvsError::UnexpectedCommand)
+                }
+                None => continue,
+            };
+            serde_json::to_writer(&mut writer, &ExportRecord { key, value })?;
+            writer.write_all(b"\n")?;
+            exported += 1;
+        }
+        writer.flush()?;
+
+        Ok(exported)
+    }
+
+    /// Size in bytes of the record holding the value of `key`, framing and key
+    /// included, or `None` if the key is absent or expired.
+    ///
+    /// It is a rough estimate of the value size for placement decisions, read from the
+    /// index without touching the disk. `KvsEngine::value_len` gives the exact length.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo".to_owned(), "bar".repeat(100)).unwrap();
+    /// assert!(store.value_size("foo").unwrap() >= 300);
+    /// ```
+    pub fn value_size(&self, key: &str) -> Option<u64> {
+        match self.map.get(key) {
+            Some(metadata) if !metadata.is_expired(self.clock.now_millis()) => {
+                Some(metadata.record_len())
+            }
+            _ => None,
+        }
+    }
+
+    /// The `n` live keys with the largest values along with their sizes, largest
+    /// first and ties ordered by key.
+    ///
+    /// Sizes are the ones of `KvStore::value_size`, read from the index without
+    /// touching the disk.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo".to_owned(), "bar".repeat(100)).unwrap();
+    /// store.set("baz".to_owned(), "bar".to_owned()).unwrap();
+    /// assert_eq!(store.largest_values(1)[0].0, "foo");
+    /// ```
+    pub fn largest_values(&self, n: usize) -> Vec<(String, u64)> {
+        let now = self.clock.now_millis();
+        let mut sizes: Vec<(String, u64)> = self
+            .map
+            .iter()
+            .filter(|(_, metadata)| !metadata.is_expired(now))
+            .map(|(key, metadata)| (key.to_owned(), metadata.record_len()))
+            .collect();
+        sizes.sort_unstable_by(|(a_key, a_size), (b_key, b_size)| {
+            b_size.cmp(a_size).then_with(|| a_key.cmp(b_key))
+        });
+        sizes.truncate(n);
+        sizes
+    }
+
+    /// When `key` was last written, or `None` if the key is absent, expired or was
+    /// last written by a log predating timestamps.
+    ///
+    /// Timestamps come from the clock of the store and are persisted along with the
+    /// commands, compactions and restarts keep them.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use std::time::SystemTime;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// assert!(store.modified_at("foo").unwrap() <= SystemTime::now());
+    /// ```
+    pub fn modified_at(&self, key: &str) -> Option<SystemTime> {
+        match self.map.get(key) {
+            Some(metadata) if !metadata.is_expired(self.clock.now_millis()) => metadata
+                .modified_at
+                .map(|millis| UNIX_EPOCH + Duration::from_millis(millis)),
+            _ => None,
+        }
+    }
+
+    /// Lists the live keys for which `pred` returns `true`, in ascending order.
+    ///
+    /// Only the in-memory index is walked, no value is read.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// store.set("foobar".to_owned(), "bar".to_owned()).unwrap();
+    /// assert_eq!(store.keys_matching(|key| key.len() > 3), vec!["foobar".to_owned()]);
+    /// ```
+    pub fn keys_matching<F: Fn(&str) -> bool>(&self, pred: F) -> Vec<String> {
+        let now = self.clock.now_millis();
+        let mut keys: Vec<String> = self
+            .map
+            .iter()
+            .filter(|(key, metadata)| !metadata.is_expired(now) && pred(key))
+            .map(|(key, _)| key.to_owned())
+            .collect();
+        if !self.map.is_ordered() {
+            keys.sort_unstable();
+        }
+        keys
+    }
+
+    /// Amount of commands read from the log files to serve gets, the ones answered by
+    /// the value cache excluded.
+    pub fn disk_reads(&self) -> u64 {
+        self.disk_reads
+    }
+
+    /// Amount of gets answered without looking the key up, the bloom filters of the
+    /// log files telling none of them sets it.
+    pub fn bloom_rejections(&self) -> u64 {
+        self.bloom_rejections
+    }
+
+    /// Counts of the I/O operations issued on the log files since the store was
+    /// opened, its snapshots included.
+    ///
+    /// Every set and remove flushes the writer once, as do compactions.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// let before = store.stats();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// assert_eq!(store.stats().flushes, before.flushes + 1);
+    /// ```
+    pub fn stats(&self) -> IoStats {
+        self.io.stats()
+    }
+
+    /// Checks that every log file parses and that every indexed key reads back
+    /// as a Command::Set of that same key, without modifying the store.
+    ///
+    /// Only I/O failures are returned as errors, corrupt data is listed in the report.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// assert!(store.verify().unwrap().is_healthy());
+    /// ```
+    pub fn verify(&mut self) -> Result<VerifyReport> {
+        self.build_index()?;
+        self.write_buffered()?;
+        let mut report = VerifyReport::default();
+
+        let file_indexes: Vec<u64> = self.readers.file_indexes().collect();
+        for file_index in file_indexes {
+            let reader = self.readers.get_mut(file_index)?;
+            reader.seek(SeekFrom::Start(0))?;
+            let start = match LogHeader::read(reader) {
+                Ok((_, start)) => start,
+                Err(KvsError::Io(e)) if e.kind() != io::ErrorKind::UnexpectedEof => {
+                    return Err(KvsError::Io(e))
+                }
+                Err(_) => {
+                    report.corrupt_files.push(file_index);
+                    continue;
+                }
+            };
+            reader.seek(SeekFrom::Start(start))?;
+
+            for command in self.format.stream(reader) {
+                match command {
+                    Ok(_) => (),
+                    Err(KvsError::Io(e)) if e.kind() != io::ErrorKind::UnexpectedEof => {
+                        return Err(KvsError::Io(e))
+                    }
+                    Err(_) => {
+                        report.corrupt_files.push(file_index);
+                        break;
+                    }
+                }
+            }
+        }
+
+        let discrepancies = self.audit_index()?;
+        report.ok = (self.map.iter().count() - discrepancies.len()) as u64;
+        report.corrupt_keys = discrepancies
+            .into_iter()
+            .map(|discrepancy| discrepancy.key)
+            .collect();
+
+        Ok(report)
+    }
+
+    /// Checks that every indexed key reads back, at the offset the index records for
+    /// it, as a set of that same key, listing the keys which don't.
+    ///
+    /// Only I/O failures are returned as errors. The index is left as is, reopening
+    /// the store rebuilds it from the log files.
+    ///
+    /// ```
+    /// use crate::kvs::KvsEngine;
+    /// use self::kvs::KvStore;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
+    /// store.set("foo".to_owned(), "bar".to_owned()).unwrap();
+    /// assert!(store.audit_index().unwrap().is_empty());
+    /// ```
+    pub fn audit_index(&mut self) -> Result<Vec<IndexDiscrepancy>> {
+        self.build_index()?;
+        self.write_buffered()?;
+
+        let mut discrepancies = Vec::new();
+        for (key, metadata) in self.map.iter() {
+            let reader = self.readers.get_mut(metadata.file_index)?;
+            // A patch which doesn't fit the value corrupts the key as well.
+            let command = match read_command(reader, metadata, &*self.format, &self.io) {
+                Ok(command) => patch_command(
+                    &mut self.readers,
+                    command,
+                    &metadata.patches,
+                    &*self.format,
+                    &self.io,
+                ),
+                Err(e) => Err(e),
+            };
+            let found = match command {
+                Ok(Command::Set {
+                    key: stored_key, ..
+                })
+                | Ok(Command::SetInt {
+                    key: stored_key, ..
+                }) if stored_key == *key => continue,
+                Ok(Command::Set {
+                    key: stored_key, ..
+                })
+                | Ok(Command::SetInt {
+                    key: stored_key, ..
+                }) => Some(stored_key),
+                Err(KvsError::Io(e)) => return Err(KvsError::Io(e)),
+                _ => None,
+            };
+            discrepancies.push(IndexDiscrepancy {
+                key: key.to_owned(),
+                file_index: metadata.file_index,
+                position: metadata.position,
+                found,
+            });
+        }
+
+        Ok(discrepancies)
+    }
+
+    /// Returns a handle to the writer log file, letting it be synced to disk
+    /// without holding the store.
+    pub(crate) fn writer_file(&self) -> Result<File> {
+        let writer = self.writer.as_ref().ok_or(KvsError::ReadOnly)?;
+        Ok(writer.get_ref().try_clone_file()?)
+    }
+
+    /// Compacts the log files once the COMPACTION_THRESHOLD is surpassed or when the
+    /// compaction interval elapsed with stale commands around.
+    /// Read-only stores are never compacted, nor are paused ones.
+    fn maybe_compact(&mut self) -> Result<()> {
+        // Every read and mutation goes through here, writing the sets buffered for
+        // too long on the way.
+        self.write_buffered_if_due()?;
+        if self.writer.is_none()
+            || self.paused
+            || !self.index_built
+            || self.corrupt_record.is_some()
+        {
+            return Ok(());
+        }
+
+        let interval_elapsed = match &self.compaction_due {
+            Some(due) => due.swap(false, Ordering::SeqCst),
+            None => false,
+        };
+
+        let too_many_files = self
+            .max_log_files
+            .is_some_and(|max| self.readers.file_count() > max);
+
+        if self.compaction.is_some()
+            || self.umcompacted_bytes > COMPACTION_THRESHOLD
+            || (interval_elapsed && self.umcompacted_bytes > 0)
+            || too_many_files
+        {
+            // The operation triggering the compaction has nothing to do with the
+            // corrupt record, only explicit compactions report it.
+            match self.compact() {
+                Err(KvsError::CorruptRecord(key)) => {
+                    error!(
+                        "Automatic compactions stopped, the command of key {} is corrupt",
+                        key
+                    );
+                    self.corrupt_record = Some(key);
+                }
+                res => res.map(|_| ())?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Appends a Command::Set to the writer log file and indexes it, returning the
+    /// new version of the key.
+    fn append_set(&mut self, key: String, value: String, expires_at: Option<u64>) -> Result<u64> {
+        self.try_set(&key, &value)?;
+
+        let cmd = Command::Set {
+            key: key.to_owned(),
+            value,
+            expires_at,
+            modified_at: Some(self.clock.now_millis()),
+            version: self.next_version(&key),
+        };
+        self.append_key_command(key, cmd, expires_at)
+    }
+
+    /// Appends `cmd`, setting `key`, to the writer log file and indexes it, returning
+    /// the new version of the key.
+    fn append_key_command(
+        &mut self,
+        key: String,
+        cmd: Command,
+        expires_at: Option<u64>,
+    ) -> Result<u64> {
+        let (pos, new_pos) = self.append_command(&key, &cmd)?;
+        let metadata = CommandMetadata {
+            file_index: self.current_index,
+            position: pos,
+            length: (new_pos - pos),
+            stale: 0,
+            expires_at,
+            version: cmd.version().unwrap_or(0),
+            value_len: cmd.value_len(),
+            modified_at: cmd.modified_at(),
+            patches: Vec::new(),
+        };
+        self.index_command(key, metadata)
+    }
+
+    /// Indexes `metadata` as the latest command setting `key`, accounting the
+    /// command it supersedes, and returns the version of the key it holds.
+    fn index_command(&mut self, key: String, mut metadata: CommandMetadata) -> Result<u64> {
+        metadata.stale = self
+            .map
+            .get(&key)
+            .map_or(0, |metadata| metadata.stale_once_superseded());
+        let version = metadata.version;
+        self.soft_deleted.remove(&key);
+        self.blooms.insert(metadata.file_index, &key);
+        let old_metadata = self.map.insert(key, metadata);
+
+        self.umcompacted_bytes += match old_metadata {
+            Some(metadata) => metadata.record_len(),
+            None => 0,
+        };
+
+        self.maybe_compact()?;
+
+        Ok(version)
+    }
+
+    /// Reads the bytes `start..end` of the value of `key` straight from its record,
+    /// returning `None` when the value has to be read whole: for a missing, cached,
+    /// escaped or patched value.
+    fn read_verbatim_range(
+        &mut self,
+        key: &str,
+        start: usize,
+        end: usize,
+    ) -> Result<Option<String>> {
+        let metadata = match self.map.get(key) {
+            Some(metadata) if !metadata.is_expired(self.clock.now_millis()) => metadata,
+            _ => return Ok(None),
+        };
+        let cached = self.value_cache.as_mut().and_then(|cache| cache.get(key));
+        let value = match verbatim_value(&*self.format, key, metadata)? {
+            Some(value) if cached.is_none() => value,
+            _ => return Ok(None),
+        };
+
+        let reader = self.readers.get_mut(metadata.file_index)?;
+        let mut suffix = vec![0; value.suffix.len()];
+        reader.seek(SeekFrom::Start(
+            metadata.position + metadata.length - suffix.len() as u64,
+        ))?;
+        reader.read_exact(&mut suffix)?;
+        self.io.seeked();
+        self.io.read(suffix.len() as u64);
+        if suffix != value.suffix {
+            return Ok(None);
+        }
+
+        let end = end.min(value.len as usize);
+        if start > end {
+            return Err(KvsError::InvalidRange);
+        }
+        let reader = self.readers.get_mut(metadata.file_index)?;
+        reader.seek(SeekFrom::Start(
+            metadata.position + value.offset + start as u64,
+        ))?;
+        let mut range = vec![0; end - start];
+        reader.read_exact(&mut range)?;
+        self.io.seeked();
+        self.io.read(range.len() as u64);
+        self.disk_reads += 1;
+
+        // The range cuts a character unless its bytes are valid UTF-8 on their own.
+        String::from_utf8(range)
+            .map(Some)
+            .map_err(|_| KvsError::InvalidRange)
+    }
+
+    /// Hands out the version `key` gets from its next set, see `next_version`.
+    ///
+    /// Returns `None` until the index of a store opened with `lazy_index` is built, as
+    /// the versions of the keys are unknown meanwhile. The replay counts them instead.
+    fn next_version(&mut self, key: &str) -> Option<u64> {
+        if !self.index_built {
+            return None;
+        }
+        let version = next_version(&*self.map, self.max_version, key);
+        self.max_version = self.max_version.max(version);
+        Some(version)
+    }
+
+    /// Appends `cmd`, a command of `key`, to the writer log file without indexing it,
+    /// returning the offsets it starts and ends at.
+    fn append_command(&mut self, key: &str, cmd: &Command) -> Result<(u64, u64)> {
+        self.apply_backpressure()?;
+
+        let mut bytes = Vec::new();
+        self.format.write_command(&mut bytes, cmd)?;
+        self.roll_writer_if_full(bytes.len() as u64)?;
+
+        let (buffered, capacity) = self
+            .writer
+            .as_ref()
+            .map_or((0, 0), |writer| (writer.buffer().len(), writer.capacity()));
+        // The writer would otherwise write the buffer out on its own to make room,
+        // a command larger than the buffer being written right away.
+        if self.coalesce_bytes.is_some() && buffered + bytes.len() > capacity {
+            self.write_buffered()?;
+        }
+
+        let writer = self.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        let (pos, new_pos) = if self.coalesce_bytes.is_some() && bytes.len() <= capacity {
+            // Seeking would write the buffered commands, the writer position tells
+            // where this one lands instead.
+            if writer.buffer().is_empty() {
+                self.buffered_since = Some(Instant::now());
+            }
+            writer.write_all(&bytes)?;
+            self.io.wrote(bytes.len() as u64);
+            let pos = self.writer_pos;
+            self.writer_pos += bytes.len() as u64;
+            (pos, self.writer_pos)
+        } else {
+            let pos = self.write_commands(&[&bytes])?;
+            (pos, pos + bytes.len() as u64)
+        };
+        if let Some(cache) = self.value_cache.as_mut() {
+            cache.invalidate(key);
+        }
+        self.audit(cmd)?;
+        self.write_buffered_if_due()?;
+
+        Ok((pos, new_pos))
+    }
+
+    /// Appends the serialized `commands` to the writer log file with a single flush,
+    /// returning the position the first one starts at.
+    ///
+    /// A write failing partway is cut off the log file, so a retry doesn't land
+    /// after a torn command.
+    fn write_commands(&mut self, commands: &[&[u8]]) -> Result<u64> {
+        let sync_writes = self.sync_writes;
+        let writer = self.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        let pos = writer.seek(SeekFrom::End(0))?;
+        self.io.seeked();
+        let written = commands
+            .iter()
+            .try_for_each(|bytes| writer.write_all(bytes))
+            .and_then(|()| writer.flush())
+            .and_then(|()| match sync_writes {
+                true => writer.get_ref().sync_data(),
+                false => Ok(()),
+            });
+        if let Err(e) = written {
+            self.truncate_writer(pos)?;
+            return Err(e.into());
+        }
+
+        let len: u64 = commands.iter().map(|bytes| bytes.len() as u64).sum();
+        self.io.wrote(len);
+        self.io.flushed();
+        self.writer_pos = pos + len;
+        Ok(pos)
+    }
+
+    /// Cuts the writer log file back to `pos` once a write failed, dropping what
+    /// the writer still buffers as it would land after the truncation.
+    fn truncate_writer(&mut self, pos: u64) -> Result<()> {
+        if let Some(writer) = self.writer.take() {
+            let (log_file, _) = writer.into_parts();
+            let truncated = log_file.truncate(pos);
+            self.writer = Some(log_file.into_writer());
+            truncated?;
+        }
+        self.writer_pos = pos;
+
+        Ok(())
+    }
+
+    /// Writes the commands buffered under `coalesce_bytes` once there are enough of
+    /// them, or once the oldest one waited for `coalesce_window`.
+    fn write_buffered_if_due(&mut self) -> Result<()> {
+        let max = match self.coalesce_bytes {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let buffered = self.writer.as_ref().map_or(0, |w| w.buffer().len() as u64);
+        let waited = self
+            .buffered_since
+            .is_some_and(|since| since.elapsed() >= self.coalesce_window);
+        if buffered >= max || waited {
+            self.write_buffered()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the commands buffered under `coalesce_bytes` to the writer log file.
+    fn write_buffered(&mut self) -> Result<()> {
+        self.buffered_since = None;
+        let writer = match self.writer.as_mut() {
+            Some(writer) if !writer.buffer().is_empty() => writer,
+            _ => return Ok(()),
+        };
+        writer.flush()?;
+        self.io.flushed();
+
+        Ok(())
+    }
+
+    /// Returns `true` if the command `metadata` points to, or one of its patches,
+    /// is still buffered under `coalesce_bytes` and must be written to be read.
+    fn is_buffered(&self, metadata: &CommandMetadata) -> bool {
+        let buffered = self.writer.as_ref().map_or(0, |w| w.buffer().len() as u64);
+        let written = self.writer_pos - buffered;
+        let is_pending =
+            |file_index, end| buffered > 0 && file_index == self.current_index && end > written;
+
+        is_pending(metadata.file_index, metadata.position + metadata.length)
+            || metadata
+                .patches
+                .iter()
+                .any(|patch| is_pending(patch.file_index, patch.position + patch.length))
+    }
+
+    /// Writes the buffered commands first if reading the command of `key` needs them.
+    fn write_buffered_for(&mut self, key: &str) -> Result<()> {
+        if self
+            .map
+            .get(key)
+            .is_some_and(|metadata| self.is_buffered(metadata))
+        {
+            self.write_buffered()?;
+        }
+
+        Ok(())
+    }
+
+    /// Points the writer to a fresh log file at the current index.
+    fn open_writer(&mut self) -> Result<()> {
+        // The replaced writer would otherwise write its buffer to the new log file.
+        self.write_buffered()?;
+        // Syncs of the writer only reach the new log file, so the writes left in the
+        // replaced one are synced before it stops being the writer.
+        if !self.sync_writes {
+            if let Some(writer) = self.writer.as_ref() {
+                writer.get_ref().sync_data()?;
+            }
+        }
+        let writer_path = self
+            .path
+            .to_owned()
+            .join(format!("{}.log", self.current_index));
+        let mut writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&writer_path)?;
+        if writer.metadata()?.len() == 0 {
+            writer.write_all(&self.header)?;
+        }
+        let writer_pos = writer.metadata()?.len();
+
+        self.writer = self
+            .writer
+            .as_ref()
+            .map(|current| current.get_ref().replace(writer).into_writer());
+        self.readers.insert(
+            self.current_index,
+            BufReader::new(File::open(&writer_path)?),
+        );
+        self.writer_pos = writer_pos;
+        self.sync_dir()?;
+
+        Ok(())
+    }
+
+    /// Syncs the directory of the store if `sync_directory` is set.
+    fn sync_dir(&self) -> Result<()> {
+        if self.sync_directory {
+            sync_dir(&self.path)?;
+            self.io.dir_synced();
+        }
 
-        let file_indexes = fetch_file_indexes(dir_path.to_owned())?;
-        let total_umcompacted_bytes =
-            load_files(dir_path.to_owned(), &file_indexes, &mut readers, &mut map)?;
+        Ok(())
+    }
 
-        let new_index = file_indexes.last().unwrap_or(&0) + 1;
-        let writer_path = dir_path.to_owned().join(format!("{}.log", new_index));
+    /// Rolls the writer over to the next index when appending `len` bytes would take
+    /// its log file past `max_log_size`. A single command larger than the limit still
+    /// gets a log file of its own.
+    ///
+    /// Every write goes through here, which first makes sure the writer log file is
+    /// still around.
+    fn roll_writer_if_full(&mut self, len: u64) -> Result<()> {
+        self.check_writer()?;
+        match self.max_log_size {
+            Some(max)
+                if self.writer_pos > self.header.len() as u64 && self.writer_pos + len > max =>
+            {
+                self.current_index += 1;
+                self.open_writer()
+            }
+            _ => Ok(()),
+        }
+    }
 
-        let writer = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(&writer_path)?;
+    /// Fails with `KvsError::WriterLost` if the writer log file was removed, or
+    /// recovers from it if `recreate_lost_writer` is set.
+    fn check_writer(&mut self) -> Result<()> {
+        let writer = self.writer.as_ref().ok_or(KvsError::ReadOnly)?;
+        if !writer.get_ref().is_removed()? {
+            return Ok(());
+        }
+        if !self.recreate_lost_writer {
+            return Err(KvsError::WriterLost);
+        }
 
-        readers.insert(new_index, BufReader::new(File::open(&writer_path)?));
-        let mut store = KvStore::new(
-            dir_path,
-            readers,
-            BufWriter::new(writer),
-            map,
-            new_index,
-            total_umcompacted_bytes,
+        // The reader of the removed log file still reads it, the compaction copies the
+        // live commands left there along with the other ones.
+        warn!(
+            "Log file {}.log was removed, compacting to recreate it",
+            self.current_index
         );
-        store.compact()?;
+        self.compact()?;
+        let writer = self.writer.as_ref().ok_or(KvsError::ReadOnly)?;
+        if writer.get_ref().is_removed()? {
+            self.current_index += 1;
+            self.open_writer()?;
+        }
 
-        Ok(store)
+        Ok(())
     }
 
-    /// Compacts log files once the total amount of umcompacted bytes surpasses the
-    /// COMPACTION_THRESHOLD.
-    fn compact(&mut self) -> Result<()> {
-        if self.umcompacted_bytes <= COMPACTION_THRESHOLD {
-            return Ok(());
+    /// Turns the failure to parse the command of `key` into `None` when the
+    /// corruption policy skips corrupt keys.
+    fn apply_corruption_policy(
+        &self,
+        key: &str,
+        command: Result<Command>,
+    ) -> Result<Option<Command>> {
+        match command {
+            Ok(command) => Ok(Some(command)),
+            Err(KvsError::Serde(e)) if self.corruption_policy == CorruptionPolicy::Skip => {
+                warn!("Skipping corrupt command of key {}: {}", key, e);
+                Ok(None)
+            }
+            Err(e) => Err(e),
         }
+    }
 
-        let compaction_index = self.current_index + 1;
-        self.current_index += 2;
+    /// Turns the failure to find the log file of `key` into `None` when the missing
+    /// log policy drops such keys, dropping it from the index.
+    fn apply_missing_log_policy<T>(&mut self, key: &str, error: KvsError) -> Result<Option<T>> {
+        match error {
+            KvsError::MissingLogFile { file_index }
+                if self.missing_log_policy == MissingLogPolicy::DropKey =>
+            {
+                warn!(
+                    "Dropping key {} as its log file {} is missing",
+                    key, file_index
+                );
+                self.map.remove(key);
+                Ok(None)
+            }
+            error => Err(error),
+        }
+    }
 
-        let compaction_path = self
-            .path
-            .to_owned()
-            .join(format!("{}.log", compaction_index));
-        let mut compaction_writer = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(&compaction_path)?;
+    /// Fails the sets of keys longer than the `max_key_size` of the options, or
+    /// refused by their key policy.
+    fn check_key(&self, key: &str) -> Result<()> {
+        match self.max_key_size {
+            Some(max) if key.len() > max => return Err(KvsError::KeyTooLarge(max)),
+            _ => (),
+        }
+        if self.key_policy == KeyPolicy::Strict
+            && key.chars().any(|c| c.is_control() || c.is_whitespace())
+        {
+            return Err(KvsError::InvalidKey);
+        }
 
-        self.readers.insert(
-            compaction_index,
-            BufReader::new(File::open(&compaction_path)?),
-        );
+        Ok(())
+    }
 
-        let mut compaction_writer_pos: u64 = 0;
+    /// Reads the latest command of `key`, `None` if it is absent or expired.
+    fn read_live_command(&mut self, key: &str) -> Result<Option<Command>> {
+        self.write_buffered_for(key)?;
+        let metadata = match self.map.get(key) {
+            Some(metadata) if !metadata.is_expired(self.clock.now_millis()) => metadata,
+            _ => return Ok(None),
+        };
 
-        for cmd_metadata in self.map.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(&cmd_metadata.file_index)
-                .ok_or(KvsError::UnexpectedCommand)?;
-
-            reader.seek(SeekFrom::Start(cmd_metadata.position))?;
-            let mut chunk = reader.take(cmd_metadata.length);
-            let len = std::io::copy(&mut chunk, &mut compaction_writer)?;
-            *cmd_metadata = CommandMetadata {
-                file_index: self.current_index,
-                position: compaction_writer_pos,
-                length: len,
-            };
-            compaction_writer_pos += len;
+        let reader = self.readers.get_mut(metadata.file_index)?;
+        self.disk_reads += 1;
+        let command = read_command(reader, metadata, &*self.format, &self.io)?;
+        let patches = &metadata.patches;
+        patch_command(&mut self.readers, command, patches, &*self.format, &self.io).map(Some)
+    }
+
+    /// Fails the mutations of a read-only store, or of a store whose writes are paused.
+    fn check_writable(&self) -> Result<()> {
+        if self.writer.is_none() {
+            return Err(KvsError::ReadOnly);
+        }
+        if self.paused {
+            return Err(KvsError::Paused);
         }
 
-        compaction_writer.flush()?;
-        let stale_log_indexes: Vec<u64> = self
-            .readers
-            .keys()
-            .filter(|key| **key < compaction_index)
-            .cloned()
-            .collect();
+        Ok(())
+    }
 
-        for stale_log_index in stale_log_indexes {
-            self.readers.remove(&stale_log_index);
-            let stale_path = self
-                .path
-                .to_owned()
-                .join(format!("{}.log", stale_log_index));
-            std::fs::remove_file(stale_path)?;
+    /// Compacts right away, holding the write back, once the uncompacted bytes
+    /// surpass the high-water mark.
+    fn apply_backpressure(&mut self) -> Result<()> {
+        match self.max_uncompacted_bytes {
+            Some(max) if self.umcompacted_bytes > max => {
+                warn!(
+                    "{} uncompacted bytes over the high-water mark, compacting before writing",
+                    self.umcompacted_bytes
+                );
+                self.compact()?;
+            }
+            _ => (),
         }
 
-        let writer_path = self
-            .path
-            .to_owned()
-            .join(format!("{}.log", self.current_index));
-        let writer = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(true)
-            .open(&writer_path)?;
+        Ok(())
+    }
 
-        self.writer = BufWriter::new(writer);
-        self.readers.insert(
-            self.current_index,
-            BufReader::new(File::open(&writer_path)?),
-        );
-        self.umcompacted_bytes = 0;
+    /// Appends `command` to the audit log, if any.
+    fn audit(&mut self, command: &Command) -> Result<()> {
+        if let Some(audit_log) = self.audit_log.as_mut() {
+            let timestamp = self.clock.now_millis();
+
+            serde_json::to_writer(&mut *audit_log, &AuditRecord { timestamp, command })?;
+            audit_log.write_all(b"\n")?;
+            audit_log.flush()?;
+        }
 
         Ok(())
     }
+
+    /// Sums up the size of every log file currently tracked by the store.
+    fn log_files_size(&self) -> Result<u64> {
+        let mut total: u64 = 0;
+
+        for file_index in self.readers.file_indexes() {
+            let file_path = self.path.join(format!("{}.log", file_index));
+            match std::fs::metadata(file_path) {
+                Ok(metadata) => total += metadata.len(),
+                // Such as the writer log file removed from under the store.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(total)
+    }
 }
 
 impl KvsEngine for KvStore {
@@ -194,38 +3460,14 @@ impl KvsEngine for KvStore {
     /// ```
     /// use crate::kvs::KvsEngine;
     /// use self::kvs::KvStore;
-    /// use std::env::current_dir;
+    /// use tempfile::TempDir;
     ///
-    /// let mut store = KvStore::open(current_dir().unwrap()).unwrap();
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
     /// store.set("foo".to_owned(), "bar".to_owned());
     /// ```
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set {
-            key: key.to_owned(),
-            value: value.to_owned(),
-        };
-        let pos = self.writer.seek(SeekFrom::End(0))?;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        let new_pos = self.writer.seek(SeekFrom::End(0))?;
-
-        let old_metadata = self.map.insert(
-            key,
-            CommandMetadata {
-                file_index: self.current_index,
-                position: pos,
-                length: (new_pos - pos),
-            },
-        );
-
-        self.umcompacted_bytes += match old_metadata {
-            Some(metadata) => metadata.length,
-            None => 0,
-        };
-
-        self.compact()?;
-
-        Ok(())
+        KvStore::set(self, key, value)
     }
 
     /// Fetches the serialized command associated with the `key` from a log file,
@@ -238,28 +3480,106 @@ impl KvsEngine for KvStore {
     /// ```
     /// use crate::kvs::KvsEngine;
     /// use self::kvs::KvStore;
-    /// use std::env::current_dir;
+    /// use tempfile::TempDir;
     ///
-    /// let mut store = KvStore::open(current_dir().unwrap()).unwrap();
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
     /// store.set("foo".to_owned(), "bar".to_owned());
     /// println!("{:?}", store.get("foo".to_owned()));
     /// ```
     fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    /// Reads only the requested bytes of the value when its record holds it as is,
+    /// falling back to reading the whole value when it was escaped or patched.
+    fn get_range(&mut self, key: String, start: usize, end: usize) -> Result<Option<String>> {
+        self.build_index()?;
+        self.maybe_compact()?;
+        self.write_buffered_for(&key)?;
+
+        if let Some(range) = self.read_verbatim_range(&key, start, end)? {
+            return Ok(Some(range));
+        }
+        match KvStore::get(self, key)? {
+            Some(value) => value_range(&value, start, end).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Increments the key as an integer value, see `KvStore::increment`.
+    fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        KvStore::increment(self, key, delta)
+    }
+
+    /// Answers from the index without reading the log files, except for integers
+    /// which are read to report a `KvsError::WrongType`.
+    fn value_len(&mut self, key: String) -> Result<Option<u64>> {
+        self.build_index()?;
         let metadata = match self.map.get(&key) {
-            Some(metadata) => metadata,
-            None => return Ok(None),
+            Some(metadata) if !metadata.is_expired(self.clock.now_millis()) => metadata,
+            _ => return Ok(None),
         };
+        if let Some(len) = metadata.value_len {
+            return Ok(Some(len));
+        }
 
-        let mut reader = self
-            .readers
-            .get_mut(&metadata.file_index)
-            .ok_or(KvsError::UnexpectedCommand)?;
+        Ok(self.get(key)?.map(|value| value.len() as u64))
+    }
 
-        if let Command::Set { value, .. } = read_command(&mut reader, metadata)? {
-            Ok(Some(value))
-        } else {
-            Err(KvsError::UnexpectedCommand)
+    /// Walks the keys of our BTreeMap from `after`, skipping expired ones.
+    fn scan_keys(&mut self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        self.build_index()?;
+        self.maybe_compact()?;
+
+        let now = self.clock.now_millis();
+
+        Ok(self
+            .map
+            .range_after(after)?
+            .filter(|(_, metadata)| !metadata.is_expired(now))
+            .map(|(key, _)| key.to_owned())
+            .take(limit)
+            .collect())
+    }
+
+    /// Compacts the log files regardless of the amount of umcompacted bytes.
+    fn force_compact(&mut self) -> Result<CompactionReport> {
+        self.compact()
+    }
+
+    fn key_count(&self) -> u64 {
+        let now = self.clock.now_millis();
+        self.map
+            .iter()
+            .filter(|(_, metadata)| !metadata.is_expired(now))
+            .count() as u64
+    }
+
+    /// Amount of bytes taken by stale commands, to be reclaimed by a compaction.
+    /// Entries of expired keys are only accounted once compacted away.
+    fn uncompacted_bytes(&self) -> u64 {
+        self.umcompacted_bytes
+    }
+
+    fn compaction_threshold(&self) -> Option<u64> {
+        Some(COMPACTION_THRESHOLD)
+    }
+
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
+
+    /// Syncs the writer log file to disk, which `sync_writes` otherwise leaves to the OS.
+    fn flush(&mut self) -> Result<()> {
+        self.buffered_since = None;
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+            writer.get_ref().sync_data()?;
+            self.io.flushed();
         }
+
+        Ok(())
     }
 
     /// Removes a `key` and its associated metadata from our BTreeMap and
@@ -272,21 +3592,47 @@ impl KvsEngine for KvStore {
     /// ```
     /// use crate::kvs::KvsEngine;
     /// use self::kvs::KvStore;
-    /// use std::env::current_dir;
+    /// use tempfile::TempDir;
     ///
-    /// let mut store = KvStore::open(current_dir().unwrap()).unwrap();
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let mut store = KvStore::open(temp_dir.path()).unwrap();
     /// store.set("foo".to_owned(), "bar".to_owned());
     /// store.remove("foo".to_owned());
     /// ```
     fn remove(&mut self, key: String) -> Result<()> {
-        self.map.remove(&key).ok_or(KvsError::KeyNotFound)?;
+        KvStore::remove(self, key)
+    }
+}
 
-        let cmd = Command::Remove {
-            key: key.to_owned(),
-        };
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        Ok(())
+/// Spawns a timer raising the returned flag every `interval`.
+/// The timer stops once the store drops the flag.
+fn spawn_compaction_timer(interval: Duration) -> Arc<AtomicBool> {
+    let due = Arc::new(AtomicBool::new(false));
+    let timer_due = Arc::downgrade(&due);
+
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        match timer_due.upgrade() {
+            Some(due) => due.store(true, Ordering::SeqCst),
+            None => break,
+        }
+    });
+
+    due
+}
+
+/// Takes the exclusive lock of the store directory `dir_path`, so a single `KvStore`
+/// appends to its log files at a time.
+fn lock_dir(dir_path: &Path) -> Result<File> {
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(dir_path.join(LOCK_FILE))?;
+    match lock.try_lock() {
+        Ok(()) => Ok(lock),
+        Err(TryLockError::WouldBlock) => Err(KvsError::Locked),
+        Err(TryLockError::Error(e)) => Err(e.into()),
     }
 }
 
@@ -307,77 +3653,618 @@ fn fetch_file_indexes(dir_path: impl Into<PathBuf>) -> Result<Vec<u64>> {
     Ok(indexes)
 }
 
+/// Removes the empty log files left behind by crashes, except the most recent one
+/// which gets reused as the writer log file, and the outputs of compactions whose
+/// header keeps the highest version handed out.
+fn remove_empty_logs(dir_path: &Path, file_indexes: Vec<u64>) -> Result<Vec<u64>> {
+    let last_index = match file_indexes.last() {
+        Some(last_index) => *last_index,
+        None => return Ok(file_indexes),
+    };
+
+    let mut kept_indexes = Vec::with_capacity(file_indexes.len());
+    for file_index in file_indexes {
+        let file_path = dir_path.join(format!("{}.log", file_index));
+        let len = std::fs::metadata(&file_path)?.len();
+        let header_only = len > 0
+            && matches!(
+                LogHeader::read(&mut BufReader::new(File::open(&file_path)?)),
+                Ok((Some(header), start)) if start == len && header.max_version.is_none()
+            );
+        if file_index != last_index && (len == 0 || header_only) {
+            info!("Removing empty log file {:?}", file_path);
+            std::fs::remove_file(&file_path)?;
+        } else {
+            kept_indexes.push(file_index);
+        }
+    }
+
+    Ok(kept_indexes)
+}
+
+/// Picks the index of the log file that will receive new commands.
+fn writer_index(dir_path: &Path, file_indexes: &[u64]) -> Result<u64> {
+    let last_index = match file_indexes.last() {
+        Some(last_index) => *last_index,
+        None => return Ok(1),
+    };
+
+    let last_path = dir_path.join(format!("{}.log", last_index));
+    if std::fs::metadata(last_path)?.len() < COMPACTION_THRESHOLD {
+        Ok(last_index)
+    } else {
+        Ok(last_index + 1)
+    }
+}
+
+/// What happens to the unreadable parts of the log files while opening a store.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Recovery {
+    /// Leaves the log files untouched, for read-only stores.
+    Untouched,
+    /// Drops the incomplete commands at the end of the log files.
+    TornTails,
+    /// Drops corrupt commands along with the ones following them, on top of the
+    /// incomplete ones.
+    Repair,
+}
+
+/// Counts the log bytes replayed while opening a store, reporting them to the
+/// `replay_progress` callback of its options.
+struct ReplayProgress<'a> {
+    callback: Option<&'a mut Box<dyn FnMut(u64, u64) + Send>>,
+    // Bytes of the log files fully replayed.
+    replayed: u64,
+    total: u64,
+    last_reported: u64,
+}
+
+impl<'a> ReplayProgress<'a> {
+    /// Records that the current log file was replayed up to `pos`.
+    fn advance(&mut self, pos: u64) {
+        let replayed = self.replayed + pos;
+        if replayed - self.last_reported >= REPLAY_PROGRESS_INTERVAL {
+            self.report(replayed);
+        }
+    }
+
+    /// Records that the current log file, of `len` bytes, was entirely replayed.
+    fn finish_file(&mut self, len: u64) {
+        self.replayed += len;
+        self.report(self.replayed);
+    }
+
+    fn report(&mut self, replayed: u64) {
+        if let Some(callback) = self.callback.as_mut() {
+            callback(replayed, self.total);
+        }
+        self.last_reported = replayed;
+    }
+}
+
 fn load_files(
     dir_path: impl Into<PathBuf>,
     file_indexes: &Vec<u64>,
-    readers: &mut HashMap<u64, BufReader<File>>,
-    map: &mut BTreeMap<String, CommandMetadata>,
-) -> Result<u64> {
+    readers: &mut ReaderCache,
+    index: &mut Index,
+    format: &dyn LogFormat,
+    recovery: Recovery,
+    callback: Option<&mut Box<dyn FnMut(u64, u64) + Send>>,
+) -> Result<(u64, u64)> {
     let dir_path = dir_path.into();
     let mut total_umcompacted_bytes: u64 = 0;
+    let mut end = 0;
+
+    let mut progress = ReplayProgress {
+        callback,
+        replayed: 0,
+        total: 0,
+        last_reported: 0,
+    };
+    if progress.callback.is_some() {
+        for file_index in file_indexes {
+            let file_path = dir_path.join(format!("{}.log", file_index));
+            progress.total += std::fs::metadata(&file_path)?.len();
+        }
+    }
 
     for file_index in file_indexes {
         let file_path = dir_path.join(format!("{}.log", file_index));
-        let reader = OpenOptions::new().read(true).open(file_path)?;
+        let reader = OpenOptions::new().read(true).open(&file_path)?;
+        let file_len = reader.metadata()?.len();
         let mut buffer = BufReader::new(reader);
 
-        total_umcompacted_bytes += load_file(file_index.to_owned(), &mut buffer, map)?;
+        let (umcompacted_bytes, file_end) = load_file(
+            &file_path,
+            file_index.to_owned(),
+            &mut buffer,
+            index,
+            format,
+            recovery,
+            &mut progress,
+        )?;
+        total_umcompacted_bytes += umcompacted_bytes;
+        end = file_end;
         readers.insert(file_index.to_owned(), buffer);
+        progress.finish_file(file_len);
     }
 
-    Ok(total_umcompacted_bytes)
+    // Offset right after the last command of the last log file.
+    Ok((total_umcompacted_bytes, end))
 }
 
 fn load_file(
+    file_path: &Path,
     file_index: u64,
     reader: &mut BufReader<File>,
-    map: &mut BTreeMap<String, CommandMetadata>,
-) -> Result<u64> {
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    index: &mut Index,
+    format: &dyn LogFormat,
+    recovery: Recovery,
+    progress: &mut ReplayProgress,
+) -> Result<(u64, u64)> {
+    reader.seek(SeekFrom::Start(0))?;
+    let start = match LogHeader::read(reader) {
+        Ok((Some(header), start)) => {
+            header.check(file_path, format)?;
+            index.max_version = index.max_version.max(header.max_version.unwrap_or(0));
+            start
+        }
+        Ok((None, _)) => 0,
+        // Like a torn command, a torn header is left by a crash right after creating
+        // the log file, which can't hold any command yet.
+        Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            if recovery != Recovery::Untouched {
+                warn!("Dropping incomplete header of {:?}", file_path);
+                OpenOptions::new().write(true).open(file_path)?.set_len(0)?;
+            }
+            return Ok((0, 0));
+        }
+        Err(e) => return Err(e),
+    };
+    let mut pos = reader.seek(SeekFrom::Start(start))?;
     let mut umcompacted_bytes: u64 = 0;
 
-    while let Some(command_result) = stream.next() {
-        let next_pos = stream.byte_offset() as u64;
-        let command = command_result?;
+    for command_result in format.stream(reader) {
+        let (command, next_pos) = match command_result {
+            Ok((command, offset)) => (command, start + offset),
+            // An incomplete command at the end of the log means we crashed while
+            // writing it, since it was never acknowledged it is safe to drop it.
+            // Read-only stores leave it in place as it might still be being written.
+            Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                if recovery != Recovery::Untouched {
+                    warn!("Dropping incomplete command at the end of {:?}", file_path);
+                    OpenOptions::new()
+                        .write(true)
+                        .open(file_path)?
+                        .set_len(pos)?;
+                }
+                break;
+            }
+            Err(KvsError::Io(e)) => return Err(e.into()),
+            Err(e) if recovery == Recovery::Repair => {
+                let file = OpenOptions::new().write(true).open(file_path)?;
+                warn!(
+                    "Repairing {:?}: dropping {} bytes from the corrupt command at offset {}: {}",
+                    file_path,
+                    file.metadata()?.len() - pos,
+                    pos,
+                    e
+                );
+                file.set_len(pos)?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
 
-        umcompacted_bytes += load_command(map, command, file_index, pos, next_pos);
+        note_key(&mut index.blooms, file_index, &command);
+        umcompacted_bytes += load_command(
+            &mut *index.map,
+            &mut index.soft_deleted,
+            &mut index.max_version,
+            command,
+            file_index,
+            pos,
+            next_pos,
+        );
+        progress.advance(next_pos);
         pos = next_pos;
     }
 
-    Ok(umcompacted_bytes)
+    Ok((umcompacted_bytes, pos))
+}
+
+/// Notes in `blooms` the key `command` sets in the log file `file_index`.
+fn note_key(blooms: &mut LogBlooms, file_index: u64, command: &Command) {
+    match command {
+        Command::Set { key, .. } | Command::SetInt { key, .. } => blooms.insert(file_index, key),
+        // Patches only apply to keys already set, removals don't set any.
+        Command::Patch { .. } | Command::Remove { .. } => {}
+    }
 }
 
 /// Load command into our BTreeMap and return the length of superseeded commands
 fn load_command(
-    map: &mut BTreeMap<String, CommandMetadata>,
+    map: &mut dyn KeyIndex,
+    soft_deleted: &mut BTreeMap<String, SoftDeleted>,
+    max_version: &mut u64,
     command: Command,
     file_index: u64,
     pos: u64,
     next_pos: u64,
 ) -> u64 {
-    let old_metadata = match command {
-        Command::Set { key, .. } => map.insert(
-            key,
-            CommandMetadata {
-                file_index: file_index,
-                position: pos,
-                length: (next_pos - pos),
-            },
-        ),
-        Command::Remove { key } => map.remove(&key),
+    let value_len = command.value_len();
+    let modified_at = command.modified_at();
+    let recorded_version = command.version();
+    let (key, expires_at) = match command {
+        Command::Set {
+            key, expires_at, ..
+        } => (key, expires_at),
+        Command::SetInt { key, .. } => (key, None),
+        Command::Patch { key, .. } => {
+            return match map.get_mut(&key) {
+                Some(metadata) => {
+                    metadata.patches.push(PatchRecord {
+                        file_index,
+                        position: pos,
+                        length: next_pos - pos,
+                    });
+                    metadata.version += 1;
+                    *max_version = (*max_version).max(metadata.version);
+                    // Learnt again by applying the patches.
+                    metadata.value_len = None;
+                    metadata.modified_at = modified_at.or(metadata.modified_at);
+                    0
+                }
+                // The patch of a key removed since is dead weight as well.
+                None => next_pos - pos,
+            };
+        }
+        // Tombstones are dead weight once replayed, compactions drop them.
+        Command::Remove { key, removed_at } => {
+            let removed = map.remove(&key);
+            let removed_len = removed.as_ref().map_or(0, |metadata| metadata.record_len());
+            // Whether the grace period still holds is up to the store.
+            if let (Some(set), Some(removed_at)) = (removed, removed_at) {
+                soft_deleted.insert(
+                    key,
+                    SoftDeleted {
+                        set,
+                        tombstone_file_index: file_index,
+                        tombstone_position: pos,
+                        tombstone_length: next_pos - pos,
+                        removed_at,
+                    },
+                );
+            }
+            return removed_len + (next_pos - pos);
+        }
     };
+    soft_deleted.remove(&key);
+
+    let stale = map
+        .get(&key)
+        .map_or(0, |metadata| metadata.stale_once_superseded());
+    let version = recorded_version.unwrap_or_else(|| next_version(&*map, *max_version, &key));
+    *max_version = (*max_version).max(version);
+    let old_metadata = map.insert(
+        key,
+        CommandMetadata {
+            file_index,
+            position: pos,
+            length: (next_pos - pos),
+            stale,
+            expires_at,
+            version,
+            value_len,
+            modified_at,
+            patches: Vec::new(),
+        },
+    );
 
     match old_metadata {
-        Some(metadata) => metadata.length,
+        Some(metadata) => metadata.record_len(),
         None => 0,
     }
 }
 
-fn read_command<R: Read + Seek>(mut reader: R, metadata: &CommandMetadata) -> Result<Command> {
-    reader.seek(SeekFrom::Start(metadata.position))?;
-    let mut chunk = reader.take(metadata.length);
-    let command = serde_json::from_reader(&mut chunk)?;
+/// The version `key` gets from its next set: the one following its current version,
+/// or following every version handed out so far while it is missing, so a key
+/// removed and set again doesn't get the versions it had back.
+fn next_version(map: &dyn KeyIndex, max_version: u64, key: &str) -> u64 {
+    map.get(key)
+        .map_or(max_version, |metadata| metadata.version)
+        + 1
+}
+
+/// The command giving `key` the value `command` holds, or removing `key` if `None`,
+/// written at `now` in milliseconds since the Unix epoch. A removal records
+/// `removed_at` so the key can be restored, like `KvStore::remove` does.
+fn swapped_command(
+    command: Option<Command>,
+    key: String,
+    now: u64,
+    version: Option<u64>,
+    removed_at: Option<u64>,
+) -> Result<Command> {
+    match command {
+        Some(Command::Set {
+            value, expires_at, ..
+        }) => Ok(Command::Set {
+            key,
+            value,
+            expires_at,
+            modified_at: Some(now),
+            version,
+        }),
+        Some(Command::SetInt { value, .. }) => Ok(Command::SetInt {
+            key,
+            value,
+            modified_at: Some(now),
+            version,
+        }),
+        Some(Command::Patch { .. }) | Some(Command::Remove { .. }) => {
+            Err(KvsError::UnexpectedCommand)
+        }
+        None => Ok(Command::Remove { key, removed_at }),
+    }
+}
+
+/// Appends the `length` bytes at `position` of the log file `file_index` to `writer`,
+/// returning how many got copied.
+fn copy_command(
+    readers: &mut ReaderCache,
+    io: &IoCounters,
+    file_index: u64,
+    position: u64,
+    length: u64,
+    writer: &mut File,
+) -> Result<u64> {
+    let reader = readers.get_mut(file_index)?;
+    reader.seek(SeekFrom::Start(position))?;
+    let len = std::io::copy(&mut reader.take(length), writer)?;
+    io.seeked();
+    io.read(len);
+    io.wrote(len);
+    Ok(len)
+}
+
+/// Appends the command `metadata` points to to `writer` like `copy_command`, its
+/// patches living before `compaction_index` applied on top of its value, returning
+/// how many bytes got written along with the patches left.
+fn copy_patched_command(
+    readers: &mut ReaderCache,
+    io: &IoCounters,
+    format: &dyn LogFormat,
+    metadata: &CommandMetadata,
+    compaction_index: u64,
+    writer: &mut File,
+) -> Result<(u64, Vec<PatchRecord>)> {
+    let applied = metadata
+        .patches
+        .partition_point(|patch| patch.file_index < compaction_index);
+    if applied == 0 {
+        let len = copy_command(
+            readers,
+            io,
+            metadata.file_index,
+            metadata.position,
+            metadata.length,
+            writer,
+        )?;
+        return Ok((len, metadata.patches.to_owned()));
+    }
+
+    let (applied, left) = metadata.patches.split_at(applied);
+    let command = read_command(readers.get_mut(metadata.file_index)?, metadata, format, io)?;
+    let command = patch_command(readers, command, applied, format, io)?;
+    let mut bytes = Vec::new();
+    format.write_command(&mut bytes, &command)?;
+    writer.write_all(&bytes)?;
+    io.wrote(bytes.len() as u64);
+    Ok((bytes.len() as u64, left.to_owned()))
+}
+
+/// Fails with `KvsError::CorruptRecord` unless the command `metadata` points to,
+/// patches applied, reads back as a set of `key`.
+fn check_record(
+    readers: &mut ReaderCache,
+    format: &dyn LogFormat,
+    io: &IoCounters,
+    key: &str,
+    metadata: &CommandMetadata,
+) -> Result<()> {
+    let command = read_command(readers.get_mut(metadata.file_index)?, metadata, format, io)
+        .and_then(|command| patch_command(readers, command, &metadata.patches, format, io));
+    match command {
+        Ok(Command::Set {
+            key: stored_key, ..
+        })
+        | Ok(Command::SetInt {
+            key: stored_key, ..
+        }) if stored_key == key => Ok(()),
+        Err(KvsError::Io(e)) if e.kind() != io::ErrorKind::UnexpectedEof => Err(KvsError::Io(e)),
+        _ => Err(KvsError::CorruptRecord(key.to_owned())),
+    }
+}
+
+/// Applies `patches`, in order, on top of the value `command` sets.
+fn patch_command(
+    readers: &mut ReaderCache,
+    mut command: Command,
+    patches: &[PatchRecord],
+    format: &dyn LogFormat,
+    io: &IoCounters,
+) -> Result<Command> {
+    for patch in patches {
+        let reader = readers.get_mut(patch.file_index)?;
+        let patch = read_at(reader, patch.position, patch.length, format, io)?;
+        apply_patch(&mut command, patch)?;
+    }
 
     Ok(command)
 }
+
+/// Applies the edits of `patch`, a `Command::Patch`, to the value `command` sets.
+fn apply_patch(command: &mut Command, patch: Command) -> Result<()> {
+    match (command, patch) {
+        (
+            Command::Set {
+                value,
+                modified_at,
+                version,
+                ..
+            },
+            Command::Patch {
+                patch,
+                modified_at: patched_at,
+                ..
+            },
+        ) => {
+            patch.apply(value)?;
+            *modified_at = patched_at.or(*modified_at);
+            // Every patch makes a new version, like it does once indexed.
+            *version = version.map(|version| version + 1);
+            Ok(())
+        }
+        (Command::SetInt { .. }, Command::Patch { .. }) => Err(KvsError::WrongType),
+        _ => Err(KvsError::UnexpectedCommand),
+    }
+}
+
+/// Largest chunk of a streamed value read at once, in bytes.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Writes the JSON `Command::Set` of `key` with the value read from `reader`,
+/// escaping it chunk by chunk, and returns the length of the value.
+fn write_streamed_set(
+    writer: &mut impl Write,
+    key: &str,
+    mut reader: impl Read,
+    modified_at: u64,
+    version: Option<u64>,
+    max_value_size: Option<usize>,
+) -> Result<u64> {
+    writer.write_all(b"{\"Set\":{\"key\":")?;
+    serde_json::to_writer(&mut *writer, key)?;
+    writer.write_all(b",\"value\":\"")?;
+
+    let mut chunk = vec![0; STREAM_CHUNK_SIZE];
+    // Bytes of a character split by the end of the previous read.
+    let mut pending = 0;
+    let mut value_len = 0;
+    loop {
+        let read = match reader.read(&mut chunk[pending..]) {
+            Ok(0) if pending > 0 => return Err(invalid_utf8().into()),
+            Ok(0) => break,
+            Ok(read) => pending + read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let valid = match std::str::from_utf8(&chunk[..read]) {
+            Ok(valid) => valid,
+            Err(e) if e.error_len().is_none() => {
+                std::str::from_utf8(&chunk[..e.valid_up_to()]).map_err(|_| invalid_utf8())?
+            }
+            Err(_) => return Err(invalid_utf8().into()),
+        };
+
+        value_len += valid.len() as u64;
+        match max_value_size {
+            Some(max) if value_len > max as u64 => return Err(KvsError::ValueTooLarge(max)),
+            _ => (),
+        }
+        // The escaped chunk without the quotes around it.
+        let escaped = serde_json::to_string(valid)?;
+        writer.write_all(&escaped.as_bytes()[1..escaped.len() - 1])?;
+
+        let consumed = valid.len();
+        chunk.copy_within(consumed..read, 0);
+        pending = read - consumed;
+    }
+
+    write!(writer, "\",\"modified_at\":{}", modified_at)?;
+    if let Some(version) = version {
+        write!(writer, ",\"version\":{}", version)?;
+    }
+    writer.write_all(b"}}")?;
+    Ok(value_len)
+}
+
+/// A value written as is in the record of a `Command::Set` in the JSON format,
+/// without escapes, so ranges of it can be read on their own.
+struct VerbatimValue {
+    len: u64,
+    /// Offset of the value in the record.
+    offset: u64,
+    /// The fields the record ends with, read back to tell it apart from a record
+    /// whose escapes add up to the length of a field it lacks.
+    suffix: Vec<u8>,
+}
+
+/// Returns `None` for the records whose value can only be known by reading it whole.
+fn verbatim_value(
+    format: &dyn LogFormat,
+    key: &str,
+    metadata: &CommandMetadata,
+) -> Result<Option<VerbatimValue>> {
+    let len = match metadata.value_len {
+        Some(len) if metadata.patches.is_empty() && format.name() == JsonFormat.name() => len,
+        _ => return Ok(None),
+    };
+
+    // Fields are serialized in their order of declaration in `Command::Set`.
+    let offset = format!(
+        "{{\"Set\":{{\"key\":{},\"value\":\"",
+        serde_json::to_string(key)?
+    )
+    .len() as u64;
+    let mut suffix = String::from("\"");
+    if let Some(expires_at) = metadata.expires_at {
+        suffix += &format!(",\"expires_at\":{}", expires_at);
+    }
+    if let Some(modified_at) = metadata.modified_at {
+        suffix += &format!(",\"modified_at\":{}", modified_at);
+    }
+    // The records predating versions are read whole.
+    suffix += &format!(",\"version\":{}}}}}", metadata.version);
+
+    // Escapes would make the record longer than its parts.
+    let verbatim = offset + len + suffix.len() as u64 == metadata.length;
+    Ok(verbatim.then(|| VerbatimValue {
+        len,
+        offset,
+        suffix: suffix.into_bytes(),
+    }))
+}
+
+fn invalid_utf8() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "stream did not contain valid UTF-8",
+    )
+}
+
+fn read_command<R: Read + Seek>(
+    reader: R,
+    metadata: &CommandMetadata,
+    format: &dyn LogFormat,
+    io: &IoCounters,
+) -> Result<Command> {
+    read_at(reader, metadata.position, metadata.length, format, io)
+}
+
+/// Reads the command of `length` bytes at `position` of `reader`.
+fn read_at<R: Read + Seek>(
+    mut reader: R,
+    position: u64,
+    length: u64,
+    format: &dyn LogFormat,
+    io: &IoCounters,
+) -> Result<Command> {
+    reader.seek(SeekFrom::Start(position))?;
+    io.seeked();
+    io.read(length);
+    let mut chunk = reader.take(length);
+
+    format.read_command(&mut chunk)
+}
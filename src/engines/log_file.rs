@@ -0,0 +1,162 @@
+use super::KvStoreOptions;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+#[cfg(feature = "crash-test")]
+use std::sync::{Arc, Mutex};
+
+/// Syncs the directory at `path`, making the creation, removal or renaming of the files
+/// it holds durable. Only Unix platforms can sync a directory, it is a no-op elsewhere.
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(path)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = path;
+
+    Ok(())
+}
+
+/// Bytes buffered by the writer of a log file, as much as a `BufWriter` by default.
+const DEFAULT_CAPACITY: usize = 8 * 1024;
+
+/// A log file receiving new commands.
+pub(crate) struct LogFile {
+    file: File,
+    /// Bytes its writer buffers, enough for every set pending under `coalesce_bytes`.
+    capacity: usize,
+    #[cfg(feature = "crash-test")]
+    crash_test: Option<CrashTest>,
+}
+
+impl LogFile {
+    /// Wraps a file opened in append mode, configured by the store `options`.
+    pub(crate) fn new(file: File, options: &KvStoreOptions) -> Self {
+        LogFile {
+            file,
+            capacity: options
+                .coalesce_bytes
+                .map_or(DEFAULT_CAPACITY, |bytes| bytes as usize),
+            #[cfg(feature = "crash-test")]
+            crash_test: options.crash_test.clone(),
+        }
+    }
+
+    /// Wraps another file sharing the configuration of the current one.
+    pub(crate) fn replace(&self, file: File) -> Self {
+        LogFile {
+            file,
+            capacity: self.capacity,
+            #[cfg(feature = "crash-test")]
+            crash_test: self.crash_test.clone(),
+        }
+    }
+
+    /// Flushes the data written so far to disk.
+    pub(crate) fn sync_data(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    /// Returns `true` if the file was removed since it got opened, writes then going
+    /// nowhere. Only detected on Unix platforms.
+    pub(crate) fn is_removed(&self) -> io::Result<bool> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            Ok(self.file.metadata()?.nlink() == 0)
+        }
+        #[cfg(not(unix))]
+        Ok(false)
+    }
+
+    /// Cuts the file down to `len` bytes, dropping what was written past it.
+    pub(crate) fn truncate(&self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    /// Returns a new handle to the underlying file.
+    pub(crate) fn try_clone_file(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+
+    /// Buffers writes to this log file.
+    pub(crate) fn into_writer(self) -> BufWriter<LogFile> {
+        #[cfg(feature = "crash-test")]
+        {
+            // Unbuffered so every single byte goes through the crash test.
+            if self.crash_test.is_some() {
+                return BufWriter::with_capacity(0, self);
+            }
+        }
+
+        BufWriter::with_capacity(self.capacity, self)
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(feature = "crash-test")]
+        {
+            if let Some(crash_test) = &self.crash_test {
+                crash_test.before_write()?;
+                let written = self.file.write(&buf[..buf.len().min(1)])?;
+                self.file.flush()?;
+                return Ok(written);
+            }
+        }
+
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for LogFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+/// Simulates crashes by making writes to the log files fail on demand.
+///
+/// While a `CrashTest` is attached to a store every serialized byte is written
+/// and flushed individually, so a failure can be injected at any point of a
+/// command. Clones share the same state, allowing tests to keep a handle around.
+#[cfg(feature = "crash-test")]
+#[derive(Clone, Debug, Default)]
+pub struct CrashTest {
+    remaining_writes: Arc<Mutex<Option<u64>>>,
+}
+
+#[cfg(feature = "crash-test")]
+impl CrashTest {
+    /// Creates a `CrashTest` that doesn't fail any write until armed.
+    pub fn new() -> Self {
+        CrashTest::default()
+    }
+
+    /// Lets `writes` more bytes reach the log files, every write after that fails
+    /// as if the process had crashed.
+    pub fn fail_after(&self, writes: u64) {
+        *self.remaining_writes.lock().unwrap() = Some(writes);
+    }
+
+    /// Stops injecting failures.
+    pub fn disarm(&self) {
+        *self.remaining_writes.lock().unwrap() = None;
+    }
+
+    fn before_write(&self) -> io::Result<()> {
+        match self.remaining_writes.lock().unwrap().as_mut() {
+            Some(0) => Err(io::Error::other("injected crash")),
+            Some(remaining) => {
+                *remaining -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use super::LogFormat;
+use crate::{KvsError, Result};
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, BufRead, Cursor, Read};
+
+/// Marks a log file starting with a header. Log files predating headers start
+/// right away with a command, which never begins with a NUL byte.
+const MAGIC: &[u8; 4] = b"\0KVS";
+
+/// Latest version of the log files, the ones without a header being version 0.
+pub(crate) const LOG_VERSION: u32 = 1;
+
+/// A stream along with the bytes read ahead from it.
+pub(crate) type Unread<R> = io::Chain<Cursor<Vec<u8>>, R>;
+
+/// Describes how the commands following it in a log file are laid out, letting a
+/// store refuse the log files it doesn't know how to read.
+///
+/// It is written as the magic bytes followed by a line of JSON.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LogHeader {
+    pub(crate) version: u32,
+    /// Name of the `LogFormat` of the commands.
+    pub(crate) format: String,
+    pub(crate) compressed: bool,
+    /// Highest version the store handed out when a compaction created the log file,
+    /// kept as the commands of the removed keys holding it are gone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) max_version: Option<u64>,
+}
+
+impl LogHeader {
+    /// The header of the log files created with `format`.
+    pub(crate) fn new(format: &dyn LogFormat) -> Self {
+        LogHeader {
+            version: LOG_VERSION,
+            format: format.name().to_owned(),
+            compressed: false,
+            max_version: None,
+        }
+    }
+
+    /// Serializes the header along with its magic bytes.
+    pub(crate) fn encode(&self) -> Result<Vec<u8>> {
+        let mut bytes = MAGIC.to_vec();
+        serde_json::to_writer(&mut bytes, self)?;
+        bytes.push(b'\n');
+        Ok(bytes)
+    }
+
+    /// Reads the header at the start of `reader`, returning `None` for a log file
+    /// predating headers, along with the offset its first command starts at.
+    ///
+    /// A header cut short by the end of the file is reported as a `KvsError::Io` of
+    /// kind `UnexpectedEof`, like a torn command.
+    pub(crate) fn read<R: BufRead>(reader: &mut R) -> Result<(Option<LogHeader>, u64)> {
+        let mut magic = Vec::with_capacity(MAGIC.len());
+        reader
+            .by_ref()
+            .take(MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+        if magic != MAGIC {
+            if MAGIC.starts_with(&magic) && !magic.is_empty() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            return Ok((None, 0));
+        }
+
+        let (header, len) = read_line(reader)?;
+        Ok((Some(header), (MAGIC.len() + len) as u64))
+    }
+
+    /// Reads the header at the start of a stream which can't seek back, returning
+    /// `None` for a log predating headers along with the commands following it.
+    pub(crate) fn split<R: BufRead>(mut reader: R) -> Result<(Option<LogHeader>, Unread<R>)> {
+        let mut magic = Vec::with_capacity(MAGIC.len());
+        reader
+            .by_ref()
+            .take(MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+        if magic != MAGIC {
+            // The bytes read belong to the first command.
+            return Ok((None, Cursor::new(magic).chain(reader)));
+        }
+
+        let (header, _) = read_line(&mut reader)?;
+        Ok((Some(header), Cursor::new(Vec::new()).chain(reader)))
+    }
+
+    /// Fails unless the commands following the header of `source`, a log file or
+    /// stream, can be read with `format`.
+    pub(crate) fn check(&self, source: impl fmt::Debug, format: &dyn LogFormat) -> Result<()> {
+        if self.version > LOG_VERSION {
+            return Err(KvsError::IncompatibleLog(format!(
+                "{:?} has version {}, newer than the supported version {}",
+                source, self.version, LOG_VERSION
+            )));
+        }
+        if self.format != format.name() {
+            return Err(KvsError::IncompatibleLog(format!(
+                "{:?} is written in the {} format instead of {}",
+                source,
+                self.format,
+                format.name()
+            )));
+        }
+        if self.compressed {
+            return Err(KvsError::IncompatibleLog(format!(
+                "{:?} is compressed, which is not supported",
+                source
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads the JSON line of a header following its magic bytes, along with its length.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<(LogHeader, usize)> {
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+
+    Ok((serde_json::from_slice(&line)?, line.len()))
+}
@@ -1,4 +1,6 @@
-use crate::Result;
+use crate::{KvsError, Result};
+
+use serde::{Deserialize, Serialize};
 
 /// Trait for a key value storage engine.
 pub trait KvsEngine {
@@ -12,14 +14,215 @@ pub trait KvsEngine {
     /// Returns `None` if the given key does not exist.
     fn get(&mut self, key: String) -> Result<Option<String>>;
 
+    /// Gets the bytes `start..end` of the string value of a given string key,
+    /// `end` being clamped to the length of the value.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidRange` if `start` is past `end` or if the range
+    /// doesn't fall on character boundaries.
+    fn get_range(&mut self, key: String, start: usize, end: usize) -> Result<Option<String>> {
+        match self.get(key)? {
+            Some(value) => value_range(&value, start, end).map(Some),
+            None => Ok(None),
+        }
+    }
+
     /// Removes a given key.
     ///
     /// # Errors
     ///
     /// It returns `KvsError::KeyNotFound` if the given key is not found.
     fn remove(&mut self, key: String) -> Result<()>;
+
+    /// Removes a given key only if its value is still `expected`, returning whether
+    /// it was removed. A missing key is left alone as well.
+    ///
+    /// Engines shared between threads override it so no write can slip in between
+    /// the comparison and the removal.
+    fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        match self.get(key.to_owned())? {
+            Some(value) if value == expected => {
+                self.remove(key)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Length in bytes of the value of a given string key, `None` if the key
+    /// doesn't exist.
+    ///
+    /// By default the value itself is fetched, engines override it when they can
+    /// tell the length without reading the value.
+    fn value_len(&mut self, key: String) -> Result<Option<u64>> {
+        Ok(self.get(key)?.map(|value| value.len() as u64))
+    }
+
+    /// Adds `delta` to the integer held by `key`, a missing key counting as 0, and
+    /// returns the result.
+    ///
+    /// By default the integer is stored as its decimal string, a value which
+    /// isn't one failing with `KvsError::WrongType`. Engines with typed values
+    /// override it, as do the ones shared between threads.
+    fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        let current = match self.get(key.to_owned())? {
+            Some(value) => value.parse::<i64>().map_err(|_| KvsError::WrongType)?,
+            None => 0,
+        };
+        let value = current
+            .checked_add(delta)
+            .ok_or(KvsError::IntegerOverflow)?;
+
+        self.set(key, value.to_string())?;
+        Ok(value)
+    }
+
+    /// Lists up to `limit` keys greater than `after` in ascending order, every key
+    /// when `after` is `None`. Paginates through keys by passing the last key listed.
+    fn scan_keys(&mut self, after: Option<String>, limit: usize) -> Result<Vec<String>>;
+
+    /// Compacts the underlying storage right away, reporting the space reclaimed.
+    fn force_compact(&mut self) -> Result<CompactionReport>;
+
+    /// Amount of keys currently stored.
+    fn key_count(&self) -> u64;
+
+    /// Amount of bytes taken by stale data, to be reclaimed by a compaction.
+    fn uncompacted_bytes(&self) -> u64;
+
+    /// Uncompacted bytes past which the engine compacts on its own, `None` for the
+    /// engines which don't compact or decide on their own when to.
+    fn compaction_threshold(&self) -> Option<u64> {
+        None
+    }
+
+    /// Name of the engine, as reported to clients by `KvsClient::config`, `"custom"`
+    /// for the engines which don't tell it.
+    fn name(&self) -> &'static str {
+        "custom"
+    }
+
+    /// Makes every acknowledged write durable, called before shutting down.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The bytes `start..end` of `value` as `KvsEngine::get_range` returns them.
+pub(crate) fn value_range(value: &str, start: usize, end: usize) -> Result<String> {
+    let end = end.min(value.len());
+    match value.get(start..end) {
+        Some(range) => Ok(range.to_owned()),
+        None => Err(KvsError::InvalidRange),
+    }
+}
+
+impl<E: KvsEngine + ?Sized> KvsEngine for Box<E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        (**self).set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        (**self).get(key)
+    }
+
+    fn get_range(&mut self, key: String, start: usize, end: usize) -> Result<Option<String>> {
+        (**self).get_range(key, start, end)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        (**self).remove(key)
+    }
+
+    fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        (**self).remove_if(key, expected)
+    }
+
+    fn value_len(&mut self, key: String) -> Result<Option<u64>> {
+        (**self).value_len(key)
+    }
+
+    fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        (**self).increment(key, delta)
+    }
+
+    fn scan_keys(&mut self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        (**self).scan_keys(after, limit)
+    }
+
+    fn force_compact(&mut self) -> Result<CompactionReport> {
+        (**self).force_compact()
+    }
+
+    fn key_count(&self) -> u64 {
+        (**self).key_count()
+    }
+
+    fn uncompacted_bytes(&self) -> u64 {
+        (**self).uncompacted_bytes()
+    }
+
+    fn compaction_threshold(&self) -> Option<u64> {
+        (**self).compaction_threshold()
+    }
+
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+/// Summary of a compaction run, useful for tuning the threshold and for logging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionReport {
+    /// Total size in bytes of the log files right before compacting.
+    pub bytes_before: u64,
+    /// Total size in bytes of the log files right after compacting.
+    pub bytes_after: u64,
+    /// Amount of stale log files removed from disk.
+    pub files_removed: u64,
+}
+
+impl CompactionReport {
+    /// Amount of bytes freed by the compaction.
+    pub fn bytes_reclaimed(&self) -> u64 {
+        self.bytes_before.saturating_sub(self.bytes_after)
+    }
 }
 
+mod bloom;
+mod cleaner;
+mod clock;
+mod format;
+mod group_commit;
+mod io_stats;
+mod key_index;
 mod kvs;
+mod log_file;
+mod log_header;
+mod patch;
+mod reader_cache;
+mod sled;
+mod store_manager;
+mod value_cache;
 
-pub use self::kvs::KvStore;
+pub use self::clock::{Clock, SystemClock};
+pub use self::format::{Command, CommandStream, JsonFormat, LogFormat};
+pub use self::group_commit::GroupCommit;
+pub use self::io_stats::IoStats;
+pub use self::key_index::KeyIndexKind;
+pub use self::kvs::{
+    CorruptionPolicy, IndexDiscrepancy, IndexPolicy, KeyPolicy, KvStore, KvStoreOptions,
+    MergePolicy, MissingLogPolicy, Snapshot, SwapPolicy, VerifyReport,
+};
+#[cfg(feature = "crash-test")]
+pub use self::log_file::CrashTest;
+pub use self::patch::Patch;
+pub use self::sled::SledKvsEngine;
+pub use self::store_manager::StoreManager;
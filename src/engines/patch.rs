@@ -0,0 +1,64 @@
+use crate::{KvsError, Result};
+
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Byte-range edits to the string value of a key, see `KvStore::patch`.
+///
+/// Edits apply in order, the range of each one counting in the value left by the
+/// previous ones.
+///
+/// ```
+/// use self::kvs::Patch;
+///
+/// let patch = Patch::new().replace(0..3, "foo").replace(3..3, "bar");
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Patch {
+    edits: Vec<Edit>,
+}
+
+/// Replaces the bytes `start..end` of a value with `data`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Edit {
+    start: usize,
+    end: usize,
+    data: String,
+}
+
+impl Patch {
+    /// Creates a patch leaving the value untouched.
+    pub fn new() -> Self {
+        Patch::default()
+    }
+
+    /// Adds an edit replacing the bytes `range` of the value with `data`, an empty
+    /// range inserting it and an empty `data` deleting the range.
+    pub fn replace(mut self, range: Range<usize>, data: impl Into<String>) -> Self {
+        self.edits.push(Edit {
+            start: range.start,
+            end: range.end,
+            data: data.into(),
+        });
+        self
+    }
+
+    /// Applies the edits to `value`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::InvalidRange`, leaving `value` untouched, if an edit is
+    /// reversed, goes past the end of the value or splits a character.
+    pub(crate) fn apply(&self, value: &mut String) -> Result<()> {
+        let mut patched = value.to_owned();
+        for edit in &self.edits {
+            if patched.get(edit.start..edit.end).is_none() {
+                return Err(KvsError::InvalidRange);
+            }
+            patched.replace_range(edit.start..edit.end, &edit.data);
+        }
+
+        *value = patched;
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{KvsError, Result};
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+
+/// Keeps track of the log files of a store, holding a bounded amount of them open.
+///
+/// Once `capacity` readers are open the least recently used one is closed, it gets
+/// reopened on demand the next time its log file is read.
+pub(crate) struct ReaderCache {
+    path: PathBuf,
+    capacity: Option<usize>,
+    file_indexes: BTreeSet<u64>,
+    readers: HashMap<u64, (BufReader<File>, u64)>,
+    uses: u64,
+}
+
+impl ReaderCache {
+    /// Creates a cache for the log files in `path`, `None` leaves it unbounded.
+    pub(crate) fn new(path: PathBuf, capacity: Option<usize>) -> Self {
+        ReaderCache {
+            path,
+            // A reader is always needed to serve the current read.
+            capacity: capacity.map(|capacity| capacity.max(1)),
+            file_indexes: BTreeSet::new(),
+            readers: HashMap::new(),
+            uses: 0,
+        }
+    }
+
+    /// Tracks the log file `file_index`, caching the already open `reader` for it.
+    pub(crate) fn insert(&mut self, file_index: u64, reader: BufReader<File>) {
+        self.file_indexes.insert(file_index);
+        self.readers.remove(&file_index);
+        self.cache(file_index, reader);
+    }
+
+    /// Returns a reader of the log file `file_index`, reopening it if it was evicted.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::MissingLogFile` if the log file isn't tracked or can't
+    /// be found when reopening it.
+    pub(crate) fn get_mut(&mut self, file_index: u64) -> Result<&mut BufReader<File>> {
+        if !self.file_indexes.contains(&file_index) {
+            return Err(KvsError::MissingLogFile { file_index });
+        }
+
+        if !self.readers.contains_key(&file_index) {
+            let file_path = self.path.join(format!("{}.log", file_index));
+            let file = match File::open(file_path) {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(KvsError::MissingLogFile { file_index })
+                }
+                Err(e) => return Err(e.into()),
+            };
+            self.cache(file_index, BufReader::new(file));
+        }
+
+        self.uses += 1;
+        let (reader, last_use) = self
+            .readers
+            .get_mut(&file_index)
+            .ok_or(KvsError::UnexpectedCommand)?;
+        *last_use = self.uses;
+
+        Ok(reader)
+    }
+
+    /// Stops tracking the log file `file_index`, handing over its reader if open.
+    pub(crate) fn remove(&mut self, file_index: u64) -> Option<BufReader<File>> {
+        self.file_indexes.remove(&file_index);
+        self.readers.remove(&file_index).map(|(reader, _)| reader)
+    }
+
+    /// Indexes of every tracked log file, open or not, in ascending order.
+    pub(crate) fn file_indexes(&self) -> impl Iterator<Item = u64> + '_ {
+        self.file_indexes.iter().cloned()
+    }
+
+    /// Amount of tracked log files, open or not.
+    pub(crate) fn file_count(&self) -> usize {
+        self.file_indexes.len()
+    }
+
+    fn cache(&mut self, file_index: u64, reader: BufReader<File>) {
+        if let Some(capacity) = self.capacity {
+            while self.readers.len() >= capacity {
+                let least_recent = self
+                    .readers
+                    .iter()
+                    .min_by_key(|(_, (_, last_use))| *last_use)
+                    .map(|(index, _)| *index);
+
+                match least_recent {
+                    Some(index) => self.readers.remove(&index),
+                    None => break,
+                };
+            }
+        }
+
+        self.uses += 1;
+        self.readers.insert(file_index, (reader, self.uses));
+    }
+}
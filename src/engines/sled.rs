@@ -0,0 +1,108 @@
+use super::{CompactionReport, KvsEngine};
+use crate::{KvsError, Result};
+
+use sled::Db;
+use std::ops::Bound;
+
+/// A `KvsEngine` backed by the `sled` embedded database.
+pub struct SledKvsEngine {
+    db: Db,
+}
+
+impl SledKvsEngine {
+    /// Wraps an already started `sled` database.
+    ///
+    /// ```
+    /// use self::kvs::SledKvsEngine;
+    /// use tempfile::TempDir;
+    ///
+    /// let temp_dir = TempDir::new().unwrap();
+    /// let db = sled::Db::start_default(temp_dir.path()).unwrap();
+    /// let engine = SledKvsEngine::new(db);
+    /// ```
+    pub fn new(db: Db) -> Self {
+        SledKvsEngine { db }
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    /// Inserts the value and flushes it to disk.
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.db.set(key, value.into_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.db.get(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Deletes the key and flushes the deletion to disk.
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.db.del(key)?.ok_or(KvsError::KeyNotFound)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Measures the stored bytes without decoding them as a string.
+    fn value_len(&mut self, key: String) -> Result<Option<u64>> {
+        Ok(self.db.get(key)?.map(|value| value.len() as u64))
+    }
+
+    /// Deletes the key through a compare-and-swap and flushes the deletion to disk.
+    fn remove_if(&mut self, key: String, expected: String) -> Result<bool> {
+        let removed = self
+            .db
+            .cas(key, Some(expected), None as Option<&[u8]>)?
+            .is_ok();
+        if removed {
+            self.db.flush()?;
+        }
+        Ok(removed)
+    }
+
+    fn scan_keys(&mut self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let lower = after.map_or(Bound::Unbounded, |after| {
+            Bound::Excluded(after.into_bytes())
+        });
+        let mut keys = Vec::new();
+
+        for entry in self.db.range((lower, Bound::Unbounded)).take(limit) {
+            let (key, _) = entry?;
+            keys.push(String::from_utf8(key)?);
+        }
+
+        Ok(keys)
+    }
+
+    /// `sled` reclaims space on its own, this only flushes pending writes.
+    fn force_compact(&mut self) -> Result<CompactionReport> {
+        self.db.flush()?;
+        Ok(CompactionReport {
+            bytes_before: 0,
+            bytes_after: 0,
+            files_removed: 0,
+        })
+    }
+
+    fn key_count(&self) -> u64 {
+        self.db.len() as u64
+    }
+
+    /// Space management is internal to `sled`, so nothing is reported.
+    fn uncompacted_bytes(&self) -> u64 {
+        0
+    }
+
+    fn name(&self) -> &'static str {
+        "sled"
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,134 @@
+use super::{KvStore, KvStoreOptions, KvsEngine};
+use crate::{KvsError, Result};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Opens a `KvStore` per tenant, each in its own subdirectory, holding a bounded
+/// amount of them open.
+///
+/// Once `capacity` stores are open the least recently used one is flushed and
+/// closed, it gets reopened on demand the next time its tenant is asked for.
+///
+/// ```
+/// use kvs::{KvsEngine, StoreManager};
+/// use tempfile::TempDir;
+///
+/// let temp_dir = TempDir::new().unwrap();
+/// let mut manager = StoreManager::new(temp_dir.path(), 2);
+/// manager
+///     .get_store("acme")?
+///     .set("foo".to_owned(), "bar".to_owned())?;
+/// assert_eq!(manager.get_store("initech")?.get("foo".to_owned())?, None);
+/// # Ok::<(), kvs::KvsError>(())
+/// ```
+pub struct StoreManager {
+    path: PathBuf,
+    capacity: usize,
+    options: Box<dyn Fn() -> KvStoreOptions + Send>,
+    stores: HashMap<String, (KvStore, u64)>,
+    uses: u64,
+}
+
+impl StoreManager {
+    /// Creates a manager of the stores in the subdirectories of `dir_path`, holding
+    /// up to `capacity` of them open.
+    pub fn new(dir_path: impl Into<PathBuf>, capacity: usize) -> Self {
+        StoreManager {
+            path: dir_path.into(),
+            // A store is always needed to serve the current tenant.
+            capacity: capacity.max(1),
+            options: Box::new(KvStoreOptions::default),
+            stores: HashMap::new(),
+            uses: 0,
+        }
+    }
+
+    /// Opens the stores with the options `options` returns, instead of the default ones.
+    pub fn with_options(mut self, options: impl Fn() -> KvStoreOptions + Send + 'static) -> Self {
+        self.options = Box::new(options);
+        self
+    }
+
+    /// Returns the store of `tenant`, opening it in the subdirectory named after it if
+    /// it isn't open, which closes the least recently used store when full.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::MessageError` if `tenant` isn't usable as a directory
+    /// name, such as one holding a path separator.
+    pub fn get_store(&mut self, tenant: &str) -> Result<&mut KvStore> {
+        if !self.stores.contains_key(tenant) {
+            if tenant.is_empty()
+                || tenant == "."
+                || tenant == ".."
+                || tenant.contains(['/', '\\', '\0'])
+            {
+                return Err(KvsError::MessageError(format!(
+                    "Invalid tenant name {:?}",
+                    tenant
+                )));
+            }
+            while self.stores.len() >= self.capacity {
+                let least_recent = self
+                    .stores
+                    .iter()
+                    .min_by_key(|(_, (_, last_use))| *last_use)
+                    .map(|(tenant, _)| tenant.to_owned());
+
+                match least_recent {
+                    Some(least_recent) => self.close(&least_recent)?,
+                    None => break,
+                };
+            }
+
+            let store_path = self.path.join(tenant);
+            fs::create_dir_all(&store_path)?;
+            let store = KvStore::open_with_options(store_path, (self.options)())?;
+            self.stores.insert(tenant.to_owned(), (store, 0));
+        }
+
+        self.uses += 1;
+        let (store, last_use) = self
+            .stores
+            .get_mut(tenant)
+            .ok_or(KvsError::UnexpectedCommand)?;
+        *last_use = self.uses;
+
+        Ok(store)
+    }
+
+    /// Returns `true` if the store of `tenant` is currently open.
+    pub fn is_open(&self, tenant: &str) -> bool {
+        self.stores.contains_key(tenant)
+    }
+
+    /// Amount of stores currently open.
+    pub fn open_count(&self) -> usize {
+        self.stores.len()
+    }
+
+    /// Flushes and closes the store of `tenant`, returning `false` if it wasn't open.
+    ///
+    /// The store is kept open if flushing it fails.
+    pub fn close(&mut self, tenant: &str) -> Result<bool> {
+        match self.stores.get_mut(tenant) {
+            Some((store, _)) => store.flush()?,
+            None => return Ok(false),
+        }
+
+        self.stores.remove(tenant);
+        Ok(true)
+    }
+
+    /// Flushes and closes every open store, stopping at the first one failing to flush.
+    pub fn close_all(&mut self) -> Result<()> {
+        let tenants: Vec<String> = self.stores.keys().cloned().collect();
+        for tenant in tenants {
+            self.close(&tenant)?;
+        }
+
+        Ok(())
+    }
+}
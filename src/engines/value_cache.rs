@@ -0,0 +1,61 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Keeps the values of the most recently read keys in memory.
+///
+/// Once `capacity` values are cached the least recently used one is evicted.
+pub(crate) struct ValueCache {
+    capacity: usize,
+    values: HashMap<String, (String, u64)>,
+    /// The key of each cached value by its last use, the least recent first.
+    recency: BTreeMap<u64, String>,
+    uses: u64,
+}
+
+impl ValueCache {
+    /// Creates a cache holding up to `capacity` values.
+    pub(crate) fn new(capacity: usize) -> Self {
+        ValueCache {
+            capacity,
+            values: HashMap::new(),
+            recency: BTreeMap::new(),
+            uses: 0,
+        }
+    }
+
+    /// Returns the cached value of `key`, marking it as recently used.
+    pub(crate) fn get(&mut self, key: &str) -> Option<String> {
+        let (value, last_use) = self.values.get_mut(key)?;
+        self.uses += 1;
+        let key = self.recency.remove(last_use)?;
+        *last_use = self.uses;
+        self.recency.insert(self.uses, key);
+
+        Some(value.to_owned())
+    }
+
+    /// Caches the `value` of `key`, evicting the least recently used value if full.
+    pub(crate) fn insert(&mut self, key: String, value: String) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if let Some((_, last_use)) = self.values.get(&key) {
+            self.recency.remove(last_use);
+        } else if self.values.len() >= self.capacity {
+            if let Some((_, least_recent)) = self.recency.pop_first() {
+                self.values.remove(&least_recent);
+            }
+        }
+
+        self.uses += 1;
+        self.recency.insert(self.uses, key.to_owned());
+        self.values.insert(key, (value, self.uses));
+    }
+
+    /// Drops the cached value of `key`, if any.
+    pub(crate) fn invalidate(&mut self, key: &str) {
+        if let Some((_, last_use)) = self.values.remove(key) {
+            self.recency.remove(&last_use);
+        }
+    }
+}
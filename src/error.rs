@@ -1,39 +1,102 @@
-use failure::Fail;
 use std::io;
 use std::result;
+use std::string::FromUtf8Error;
+use thiserror::Error;
 
 /// The error type for our key value store.
-#[derive(Fail, Debug)]
+#[derive(Error, Debug)]
 pub enum KvsError {
     /// Triggered when the provided key cannot be found.
-    #[fail(display = "Key not found error")]
+    #[error("Key not found error")]
     KeyNotFound,
+    /// Triggered when inserting a key through `KvStore::insert` which is already present.
+    #[error("Key already exists error")]
+    KeyExists,
     /// Triggered when the provided command cannot be found.
     /// It might indicate a corrupted log or a program bug.
-    #[fail(display = "UnexpectedCommand error")]
+    #[error("UnexpectedCommand error")]
     UnexpectedCommand,
+    /// Triggered when the log file holding the command of a key is gone, such as
+    /// when deleted from under the store.
+    #[error("Log file {file_index}.log is missing")]
+    MissingLogFile {
+        /// Index of the missing log file.
+        file_index: u64,
+    },
     /// Triggered when an IO error occurs.
-    #[fail(display = "IO error: {}", _0)]
-    Io(io::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
     /// Triggered when serializing/deserializing fails.
-    #[fail(display = "serde_json error: {}", _0)]
-    Serde(serde_json::Error),
+    #[error("serde_json error: {0}")]
+    Serde(#[from] serde_json::Error),
+    /// Triggered when the sled engine fails.
+    #[error("sled error: {0}")]
+    Sled(#[from] sled::Error),
+    /// Triggered when a stored value isn't valid UTF-8.
+    #[error("UTF-8 error: {0}")]
+    Utf8(#[from] FromUtf8Error),
+    /// Triggered when a requested range of a value is reversed or splits a character.
+    #[error("Invalid range error")]
+    InvalidRange,
+    /// Triggered when a conditional write expected another version of the key,
+    /// holding its current version.
+    #[error("Version mismatch error, current version is {0}")]
+    VersionMismatch(u64),
+    /// Triggered when setting a key longer than the limit, in bytes, it holds.
+    #[error("Key is larger than the limit of {0} bytes")]
+    KeyTooLarge(usize),
+    /// Triggered when setting a value longer than the limit, in bytes, it holds.
+    #[error("Value is larger than the limit of {0} bytes")]
+    ValueTooLarge(usize),
+    /// Triggered when a server receives a request longer than the limit, in bytes,
+    /// it holds. The server closes the connection after answering it.
+    #[error("Request is larger than the limit of {0} bytes")]
+    RequestTooLarge(usize),
+    /// Triggered when setting a key refused by the `KeyPolicy` of the store.
+    #[error("Invalid key error, keys can't contain control characters or whitespace")]
+    InvalidKey,
+    /// Triggered when mutating a store opened with `KvStore::open_read_only`, or
+    /// through a server made read-only with `KvsServer::with_read_only`.
+    #[error("Store opened in read-only mode")]
+    ReadOnly,
+    /// Triggered when writing to a store whose writer log file was removed from under
+    /// it, unless `KvStoreOptions::recreate_lost_writer` is set.
+    #[error("Writer log file was removed")]
+    WriterLost,
+    /// Triggered when a compaction verifying what it copies finds a corrupt command,
+    /// holding its key, see `KvStoreOptions::verify_compaction`.
+    #[error("Corrupt record for key {0}")]
+    CorruptRecord(String),
+    /// Triggered when mutating a store while its writes are paused by `KvStore::pause_writes`.
+    #[error("Writes are paused")]
+    Paused,
+    /// Triggered when reading a key as a string while it holds an integer, or the
+    /// other way around.
+    #[error("Wrong type error, the key holds a value of another type")]
+    WrongType,
+    /// Triggered when a connection sends requests faster than the rate allowed by
+    /// `KvsServer::with_rate_limit`.
+    #[error("Rate limit exceeded")]
+    RateLimited,
+    /// Triggered when incrementing an integer past the bounds of an `i64`.
+    #[error("Integer overflow error")]
+    IntegerOverflow,
+    /// Triggered when scanning the keys of a store indexed by `KeyIndexKind::Hashed`,
+    /// which doesn't keep them ordered.
+    #[error("Range scans are not supported by a hashed key index")]
+    UnorderedIndex,
+    /// Triggered when a log file has a header this version can't read, such as the
+    /// one of a newer version.
+    #[error("Incompatible log file: {0}")]
+    IncompatibleLog(String),
+    /// Triggered when opening a store whose directory is locked by another open
+    /// `KvStore`, of this process or another one.
+    #[error("Store directory is locked by another KvStore")]
+    Locked,
     /// Error with a string message.
-    #[fail(display = "{}", _0)]
+    #[error("{0}")]
     MessageError(String),
 }
 
-impl From<io::Error> for KvsError {
-    fn from(err: io::Error) -> KvsError {
-        KvsError::Io(err)
-    }
-}
-
-impl From<serde_json::Error> for KvsError {
-    fn from(err: serde_json::Error) -> KvsError {
-        KvsError::Serde(err)
-    }
-}
-
 /// The result type for our key value store
 pub type Result<T> = result::Result<T, KvsError>;
@@ -0,0 +1,41 @@
+/// Returns `true` if the whole of `text` matches the glob `pattern`.
+///
+/// Patterns are anchored at both ends: `*` matches any sequence of characters,
+/// separators included and possibly empty, `?` matches exactly one character and
+/// every other character matches itself. There is no escaping nor character classes.
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` seen and of the text it was matched against, to
+    // backtrack to when the rest of the pattern fails.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some('?') => {
+                p += 1;
+                t += 1;
+            }
+            Some(c) if *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // The last `*` swallows one more character.
+                Some((star, star_t)) => {
+                    p = star + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
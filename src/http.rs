@@ -0,0 +1,142 @@
+//! A minimal HTTP/1.1 framing of requests, see `KvsServer::with_http`.
+
+use crate::{KvsError, Result};
+
+use std::io::{BufRead, Read, Take, Write};
+
+/// A request whose path names a key, such as `GET /foo`.
+#[derive(Debug)]
+pub(crate) struct HttpRequest {
+    pub(crate) method: String,
+    /// The path stripped of its leading slash, percent-decoded.
+    pub(crate) key: String,
+    pub(crate) body: Vec<u8>,
+}
+
+/// Reads the request sent over `reader`, returning `None` if the connection got
+/// closed before any. Requests whose head or body go past `max_bytes` fail with
+/// `KvsError::RequestTooLarge`.
+pub(crate) fn read_request<R: BufRead>(
+    reader: &mut R,
+    max_bytes: usize,
+) -> Result<Option<HttpRequest>> {
+    let mut head = (&mut *reader).take(max_bytes as u64);
+    let request_line = match read_line(&mut head, max_bytes)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let mut parts = request_line.split(' ');
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/") => {
+            (method.to_owned(), target.to_owned())
+        }
+        _ => return Err(malformed("invalid request line")),
+    };
+
+    let mut content_length = 0;
+    loop {
+        let line = read_line(&mut head, max_bytes)?.ok_or_else(|| malformed("truncated head"))?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| malformed("invalid header"))?;
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .trim()
+                .parse()
+                .map_err(|_| malformed("invalid Content-Length"))?;
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            return Err(malformed("chunked bodies are not supported"));
+        }
+    }
+    if content_length > max_bytes {
+        return Err(KvsError::RequestTooLarge(max_bytes));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    let path = target.split('?').next().unwrap_or_default();
+    let key = percent_decode(path.strip_prefix('/').unwrap_or(path))?;
+
+    Ok(Some(HttpRequest { method, key, body }))
+}
+
+/// Writes a response with `status` and `body`, closing the connection afterwards.
+pub(crate) fn write_response<W: Write>(writer: &mut W, status: u16, body: &[u8]) -> Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nContent-Type: text/plain; charset=utf-8\r\nConnection: close\r\n\r\n",
+        status,
+        reason(status),
+        body.len()
+    )?;
+    writer.write_all(body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a line of the head without its line ending, `None` at the end of the stream.
+fn read_line<R: BufRead>(reader: &mut Take<R>, max_bytes: usize) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.last() != Some(&b'\n') {
+        return match reader.limit() {
+            0 => Err(KvsError::RequestTooLarge(max_bytes)),
+            _ => Err(malformed("truncated head")),
+        };
+    }
+
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| malformed("head is not UTF-8"))
+}
+
+/// Decodes the `%XX` escapes of a path.
+fn percent_decode(path: &str) -> Result<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = path
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| malformed("invalid percent-encoding"))?;
+            decoded.push(hex);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| malformed("key is not UTF-8"))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        _ => "Internal Server Error",
+    }
+}
+
+fn malformed(reason: &str) -> KvsError {
+    KvsError::MessageError(format!("Malformed HTTP request: {}", reason))
+}
@@ -6,12 +6,27 @@
 extern crate log;
 
 mod client;
+mod cluster_client;
 mod engines;
 mod error;
+mod glob;
+#[cfg(feature = "http")]
+mod http;
 mod protocol;
 mod server;
+mod sharded_client;
+pub mod test_util;
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine};
+pub use client::{KeyScan, KvsClient, RequestToken, Response};
+pub use cluster_client::KvsClusterClient;
+#[cfg(feature = "crash-test")]
+pub use engines::CrashTest;
+pub use engines::{
+    Clock, Command, CommandStream, CompactionReport, CorruptionPolicy, GroupCommit,
+    IndexDiscrepancy, IndexPolicy, IoStats, JsonFormat, KeyIndexKind, KeyPolicy, KvStore,
+    KvStoreOptions, KvsEngine, LogFormat, MergePolicy, MissingLogPolicy, Patch, SledKvsEngine,
+    Snapshot, StoreManager, SwapPolicy, SystemClock, VerifyReport,
+};
 pub use error::{KvsError, Result};
-pub use server::KvsServer;
+pub use server::{Health, KvsServer, RecordedRequest, ServerConfig, ServerInfo, ShutdownHandle};
+pub use sharded_client::ShardedKvsClient;
@@ -1,26 +1,468 @@
+use crate::{CompactionReport, KvsError, Result, ServerConfig, ServerInfo};
+
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::{decompress_to_vec_with_limit, TINFLStatus};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use serde_json::{Deserializer, Value};
+use std::io::{self, BufRead, Read, Write};
+
+/// Requests and responses are concatenated JSON values, relying on JSON being
+/// self-delimiting. This is what every connection starts with.
+pub const JSON_STREAM_VERSION: u32 = 1;
+
+/// Every message is a 4-byte big-endian length followed by its JSON payload,
+/// a malformed message can be skipped without losing sync with the stream.
+pub const LENGTH_PREFIXED_VERSION: u32 = 2;
+
+/// Frames are length-prefixed, their payload led by a byte telling whether it is
+/// deflated. Only negotiated by peers both enabling compression, see
+/// `KvsClient::connect_compressed` and `KvsServer::with_compression`.
+pub const COMPRESSED_VERSION: u32 = 3;
+
+/// Latest protocol version supported, negotiated through `Protocol::Handshake`.
+pub const PROTOCOL_VERSION: u32 = COMPRESSED_VERSION;
+
+/// Payloads shorter than this are sent as is, compressing them isn't worth it.
+const COMPRESSION_MIN_BYTES: usize = 1024;
 
+const RAW_PAYLOAD: u8 = 0;
+const DEFLATED_PAYLOAD: u8 = 1;
+
+/// Level of the deflate compression, trading a little ratio for speed.
+const COMPRESSION_LEVEL: u8 = 1;
+
+// Unknown fields are rejected so a client and server that drifted apart
+// produce an explicit error instead of a misinterpreted request.
 #[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
 pub enum Protocol {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+    },
+    GetStream {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+    },
+    Len {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+    },
+    GetRange {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+        start: usize,
+        end: usize,
+    },
+    Set {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+        value: String,
+    },
+    Remove {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+    },
+    RemoveIf {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+        expected: String,
+    },
+    // A retried request carries the `op_id` of the original one, letting the
+    // server answer it without incrementing twice.
+    Increment {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        key: String,
+        delta: i64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        op_id: Option<u64>,
+    },
+    GetPattern {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        pattern: String,
+    },
+    ScanKeys {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        namespace: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        after: Option<String>,
+        limit: usize,
+    },
+    Handshake {
+        version: u32,
+    },
+    Compact,
+    Sync,
+    Info,
+    Config,
+}
+
+#[cfg(feature = "structured-logs")]
+impl Protocol {
+    /// Name of the request, as logged by the server.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::Get { .. } => "get",
+            Protocol::GetStream { .. } => "get_stream",
+            Protocol::Len { .. } => "len",
+            Protocol::GetRange { .. } => "get_range",
+            Protocol::Set { .. } => "set",
+            Protocol::Remove { .. } => "remove",
+            Protocol::RemoveIf { .. } => "remove_if",
+            Protocol::Increment { .. } => "increment",
+            Protocol::GetPattern { .. } => "get_pattern",
+            Protocol::ScanKeys { .. } => "scan_keys",
+            Protocol::Handshake { .. } => "handshake",
+            Protocol::Compact => "compact",
+            Protocol::Sync => "sync",
+            Protocol::Info => "info",
+            Protocol::Config => "config",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum GetResponse {
     Ok(Option<String>),
-    Err(String),
+    Err(RemoteError),
+}
+
+// A value streamed as a sequence of chunks, each of them a message of its own,
+// followed by `End` telling whether the key was found.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum GetStreamResponse {
+    Chunk(String),
+    End(bool),
+    Err(RemoteError),
+}
+
+// Carries the length of the value in bytes, `None` for a missing key.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum LenResponse {
+    Ok(Option<u64>),
+    Err(RemoteError),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum SetResponse {
     Ok(()),
-    Err(String),
+    Err(RemoteError),
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum RemoveResponse {
     Ok(()),
+    Err(RemoteError),
+}
+
+// Tells whether the key was removed.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RemoveIfResponse {
+    Ok(bool),
+    Err(RemoteError),
+}
+
+// Carries the integer after the increment.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum IncrementResponse {
+    Ok(i64),
+    Err(RemoteError),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum GetPatternResponse {
+    Ok(Vec<(String, String)>),
+    Err(RemoteError),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ScanKeysResponse {
+    Ok(Vec<String>),
+    Err(RemoteError),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum CompactResponse {
+    Ok(CompactionReport),
+    Err(RemoteError),
+}
+
+// Sent once every write acknowledged before the request is durable.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SyncResponse {
+    Ok(()),
+    Err(RemoteError),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum InfoResponse {
+    Ok(ServerInfo),
+    Err(RemoteError),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ConfigResponse {
+    Ok(ServerConfig),
+    Err(RemoteError),
+}
+
+// Any of the responses made of a value or an error, read back when replaying a
+// recorded request.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum AnyResponse {
+    Ok(Value),
+    Err(RemoteError),
+}
+
+/// An error sent back to a client, rebuilt into the matching `KvsError` on its side.
+///
+/// It deserializes from a plain message as well, which is how errors without a
+/// dedicated kind travel and how servers predating typed errors report them all.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum RemoteError {
+    Kind(ErrorKind),
+    Message(String),
+}
+
+/// The variants of `KvsError` which only make sense to a client as they are, the
+/// other ones are sent as messages.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ErrorKind {
+    KeyNotFound,
+    InvalidRange,
+    VersionMismatch(u64),
+    KeyTooLarge(usize),
+    ValueTooLarge(usize),
+    RequestTooLarge(usize),
+    InvalidKey,
+    ReadOnly,
+    Paused,
+    WrongType,
+    IntegerOverflow,
+    RateLimited,
+}
+
+impl From<KvsError> for RemoteError {
+    fn from(error: KvsError) -> Self {
+        match error {
+            KvsError::KeyNotFound => RemoteError::Kind(ErrorKind::KeyNotFound),
+            KvsError::InvalidRange => RemoteError::Kind(ErrorKind::InvalidRange),
+            KvsError::VersionMismatch(version) => {
+                RemoteError::Kind(ErrorKind::VersionMismatch(version))
+            }
+            KvsError::KeyTooLarge(limit) => RemoteError::Kind(ErrorKind::KeyTooLarge(limit)),
+            KvsError::ValueTooLarge(limit) => RemoteError::Kind(ErrorKind::ValueTooLarge(limit)),
+            KvsError::RequestTooLarge(limit) => {
+                RemoteError::Kind(ErrorKind::RequestTooLarge(limit))
+            }
+            KvsError::InvalidKey => RemoteError::Kind(ErrorKind::InvalidKey),
+            KvsError::ReadOnly => RemoteError::Kind(ErrorKind::ReadOnly),
+            KvsError::Paused => RemoteError::Kind(ErrorKind::Paused),
+            KvsError::WrongType => RemoteError::Kind(ErrorKind::WrongType),
+            KvsError::IntegerOverflow => RemoteError::Kind(ErrorKind::IntegerOverflow),
+            KvsError::RateLimited => RemoteError::Kind(ErrorKind::RateLimited),
+            error => RemoteError::Message(format!("{}", error)),
+        }
+    }
+}
+
+impl From<RemoteError> for KvsError {
+    fn from(error: RemoteError) -> Self {
+        match error {
+            RemoteError::Kind(ErrorKind::KeyNotFound) => KvsError::KeyNotFound,
+            RemoteError::Kind(ErrorKind::InvalidRange) => KvsError::InvalidRange,
+            RemoteError::Kind(ErrorKind::VersionMismatch(version)) => {
+                KvsError::VersionMismatch(version)
+            }
+            RemoteError::Kind(ErrorKind::KeyTooLarge(limit)) => KvsError::KeyTooLarge(limit),
+            RemoteError::Kind(ErrorKind::ValueTooLarge(limit)) => KvsError::ValueTooLarge(limit),
+            RemoteError::Kind(ErrorKind::RequestTooLarge(limit)) => {
+                KvsError::RequestTooLarge(limit)
+            }
+            RemoteError::Kind(ErrorKind::InvalidKey) => KvsError::InvalidKey,
+            RemoteError::Kind(ErrorKind::ReadOnly) => KvsError::ReadOnly,
+            RemoteError::Kind(ErrorKind::Paused) => KvsError::Paused,
+            RemoteError::Kind(ErrorKind::WrongType) => KvsError::WrongType,
+            RemoteError::Kind(ErrorKind::IntegerOverflow) => KvsError::IntegerOverflow,
+            RemoteError::Kind(ErrorKind::RateLimited) => KvsError::RateLimited,
+            RemoteError::Message(message) => KvsError::MessageError(message),
+        }
+    }
+}
+
+// Carries the version both peers will use from now on.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum HandshakeResponse {
+    Ok(u32),
     Err(String),
 }
+
+// Sent when a request can't be parsed or is refused before being looked at. It
+// serializes exactly like the `Err` variant of every other response, so clients
+// handle it transparently.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ErrorResponse {
+    Err(RemoteError),
+}
+
+/// Maps a key into the keyspace of `namespace`, the default namespace
+/// leaves keys untouched.
+///
+/// The namespace is prefixed with its length in bytes, which tells where it ends
+/// whatever it contains: `b` in the `a:` namespace and `:b` in the `a` one map to
+/// `2:a::b` and `1:a::b` instead of colliding as `a::b`.
+pub fn namespaced_key(namespace: Option<String>, key: String) -> String {
+    match namespace {
+        Some(namespace) => format!("{}:{}:{}", namespace.len(), namespace, key),
+        None => key,
+    }
+}
+
+/// Writes and flushes a single message framed according to `version`.
+pub fn write_message<W: Write, T: Serialize>(
+    mut writer: W,
+    version: u32,
+    message: &T,
+) -> Result<()> {
+    buffer_message(&mut writer, version, message)?;
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes a single message framed according to `version`, leaving it to the
+/// caller to flush the writer.
+pub fn buffer_message<W: Write, T: Serialize>(
+    mut writer: W,
+    version: u32,
+    message: &T,
+) -> Result<()> {
+    if version >= LENGTH_PREFIXED_VERSION {
+        let mut payload = serde_json::to_vec(message)?;
+        if version >= COMPRESSED_VERSION {
+            payload = encode_payload(payload);
+        }
+        writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+        writer.write_all(&payload)?;
+    } else {
+        serde_json::to_writer(&mut writer, message)?;
+    }
+
+    Ok(())
+}
+
+/// Reads a single message framed according to `version`.
+pub fn read_message<R: BufRead, T: DeserializeOwned>(reader: &mut R, version: u32) -> Result<T> {
+    if version >= LENGTH_PREFIXED_VERSION {
+        match read_frame(reader)? {
+            Some(frame) if version >= COMPRESSED_VERSION => {
+                let payload = decode_payload(frame, u32::MAX as usize)?;
+                Ok(serde_json::from_slice(&payload)?)
+            }
+            Some(frame) => Ok(serde_json::from_slice(&frame)?),
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        }
+    } else {
+        // Messages are JSON objects or strings, so the deserializer never needs
+        // to look past the end of one and the reader stays in sync.
+        Ok(T::deserialize(&mut Deserializer::from_reader(reader))?)
+    }
+}
+
+/// Leads `payload` with its compression byte, deflating it when large enough and
+/// deflating actually shrinks it.
+fn encode_payload(payload: Vec<u8>) -> Vec<u8> {
+    if payload.len() >= COMPRESSION_MIN_BYTES {
+        let deflated = compress_to_vec(&payload, COMPRESSION_LEVEL);
+        if deflated.len() < payload.len() {
+            let mut frame = Vec::with_capacity(deflated.len() + 1);
+            frame.push(DEFLATED_PAYLOAD);
+            frame.extend_from_slice(&deflated);
+            return frame;
+        }
+    }
+
+    let mut frame = Vec::with_capacity(payload.len() + 1);
+    frame.push(RAW_PAYLOAD);
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// Recovers the payload of a frame of the `COMPRESSED_VERSION`, failing with
+/// `KvsError::RequestTooLarge` instead of inflating it past `max` bytes.
+pub fn decode_payload(mut frame: Vec<u8>, max: usize) -> Result<Vec<u8>> {
+    let invalid = || KvsError::MessageError("Invalid compressed frame".to_owned());
+    match frame.first() {
+        Some(&RAW_PAYLOAD) => {
+            frame.remove(0);
+            Ok(frame)
+        }
+        Some(&DEFLATED_PAYLOAD) => {
+            decompress_to_vec_with_limit(&frame[1..], max).map_err(|e| match e.status {
+                TINFLStatus::HasMoreOutput => KvsError::RequestTooLarge(max),
+                _ => invalid(),
+            })
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Reads the payload of the next length-prefixed frame.
+/// Returns `None` if the stream ended cleanly before a new frame.
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    read_bounded_frame(reader, u32::MAX as usize)
+}
+
+/// Reads the payload of the next length-prefixed frame like `read_frame`, failing
+/// with `KvsError::RequestTooLarge` instead of reading a payload longer than `max`
+/// bytes.
+pub fn read_bounded_frame<R: Read>(reader: &mut R, max: usize) -> Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(KvsError::Io(e)),
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > max {
+        return Err(KvsError::RequestTooLarge(max));
+    }
+    let mut frame = vec![0u8; length];
+    reader.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+/// Returns `true` once only whitespace is left before the end of the stream.
+pub fn at_end_of_stream<R: BufRead>(reader: &mut R) -> Result<bool> {
+    loop {
+        let (whitespace, exhausted) = {
+            let buffer = reader.fill_buf()?;
+            if buffer.is_empty() {
+                return Ok(true);
+            }
+
+            let whitespace = buffer
+                .iter()
+                .take_while(|byte| byte.is_ascii_whitespace())
+                .count();
+            (whitespace, whitespace == buffer.len())
+        };
+
+        reader.consume(whitespace);
+        if !exhausted {
+            return Ok(false);
+        }
+    }
+}
@@ -1,33 +1,368 @@
-use crate::{KvsEngine, Result};
+use crate::glob::glob_match;
+#[cfg(feature = "http")]
+use crate::http;
+use crate::{Clock, KvsEngine, KvsError, Result, SystemClock};
 
-use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+#[cfg(feature = "http")]
+use std::net::IpAddr;
 use std::net::SocketAddr;
-use std::net::{TcpListener, TcpStream};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::protocol::{GetResponse, Protocol, RemoveResponse, SetResponse};
+use crate::protocol::{
+    at_end_of_stream, buffer_message, decode_payload, namespaced_key, read_bounded_frame,
+    read_message, write_message, CompactResponse, ConfigResponse, ErrorResponse,
+    GetPatternResponse, GetResponse, GetStreamResponse, HandshakeResponse, IncrementResponse,
+    InfoResponse, LenResponse, Protocol, RemoteError, RemoveIfResponse, RemoveResponse,
+    ScanKeysResponse, SetResponse, SyncResponse, COMPRESSED_VERSION, JSON_STREAM_VERSION,
+    LENGTH_PREFIXED_VERSION, PROTOCOL_VERSION,
+};
+
+/// Uncompacted bytes past which the server reports itself as degraded by default,
+/// eight times the compaction threshold of `KvStore`.
+const DEFAULT_DEGRADED_UNCOMPACTED_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Largest request a server accepts by default, in bytes.
+const DEFAULT_MAX_REQUEST_BYTES: usize = 64 * 1024 * 1024;
+
+/// Times a set failing with a transient error is retried by default.
+const DEFAULT_WRITE_RETRIES: u32 = 3;
+
+/// Backoff before the first retry of a set, doubled on each following one.
+const WRITE_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Peers whose HTTP rate limit is tracked before the ones gone quiet are forgotten.
+#[cfg(feature = "http")]
+const MAX_HTTP_PEERS: usize = 1024;
+
+/// Whether a server is fit to receive traffic, beyond the process being up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Health {
+    /// The store keeps up with the writes it receives.
+    #[default]
+    Healthy,
+    /// Compaction isn't keeping up with the stale data or the last write failed,
+    /// traffic should be routed to another server.
+    Degraded,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Health::Healthy => write!(f, "healthy"),
+            Health::Degraded => write!(f, "degraded"),
+        }
+    }
+}
+
+/// Statistics of a running server, answered to `KvsClient::info`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// Version of the `kvs` crate the server was built from.
+    pub version: String,
+    /// Seconds elapsed since the server was created.
+    pub uptime_secs: u64,
+    /// Amount of keys stored by the engine.
+    pub key_count: u64,
+    /// Bytes of stale data the engine would reclaim by compacting.
+    pub uncompacted_bytes: u64,
+    /// Uncompacted bytes past which the engine compacts on its own, `None` when it
+    /// doesn't or for servers predating it.
+    #[serde(default)]
+    pub compaction_threshold: Option<u64>,
+    /// Connections accepted since the server started, including the current one.
+    pub connections: u64,
+    /// Health of the store, `Health::Healthy` for servers predating it.
+    #[serde(default)]
+    pub health: Health,
+}
+
+/// Version of the layout of `ServerConfig`, bumped whenever a field changes meaning
+/// or goes away.
+const SERVER_CONFIG_VERSION: u32 = 1;
+
+/// How a running server is configured, answered to `KvsClient::config`.
+///
+/// Fields only ever get added, with defaults for servers predating them, unless
+/// `config_version` changes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerConfig {
+    /// Version of the layout of this struct.
+    pub config_version: u32,
+    /// Name of the storage engine, see `KvsEngine::name`.
+    pub engine: String,
+    /// Threads serving requests, connections being served one at a time.
+    pub threads: usize,
+    /// Uncompacted bytes past which the engine compacts on its own, `None` when it
+    /// doesn't.
+    pub compaction_threshold: Option<u64>,
+    /// Whether writes are refused, see `KvsServer::with_read_only`.
+    pub read_only: bool,
+    /// Latest protocol version the server negotiates.
+    pub protocol_version: u32,
+    /// Requests per second allowed to every connection, `None` when unlimited.
+    pub rate_limit: Option<u32>,
+    /// Largest request accepted, in bytes.
+    pub max_request_bytes: u64,
+    /// Times a set failing with a transient error is retried.
+    pub write_retries: u32,
+}
+
+/// A request received by a server, as recorded in its request log, see
+/// `KvsServer::with_request_log`.
+///
+/// The log holds a record per line, which `KvsClient::replay` sends again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    /// Milliseconds since the Unix epoch at which the request was received.
+    pub at_millis: u64,
+    /// Address of the client which sent the request.
+    pub peer: SocketAddr,
+    /// The request as sent over the kvs protocol.
+    pub request: Value,
+}
+
+impl RecordedRequest {
+    /// Reads the records of a request log, in the order they were received.
+    pub fn read_log<'a>(reader: impl Read + 'a) -> impl Iterator<Item = Result<Self>> + 'a {
+        serde_json::Deserializer::from_reader(reader)
+            .into_iter()
+            .map(|record| record.map_err(KvsError::from))
+    }
+}
+
+/// Stops a running `KvsServer` gracefully, usable from another thread such as a
+/// signal handler.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    local_addrs: Arc<Mutex<Vec<SocketAddr>>>,
+    /// The connection being handled, if any.
+    serving: Arc<Mutex<Option<TcpStream>>>,
+}
+
+impl ShutdownHandle {
+    /// Asks the server to stop accepting connections.
+    ///
+    /// The connection being handled, if any, stops being read: the request it is
+    /// answering is served, an idle client doesn't hold the server up. The engine is
+    /// then flushed and dropped before `KvsServer::run` returns.
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        if let Some(stream) = self.serving.lock().unwrap().as_ref() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+
+        // Accepting is blocking, so every listener is woken up with a connection of our own.
+        for addr in self.local_addrs.lock().unwrap().iter() {
+            let _ = TcpStream::connect(addr);
+        }
+    }
+
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Notes that `stream` is being handled, `None` once it is done with, so a
+    /// shutdown stops reading it.
+    fn set_serving(&self, stream: Option<&TcpStream>) {
+        let mut serving = self.serving.lock().unwrap();
+        *serving = stream.and_then(|stream| stream.try_clone().ok());
+        // A shutdown requested meanwhile didn't see the stream.
+        if let Some(stream) = serving.as_ref().filter(|_| self.is_requested()) {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+}
 
 /// The server of our key-value store tied to a storage engine.
 pub struct KvsServer<E: KvsEngine> {
     engine: E,
+    started_at: Instant,
+    connections: u64,
+    shutdown: ShutdownHandle,
+    degraded_uncompacted_bytes: u64,
+    last_write_failed: bool,
+    read_only: bool,
+    rate_limit: Option<u32>,
+    recent_ops: RecentOps,
+    max_request_bytes: usize,
+    write_retries: u32,
+    compression: bool,
+    request_log: Option<Box<dyn Write + Send>>,
+    #[cfg(feature = "http")]
+    http: bool,
+    /// The rate limit of each peer in HTTP mode, as every request gets its own
+    /// connection.
+    #[cfg(feature = "http")]
+    http_buckets: HashMap<IpAddr, TokenBucket>,
 }
 
 impl<E: KvsEngine> KvsServer<E> {
     /// Creates a `KvsServer` tied to a storage engine.
     pub fn new(engine: E) -> Self {
-        KvsServer { engine }
+        KvsServer {
+            engine,
+            started_at: Instant::now(),
+            connections: 0,
+            shutdown: ShutdownHandle::default(),
+            degraded_uncompacted_bytes: DEFAULT_DEGRADED_UNCOMPACTED_BYTES,
+            last_write_failed: false,
+            read_only: false,
+            rate_limit: None,
+            recent_ops: RecentOps::default(),
+            max_request_bytes: DEFAULT_MAX_REQUEST_BYTES,
+            write_retries: DEFAULT_WRITE_RETRIES,
+            compression: false,
+            request_log: None,
+            #[cfg(feature = "http")]
+            http: false,
+            #[cfg(feature = "http")]
+            http_buckets: HashMap::new(),
+        }
+    }
+
+    /// Makes the server refuse sets, removes and compactions with an error, leaving
+    /// the engine untouched, while reads keep being served.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Limits every connection to `requests_per_sec` requests per second, allowing
+    /// bursts of as many requests. Requests past the limit are answered with a
+    /// `KvsError::RateLimited` error instead of being served, handshakes excepted.
+    /// In HTTP mode, every peer address is limited instead and answered with a 429.
+    ///
+    /// Connections aren't limited by default.
+    pub fn with_rate_limit(mut self, requests_per_sec: u32) -> Self {
+        self.rate_limit = Some(requests_per_sec);
+        self
+    }
+
+    /// Bounds every request to `bytes`, 64 MiB by default, before parsing it. A
+    /// larger request is answered with a `KvsError::RequestTooLarge` error and its
+    /// connection closed, since the rest of it can't be told apart from the next one.
+    pub fn with_max_request_bytes(mut self, bytes: usize) -> Self {
+        self.max_request_bytes = bytes;
+        self
+    }
+
+    /// Retries a set failing with a transient I/O error, such as an interrupted
+    /// write or a momentarily full disk, up to `retries` times with a jittered
+    /// exponential backoff before answering the error. 3 by default, 0 disables it.
+    ///
+    /// Only sets are retried, the other writes not being idempotent.
+    pub fn with_write_retries(mut self, retries: u32) -> Self {
+        self.write_retries = retries;
+        self
+    }
+
+    /// Lets the connections of clients asking for it compress their messages larger
+    /// than a kilobyte, see `KvsClient::connect_compressed`. Other clients are
+    /// served uncompressed either way.
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Records every request received over the kvs protocol to `log`, with the
+    /// time it was received and the peer which sent it, one `RecordedRequest` per
+    /// line. `log` is typically a file opened for appending. HTTP requests are
+    /// recorded as the kvs requests they amount to.
+    ///
+    /// Failing to record a request is logged, the request still being served.
+    pub fn with_request_log(mut self, log: impl Write + Send + 'static) -> Self {
+        self.request_log = Some(Box::new(log));
+        self
+    }
+
+    /// Makes the server speak HTTP instead of the kvs protocol, for clients such as
+    /// curl: `GET /key`, `PUT /key` and `DELETE /key` get, set and remove the key
+    /// named by the percent-decoded path, the value being the body.
+    ///
+    /// Missing keys are answered with a 404 and every connection is closed once its
+    /// request is answered, since connections are served one at a time.
+    #[cfg(feature = "http")]
+    pub fn with_http(mut self, http: bool) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Reports the server as degraded once the engine holds more than `bytes` of
+    /// uncompacted data.
+    pub fn with_degraded_threshold(mut self, bytes: u64) -> Self {
+        self.degraded_uncompacted_bytes = bytes;
+        self
+    }
+
+    /// Returns a handle to stop the server once it runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
     }
 
     /// Runs our KvsServer bound to the specified IP address.
-    /// The server will be listening to incoming messages.
-    pub fn run(mut self, addr: SocketAddr) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        info!("KvsServer listening in {}", addr);
+    /// The server will be listening to incoming messages until it gets shut down
+    /// through a `ShutdownHandle`.
+    pub fn run(self, addr: SocketAddr) -> Result<()> {
+        self.run_multi(vec![addr])
+    }
+
+    /// Runs our KvsServer bound to every one of the specified IP addresses, such as
+    /// an IPv4 and an IPv6 one.
+    /// Connections accepted on any of them are served one at a time against the same
+    /// engine, until the server gets shut down through a `ShutdownHandle`.
+    pub fn run_multi(mut self, addrs: Vec<SocketAddr>) -> Result<()> {
+        let mut listeners = Vec::with_capacity(addrs.len());
+        for addr in &addrs {
+            listeners.push(TcpListener::bind(addr)?);
+        }
+        *self.shutdown.local_addrs.lock().unwrap() = listeners
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<std::io::Result<_>>()?;
+
+        // Each listener accepts on its own thread, handing the connections over to
+        // this one.
+        let (sender, receiver) = mpsc::channel();
+        let mut acceptors = Vec::with_capacity(listeners.len());
+        for (addr, listener) in addrs.into_iter().zip(listeners) {
+            info!("KvsServer listening in {}", addr);
+            let sender = sender.clone();
+            let shutdown = self.shutdown.clone();
+            acceptors.push(thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if shutdown.is_requested() || sender.send(stream).is_err() {
+                        break;
+                    }
+                }
+            }));
+        }
+        drop(sender);
+
+        for stream in receiver {
+            if self.shutdown.is_requested() {
+                break;
+            }
 
-        for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
-                    if let Err(e) = self.handle_connection(stream) {
+                    self.connections += 1;
+                    self.shutdown.set_serving(Some(&stream));
+                    let handled = self.handle_connection(stream);
+                    self.shutdown.set_serving(None);
+                    if let Err(e) = handled {
                         error!("Failed to handle connection: {}", e)
                     }
                 }
@@ -35,55 +370,762 @@ impl<E: KvsEngine> KvsServer<E> {
             }
         }
 
+        for acceptor in acceptors {
+            let _ = acceptor.join();
+        }
+        self.engine.flush()?;
+        info!("KvsServer shut down");
+
         Ok(())
     }
 
     fn handle_connection(&mut self, stream: TcpStream) -> Result<()> {
-        let reader = BufReader::new(&stream);
-        let mut writer = BufWriter::new(&stream);
         let peer_addr = stream.peer_addr()?;
+        #[cfg(feature = "structured-logs")]
+        let _connection = tracing::info_span!(
+            "connection",
+            connection = self.connections,
+            peer_addr = %peer_addr
+        )
+        .entered();
+
+        #[cfg(feature = "http")]
+        {
+            if self.http {
+                return self.handle_http_connection(stream);
+            }
+        }
+
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        let mut version = JSON_STREAM_VERSION;
+        let mut bucket = self.rate_limit.map(TokenBucket::new);
+
+        loop {
+            // Requests are parsed as generic JSON values first so a malformed request
+            // doesn't desync the stream for the following ones.
+            let request: Value = if version >= LENGTH_PREFIXED_VERSION {
+                let frame = match read_bounded_frame(&mut reader, self.max_request_bytes) {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => break,
+                    Err(KvsError::RequestTooLarge(max)) => {
+                        return reject_oversized(&mut writer, version, peer_addr, max)
+                    }
+                    Err(e) => return Err(e),
+                };
+                let frame = if version >= COMPRESSED_VERSION {
+                    match decode_payload(frame, self.max_request_bytes) {
+                        Ok(payload) => payload,
+                        Err(KvsError::RequestTooLarge(max)) => {
+                            return reject_oversized(&mut writer, version, peer_addr, max)
+                        }
+                        Err(e) => {
+                            let response = ErrorResponse::Err(e.into());
+
+                            write_message(&mut writer, version, &response)?;
+                            debug!("ErrorResponse sent to {}: {:?}", peer_addr, response);
+                            continue;
+                        }
+                    }
+                } else {
+                    frame
+                };
+
+                match serde_json::from_slice(&frame) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        let response = ErrorResponse::Err(RemoteError::Message(format!(
+                            "Invalid request: {}",
+                            e
+                        )));
 
-        // https://docs.serde.rs/serde_json/de/struct.Deserializer.html#method.from_reader
-        // https://doc.rust-lang.org/nightly/std/net/struct.TcpStream.html#impl-Read
-        let commands = Deserializer::from_reader(reader).into_iter::<Protocol>();
+                        write_message(&mut writer, version, &response)?;
+                        debug!("ErrorResponse sent to {}: {:?}", peer_addr, response);
+                        continue;
+                    }
+                }
+            } else {
+                if at_end_of_stream(&mut reader)? {
+                    break;
+                }
+
+                // A syntax error can't be recovered from in a JSON stream since
+                // we can't know where the next request starts.
+                let mut limited = (&mut reader).take(self.max_request_bytes as u64);
+                match read_message(&mut limited, version) {
+                    Ok(request) => request,
+                    Err(_) if limited.limit() == 0 => {
+                        let max = self.max_request_bytes;
+                        return reject_oversized(&mut writer, version, peer_addr, max);
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
 
-        for command in commands {
-            let command = command?;
+            let command = match Protocol::deserialize(request) {
+                Ok(command) => command,
+                Err(e) => {
+                    let response =
+                        ErrorResponse::Err(RemoteError::Message(format!("Invalid request: {}", e)));
+
+                    write_message(&mut writer, version, &response)?;
+                    debug!("ErrorResponse sent to {}: {:?}", peer_addr, response);
+                    continue;
+                }
+            };
+
+            if let Some(log) = self.request_log.as_mut() {
+                if let Err(e) = record_request(log, peer_addr, &command) {
+                    error!("Failed to record request from {}: {}", peer_addr, e);
+                }
+            }
+
+            #[cfg(feature = "structured-logs")]
+            let _request = tracing::info_span!("request", command = command.name()).entered();
+
+            // Throttling the handshake would leave the client unable to read the error.
+            let throttled = match (&command, bucket.as_mut()) {
+                (Protocol::Handshake { .. }, _) | (_, None) => false,
+                (_, Some(bucket)) => !bucket.try_acquire(),
+            };
+            if throttled {
+                let response = ErrorResponse::Err(KvsError::RateLimited.into());
+
+                write_message(&mut writer, version, &response)?;
+                debug!("ErrorResponse sent to {}: {:?}", peer_addr, response);
+                continue;
+            }
 
             match command {
-                Protocol::Get { key } => {
-                    let response = match self.engine.get(key) {
+                Protocol::Get { namespace, key } => {
+                    let response = match self.engine.get(namespaced_key(namespace, key)) {
                         Ok(value) => GetResponse::Ok(value),
-                        Err(e) => GetResponse::Err(format!("{}", e)),
+                        Err(e) => GetResponse::Err(e.into()),
                     };
 
-                    serde_json::to_writer(&mut writer, &response)?;
-                    writer.flush()?;
+                    write_message(&mut writer, version, &response)?;
                     debug!("GetResponse sent to {}: {:?}", peer_addr, response);
                 }
-                Protocol::Set { key, value } => {
-                    let response = match self.engine.set(key, value) {
+                Protocol::GetStream { namespace, key } => {
+                    let response = match self.engine.get(namespaced_key(namespace, key)) {
+                        Ok(Some(value)) => {
+                            let chunks = write_chunks(&mut writer, version, &value)?;
+                            debug!("{} GetStreamResponse chunks sent to {}", chunks, peer_addr);
+                            GetStreamResponse::End(true)
+                        }
+                        Ok(None) => GetStreamResponse::End(false),
+                        Err(e) => GetStreamResponse::Err(e.into()),
+                    };
+
+                    write_message(&mut writer, version, &response)?;
+                    debug!("GetStreamResponse sent to {}: {:?}", peer_addr, response);
+                }
+                Protocol::Len { namespace, key } => {
+                    let response = match self.engine.value_len(namespaced_key(namespace, key)) {
+                        Ok(len) => LenResponse::Ok(len),
+                        Err(e) => LenResponse::Err(e.into()),
+                    };
+
+                    write_message(&mut writer, version, &response)?;
+                    debug!("LenResponse sent to {}: {:?}", peer_addr, response);
+                }
+                Protocol::GetRange {
+                    namespace,
+                    key,
+                    start,
+                    end,
+                } => {
+                    let key = namespaced_key(namespace, key);
+                    let response = match self.engine.get_range(key, start, end) {
+                        Ok(value) => GetResponse::Ok(value),
+                        Err(e) => GetResponse::Err(e.into()),
+                    };
+
+                    write_message(&mut writer, version, &response)?;
+                    debug!("GetResponse sent to {}: {:?}", peer_addr, response);
+                }
+                Protocol::Set {
+                    namespace,
+                    key,
+                    value,
+                } => {
+                    let result = if self.read_only {
+                        Err(KvsError::ReadOnly)
+                    } else {
+                        let result = self.set_with_retries(namespaced_key(namespace, key), value);
+                        self.track_write(&result);
+                        result
+                    };
+                    let response = match result {
                         Ok(()) => SetResponse::Ok(()),
-                        Err(e) => SetResponse::Err(format!("{}", e)),
+                        Err(e) => SetResponse::Err(e.into()),
                     };
 
-                    serde_json::to_writer(&mut writer, &response)?;
-                    writer.flush()?;
+                    write_message(&mut writer, version, &response)?;
                     debug!("SetResponse sent to {}: {:?}", peer_addr, response);
                 }
-                Protocol::Remove { key } => {
-                    let response = match self.engine.remove(key) {
+                Protocol::Remove { namespace, key } => {
+                    let result = if self.read_only {
+                        Err(KvsError::ReadOnly)
+                    } else {
+                        let result = self.engine.remove(namespaced_key(namespace, key));
+                        self.track_write(&result);
+                        result
+                    };
+                    let response = match result {
                         Ok(()) => RemoveResponse::Ok(()),
-                        Err(e) => RemoveResponse::Err(format!("{}", e)),
+                        Err(e) => RemoveResponse::Err(e.into()),
                     };
 
-                    serde_json::to_writer(&mut writer, &response)?;
-                    writer.flush()?;
+                    write_message(&mut writer, version, &response)?;
                     debug!("RemoveResponse sent to {}: {:?}", peer_addr, response);
                 }
+                Protocol::RemoveIf {
+                    namespace,
+                    key,
+                    expected,
+                } => {
+                    let result = if self.read_only {
+                        Err(KvsError::ReadOnly)
+                    } else {
+                        let key = namespaced_key(namespace, key);
+                        let result = self.engine.remove_if(key, expected);
+                        self.track_write(&result);
+                        result
+                    };
+                    let response = match result {
+                        Ok(removed) => RemoveIfResponse::Ok(removed),
+                        Err(e) => RemoveIfResponse::Err(e.into()),
+                    };
+
+                    write_message(&mut writer, version, &response)?;
+                    debug!("RemoveIfResponse sent to {}: {:?}", peer_addr, response);
+                }
+                Protocol::Increment {
+                    namespace,
+                    key,
+                    delta,
+                    op_id,
+                } => {
+                    if let Some(response) = op_id.and_then(|op_id| self.recent_ops.get(op_id)) {
+                        write_message(&mut writer, version, response)?;
+                        debug!(
+                            "Repeated IncrementResponse sent to {}: {}",
+                            peer_addr, response
+                        );
+                        continue;
+                    }
+
+                    let result = if self.read_only {
+                        Err(KvsError::ReadOnly)
+                    } else {
+                        let result = self.engine.increment(namespaced_key(namespace, key), delta);
+                        self.track_write(&result);
+                        result
+                    };
+                    let response = match result {
+                        Ok(value) => IncrementResponse::Ok(value),
+                        Err(e) => IncrementResponse::Err(e.into()),
+                    };
+
+                    // Failed increments weren't applied, retrying them is fine.
+                    if let (Some(op_id), IncrementResponse::Ok(_)) = (op_id, &response) {
+                        self.recent_ops
+                            .insert(op_id, serde_json::to_value(&response)?);
+                    }
+                    write_message(&mut writer, version, &response)?;
+                    debug!("IncrementResponse sent to {}: {:?}", peer_addr, response);
+                }
+                Protocol::GetPattern { namespace, pattern } => {
+                    let response = match get_pattern(&mut self.engine, namespace, &pattern) {
+                        Ok(pairs) => GetPatternResponse::Ok(pairs),
+                        Err(e) => GetPatternResponse::Err(e.into()),
+                    };
+
+                    write_message(&mut writer, version, &response)?;
+                    debug!("GetPatternResponse sent to {}: {:?}", peer_addr, response);
+                }
+                Protocol::ScanKeys {
+                    namespace,
+                    after,
+                    limit,
+                } => {
+                    let response = match scan_keys(&mut self.engine, namespace, after, limit) {
+                        Ok(keys) => ScanKeysResponse::Ok(keys),
+                        Err(e) => ScanKeysResponse::Err(e.into()),
+                    };
+
+                    write_message(&mut writer, version, &response)?;
+                    debug!("ScanKeysResponse sent to {}: {:?}", peer_addr, response);
+                }
+                Protocol::Compact => {
+                    let result = if self.read_only {
+                        Err(KvsError::ReadOnly)
+                    } else {
+                        self.engine.force_compact()
+                    };
+                    let response = match result {
+                        Ok(report) => CompactResponse::Ok(report),
+                        Err(e) => CompactResponse::Err(e.into()),
+                    };
+
+                    write_message(&mut writer, version, &response)?;
+                    debug!("CompactResponse sent to {}: {:?}", peer_addr, response);
+                }
+                Protocol::Sync => {
+                    let result = self.engine.flush();
+                    self.track_write(&result);
+                    let response = match result {
+                        Ok(()) => SyncResponse::Ok(()),
+                        Err(e) => SyncResponse::Err(e.into()),
+                    };
+
+                    write_message(&mut writer, version, &response)?;
+                    debug!("SyncResponse sent to {}: {:?}", peer_addr, response);
+                }
+                Protocol::Info => {
+                    let response = InfoResponse::Ok(ServerInfo {
+                        version: env!("CARGO_PKG_VERSION").to_owned(),
+                        uptime_secs: self.started_at.elapsed().as_secs(),
+                        key_count: self.engine.key_count(),
+                        uncompacted_bytes: self.engine.uncompacted_bytes(),
+                        compaction_threshold: self.engine.compaction_threshold(),
+                        connections: self.connections,
+                        health: self.health(),
+                    });
+
+                    write_message(&mut writer, version, &response)?;
+                    debug!("InfoResponse sent to {}: {:?}", peer_addr, response);
+                }
+                Protocol::Config => {
+                    let response = ConfigResponse::Ok(self.config());
+
+                    write_message(&mut writer, version, &response)?;
+                    debug!("ConfigResponse sent to {}: {:?}", peer_addr, response);
+                }
+                Protocol::Handshake {
+                    version: client_version,
+                } => {
+                    let response = if version != JSON_STREAM_VERSION {
+                        HandshakeResponse::Err("Protocol version already negotiated".to_owned())
+                    } else {
+                        let latest = self.latest_version();
+                        HandshakeResponse::Ok(client_version.clamp(JSON_STREAM_VERSION, latest))
+                    };
+
+                    // The response is framed with the version the request came in.
+                    write_message(&mut writer, version, &response)?;
+                    debug!("HandshakeResponse sent to {}: {:?}", peer_addr, response);
+
+                    if let HandshakeResponse::Ok(negotiated) = response {
+                        version = negotiated;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answers the single HTTP request of a connection, see `KvsServer::with_http`.
+    #[cfg(feature = "http")]
+    fn handle_http_connection(&mut self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        let peer_addr = stream.peer_addr()?;
+
+        let request = match http::read_request(&mut reader, self.max_request_bytes) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e @ KvsError::Io(_)) => return Err(e),
+            Err(e) => {
+                return http::write_response(&mut writer, http_status(&e), e.to_string().as_bytes())
+            }
+        };
+        debug!(
+            "HTTP {} of {:?} received from {}",
+            request.method, request.key, peer_addr
+        );
+        #[cfg(feature = "structured-logs")]
+        let _request = tracing::info_span!("request", command = %request.method).entered();
+
+        if request.key.is_empty() {
+            return http::write_response(&mut writer, 400, b"Missing key");
+        }
+        let (namespace, key) = (None, request.key);
+        let command = match request.method.as_str() {
+            "GET" => Protocol::Get { namespace, key },
+            "PUT" => match String::from_utf8(request.body) {
+                Ok(value) => Protocol::Set {
+                    namespace,
+                    key,
+                    value,
+                },
+                Err(_) => return http::write_response(&mut writer, 400, b"Value is not UTF-8"),
+            },
+            "DELETE" => Protocol::Remove { namespace, key },
+            _ => {
+                let body = b"Only GET, PUT and DELETE are supported";
+                return http::write_response(&mut writer, 405, body);
+            }
+        };
+
+        if let Some(log) = self.request_log.as_mut() {
+            if let Err(e) = record_request(log, peer_addr, &command) {
+                error!("Failed to record request from {}: {}", peer_addr, e);
             }
         }
+        if !self.acquire_http_token(peer_addr.ip()) {
+            let e = KvsError::RateLimited;
+            return http::write_response(&mut writer, http_status(&e), e.to_string().as_bytes());
+        }
+        let writing = !matches!(command, Protocol::Get { .. });
+        if writing && self.read_only {
+            let e = KvsError::ReadOnly;
+            return http::write_response(&mut writer, http_status(&e), e.to_string().as_bytes());
+        }
+
+        let (status, body) = match command {
+            Protocol::Get { key, .. } => match self.engine.get(key) {
+                Ok(Some(value)) => (200, value.into_bytes()),
+                Ok(None) => (404, KvsError::KeyNotFound.to_string().into_bytes()),
+                Err(e) => (http_status(&e), e.to_string().into_bytes()),
+            },
+            Protocol::Set { key, value, .. } => {
+                let result = self.set_with_retries(key, value);
+                self.track_write(&result);
+                match result {
+                    Ok(()) => (204, Vec::new()),
+                    Err(e) => (http_status(&e), e.to_string().into_bytes()),
+                }
+            }
+            Protocol::Remove { key, .. } => {
+                let result = self.engine.remove(key);
+                self.track_write(&result);
+                match result {
+                    Ok(()) => (204, Vec::new()),
+                    Err(e) => (http_status(&e), e.to_string().into_bytes()),
+                }
+            }
+            _ => return Err(KvsError::UnexpectedCommand),
+        };
 
+        http::write_response(&mut writer, status, &body)?;
+        debug!("HTTP {} sent to {}", status, peer_addr);
         Ok(())
     }
+
+    /// Takes a token out of the bucket of `peer`, returning whether the rate limit
+    /// lets its HTTP request through, see `KvsServer::with_rate_limit`.
+    #[cfg(feature = "http")]
+    fn acquire_http_token(&mut self, peer: IpAddr) -> bool {
+        let per_sec = match self.rate_limit {
+            Some(per_sec) => per_sec,
+            None => return true,
+        };
+        // A refilled bucket is as good as a new one, so peers gone quiet are dropped.
+        if self.http_buckets.len() >= MAX_HTTP_PEERS {
+            self.http_buckets.retain(|_, bucket| !bucket.is_full());
+        }
+        self.http_buckets
+            .entry(peer)
+            .or_insert_with(|| TokenBucket::new(per_sec))
+            .try_acquire()
+    }
+
+    /// Sets `key` to `value`, retrying transient failures, see
+    /// `KvsServer::with_write_retries`.
+    fn set_with_retries(&mut self, key: String, value: String) -> Result<()> {
+        let mut attempt = 0;
+        // The last attempt gets the key and value themselves, only retries copy them.
+        while attempt < self.write_retries {
+            match self.engine.set(key.to_owned(), value.to_owned()) {
+                Err(e) if is_transient(&e) => {
+                    let backoff = WRITE_RETRY_BACKOFF * 2u32.saturating_pow(attempt);
+                    debug!("Retrying set of {:?} in {:?}: {}", key, backoff, e);
+                    thread::sleep(jittered(backoff));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+        self.engine.set(key, value)
+    }
+
+    /// Remembers whether the store failed the last write, rejected requests aside.
+    fn track_write<T>(&mut self, result: &Result<T>) {
+        self.last_write_failed = match result {
+            Err(e) => matches!(
+                e,
+                KvsError::Io(_)
+                    | KvsError::Serde(_)
+                    | KvsError::Sled(_)
+                    | KvsError::UnexpectedCommand
+            ),
+            Ok(_) => false,
+        };
+    }
+
+    /// Latest protocol version negotiated with clients.
+    fn latest_version(&self) -> u32 {
+        if self.compression {
+            PROTOCOL_VERSION
+        } else {
+            LENGTH_PREFIXED_VERSION
+        }
+    }
+
+    fn config(&self) -> ServerConfig {
+        ServerConfig {
+            config_version: SERVER_CONFIG_VERSION,
+            engine: self.engine.name().to_owned(),
+            threads: 1,
+            compaction_threshold: self.engine.compaction_threshold(),
+            read_only: self.read_only,
+            protocol_version: self.latest_version(),
+            rate_limit: self.rate_limit,
+            max_request_bytes: self.max_request_bytes as u64,
+            write_retries: self.write_retries,
+        }
+    }
+
+    fn health(&self) -> Health {
+        if self.last_write_failed
+            || self.engine.uncompacted_bytes() > self.degraded_uncompacted_bytes
+        {
+            Health::Degraded
+        } else {
+            Health::Healthy
+        }
+    }
+}
+
+/// Appends `command` to a request log, see `KvsServer::with_request_log`.
+fn record_request(log: &mut dyn Write, peer: SocketAddr, command: &Protocol) -> Result<()> {
+    let record = RecordedRequest {
+        at_millis: SystemClock.now_millis(),
+        peer,
+        request: serde_json::to_value(command)?,
+    };
+    serde_json::to_writer(&mut *log, &record)?;
+    log.write_all(b"\n")?;
+    log.flush()?;
+    Ok(())
+}
+
+/// Whether `e` is an I/O error likely gone by the time the operation is retried.
+fn is_transient(e: &KvsError) -> bool {
+    match e {
+        KvsError::Io(e) => matches!(
+            e.kind(),
+            io::ErrorKind::Interrupted
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::StorageFull
+        ),
+        _ => false,
+    }
+}
+
+/// Picks a duration between half of `backoff` and `backoff`, so the retries of
+/// concurrent servers sharing a disk spread out.
+fn jittered(backoff: Duration) -> Duration {
+    // Each `RandomState` is seeded differently, sparing a dependency on `rand`.
+    let random = RandomState::new().build_hasher().finish();
+    let fraction = (random % 1024) as f64 / 1024.0;
+    backoff / 2 + (backoff / 2).mul_f64(fraction)
+}
+
+/// Lists up to `limit` keys of `namespace` greater than `after`, stripped of
+/// their namespace.
+fn scan_keys<E: KvsEngine>(
+    engine: &mut E,
+    namespace: Option<String>,
+    after: Option<String>,
+    limit: usize,
+) -> Result<Vec<String>> {
+    let namespace = match namespace {
+        Some(namespace) => namespace,
+        None => return engine.scan_keys(after, limit),
+    };
+
+    // The prefix without its trailing separator sorts right before every key of the
+    // namespace, while keys outside of it might sort in between.
+    let prefix = namespaced_key(Some(namespace.to_owned()), String::new());
+    let mut cursor = Some(match after {
+        Some(after) => namespaced_key(Some(namespace), after),
+        None => prefix[..prefix.len() - 1].to_owned(),
+    });
+    let mut keys = Vec::new();
+
+    while keys.len() < limit {
+        let page = engine.scan_keys(cursor.take(), limit - keys.len())?;
+        let last = match page.last() {
+            Some(last) => last.to_owned(),
+            None => break,
+        };
+
+        for key in page {
+            if key.starts_with(&prefix) {
+                keys.push(key[prefix.len()..].to_owned());
+            } else if key > prefix {
+                return Ok(keys);
+            }
+        }
+        cursor = Some(last);
+    }
+
+    Ok(keys)
+}
+
+/// Status of the HTTP response answering a request that failed with `e`.
+#[cfg(feature = "http")]
+fn http_status(e: &KvsError) -> u16 {
+    match e {
+        KvsError::KeyNotFound => 404,
+        KvsError::ReadOnly => 403,
+        KvsError::RequestTooLarge(_) => 413,
+        KvsError::MessageError(_) => 400,
+        KvsError::WrongType => 409,
+        KvsError::RateLimited => 429,
+        _ => 500,
+    }
+}
+
+/// Answers a request larger than `max` bytes with an error, failing the connection
+/// so it gets closed.
+fn reject_oversized<W: Write>(
+    writer: &mut W,
+    version: u32,
+    peer_addr: SocketAddr,
+    max: usize,
+) -> Result<()> {
+    let response = ErrorResponse::Err(KvsError::RequestTooLarge(max).into());
+
+    write_message(writer, version, &response)?;
+    debug!("ErrorResponse sent to {}: {:?}", peer_addr, response);
+    Err(KvsError::RequestTooLarge(max))
+}
+
+/// Operations remembered to answer their retries, whatever the connection they
+/// come from.
+const RECENT_OPS_WINDOW: usize = 1024;
+
+/// Responses to the latest operations carrying an id, the oldest ones being
+/// forgotten past `RECENT_OPS_WINDOW`.
+#[derive(Default)]
+struct RecentOps {
+    order: VecDeque<u64>,
+    responses: HashMap<u64, Value>,
+}
+
+impl RecentOps {
+    fn get(&self, op_id: u64) -> Option<&Value> {
+        self.responses.get(&op_id)
+    }
+
+    fn insert(&mut self, op_id: u64, response: Value) {
+        if self.responses.insert(op_id, response).is_none() {
+            self.order.push_back(op_id);
+        }
+        if self.order.len() > RECENT_OPS_WINDOW {
+            if let Some(oldest) = self.order.pop_front() {
+                self.responses.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// The requests a connection may still send, refilled at a steady rate.
+struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket refilled with `per_sec` tokens per second, holding as many.
+    fn new(per_sec: u32) -> Self {
+        TokenBucket {
+            capacity: f64::from(per_sec),
+            tokens: f64::from(per_sec),
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Returns `true` once the bucket refilled to its capacity.
+    #[cfg(feature = "http")]
+    fn is_full(&self) -> bool {
+        let elapsed = self.refilled_at.elapsed().as_secs_f64();
+        self.tokens + elapsed * self.capacity >= self.capacity
+    }
+
+    /// Takes a token out of the bucket, returning whether there was one.
+    fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.capacity).min(self.capacity);
+        self.refilled_at = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Largest chunk of a streamed value, in bytes.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Buffers `value` as GetStreamResponse chunks cut on character boundaries,
+/// returning how many were written.
+fn write_chunks<W: Write>(writer: &mut W, version: u32, value: &str) -> Result<usize> {
+    let mut chunks = 0;
+    let mut start = 0;
+
+    while start < value.len() {
+        let mut end = (start + STREAM_CHUNK_SIZE).min(value.len());
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        let chunk = GetStreamResponse::Chunk(value[start..end].to_owned());
+        buffer_message(&mut *writer, version, &chunk)?;
+        chunks += 1;
+        start = end;
+    }
+
+    Ok(chunks)
+}
+
+/// Keys listed per engine call while looking for the ones matching a pattern.
+const PATTERN_SCAN_PAGE: usize = 1000;
+
+/// Fetches every key of `namespace` matching the glob `pattern` along with its value.
+fn get_pattern<E: KvsEngine>(
+    engine: &mut E,
+    namespace: Option<String>,
+    pattern: &str,
+) -> Result<Vec<(String, String)>> {
+    let mut pairs = Vec::new();
+    let mut after = None;
+
+    loop {
+        let keys = scan_keys(engine, namespace.to_owned(), after, PATTERN_SCAN_PAGE)?;
+        for key in &keys {
+            if !glob_match(pattern, key) {
+                continue;
+            }
+            // The key might have expired since it was listed.
+            if let Some(value) = engine.get(namespaced_key(namespace.to_owned(), key.to_owned()))? {
+                pairs.push((key.to_owned(), value));
+            }
+        }
+
+        if keys.len() < PATTERN_SCAN_PAGE {
+            return Ok(pairs);
+        }
+        after = keys.last().cloned();
+    }
 }
@@ -0,0 +1,110 @@
+use crate::cluster_client::Node;
+use crate::{KvsError, Result};
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+
+/// Points each shard takes on the hash ring, evening out the share of keys it gets.
+const VIRTUAL_NODES: u32 = 64;
+
+/// A client routing each key to one of several shard servers through consistent
+/// hashing.
+///
+/// Every shard takes several points on a hash ring, a key going to the shard owning
+/// the first point at or after its hash. Adding or removing a shard only moves the
+/// keys of the points it takes or gives back. Connections are opened lazily, one per
+/// shard.
+pub struct ShardedKvsClient {
+    ring: BTreeMap<u64, SocketAddr>,
+    shards: HashMap<SocketAddr, Node>,
+}
+
+impl ShardedKvsClient {
+    /// Creates a client spreading keys across `shards`.
+    pub fn new(shards: Vec<SocketAddr>) -> Self {
+        let mut client = ShardedKvsClient {
+            ring: BTreeMap::new(),
+            shards: HashMap::new(),
+        };
+        for addr in shards {
+            client.add_shard(addr);
+        }
+
+        client
+    }
+
+    /// Adds a shard to the ring, taking over its share of the keys of the others.
+    pub fn add_shard(&mut self, addr: SocketAddr) {
+        if self.shards.contains_key(&addr) {
+            return;
+        }
+
+        for point in 0..VIRTUAL_NODES {
+            self.ring.insert(hash(&format!("{}#{}", addr, point)), addr);
+        }
+        self.shards.insert(addr, Node::new(addr));
+    }
+
+    /// Removes a shard from the ring, its keys moving to the other shards. Returns
+    /// `false` if it wasn't part of it.
+    pub fn remove_shard(&mut self, addr: SocketAddr) -> bool {
+        if self.shards.remove(&addr).is_none() {
+            return false;
+        }
+
+        self.ring.retain(|_, shard| *shard != addr);
+        true
+    }
+
+    /// The shard `key` is routed to, `None` when there's no shard.
+    pub fn shard_for(&self, key: &str) -> Option<SocketAddr> {
+        let hash = hash(key);
+        self.ring
+            .range(hash..)
+            .next()
+            .or_else(|| self.ring.iter().next())
+            .map(|(_, addr)| *addr)
+    }
+
+    /// Sends a GET request to the shard of `key`.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.shard(&key)?.call(|client| client.get(key))
+    }
+
+    /// Sends a SET request to the shard of `key`.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.shard(&key)?.call(|client| client.set(key, value))
+    }
+
+    /// Sends a REMOVE request to the shard of `key`.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.shard(&key)?.call(|client| client.remove(key))
+    }
+
+    fn shard(&mut self, key: &str) -> Result<&mut Node> {
+        let addr = self
+            .shard_for(key)
+            .ok_or_else(|| KvsError::MessageError("No shard to route keys to".to_owned()))?;
+        Ok(self
+            .shards
+            .get_mut(&addr)
+            .expect("every shard of the ring has a node"))
+    }
+}
+
+/// FNV-1a followed by a 64-bit finalizer, stable across processes and platforms so
+/// every client routes keys alike.
+fn hash(value: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in value.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    // Similar strings, such as the points of a shard, end up far apart.
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
@@ -0,0 +1,363 @@
+//! Helpers to exercise the server and clients without touching the disk.
+
+use crate::{Clock, CompactionReport, KvsEngine, KvsError, Result};
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// An operation received by a `RecordingEngine`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineCall {
+    /// A call to `KvsEngine::set`.
+    Set {
+        /// The key being set.
+        key: String,
+        /// The value being set.
+        value: String,
+    },
+    /// A call to `KvsEngine::get`.
+    Get {
+        /// The key being fetched.
+        key: String,
+    },
+    /// A call to `KvsEngine::get_range`.
+    GetRange {
+        /// The key being fetched.
+        key: String,
+        /// Start of the range requested.
+        start: usize,
+        /// End of the range requested.
+        end: usize,
+    },
+    /// A call to `KvsEngine::remove`.
+    Remove {
+        /// The key being removed.
+        key: String,
+    },
+    /// A call to `KvsEngine::scan_keys`.
+    ScanKeys {
+        /// The key listed keys come after.
+        after: Option<String>,
+        /// Maximum amount of keys requested.
+        limit: usize,
+    },
+    /// A call to `KvsEngine::force_compact`.
+    ForceCompact,
+    /// A call to `KvsEngine::flush`.
+    Flush,
+}
+
+/// An in-memory engine logging every operation it receives.
+///
+/// Clones share both the stored data and the log of calls, so a clone can be kept
+/// around to inspect an engine moved into a `KvsServer`.
+///
+/// ```
+/// use kvs::test_util::{EngineCall, RecordingEngine};
+/// use kvs::KvsEngine;
+///
+/// let mut engine = RecordingEngine::new();
+/// engine.set("foo".to_owned(), "bar".to_owned()).unwrap();
+/// assert_eq!(
+///     engine.calls(),
+///     vec![EngineCall::Set { key: "foo".to_owned(), value: "bar".to_owned() }]
+/// );
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct RecordingEngine {
+    map: Arc<Mutex<BTreeMap<String, String>>>,
+    calls: Arc<Mutex<Vec<EngineCall>>>,
+}
+
+impl RecordingEngine {
+    /// Creates an empty engine.
+    pub fn new() -> Self {
+        RecordingEngine::default()
+    }
+
+    /// Returns every call received so far, in order.
+    pub fn calls(&self) -> Vec<EngineCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    fn record(&self, call: EngineCall) {
+        self.calls.lock().unwrap().push(call);
+    }
+}
+
+impl KvsEngine for RecordingEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.record(EngineCall::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        });
+        self.map.lock().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.record(EngineCall::Get {
+            key: key.to_owned(),
+        });
+        Ok(self.map.lock().unwrap().get(&key).cloned())
+    }
+
+    fn get_range(&mut self, key: String, start: usize, end: usize) -> Result<Option<String>> {
+        self.record(EngineCall::GetRange {
+            key: key.to_owned(),
+            start,
+            end,
+        });
+
+        let map = self.map.lock().unwrap();
+        let value = match map.get(&key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        match value.get(start..end.min(value.len())) {
+            Some(range) => Ok(Some(range.to_owned())),
+            None => Err(KvsError::InvalidRange),
+        }
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.record(EngineCall::Remove {
+            key: key.to_owned(),
+        });
+        match self.map.lock().unwrap().remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn scan_keys(&mut self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        self.record(EngineCall::ScanKeys {
+            after: after.to_owned(),
+            limit,
+        });
+
+        let map = self.map.lock().unwrap();
+        let lower = after.map_or(Bound::Unbounded, Bound::Excluded);
+        Ok(map
+            .range((lower, Bound::Unbounded))
+            .map(|(key, _)| key.to_owned())
+            .take(limit)
+            .collect())
+    }
+
+    fn force_compact(&mut self) -> Result<CompactionReport> {
+        self.record(EngineCall::ForceCompact);
+        Ok(CompactionReport {
+            bytes_before: 0,
+            bytes_after: 0,
+            files_removed: 0,
+        })
+    }
+
+    fn key_count(&self) -> u64 {
+        self.map.lock().unwrap().len() as u64
+    }
+
+    fn uncompacted_bytes(&self) -> u64 {
+        0
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.record(EngineCall::Flush);
+        Ok(())
+    }
+}
+
+/// An in-memory engine which never touches the disk, to exercise the server and
+/// clients without setting up a directory.
+///
+/// ```
+/// use kvs::test_util::MemoryKvsEngine;
+/// use kvs::KvsEngine;
+///
+/// let mut engine = MemoryKvsEngine::new();
+/// engine.set("foo".to_owned(), "bar".to_owned()).unwrap();
+/// assert_eq!(engine.get("foo".to_owned()).unwrap(), Some("bar".to_owned()));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemoryKvsEngine {
+    map: HashMap<String, String>,
+}
+
+impl MemoryKvsEngine {
+    /// Creates an empty engine.
+    pub fn new() -> Self {
+        MemoryKvsEngine::default()
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.map.insert(key, value);
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.map.get(&key).cloned())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        match self.map.remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn scan_keys(&mut self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = self
+            .map
+            .keys()
+            .filter(|key| after.as_ref().is_none_or(|after| *key > after))
+            .cloned()
+            .collect();
+        keys.sort_unstable();
+        keys.truncate(limit);
+        Ok(keys)
+    }
+
+    fn force_compact(&mut self) -> Result<CompactionReport> {
+        Ok(CompactionReport {
+            bytes_before: 0,
+            bytes_after: 0,
+            files_removed: 0,
+        })
+    }
+
+    fn key_count(&self) -> u64 {
+        self.map.len() as u64
+    }
+
+    fn uncompacted_bytes(&self) -> u64 {
+        0
+    }
+
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+}
+
+/// An in-memory engine whose first sets fail with a transient I/O error, to
+/// exercise how failed writes get retried.
+///
+/// Clones share the failures left, so a clone can be kept around to inspect an
+/// engine moved into a `KvsServer`.
+///
+/// ```
+/// use kvs::test_util::FlakyEngine;
+/// use kvs::KvsEngine;
+///
+/// let mut engine = FlakyEngine::new(1);
+/// assert!(engine.set("foo".to_owned(), "bar".to_owned()).is_err());
+/// engine.set("foo".to_owned(), "bar".to_owned()).unwrap();
+/// assert_eq!(engine.failures_left(), 0);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct FlakyEngine {
+    engine: MemoryKvsEngine,
+    failures_left: Arc<AtomicU32>,
+}
+
+impl FlakyEngine {
+    /// Creates an empty engine failing its first `failures` sets with an
+    /// `io::ErrorKind::Interrupted` error.
+    pub fn new(failures: u32) -> Self {
+        FlakyEngine {
+            engine: MemoryKvsEngine::new(),
+            failures_left: Arc::new(AtomicU32::new(failures)),
+        }
+    }
+
+    /// Amount of sets still bound to fail.
+    pub fn failures_left(&self) -> u32 {
+        self.failures_left.load(Ordering::SeqCst)
+    }
+}
+
+impl KvsEngine for FlakyEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let failing = self
+            .failures_left
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok();
+        if failing {
+            let e = io::Error::new(io::ErrorKind::Interrupted, "injected failure");
+            return Err(e.into());
+        }
+        self.engine.set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.engine.remove(key)
+    }
+
+    fn scan_keys(&mut self, after: Option<String>, limit: usize) -> Result<Vec<String>> {
+        self.engine.scan_keys(after, limit)
+    }
+
+    fn force_compact(&mut self) -> Result<CompactionReport> {
+        self.engine.force_compact()
+    }
+
+    fn key_count(&self) -> u64 {
+        self.engine.key_count()
+    }
+
+    fn uncompacted_bytes(&self) -> u64 {
+        self.engine.uncompacted_bytes()
+    }
+}
+
+/// A clock standing still until advanced by hand, making expiry deterministic.
+///
+/// Clones share the same time, so a clone can be kept around to advance the clock
+/// of a store.
+///
+/// ```
+/// use kvs::test_util::MockClock;
+/// use kvs::Clock;
+/// use std::time::Duration;
+///
+/// let clock = MockClock::new(1_000);
+/// clock.advance(Duration::from_secs(1));
+/// assert_eq!(clock.now_millis(), 2_000);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    millis: Arc<AtomicU64>,
+}
+
+impl MockClock {
+    /// Creates a clock set to `millis` milliseconds since the Unix epoch.
+    pub fn new(millis: u64) -> Self {
+        MockClock {
+            millis: Arc::new(AtomicU64::new(millis)),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        self.millis
+            .fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now_millis(&self) -> u64 {
+        self.millis.load(Ordering::SeqCst)
+    }
+}
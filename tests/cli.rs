@@ -1,3 +1,5 @@
+#![allow(clippy::needless_borrows_for_generic_args, clippy::zombie_processes)]
+
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
@@ -183,6 +185,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // Reap the server so the engine releases its files before the restart.
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -289,3 +293,327 @@ fn cli_access_server(engine: &str, addr: &str) {
 fn cli_access_server_kvs_engine() {
     cli_access_server("kvs", "127.0.0.1:4004");
 }
+
+#[test]
+fn cli_access_server_sled_engine() {
+    cli_access_server("sled", "127.0.0.1:4005");
+}
+
+// `kvs-client get --raw` should print values exactly and fail silently on a miss.
+#[test]
+fn cli_get_raw() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--raw", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--raw", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(is_empty());
+
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-server` should flush its engine and exit cleanly on SIGTERM.
+#[cfg(unix)]
+#[test]
+fn cli_server_graceful_shutdown() {
+    let addr = "127.0.0.1:4007";
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("KvsServer shut down"));
+
+    // The engine released its files, so a new server can take them over.
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-server` should refuse a directory served with another engine.
+#[test]
+fn cli_server_wrong_engine() {
+    let addr = "127.0.0.1:4044";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    match child.try_wait().unwrap() {
+        Some(status) => assert!(!status.success()),
+        None => {
+            child.kill().unwrap();
+            panic!("server started with the wrong engine");
+        }
+    }
+
+    // The engine it was served with still starts.
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    assert!(child.try_wait().unwrap().is_none());
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-client scan` should print the keys within the range in order, along with their values.
+#[test]
+fn cli_scan_range() {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for key in &["a", "b", "c", "d", "e"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, &format!("value-{}", key), "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "b", "e", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("b\tvalue-b\nc\tvalue-c\nd\tvalue-d\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "bb", "z", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("c\tvalue-c\nd\tvalue-d\ne\tvalue-e\n");
+
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-client import` should set every pair of a CSV file, reporting the broken rows.
+#[test]
+fn cli_import_csv() {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let csv_path = temp_dir.path().join("pairs.csv");
+    fs::write(
+        &csv_path,
+        "key,value\nkey1,value1\n\"key,2\",\"value \"\"2\"\"\"\nkey3,\"multi\nline\"\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["import", csv_path.to_str().unwrap(), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Imported 3 pairs, 0 failed\n");
+
+    for (key, value) in &[
+        ("key1", "value1\n"),
+        ("key,2", "value \"2\"\n"),
+        ("key3", "multi\nline\n"),
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", key, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(*value);
+    }
+
+    let broken_path = temp_dir.path().join("broken.csv");
+    fs::write(&broken_path, "key4,value4\nkey5\n").unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["import", broken_path.to_str().unwrap(), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout("Imported 1 pairs, 1 failed\n")
+        .stderr(contains("Line 2: expected 2 fields, got 1"));
+
+    let json_path = temp_dir.path().join("pairs.json");
+    fs::write(&json_path, r#"{"key6": "value6", "key7": 7}"#).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["import", json_path.to_str().unwrap(), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Imported 2 pairs, 0 failed\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key7", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("7\n");
+
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_replay_request_log() {
+    let recorded_addr = "127.0.0.1:4040";
+    let replayed_addr = "127.0.0.1:4041";
+    let recorded_dir = TempDir::new().unwrap();
+    let replayed_dir = TempDir::new().unwrap();
+    let log_path = recorded_dir.path().join("requests.log");
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", recorded_addr, "--request-log"])
+        .arg(&log_path)
+        .current_dir(&recorded_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for args in &[
+        &["set", "key1", "value1"][..],
+        &["set", "key2", "value2"],
+        &["rm", "key1"],
+        &["set", "key3", "value3"],
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(*args)
+            .args(&["--addr", recorded_addr])
+            .current_dir(&recorded_dir)
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key4", "--addr", recorded_addr])
+        .current_dir(&recorded_dir)
+        .assert()
+        .failure();
+    child.kill().expect("server exited before killed");
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", replayed_addr])
+        .current_dir(&replayed_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // Every kvs-client invocation opens with a handshake, recorded as well.
+    Command::cargo_bin("kvs-replay")
+        .unwrap()
+        .arg(&log_path)
+        .args(&["--addr", replayed_addr, "--rate", "1000"])
+        .assert()
+        .success()
+        .stdout("Replayed 10 requests, 1 answered with an error\n")
+        .stderr(contains("Request 10: Key not found"));
+
+    for (key, value) in &[
+        ("key1", "Key not found\n"),
+        ("key2", "value2\n"),
+        ("key3", "value3\n"),
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", key, "--addr", replayed_addr])
+            .current_dir(&replayed_dir)
+            .assert()
+            .success()
+            .stdout(*value);
+    }
+
+    child.kill().expect("server exited before killed");
+}
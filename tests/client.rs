@@ -0,0 +1,158 @@
+use kvs::{KvsClient, KvsClusterClient, Result, ShardedKvsClient};
+use serde_json::{json, Deserializer, Value};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+// Starts a fake server answering every GET with its own name.
+// Like servers predating protocol negotiation, handshakes are answered with an error.
+// Every received request is forwarded to the returned receiver.
+fn spawn_mock_server(
+    name: &'static str,
+    addr: &str,
+) -> (SocketAddr, Receiver<(&'static str, Value)>) {
+    let listener = TcpListener::bind(addr).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let requests =
+                Deserializer::from_reader(stream.try_clone().unwrap()).into_iter::<Value>();
+
+            for request in requests {
+                let request = request.unwrap();
+                let response = if request.get("Get").is_some() {
+                    json!({ "Ok": name })
+                } else if request.get("Handshake").is_some() {
+                    json!({ "Err": "unknown variant `Handshake`" })
+                } else {
+                    json!({ "Ok": null })
+                };
+
+                sender.send((name, request)).unwrap();
+                serde_json::to_writer(&mut stream, &response).unwrap();
+                stream.flush().unwrap();
+            }
+        }
+    });
+
+    (addr, receiver)
+}
+
+// Reads should be spread across replicas while writes only hit the primary.
+#[test]
+fn cluster_client_routes_requests() -> Result<()> {
+    let (primary, primary_requests) = spawn_mock_server("primary", "127.0.0.1:0");
+    let (replica1, replica1_requests) = spawn_mock_server("replica1", "127.0.0.1:0");
+    let (replica2, replica2_requests) = spawn_mock_server("replica2", "127.0.0.1:0");
+    let mut client = KvsClusterClient::new(primary, vec![replica1, replica2]);
+
+    let mut reads = Vec::new();
+    for _ in 0..4 {
+        reads.push(client.get("key1".to_owned())?.unwrap());
+    }
+    assert_eq!(reads, vec!["replica1", "replica2", "replica1", "replica2"]);
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.remove("key1".to_owned())?;
+
+    let is_handshake = |request: &Value| request.get("Handshake").is_some();
+    let primary_requests: Vec<_> = primary_requests
+        .try_iter()
+        .filter(|(_, r)| !is_handshake(r))
+        .collect();
+    assert_eq!(primary_requests.len(), 2);
+    assert!(primary_requests[0].1.get("Set").is_some());
+    assert!(primary_requests[1].1.get("Remove").is_some());
+    assert!(replica1_requests
+        .try_iter()
+        .all(|(_, r)| is_handshake(&r) || r.get("Get").is_some()));
+    assert!(replica2_requests
+        .try_iter()
+        .all(|(_, r)| is_handshake(&r) || r.get("Get").is_some()));
+
+    Ok(())
+}
+
+// Without auto-flush, queued requests should only reach the server once flushed.
+#[test]
+fn client_batches_requests_until_flushed() -> Result<()> {
+    let (addr, requests) = spawn_mock_server("node", "127.0.0.1:0");
+    let mut client = KvsClient::connect(addr)?.with_auto_flush(false);
+    let _ = requests.recv().unwrap(); // handshake
+
+    for iter in 0..3 {
+        client.send_set(format!("key{}", iter), "value".to_owned())?;
+    }
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(requests.try_iter().count(), 0);
+
+    client.flush()?;
+    for _ in 0..3 {
+        client.recv()?;
+    }
+    let sets: Vec<_> = requests.try_iter().collect();
+    assert_eq!(sets.len(), 3);
+    assert!(sets.iter().all(|(_, r)| r.get("Set").is_some()));
+
+    Ok(())
+}
+
+// Keys should spread across shards, always routing to the same one, and adding a
+// shard should only move the keys it takes over.
+#[test]
+fn sharded_client_routes_keys() -> Result<()> {
+    let (shard1, _shard1_requests) = spawn_mock_server("shard1", "127.0.0.1:0");
+    let (shard2, _shard2_requests) = spawn_mock_server("shard2", "127.0.0.1:0");
+    let (shard3, _shard3_requests) = spawn_mock_server("shard3", "127.0.0.1:0");
+    let names: HashMap<SocketAddr, &str> =
+        [(shard1, "shard1"), (shard2, "shard2"), (shard3, "shard3")].into();
+    let mut client = ShardedKvsClient::new(vec![shard1, shard2]);
+
+    // Requests reach the shard the key routes to.
+    for key_id in 0..10 {
+        let key = format!("key{}", key_id);
+        let shard = client.shard_for(&key).unwrap();
+        assert_eq!(client.get(key)?, Some(names[&shard].to_owned()));
+    }
+
+    let keys: Vec<String> = (0..1000).map(|key_id| format!("key{}", key_id)).collect();
+    let routes: Vec<SocketAddr> = keys
+        .iter()
+        .map(|key| client.shard_for(key).unwrap())
+        .collect();
+    let other = ShardedKvsClient::new(vec![shard2, shard1]);
+    for (key, shard) in keys.iter().zip(&routes) {
+        assert_eq!(other.shard_for(key), Some(*shard));
+    }
+    let on_shard1 = routes.iter().filter(|shard| **shard == shard1).count();
+    assert!(
+        (300..=700).contains(&on_shard1),
+        "{} keys on shard1",
+        on_shard1
+    );
+
+    client.add_shard(shard3);
+    let mut moved = 0;
+    for (key, shard) in keys.iter().zip(&routes) {
+        let new_shard = client.shard_for(key).unwrap();
+        if new_shard != *shard {
+            assert_eq!(new_shard, shard3);
+            moved += 1;
+        }
+    }
+    assert!((150..=500).contains(&moved), "{} keys moved", moved);
+
+    // Removing it brings every key back where it was.
+    assert!(client.remove_shard(shard3));
+    for (key, shard) in keys.iter().zip(&routes) {
+        assert_eq!(client.shard_for(key), Some(*shard));
+    }
+
+    Ok(())
+}
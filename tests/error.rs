@@ -0,0 +1,47 @@
+use kvs::{KvStore, KvsError};
+use std::error::Error;
+use std::io;
+use tempfile::TempDir;
+
+// Errors should be usable as trait objects, keeping their underlying cause.
+#[test]
+fn boxed_error_keeps_source() {
+    let err: Box<dyn Error> = Box::new(KvsError::from(io::Error::other("disk on fire")));
+    assert_eq!(err.to_string(), "IO error: disk on fire");
+
+    let source = err.source().expect("IO errors should have a source");
+    let io_err = source
+        .downcast_ref::<io::Error>()
+        .expect("the source should be the IO error");
+    assert_eq!(io_err.to_string(), "disk on fire");
+
+    let serde_err = serde_json::from_str::<u32>("nope").unwrap_err();
+    let err: Box<dyn Error + Send + Sync> = Box::new(KvsError::from(serde_err));
+    assert!(err
+        .source()
+        .and_then(|source| source.downcast_ref::<serde_json::Error>())
+        .is_some());
+
+    assert!(KvsError::KeyNotFound.source().is_none());
+}
+
+// The kind of the original IO error should be recoverable through `source()`.
+#[test]
+fn io_error_kind_through_source() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let err = match KvStore::open(temp_dir.path().join("missing")) {
+        Ok(_) => panic!("opening a missing directory should fail"),
+        Err(err) => err,
+    };
+
+    let kind = err
+        .source()
+        .and_then(|source| source.downcast_ref::<io::Error>())
+        .map(io::Error::kind);
+    assert_eq!(kind, Some(io::ErrorKind::NotFound));
+
+    match err {
+        KvsError::Io(ref e) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+        _ => panic!("expected an IO error, got {:?}", err),
+    }
+}
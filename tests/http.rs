@@ -0,0 +1,110 @@
+#![cfg(feature = "http")]
+
+use kvs::test_util::MemoryKvsEngine;
+use kvs::{KvsServer, RecordedRequest, Result};
+use serde_json::json;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Sends a single request over a new connection, returning the status and body of
+/// the response.
+fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> Result<(u16, String)> {
+    let (status_line, body) = exchange(addr, method, path, body)?;
+    let status = status_line.split(' ').nth(1).unwrap().parse().unwrap();
+    Ok((status, body))
+}
+
+/// Sends a single request like `request`, returning the status line and body of the
+/// response.
+fn exchange(addr: SocketAddr, method: &str, path: &str, body: &str) -> Result<(String, String)> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        addr,
+        body.len(),
+        body
+    )?;
+
+    // The server closes the connection once it answered.
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status_line = head.lines().next().unwrap();
+    Ok((status_line.to_owned(), body.to_owned()))
+}
+
+// PUT, GET and DELETE of a path should set, get and remove the key it names.
+#[test]
+fn http_round_trip() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4036".parse().unwrap();
+    let server = KvsServer::new(MemoryKvsEngine::new()).with_http(true);
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    assert_eq!(request(addr, "GET", "/key1", "")?.0, 404);
+    assert_eq!(request(addr, "PUT", "/key1", "value 1\n")?.0, 204);
+    assert_eq!(
+        request(addr, "GET", "/key1", "")?,
+        (200, "value 1\n".to_owned())
+    );
+
+    // Paths are percent-decoded into the key.
+    assert_eq!(request(addr, "PUT", "/key%202", "value2")?.0, 204);
+    assert_eq!(
+        request(addr, "GET", "/key%202", "")?,
+        (200, "value2".to_owned())
+    );
+
+    assert_eq!(request(addr, "DELETE", "/key1", "")?.0, 204);
+    assert_eq!(request(addr, "GET", "/key1", "")?.0, 404);
+    assert_eq!(request(addr, "DELETE", "/key1", "")?.0, 404);
+    assert_eq!(request(addr, "POST", "/key1", "")?.0, 405);
+
+    Ok(())
+}
+
+// HTTP requests should be recorded to the request log and limited per peer, each of
+// them coming over its own connection.
+#[test]
+fn http_rate_limit_and_request_log() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4043".parse().unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("requests.log");
+    let server = KvsServer::new(MemoryKvsEngine::new())
+        .with_http(true)
+        .with_rate_limit(2)
+        .with_request_log(File::create(&log_path)?);
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    assert_eq!(request(addr, "PUT", "/key1", "value1")?.0, 204);
+    assert_eq!(request(addr, "GET", "/key1", "")?.0, 200);
+    assert_eq!(
+        exchange(addr, "DELETE", "/key1", "")?.0,
+        "HTTP/1.1 429 Too Many Requests"
+    );
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(request(addr, "DELETE", "/key1", "")?.0, 204);
+
+    let records = RecordedRequest::read_log(fs::read(&log_path)?.as_slice())
+        .map(|record| Ok(record?.request))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        records,
+        vec![
+            json!({"Set": {"key": "key1", "value": "value1"}}),
+            json!({"Get": {"key": "key1"}}),
+            json!({"Remove": {"key": "key1"}}),
+            json!({"Remove": {"key": "key1"}}),
+        ]
+    );
+
+    Ok(())
+}
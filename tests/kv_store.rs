@@ -1,7 +1,32 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::test_util::MockClock;
+#[cfg(feature = "crash-test")]
+use kvs::CrashTest;
+use kvs::{
+    Command, CommandStream, CorruptionPolicy, GroupCommit, IndexDiscrepancy, IndexPolicy, IoStats,
+    KeyIndexKind, KeyPolicy, KvStore, KvStoreOptions, KvsEngine, KvsError, LogFormat, MergePolicy,
+    MissingLogPolicy, Patch, Result, StoreManager, SwapPolicy,
+};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+/// The log files of the store directory `dir`, leaving its lock file out.
+fn log_entries(dir: &Path) -> Result<Vec<fs::DirEntry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.path().extension() == Some("log".as_ref()) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
@@ -124,3 +149,2730 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Manual compaction should report the space reclaimed from stale commands.
+#[test]
+fn compaction_report() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..1000 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+
+    let report = store.compact()?;
+    assert!(report.files_removed >= 1);
+    assert!(report.bytes_before > report.bytes_after);
+    assert!(report.bytes_reclaimed() > 30_000);
+    assert_eq!(store.get("key1".to_owned())?, Some("value999".to_owned()));
+
+    // Dropping the store waits for the stale log files to be removed.
+    drop(store);
+    let log_files = log_entries(temp_dir.path())?.len();
+    assert_eq!(log_files, 2);
+
+    Ok(())
+}
+
+// Reopening a store without writing anything should not create new log files.
+#[test]
+fn reopen_reuses_writer_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    for _ in 0..5 {
+        let store = KvStore::open(temp_dir.path())?;
+        drop(store);
+    }
+    assert_eq!(log_entries(temp_dir.path())?.len(), 1);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(log_entries(temp_dir.path())?.len(), 1);
+
+    Ok(())
+}
+
+// The constructor predating `KvStore::open` should keep opening stores.
+#[test]
+#[allow(deprecated)]
+fn new_opens_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::new(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A directory should be written by a single store at a time, read-only ones aside.
+#[test]
+fn open_locks_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Locked) => (),
+        res => panic!("expected a locked error, got {:?}", res.err()),
+    }
+    let mut snapshot = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Renaming should move the value to the new key and drop the old one.
+#[test]
+fn rename_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.rename("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert!(store.rename("key1".to_owned(), "key3".to_owned()).is_err());
+
+    // Integers stay integers.
+    store.set_int("key4".to_owned(), 42)?;
+    store.rename("key4".to_owned(), "key5".to_owned())?;
+    assert_eq!(store.get_int("key4".to_owned())?, None);
+    assert_eq!(store.get_int("key5".to_owned())?, Some(42));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_int("key5".to_owned())?, Some(42));
+
+    Ok(())
+}
+
+// A crash in the middle of a set should leave a store that reopens with
+// every previously acknowledged command.
+#[cfg(feature = "crash-test")]
+#[test]
+fn crash_during_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let crash_test = CrashTest::new();
+    let options = KvStoreOptions {
+        crash_test: Some(crash_test.clone()),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    crash_test.fail_after(10);
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A write failing partway should be cut off the log file, leaving the writes that
+// follow it readable once reopened.
+#[cfg(feature = "crash-test")]
+#[test]
+fn failed_write_followed_by_successful_one() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let crash_test = CrashTest::new();
+    let options = KvStoreOptions {
+        crash_test: Some(crash_test.clone()),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1", "value1")?;
+    crash_test.fail_after(10);
+    assert!(store.set("key2", "value2").is_err());
+    crash_test.disarm();
+    store.set("key2", "value2")?;
+
+    crash_test.fail_after(10);
+    assert!(store.remove("key1").is_err());
+    crash_test.disarm();
+    store.rename("key2".to_owned(), "key3".to_owned())?;
+    assert_eq!(store.get("key3")?, Some("value2".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Compaction should be triggered by the interval even below the threshold.
+#[test]
+fn periodic_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_interval: Some(Duration::from_millis(100)),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(temp_dir.path().join("1.log").exists());
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    Ok(())
+}
+
+// Compacting a single key should reclaim its stale bytes and leave other keys alone.
+#[test]
+fn compact_single_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    let other_key_stale_bytes = store.uncompacted_bytes();
+
+    for iter in 0..1000 {
+        store.set("hot".to_owned(), format!("value{}", iter))?;
+    }
+    let stale_bytes = store.uncompacted_bytes();
+    assert!(stale_bytes > 20_000);
+
+    store.compact_key("hot")?;
+    assert!(store.uncompacted_bytes() < stale_bytes);
+    // Only the previous copy of the latest `hot` command is left as stale.
+    assert!(store.uncompacted_bytes() < other_key_stale_bytes + 100);
+    assert!(store.compact_key("missing").is_err());
+
+    assert_eq!(store.get("hot".to_owned())?, Some("value999".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    assert!(temp_dir.path().join("1.log").exists());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("hot".to_owned())?, Some("value999".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Any valid string should round-trip exactly, even when it resembles JSON.
+#[test]
+fn json_edge_cases_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let samples = vec![
+        "".to_owned(),
+        " leading and trailing whitespace\t ".to_owned(),
+        "embedded\nnew\r\nlines".to_owned(),
+        "\"quotes\" and \\backslashes\\".to_owned(),
+        "\"a\"123".to_owned(),
+        "{\"Set\":{\"key\":\"k\",\"value\":\"v\"}}".to_owned(),
+        "control\u{0}\u{7}\u{1b}\u{1f}\u{7f}chars".to_owned(),
+        "line\u{2028}and\u{2029}paragraph separators".to_owned(),
+        "unicode: ação, 日本語, 🦀".to_owned(),
+    ];
+
+    for (index, sample) in samples.iter().enumerate() {
+        store.set(sample.to_owned(), format!("{}{}", sample, index))?;
+        store.set(format!("key{}", index), sample.to_owned())?;
+    }
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        for (index, sample) in samples.iter().enumerate() {
+            assert_eq!(
+                store.get(sample.to_owned())?,
+                Some(format!("{}{}", sample, index))
+            );
+            assert_eq!(store.get(format!("key{}", index))?, Some(sample.to_owned()));
+        }
+        Ok(())
+    };
+    check(&mut store)?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)?;
+
+    // Compaction copies commands verbatim, they should still load afterwards
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)?;
+
+    Ok(())
+}
+
+// Reads should succeed across more log files than readers allowed to stay open.
+#[test]
+fn bounded_reader_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for index in 1..=5 {
+        let command = format!(
+            r#"{{"Set":{{"key":"key{}","value":"value{}"}}}}"#,
+            index, index
+        );
+        fs::write(temp_dir.path().join(format!("{}.log", index)), command)?;
+    }
+
+    let options = KvStoreOptions {
+        max_open_readers: Some(2),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    // Going through the files twice forces evicted readers to be reopened.
+    for _ in 0..2 {
+        for index in 1..=5 {
+            assert_eq!(
+                store.get(format!("key{}", index))?,
+                Some(format!("value{}", index))
+            );
+        }
+    }
+
+    store.set("key6".to_owned(), "value6".to_owned())?;
+    store.compact()?;
+    for index in 1..=6 {
+        assert_eq!(
+            store.get(format!("key{}", index))?,
+            Some(format!("value{}", index))
+        );
+    }
+
+    Ok(())
+}
+
+// Only the requested slice of a value should be returned.
+#[test]
+fn get_value_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let value: String = (0..1000).map(|i| format!("{:04}", i)).collect();
+    store.set("key1".to_owned(), value.to_owned())?;
+
+    assert_eq!(
+        store.get_range("key1".to_owned(), 400, 420)?,
+        Some(value[400..420].to_owned())
+    );
+    assert_eq!(
+        store.get_range("key1".to_owned(), 3990, 5000)?,
+        Some(value[3990..].to_owned())
+    );
+    assert_eq!(store.get_range("key2".to_owned(), 0, 10)?, None);
+    assert!(store.get_range("key1".to_owned(), 20, 10).is_err());
+
+    store.set("key2".to_owned(), "ação".to_owned())?;
+    assert_eq!(
+        store.get_range("key2".to_owned(), 1, 3)?,
+        Some("ç".to_owned())
+    );
+    assert!(store.get_range("key2".to_owned(), 0, 2).is_err());
+
+    // Escaped values are still sliced as they were set.
+    store.set("key3".to_owned(), "a\"b\\c\nd".to_owned())?;
+    assert_eq!(
+        store.get_range("key3".to_owned(), 1, 5)?,
+        Some("\"b\\c".to_owned())
+    );
+
+    Ok(())
+}
+
+// Ranges of a large value should be read without reading the whole value.
+#[test]
+fn get_range_reads_only_the_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let value: String = (0..100_000).map(|i| format!("{:06}", i)).collect();
+    store.set("key1".to_owned(), value.to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    let bytes_read = store.stats().bytes_read;
+    assert_eq!(
+        store.get_range("key1".to_owned(), 300_000, 300_012)?,
+        Some(value[300_000..300_012].to_owned())
+    );
+    assert!(store.stats().bytes_read - bytes_read < 1024);
+
+    Ok(())
+}
+
+// Verifying should flag corrupt commands without touching the store.
+#[test]
+fn verify_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for index in 1..=3 {
+        store.set(format!("key{}", index), format!("value{}", index))?;
+    }
+
+    let report = store.verify()?;
+    assert!(report.is_healthy());
+    assert_eq!(report.ok, 3);
+
+    // Pointing the command of key2 to another key keeps the log parseable.
+    let log_path = temp_dir.path().join("1.log");
+    let contents = fs::read_to_string(&log_path)?;
+    fs::write(&log_path, contents.replace("key2", "kez2"))?;
+
+    let report = store.verify()?;
+    assert_eq!(report.ok, 2);
+    assert_eq!(report.corrupt_keys, vec!["key2".to_owned()]);
+    assert!(report.corrupt_files.is_empty());
+
+    // A damaged command breaks both its key and the log file.
+    let damaged = contents.replace(r#"{"Set":{"key":"key3""#, r#"{"Sex":{"key":"key3""#);
+    fs::write(&log_path, &damaged)?;
+
+    let report = store.verify()?;
+    assert_eq!(report.ok, 2);
+    assert_eq!(report.corrupt_keys, vec!["key3".to_owned()]);
+    assert_eq!(report.corrupt_files, vec![1]);
+    assert_eq!(fs::read_to_string(&log_path)?, damaged);
+
+    Ok(())
+}
+
+// The audit log should keep every mutation, even the ones compacted away.
+#[test]
+fn audit_log_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let audit_path = temp_dir.path().join("audit.jsonl");
+    let options = KvStoreOptions {
+        audit_log: Some(Box::new(fs::File::create(&audit_path)?)),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    store.set("key2".to_owned(), "value".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    let report = store.compact()?;
+    assert!(report.bytes_after < report.bytes_before);
+
+    let records: Vec<serde_json::Value> = fs::read_to_string(&audit_path)?
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("audit records should be JSON"))
+        .collect();
+    assert_eq!(records.len(), 102);
+    for (iter, record) in records.iter().take(100).enumerate() {
+        assert!(record["timestamp"].is_u64());
+        assert_eq!(record["command"]["Set"]["value"], format!("value{}", iter));
+    }
+    assert_eq!(records[101]["command"]["Remove"]["key"], "key2");
+
+    Ok(())
+}
+
+// A snapshot taken while a command was being appended should open read-only.
+#[test]
+fn open_read_only_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot_dir = TempDir::new().expect("unable to create temporary snapshot directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let snapshot_log = snapshot_dir.path().join("1.log");
+    let mut contents = fs::read(temp_dir.path().join("1.log"))?;
+    contents.extend_from_slice(br#"{"Set":{"key":"key3","val"#);
+    fs::write(&snapshot_log, &contents)?;
+
+    let mut snapshot = KvStore::open_read_only(snapshot_dir.path())?;
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(snapshot.get("key3".to_owned())?, None);
+
+    match snapshot.set("key4".to_owned(), "value4".to_owned()) {
+        Err(KvsError::ReadOnly) => (),
+        res => panic!("expected a read-only error, got {:?}", res),
+    }
+    assert!(snapshot.remove("key1".to_owned()).is_err());
+    assert!(snapshot.compact().is_err());
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // Neither the torn command nor anything else was written to the snapshot.
+    assert_eq!(fs::read(&snapshot_log)?, contents);
+    assert_eq!(fs::read_dir(snapshot_dir.path())?.count(), 1);
+
+    // The original store keeps going independently.
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(snapshot.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// Concurrent writers going through group commit should all see their writes persisted.
+#[test]
+fn group_commit_concurrent_writers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = GroupCommit::open(
+        temp_dir.path(),
+        KvStoreOptions::default(),
+        Duration::from_millis(1),
+    )?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|writer| {
+            let mut store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for write in 0..20 {
+                    store.set(format!("key{}_{}", writer, write), format!("{}", write))?;
+                }
+                store.remove(format!("key{}_0", writer))
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.key_count(), 8 * 19);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for writer in 0..8 {
+        assert_eq!(store.get(format!("key{}_0", writer))?, None);
+        for write in 1..20 {
+            assert_eq!(
+                store.get(format!("key{}_{}", writer, write))?,
+                Some(format!("{}", write))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// The position should track the end of the writer log file.
+#[test]
+fn current_position_follows_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    // Commands start right after the header of the log file.
+    let (index, header_len) = store.current_position();
+    assert_eq!(index, 1);
+    assert_eq!(
+        header_len,
+        fs::metadata(temp_dir.path().join("1.log"))?.len()
+    );
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let (index, pos) = store.current_position();
+    assert!(pos > header_len);
+    assert_eq!(
+        pos,
+        fs::metadata(temp_dir.path().join(format!("{}.log", index)))?.len()
+    );
+
+    store.rename("key1".to_owned(), "key2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let (index, next_pos) = store.current_position();
+    assert!(next_pos > pos);
+    assert_eq!(
+        next_pos,
+        fs::metadata(temp_dir.path().join(format!("{}.log", index)))?.len()
+    );
+
+    store.compact()?;
+    assert_eq!(store.current_position(), (3, header_len));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.current_position(), (3, header_len));
+
+    Ok(())
+}
+
+/// Commands as JSON objects, one per line.
+struct JsonLinesFormat;
+
+impl LogFormat for JsonLinesFormat {
+    fn name(&self) -> &str {
+        "json-lines"
+    }
+
+    fn write_command(&self, writer: &mut dyn Write, command: &Command) -> Result<()> {
+        serde_json::to_writer(&mut *writer, command)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn read_command(&self, reader: &mut dyn Read) -> Result<Command> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    fn stream<'a>(&self, reader: &'a mut dyn Read) -> CommandStream<'a> {
+        let mut reader = BufReader::new(reader);
+        let mut offset = 0;
+
+        Box::new(std::iter::from_fn(move || {
+            let mut line = String::new();
+            match reader.read_line(&mut line) {
+                Ok(0) => return None,
+                Ok(_) if !line.ends_with('\n') => {
+                    return Some(Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()))
+                }
+                Ok(len) => offset += len as u64,
+                Err(e) => return Some(Err(e.into())),
+            }
+
+            Some(
+                serde_json::from_str(&line)
+                    .map(|command| (command, offset))
+                    .map_err(KvsError::from),
+            )
+        }))
+    }
+}
+
+// A store should read back what it wrote with the format it was opened with.
+#[test]
+fn pluggable_log_format() -> Result<()> {
+    let open = |path: &std::path::Path| {
+        let options = KvStoreOptions {
+            format: Some(Box::new(JsonLinesFormat)),
+            ..KvStoreOptions::default()
+        };
+        KvStore::open_with_options(path, options)
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.rename("key2".to_owned(), "key3".to_owned())?;
+
+    // The header of the log file takes the first line.
+    let contents = fs::read_to_string(temp_dir.path().join("1.log"))?;
+    assert_eq!(contents.lines().count(), 5);
+
+    drop(store);
+    let mut store = open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value2".to_owned()));
+
+    store.compact()?;
+    drop(store);
+    let mut store = open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value2".to_owned()));
+    assert!(store.verify()?.is_healthy());
+
+    // Read-only stores read the format they are given, rejecting any other.
+    match KvStore::open_read_only(temp_dir.path()) {
+        Err(KvsError::IncompatibleLog(_)) => (),
+        res => panic!("expected an incompatible log error, got {:?}", res.err()),
+    }
+    let options = KvStoreOptions {
+        format: Some(Box::new(JsonLinesFormat)),
+        ..KvStoreOptions::default()
+    };
+    let mut snapshot = KvStore::open_read_only_with_options(temp_dir.path(), options)?;
+    assert_eq!(snapshot.get("key3".to_owned())?, Some("value2".to_owned()));
+
+    // The default format keeps concatenating JSON objects.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let contents = fs::read_to_string(temp_dir.path().join("1.log"))?;
+    let (header, commands) = contents.split_once('\n').unwrap();
+    assert!(header.starts_with("\0KVS"));
+    assert!(commands.starts_with(r#"{"Set":{"key":"key1","value":"value1","modified_at":"#));
+
+    Ok(())
+}
+
+// Logs written before expiry support should load with keys that never expire.
+#[test]
+fn expiry_free_logs_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}"#,
+    )?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_millis(1),
+    )?;
+    store.set_with_ttl(
+        "key4".to_owned(),
+        "value4".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    thread::sleep(Duration::from_millis(10));
+
+    let contents = fs::read_to_string(temp_dir.path().join("1.log"))?;
+    assert!(contents.contains(r#""key":"key3","value":"value3","expires_at":"#));
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+        assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+        assert_eq!(store.key_count(), 3);
+        Ok(())
+    };
+    check(&mut store)?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)?;
+    assert!(store.remove("key3".to_owned()).is_err());
+
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)?;
+
+    Ok(())
+}
+
+// Sustained overwrites should not grow the logs past the high-water mark.
+#[test]
+fn backpressure_bounds_disk_usage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_uncompacted_bytes: Some(4096),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let value = "v".repeat(100);
+
+    for iter in 0..2000 {
+        store.set(format!("key{}", iter % 4), value.to_owned())?;
+        assert!(store.uncompacted_bytes() <= 4096 + 200);
+    }
+
+    assert_eq!(store.get("key3".to_owned())?, Some(value.to_owned()));
+
+    drop(store);
+    let dir_size: u64 = fs::read_dir(temp_dir.path())?
+        .map(|entry| Ok(entry?.metadata()?.len()))
+        .sum::<Result<u64>>()?;
+    assert!(dir_size < 2 * 4096, "{} bytes on disk", dir_size);
+
+    // Removals are held back as well.
+    let options = KvStoreOptions {
+        max_uncompacted_bytes: Some(4096),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..200 {
+        store.set(format!("key{}", iter), value.to_owned())?;
+    }
+    for iter in 0..200 {
+        store.remove(format!("key{}", iter))?;
+        assert!(store.uncompacted_bytes() <= 4096 + 200);
+    }
+
+    Ok(())
+}
+
+// Repeated gets of a cached key should be served from memory.
+#[test]
+fn value_cache_skips_disk_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        value_cache_capacity: Some(2),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.disk_reads(), 1);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.disk_reads(), 1);
+
+    // Setting the key invalidates its cached value.
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.disk_reads(), 2);
+
+    // The least recently used key is evicted once the cache is full.
+    store.set("key2".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value1".to_owned())?;
+    store.get("key2".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.get("key3".to_owned())?;
+    assert_eq!(store.disk_reads(), 4);
+    store.get("key1".to_owned())?;
+    assert_eq!(store.disk_reads(), 4);
+    store.get("key2".to_owned())?;
+    assert_eq!(store.disk_reads(), 5);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.rename("key2".to_owned(), "key4".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key4".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Empty logs left by crashes right after opening should not pile up.
+#[test]
+fn open_reuses_trailing_empty_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+    )?;
+    fs::write(temp_dir.path().join("2.log"), "")?;
+    fs::write(temp_dir.path().join("3.log"), "")?;
+
+    let log_files = || -> Result<Vec<String>> {
+        let mut names: Vec<String> = log_entries(temp_dir.path())?
+            .iter()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        Ok(names)
+    };
+
+    for _ in 0..3 {
+        let mut store = KvStore::open(temp_dir.path())?;
+        let header_len = fs::metadata(temp_dir.path().join("3.log"))?.len();
+        assert_eq!(store.current_position(), (3, header_len));
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(log_files()?, vec!["1.log", "3.log"]);
+    }
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(fs::metadata(temp_dir.path().join("3.log"))?.len() > 0);
+
+    Ok(())
+}
+
+// Should only apply a conditional write expecting the current version of the key
+#[test]
+fn set_if_version_rejects_stale_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(
+        store.set_if_version("key1".to_owned(), "value1".to_owned(), 0)?,
+        1
+    );
+    assert_eq!(
+        store.set_versioned("key1".to_owned(), "value2".to_owned())?,
+        2
+    );
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        Some(("value2".to_owned(), 2))
+    );
+
+    match store.set_if_version("key1".to_owned(), "stale".to_owned(), 1) {
+        Err(KvsError::VersionMismatch(2)) => (),
+        other => panic!("expected a version mismatch, got {:?}", other),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    assert_eq!(
+        store.set_if_version("key1".to_owned(), "value3".to_owned(), 2)?,
+        3
+    );
+    drop(store);
+
+    // Versions are rebuilt from the log files
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        Some(("value3".to_owned(), 3))
+    );
+
+    Ok(())
+}
+
+// A version should never be handed out twice to a key, across removals, compactions
+// and reopens
+#[test]
+fn versions_survive_removal_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..3 {
+        store.set_versioned("key1".to_owned(), format!("value{}", iter))?;
+    }
+    store.compact()?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_versioned("key1".to_owned())?,
+        Some(("value2".to_owned(), 3))
+    );
+    store.remove("key1".to_owned())?;
+    assert_eq!(
+        store.set_if_version("key1".to_owned(), "value3".to_owned(), 0)?,
+        4
+    );
+    store.remove("key1".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    // Once compacted away, the removed key still starts past its old versions.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_versioned("key1".to_owned())?, None);
+    assert_eq!(
+        store.set_versioned("key1".to_owned(), "value4".to_owned())?,
+        5
+    );
+    match store.set_if_version("key1".to_owned(), "stale".to_owned(), 1) {
+        Err(KvsError::VersionMismatch(5)) => (),
+        other => panic!("expected a version mismatch, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+// Should reject a dry-run set exactly like the set itself, writing nothing
+#[test]
+fn try_set_validates_without_writing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_value_size: Some(8),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let position = store.current_position();
+
+    store.try_set("key1", "value1")?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.current_position(), position);
+
+    let oversized = "a".repeat(9);
+    match store.try_set("key1", &oversized) {
+        Err(KvsError::ValueTooLarge(8)) => (),
+        other => panic!("expected an oversized value error, got {:?}", other),
+    }
+    match store.set("key1".to_owned(), oversized) {
+        Err(KvsError::ValueTooLarge(8)) => (),
+        other => panic!("expected an oversized value error, got {:?}", other),
+    }
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.current_position(), position);
+
+    Ok(())
+}
+
+// Compaction should leave removing stale log files to the background, reads going on meanwhile
+#[test]
+fn compaction_removes_stale_logs_in_background() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for index in 1..=50 {
+        let command = format!(
+            r#"{{"Set":{{"key":"key{}","value":"value{}"}}}}"#,
+            index, index
+        );
+        fs::write(temp_dir.path().join(format!("{}.log", index)), command)?;
+    }
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let report = store.compact()?;
+    assert_eq!(report.files_removed, 50);
+    for index in 1..=50 {
+        assert_eq!(
+            store.get(format!("key{}", index))?,
+            Some(format!("value{}", index))
+        );
+    }
+
+    let log_count = || log_entries(temp_dir.path()).unwrap().len();
+    for _ in 0..50 {
+        if log_count() == 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(log_count(), 2);
+    assert!(temp_dir.path().join("51.log").exists());
+
+    Ok(())
+}
+
+// Should reuse the lowest indexes for the log files once compacted
+#[test]
+fn compaction_reuses_lowest_indexes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for index in 1..=5 {
+        let command = format!(
+            r#"{{"Set":{{"key":"key{}","value":"value{}"}}}}"#,
+            index, index
+        );
+        fs::write(temp_dir.path().join(format!("{}.log", index)), command)?;
+    }
+    let log_files = || -> Result<Vec<String>> {
+        let mut names: Vec<String> = log_entries(temp_dir.path())?
+            .iter()
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        Ok(names)
+    };
+
+    let options = KvStoreOptions {
+        index_policy: IndexPolicy::ReuseLowest,
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.compact()?;
+    assert_eq!(log_files()?, vec!["0.log", "1.log"]);
+    assert_eq!(store.current_position().0, 1);
+
+    // Commands written after the compaction keep superseding the compacted ones.
+    store.set("key1".to_owned(), "value6".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "value7".to_owned())?;
+    assert_eq!(log_files()?, vec!["0.log", "1.log"]);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value6".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value7".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// Should roll the writer over to new log files once they reach the maximum size
+#[test]
+fn writer_rolls_over_at_max_log_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_log_size: Some(1024),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    for iter in 0..100 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+    store.remove("key0".to_owned())?;
+
+    let sizes = log_entries(temp_dir.path())?
+        .iter()
+        .map(|entry| Ok(entry.metadata()?.len()))
+        .collect::<Result<Vec<u64>>>()?;
+    assert!(sizes.len() > 3, "{} log files", sizes.len());
+    assert!(sizes.iter().all(|size| *size <= 1024));
+    assert_eq!(store.current_position().0, sizes.len() as u64);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for iter in 1..100 {
+        assert_eq!(
+            store.get(format!("key{}", iter))?,
+            Some(format!("value{}", iter))
+        );
+    }
+
+    Ok(())
+}
+
+// A snapshot should keep reading the log files compaction made stale
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let snapshot = store.snapshot();
+    assert_eq!(snapshot.generation(), 0);
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.compact()?;
+    store.compact()?;
+    assert_eq!(store.generation(), 2);
+
+    assert!(temp_dir.path().join("1.log").exists());
+    assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // The stale log files go away along with the last snapshot reading them.
+    drop(snapshot);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    Ok(())
+}
+
+// Compactions reusing indexes should leave alone the log files a snapshot taken in
+// an older generation still reads
+#[test]
+fn reuse_lowest_skips_logs_of_older_snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        index_policy: IndexPolicy::ReuseLowest,
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let snapshot = store.snapshot();
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "value4".to_owned())?;
+    store.compact()?;
+    store.set("key3".to_owned(), "value5".to_owned())?;
+
+    assert_eq!(snapshot.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2")?, Some("value2".to_owned()));
+    drop(snapshot);
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// Keys should expire once an injected clock goes past their TTL, without sleeping
+#[test]
+fn ttl_follows_injected_clock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = MockClock::new(1_000_000);
+    let options = KvStoreOptions {
+        clock: Some(Arc::new(clock.clone())),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_secs(10),
+    )?;
+    clock.advance(Duration::from_secs(9));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    clock.advance(Duration::from_secs(2));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.key_count(), 0);
+
+    Ok(())
+}
+
+// Writes should fail while paused, leaving the log files untouched, and succeed once resumed
+#[test]
+fn paused_writes_fail_until_resumed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    store.pause_writes()?;
+    let position = store.current_position();
+    match store.set("key2".to_owned(), "value2".to_owned()) {
+        Err(KvsError::Paused) => (),
+        res => panic!("expected a paused error, got {:?}", res),
+    }
+    match store.remove("key1".to_owned()) {
+        Err(KvsError::Paused) => (),
+        res => panic!("expected a paused error, got {:?}", res),
+    }
+    assert!(store.compact().is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.current_position(), position);
+
+    store.resume_writes();
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Opening should report the replay of the log files as it goes, up to every byte of them
+#[test]
+fn open_reports_replay_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_log_size: Some(512 * 1024),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for iter in 0..300 {
+        store.set(format!("key{}", iter), "v".repeat(10 * 1024))?;
+    }
+    drop(store);
+
+    let sizes = log_entries(temp_dir.path())?
+        .iter()
+        .map(|entry| Ok(entry.metadata()?.len()))
+        .collect::<Result<Vec<u64>>>()?;
+    let total: u64 = sizes.iter().sum();
+
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&reports);
+    let options = KvStoreOptions {
+        replay_progress: Some(Box::new(move |replayed, total| {
+            recorded.lock().unwrap().push((replayed, total))
+        })),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.key_count(), 300);
+
+    let reports = reports.lock().unwrap();
+    assert!(reports.len() >= sizes.len(), "{} reports", reports.len());
+    assert!(reports.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert!(reports
+        .iter()
+        .all(|(_, reported_total)| *reported_total == total));
+    assert_eq!(reports.last(), Some(&(total, total)));
+    assert_eq!(store.get("key299".to_owned())?, Some("v".repeat(10 * 1024)));
+
+    Ok(())
+}
+
+// Integers should be incremented natively, and refuse to be read as strings
+#[test]
+fn increment_integer_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    assert_eq!(store.increment("counter".to_owned(), 5)?, 5);
+    assert_eq!(store.increment("counter".to_owned(), -2)?, 3);
+    store.set_int("big".to_owned(), i64::MAX)?;
+    match store.increment("big".to_owned(), 1) {
+        Err(KvsError::IntegerOverflow) => (),
+        res => panic!("expected an overflow error, got {:?}", res),
+    }
+
+    match store.get("counter".to_owned()) {
+        Err(KvsError::WrongType) => (),
+        res => panic!("expected a wrong type error, got {:?}", res),
+    }
+    store.set("name".to_owned(), "value1".to_owned())?;
+    match store.increment("name".to_owned(), 1) {
+        Err(KvsError::WrongType) => (),
+        res => panic!("expected a wrong type error, got {:?}", res),
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_int("counter".to_owned())?, Some(3));
+    assert_eq!(store.get_int("big".to_owned())?, Some(i64::MAX));
+    assert_eq!(store.get("name".to_owned())?, Some("value1".to_owned()));
+    assert!(store.verify()?.is_healthy());
+
+    Ok(())
+}
+
+// Gets of a corrupt key should fail by default, or act as if it was missing when skipping
+#[test]
+fn get_follows_corruption_policy() -> Result<()> {
+    for policy in [CorruptionPolicy::Error, CorruptionPolicy::Skip] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            corruption_policy: policy,
+            ..KvStoreOptions::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+
+        let log_path = temp_dir.path().join("1.log");
+        let contents = fs::read_to_string(&log_path)?;
+        let damaged = contents.replace(r#"{"Set":{"key":"key1""#, r#"{"Sex":{"key":"key1""#);
+        fs::write(&log_path, damaged)?;
+
+        match (policy, store.get("key1".to_owned())) {
+            (CorruptionPolicy::Error, Err(KvsError::Serde(_))) => (),
+            (CorruptionPolicy::Skip, Ok(None)) => (),
+            (policy, res) => panic!("unexpected {:?} with {:?}", res, policy),
+        }
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Log files should be refused when their header is from a newer version, while the
+// headerless ones of older versions still load
+#[test]
+fn open_checks_log_headers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+    )?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.compact()?;
+    drop(store);
+
+    // Compacted logs get a header, read back on open.
+    let compacted = fs::read(temp_dir.path().join("2.log"))?;
+    assert!(compacted.starts_with(b"\0KVS{\"version\":1,\"format\":\"json\""));
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    let future_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        future_dir.path().join("1.log"),
+        b"\0KVS{\"version\":2,\"format\":\"json\",\"compressed\":false}\n",
+    )?;
+    match KvStore::open(future_dir.path()) {
+        Err(KvsError::IncompatibleLog(message)) => assert!(message.contains("version 2")),
+        res => panic!(
+            "expected an incompatible log error, got {:?}",
+            res.map(|_| ())
+        ),
+    }
+
+    Ok(())
+}
+
+// Should count one flush per set along with the bytes written and read
+#[test]
+fn stats_count_io_operations() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats(), IoStats::default());
+    let (_, header_len) = store.current_position();
+
+    for iter in 0..10 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+    let stats = store.stats();
+    assert_eq!(stats.flushes, 10);
+    assert_eq!(stats.bytes_written, store.current_position().1 - header_len);
+    assert_eq!(stats.bytes_read, 0);
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let stats = store.stats();
+    assert!(stats.bytes_read > 0);
+    assert_eq!(stats.flushes, 10);
+
+    Ok(())
+}
+
+// A strict key policy should refuse keys with control characters or whitespace
+#[test]
+fn strict_key_policy_rejects_control_characters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        key_policy: KeyPolicy::Strict,
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    for key in ["key\n1", "key 1", "key\u{7}1"] {
+        match store.set(key.to_owned(), "value1".to_owned()) {
+            Err(KvsError::InvalidKey) => (),
+            res => panic!("expected an invalid key error for {:?}, got {:?}", key, res),
+        }
+    }
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.key_count(), 1);
+    drop(store);
+
+    // Lenient stores accept anything.
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key\n2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key\n2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Merging should copy every live key over, the policy settling the overlapping ones
+#[test]
+fn merge_stores() -> Result<()> {
+    for policy in [MergePolicy::KeepSelf, MergePolicy::Overwrite] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let other_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        let mut other = KvStore::open(other_dir.path())?;
+
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("shared".to_owned(), "mine".to_owned())?;
+        other.set("key2".to_owned(), "value2".to_owned())?;
+        other.set("shared".to_owned(), "theirs".to_owned())?;
+        other.set("removed".to_owned(), "value3".to_owned())?;
+        other.remove("removed".to_owned())?;
+        other.set_int("counter".to_owned(), 7)?;
+
+        store.merge_from(&mut other, policy)?;
+
+        let expected = match policy {
+            MergePolicy::KeepSelf => "mine",
+            MergePolicy::Overwrite => "theirs",
+        };
+        assert_eq!(store.get("shared".to_owned())?, Some(expected.to_owned()));
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("removed".to_owned())?, None);
+        assert_eq!(store.get_int("counter".to_owned())?, Some(7));
+        assert_eq!(store.key_count(), 4);
+        assert_eq!(other.get("shared".to_owned())?, Some("theirs".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Value lengths should come from the index, without reading the log files.
+#[test]
+fn value_len_skips_disk_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    // Escaped and multi-byte characters take more room in the log than in the value.
+    let values = ["value1", "quoted \"value\"\n", "valu\u{e9}"];
+    for (i, value) in values.iter().enumerate() {
+        store.set(format!("key{}", i), value.to_string())?;
+    }
+    store.rename("key2".to_owned(), "key3".to_owned())?;
+    store.set_int("int".to_owned(), 42)?;
+
+    for _ in 0..2 {
+        let disk_reads = store.disk_reads();
+        assert_eq!(store.value_len("key0".to_owned())?, Some(6));
+        assert_eq!(
+            store.value_len("key1".to_owned())?,
+            Some(values[1].len() as u64)
+        );
+        assert_eq!(
+            store.value_len("key3".to_owned())?,
+            Some(values[2].len() as u64)
+        );
+        assert_eq!(store.value_len("key2".to_owned())?, None);
+        assert_eq!(store.disk_reads(), disk_reads);
+
+        // Lengths are rebuilt along with the index when opening.
+        drop(store);
+        store = KvStore::open(temp_dir.path())?;
+    }
+
+    match store.value_len("int".to_owned()) {
+        Err(KvsError::WrongType) => (),
+        res => panic!("expected a wrong type error, got {:?}", res),
+    }
+
+    let disk_reads = store.disk_reads();
+    store.force_compact()?;
+    assert_eq!(
+        store.value_len("key1".to_owned())?,
+        Some(values[1].len() as u64)
+    );
+    assert_eq!(store.disk_reads(), disk_reads);
+
+    Ok(())
+}
+
+// A corrupt command should fail the open unless repairing, which truncates the
+// log file right before it.
+#[test]
+fn open_repairs_corrupt_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for index in 1..4 {
+        store.set(format!("key{}", index), format!("value{}", index))?;
+    }
+    drop(store);
+
+    let log_path = temp_dir.path().join("1.log");
+    let contents = fs::read_to_string(&log_path)?;
+    let damaged = contents.replace(r#"{"Set":{"key":"key2""#, r#"{"Sex":{"key":"key2""#);
+    fs::write(&log_path, &damaged)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Serde(_)) => (),
+        res => panic!("expected a serde error, got {:?}", res.map(|_| ())),
+    }
+    assert_eq!(fs::read_to_string(&log_path)?, damaged);
+
+    let options = KvStoreOptions {
+        repair: true,
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    drop(store);
+
+    // The repaired log opens as any other.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(store.key_count(), 2);
+
+    Ok(())
+}
+
+// Keys matching a predicate should be listed without reading any value.
+#[test]
+fn keys_matching_predicate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = MockClock::new(1_000);
+    let options = KvStoreOptions {
+        clock: Some(Arc::new(clock.clone())),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key in &["a", "user:1", "user:22", "admin:1", "user:333"] {
+        store.set(key.to_string(), "value".to_owned())?;
+    }
+    store.remove("user:22".to_owned())?;
+    store.set_with_ttl(
+        "user:4444".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(1),
+    )?;
+
+    let matching = store.keys_matching(|key| key.starts_with("user:"));
+    assert_eq!(matching, vec!["user:1", "user:333", "user:4444"]);
+    assert_eq!(
+        store.keys_matching(|key| key.len() > 7),
+        vec!["user:333", "user:4444"]
+    );
+    assert!(store.keys_matching(|_| false).is_empty());
+
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(store.keys_matching(|key| key.len() > 7), vec!["user:333"]);
+    assert_eq!(store.disk_reads(), 0);
+
+    Ok(())
+}
+
+// Removed keys and their tombstones should count as stale bytes, so a store of
+// removed keys eventually compacts them away.
+#[test]
+fn removed_keys_get_compacted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_path = temp_dir.path().join("1.log");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for index in 0..10 {
+        store.set(format!("key{}", index), "value".to_owned())?;
+        store.remove(format!("key{}", index))?;
+    }
+    assert_eq!(store.key_count(), 0);
+
+    // Every byte past the header is stale, both when written and once replayed.
+    let contents = fs::read(&log_path)?;
+    let header_len = contents.iter().position(|&byte| byte == b'\n').unwrap() + 1;
+    let stale_bytes = (contents.len() - header_len) as u64;
+    assert_eq!(store.uncompacted_bytes(), stale_bytes);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.uncompacted_bytes(), stale_bytes);
+
+    let value = "v".repeat(1024);
+    for index in 0..2000 {
+        store.set(format!("key{}", index), value.to_owned())?;
+        store.remove(format!("key{}", index))?;
+    }
+    drop(store);
+
+    let dir_size: u64 = fs::read_dir(temp_dir.path())?
+        .map(|entry| Ok(entry?.metadata()?.len()))
+        .sum::<Result<u64>>()?;
+    assert!(dir_size < 1024 * 1024, "{} bytes on disk", dir_size);
+
+    Ok(())
+}
+
+// Applying the log files of a store should rebuild the same keys in another one.
+#[test]
+fn apply_log_of_another_store() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut source = KvStore::open(source_dir.path())?;
+    for index in 0..20 {
+        source.set(format!("key{}", index), format!("value{}", index))?;
+    }
+    source.set("key1".to_owned(), "other".to_owned())?;
+    source.remove("key2".to_owned())?;
+    source.set_int("counter".to_owned(), 42)?;
+    drop(source);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "stays removed".to_owned())?;
+    let log = fs::File::open(source_dir.path().join("1.log"))?;
+    assert_eq!(store.apply_log(log)?, 23);
+
+    let mut source = KvStore::open(source_dir.path())?;
+    let keys = source.scan_keys(None, 100)?;
+    assert_eq!(store.scan_keys(None, 100)?, keys);
+    for key in keys.into_iter().filter(|key| key != "counter") {
+        assert_eq!(store.get(key.to_owned())?, source.get(key)?);
+    }
+    assert_eq!(store.get_int("counter".to_owned())?, Some(42));
+
+    // Applied commands are durable like any other.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("other".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// A key whose log file got deleted from under the store should fail with an explicit
+// error, or be dropped depending on the policy.
+#[test]
+fn get_follows_missing_log_policy() -> Result<()> {
+    for policy in &[MissingLogPolicy::Error, MissingLogPolicy::DropKey] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        // Every command gets a log file of its own, of which a single one stays open.
+        let options = KvStoreOptions {
+            max_log_size: Some(1),
+            max_open_readers: Some(1),
+            missing_log_policy: *policy,
+            ..KvStoreOptions::default()
+        };
+        let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        fs::remove_file(temp_dir.path().join("1.log"))?;
+        match (policy, store.get("key1".to_owned())) {
+            (MissingLogPolicy::Error, Err(KvsError::MissingLogFile { file_index: 1 })) => {
+                assert_eq!(store.key_count(), 2)
+            }
+            (MissingLogPolicy::DropKey, Ok(None)) => assert_eq!(store.key_count(), 1),
+            (policy, res) => panic!("unexpected result with {:?}: {:?}", policy, res),
+        }
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A read-only store should pick up the commands appended to its directory by
+// another process when refreshed.
+#[test]
+fn refresh_reads_appended_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut leader = KvStore::open(temp_dir.path())?;
+    leader.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut follower = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(follower.refresh()?, 0);
+    leader.set("key2".to_owned(), "value2".to_owned())?;
+    leader.remove("key1".to_owned())?;
+    assert_eq!(follower.get("key2".to_owned())?, None);
+    assert_eq!(follower.refresh()?, 2);
+    assert_eq!(follower.get("key1".to_owned())?, None);
+    assert_eq!(follower.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(leader);
+
+    // A log file copied in from another store, its last command still being written.
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut other = KvStore::open(other_dir.path())?;
+    other.set("key3".to_owned(), "value3".to_owned())?;
+    other.set("key4".to_owned(), "value4".to_owned())?;
+    drop(other);
+    let contents = fs::read(other_dir.path().join("1.log"))?;
+    let log_path = temp_dir.path().join("5.log");
+    fs::write(&log_path, &contents[..contents.len() - 5])?;
+
+    assert_eq!(follower.refresh()?, 1);
+    assert_eq!(follower.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(follower.get("key4".to_owned())?, None);
+
+    fs::write(&log_path, &contents)?;
+    assert_eq!(follower.refresh()?, 1);
+    assert_eq!(follower.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(follower.key_count(), 3);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    match store.refresh() {
+        Err(KvsError::MessageError(_)) => (),
+        res => panic!("expected a message error, got {:?}", res),
+    }
+
+    Ok(())
+}
+
+// Inserting should only set keys which aren't present yet.
+#[test]
+fn insert_refuses_existing_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = MockClock::new(1_000);
+    let options = KvStoreOptions {
+        clock: Some(Arc::new(clock.clone())),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    store.insert("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let position = store.current_position();
+    match store.insert("key1".to_owned(), "value2".to_owned()) {
+        Err(KvsError::KeyExists) => (),
+        res => panic!("expected a key exists error, got {:?}", res),
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.current_position(), position);
+
+    // Removed and expired keys can be inserted again.
+    store.remove("key1".to_owned())?;
+    store.insert("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    store.set_with_ttl(
+        "key2".to_owned(),
+        "value1".to_owned(),
+        Duration::from_secs(1),
+    )?;
+    clock.advance(Duration::from_secs(1));
+    store.insert("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Soft deleted keys should be restorable until their grace period elapses, even
+// across compactions and reopens, and gone for good afterwards.
+#[test]
+fn undelete_within_grace_period() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = MockClock::new(1_000);
+    let open = |clock: &MockClock| {
+        KvStore::open_with_options(
+            temp_dir.path(),
+            KvStoreOptions {
+                clock: Some(Arc::new(clock.clone())),
+                soft_delete_grace: Some(Duration::from_secs(60)),
+                ..KvStoreOptions::default()
+            },
+        )
+    };
+    let mut store = open(&clock)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_int("key2".to_owned(), 42)?;
+    store.remove("key1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.uncompacted_bytes(), 0);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.undelete("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    // A renamed key can be restored like a removed one.
+    store.rename("key1".to_owned(), "key3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    store.undelete("key1".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+    store.remove("key3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    store.remove("key1".to_owned())?;
+    store.compact()?;
+    drop(store);
+    let mut store = open(&clock)?;
+    // The restorable keys are carried over by compactions, nothing to reclaim.
+    assert_eq!(store.uncompacted_bytes(), 0);
+    clock.advance(Duration::from_secs(30));
+    store.undelete("key1".to_owned())?;
+    store.undelete("key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get_int("key2".to_owned())?, Some(42));
+
+    store.remove("key1".to_owned())?;
+    clock.advance(Duration::from_secs(60));
+    match store.undelete("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => (),
+        res => panic!("expected a key not found error, got {:?}", res),
+    }
+    store.compact()?;
+    drop(store);
+    let mut store = open(&clock)?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    match store.undelete("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => (),
+        res => panic!("expected a key not found error, got {:?}", res),
+    }
+    assert_eq!(store.get_int("key2".to_owned())?, Some(42));
+
+    Ok(())
+}
+
+// Both key indexes should serve point operations, only the ordered one scanning keys.
+#[test]
+fn key_index_kinds() -> Result<()> {
+    for key_index in [KeyIndexKind::Ordered, KeyIndexKind::Hashed] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            KvStore::open_with_options(
+                temp_dir.path(),
+                KvStoreOptions {
+                    key_index,
+                    ..KvStoreOptions::default()
+                },
+            )
+        };
+        let mut store = open()?;
+
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        store.remove("key3".to_owned())?;
+        store.compact()?;
+        drop(store);
+
+        let mut store = open()?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+        assert_eq!(store.key_count(), 2);
+        assert_eq!(
+            store.keys_matching(|_| true),
+            vec!["key1".to_owned(), "key2".to_owned()]
+        );
+
+        match (key_index, store.scan_keys(None, 10)) {
+            (KeyIndexKind::Ordered, Ok(keys)) => {
+                assert_eq!(keys, vec!["key1".to_owned(), "key2".to_owned()])
+            }
+            (KeyIndexKind::Hashed, Err(KvsError::UnorderedIndex)) => (),
+            (_, res) => panic!("unexpected scan with a {:?} index: {:?}", key_index, res),
+        }
+    }
+
+    Ok(())
+}
+
+// Swapped keys should hold each other's values, types included, after a reopen.
+#[test]
+fn swap_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_int("key2".to_owned(), 2)?;
+    store.swap("key1".to_owned(), "key2".to_owned())?;
+    assert_eq!(store.get_int("key1".to_owned())?, Some(2));
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+
+    let position = store.current_position();
+    match store.swap("key1".to_owned(), "key3".to_owned()) {
+        Err(KvsError::KeyNotFound) => (),
+        res => panic!("expected a key not found error, got {:?}", res),
+    }
+    assert_eq!(store.current_position(), position);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_int("key1".to_owned())?, Some(2));
+    assert_eq!(store.get("key2".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // A missing key can be swapped as well when the policy allows it.
+    let options = KvStoreOptions {
+        swap_policy: SwapPolicy::MoveMissing,
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.swap("key2".to_owned(), "key3".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.key_count(), 2);
+    drop(store);
+
+    // The key a swap leaves without a value is soft deleted like a removal.
+    let options = KvStoreOptions {
+        swap_policy: SwapPolicy::MoveMissing,
+        soft_delete_grace: Some(Duration::from_secs(60)),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.swap("key3".to_owned(), "key4".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, None);
+    store.undelete("key3".to_owned())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// The directory should be synced once compactions create and remove log files, only
+// when asked to.
+#[test]
+fn compaction_syncs_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.stats().dir_syncs, 0);
+    drop(store);
+
+    let options = KvStoreOptions {
+        sync_directory: true,
+        index_policy: IndexPolicy::ReuseLowest,
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let before = store.stats().dir_syncs;
+    assert!(before >= 1);
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    let report = store.compact()?;
+    assert!(report.files_removed > 0);
+    // Once the compacted log file is durable, then once the stale ones are removed.
+    assert_eq!(store.stats().dir_syncs, before + 2);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Value sizes should be read from the index, within the framing of the actual length.
+#[test]
+fn value_size_estimates() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    for len in [0, 10, 1000, 100_000] {
+        store.set("key1".to_owned(), "v".repeat(len))?;
+        let reads = store.disk_reads();
+        let size = store.value_size("key1").unwrap();
+        assert_eq!(store.disk_reads(), reads);
+        assert!(size >= len as u64);
+        assert!(
+            size - len as u64 <= 80,
+            "{} bytes for a value of {}",
+            size,
+            len
+        );
+    }
+    store.set_int("key2".to_owned(), 42)?;
+    assert!(store.value_size("key2").unwrap() <= 80);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.value_size("key1"), None);
+    assert_eq!(store.value_size("key3"), None);
+
+    Ok(())
+}
+
+// Bounded compaction steps should each copy a bounded amount of bytes and eventually
+// compact the whole store, writes made in between included.
+#[test]
+fn incremental_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let step = 4096;
+    let options = KvStoreOptions {
+        compaction_step: Some(step),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let value = |key_id: u32, iter: u32| format!("{}{}", iter, "v".repeat(100 + key_id as usize));
+    for iter in 0..3 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), value(key_id, iter))?;
+        }
+    }
+    let live_bytes = store.stats().bytes_written / 3;
+    let uncompacted = store.uncompacted_bytes();
+
+    let mut steps = 0;
+    let mut files_removed = 0;
+    loop {
+        let written = store.stats().bytes_written;
+        let report = store.compact()?;
+        assert!(store.stats().bytes_written - written <= step + 512);
+        files_removed += report.files_removed;
+        steps += 1;
+        if !store.is_compacting() {
+            break;
+        }
+        assert!(steps < 1000, "the compaction never completed");
+
+        // Writes keep landing meanwhile.
+        if steps == 2 {
+            store.set("key0".to_owned(), "rewritten".to_owned())?;
+            store.remove("key199".to_owned())?;
+        }
+    }
+    // Each step copied its share of the live commands.
+    assert!(steps as u64 >= live_bytes / (step + 512));
+    assert!(files_removed > 0);
+    assert!(store.uncompacted_bytes() < uncompacted);
+    drop(store);
+
+    // Only the live commands are left, once each.
+    let log_size: u64 = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(log_size < live_bytes + 1024);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("rewritten".to_owned()));
+    assert_eq!(store.get("key199".to_owned())?, None);
+    for key_id in 1..199 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value(key_id, 2)));
+    }
+
+    Ok(())
+}
+
+// A key overwritten during an incremental compaction should still be compactable
+// on its own once the compaction completed
+#[test]
+fn compact_key_after_incremental_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        compaction_step: Some(64),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    for iter in 0..5 {
+        store.set("zhot".to_owned(), format!("value{}", iter))?;
+    }
+
+    store.compact()?;
+    assert!(store.is_compacting());
+    store.set("zhot".to_owned(), "rewritten".to_owned())?;
+    while store.is_compacting() {
+        store.compact()?;
+    }
+
+    store.compact_key("zhot")?;
+    assert_eq!(store.get("zhot".to_owned())?, Some("rewritten".to_owned()));
+
+    Ok(())
+}
+
+// A store opened with a lazy index should take sets without replaying its log files,
+// the first get replaying them along with the new commands.
+#[test]
+fn lazy_index_open() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10_000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    drop(store);
+
+    let replayed = Arc::new(Mutex::new(0));
+    let progress = Arc::clone(&replayed);
+    let options = KvStoreOptions {
+        lazy_index: true,
+        replay_progress: Some(Box::new(move |bytes, _| *progress.lock().unwrap() = bytes)),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(*replayed.lock().unwrap(), 0);
+    assert_eq!(store.key_count(), 0);
+
+    store.set("key0".to_owned(), "updated".to_owned())?;
+    store.set("key10000".to_owned(), "value10000".to_owned())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.key_count(), 10_001);
+    store.remove("key1".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("updated".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(
+        store.get("key10000".to_owned())?,
+        Some("value10000".to_owned())
+    );
+
+    Ok(())
+}
+
+// Removing the writer log file from under the store should fail the next write with
+// an explicit error, or be recovered from when asked to.
+#[cfg(unix)]
+#[test]
+fn writer_removed_from_under_the_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let writer_path = |store: &KvStore| {
+        let (file_index, _) = store.current_position();
+        temp_dir.path().join(format!("{}.log", file_index))
+    };
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    fs::remove_file(writer_path(&store))?;
+    match store.set("key2".to_owned(), "value2".to_owned()) {
+        Err(KvsError::WriterLost) => (),
+        res => panic!("expected a writer lost error, got {:?}", res),
+    }
+    drop(store);
+
+    let options = KvStoreOptions {
+        recreate_lost_writer: true,
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    fs::remove_file(writer_path(&store))?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert!(writer_path(&store).exists());
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(store);
+
+    // The compaction recreating it reuses the name of the removed log file.
+    let options = || KvStoreOptions {
+        recreate_lost_writer: true,
+        index_policy: IndexPolicy::ReuseLowest,
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    fs::remove_file(writer_path(&store))?;
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    assert!(writer_path(&store).exists());
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, Some("value5".to_owned()));
+
+    Ok(())
+}
+
+// Patching a small region of a large value should only append the edits, while reads
+// see the patched value, across reopens and compactions.
+#[test]
+fn patch_large_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut expected = "a".repeat(64 * 1024);
+    store.set("key1".to_owned(), expected.to_owned())?;
+
+    let (_, before) = store.current_position();
+    store.patch(
+        "key1".to_owned(),
+        Patch::new()
+            .replace(100..103, "xyz")
+            .replace(200..200, "inserted"),
+    )?;
+    let (_, after) = store.current_position();
+    assert!(after - before < 200, "patch took {} bytes", after - before);
+    expected.replace_range(100..103, "xyz");
+    expected.replace_range(200..200, "inserted");
+    assert_eq!(store.get("key1".to_owned())?, Some(expected.to_owned()));
+
+    store.patch("key1".to_owned(), Patch::new().replace(0..1, ""))?;
+    expected.replace_range(0..1, "");
+    match store.patch("key1".to_owned(), Patch::new().replace(0..1_000_000, "")) {
+        Err(KvsError::InvalidRange) => (),
+        res => panic!("expected an invalid range error, got {:?}", res),
+    }
+    match store.patch("key2".to_owned(), Patch::new()) {
+        Err(KvsError::KeyNotFound) => (),
+        res => panic!("expected a key not found error, got {:?}", res),
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(expected.to_owned()));
+    store.compact()?;
+    assert_eq!(store.get("key1".to_owned())?, Some(expected.to_owned()));
+    drop(store);
+
+    // A patch written while a compaction is in progress is replayed after its output.
+    let options = KvStoreOptions {
+        compaction_step: Some(1),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    store.patch("key1".to_owned(), Patch::new().replace(0..0, "b"))?;
+    store.compact()?;
+    assert!(store.is_compacting());
+    store.patch("key1".to_owned(), Patch::new().replace(0..0, "c"))?;
+    while store.is_compacting() {
+        store.compact()?;
+    }
+    expected.insert_str(0, "cb");
+    assert_eq!(store.get("key1".to_owned())?, Some(expected.to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some(expected));
+
+    Ok(())
+}
+
+// Exporting should write one JSON object per live key, in key order, which can be
+// imported back into another store.
+#[test]
+fn export_jsonl_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value \"2\"\nwith a newline".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_int("key3".to_owned(), 42)?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+
+    let mut output = Vec::new();
+    assert_eq!(store.export_jsonl(&mut output)?, 3);
+    let lines: Vec<serde_json::Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+        .collect();
+    let keys: Vec<&str> = lines
+        .iter()
+        .map(|line| line["key"].as_str().unwrap())
+        .collect();
+    assert_eq!(keys, vec!["key1", "key2", "key3"]);
+
+    let import_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut imported = KvStore::open(import_dir.path())?;
+    for line in lines {
+        let key = line["key"].as_str().unwrap().to_owned();
+        match &line["value"] {
+            serde_json::Value::String(value) => imported.set(key, value.to_owned())?,
+            serde_json::Value::Number(value) => imported.set_int(key, value.as_i64().unwrap())?,
+            value => panic!("unexpected exported value {}", value),
+        }
+    }
+    assert_eq!(
+        imported.get("key2".to_owned())?,
+        Some("value \"2\"\nwith a newline".to_owned())
+    );
+    assert_eq!(imported.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(imported.get_int("key3".to_owned())?, Some(42));
+    assert_eq!(imported.get("removed".to_owned())?, None);
+
+    Ok(())
+}
+
+// Last modified times should follow the writes of each key and survive reopening the
+// store.
+#[test]
+fn modified_at_persisted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let clock = MockClock::new(1_000_000);
+    let options = || KvStoreOptions {
+        clock: Some(Arc::new(clock.clone())),
+        ..KvStoreOptions::default()
+    };
+    let at = |millis| Some(UNIX_EPOCH + Duration::from_millis(millis));
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_int("key2".to_owned(), 1)?;
+    clock.advance(Duration::from_secs(1));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    clock.advance(Duration::from_secs(1));
+    store.patch("key3".to_owned(), Patch::new().replace(0..0, "new "))?;
+    assert_eq!(store.modified_at("key1"), at(1_000_000));
+    assert_eq!(store.modified_at("key2"), at(1_000_000));
+    assert_eq!(store.modified_at("key3"), at(1_002_000));
+    assert_eq!(store.modified_at("key4"), None);
+    drop(store);
+
+    clock.advance(Duration::from_secs(60));
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.modified_at("key1"), at(1_000_000));
+    assert_eq!(store.modified_at("key3"), at(1_002_000));
+    store.compact()?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.modified_at("key2"), at(1_000_000));
+    assert_eq!(store.modified_at("key3"), at(1_002_000));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.modified_at("key1"), at(1_062_000));
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.modified_at("key1"), None);
+
+    Ok(())
+}
+
+// The largest values should be listed from the index, largest first.
+#[test]
+fn largest_values_ordering() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("small".to_owned(), "v".repeat(10))?;
+    store.set("large".to_owned(), "v".repeat(1000))?;
+    store.set("medium".to_owned(), "v".repeat(100))?;
+    store.set("removed".to_owned(), "v".repeat(10_000))?;
+    store.remove("removed".to_owned())?;
+    // Overwritten values count with their latest size.
+    store.set("shrunk".to_owned(), "v".repeat(5000))?;
+    store.set("shrunk".to_owned(), "v".repeat(50))?;
+
+    let largest = store.largest_values(3);
+    let keys: Vec<&str> = largest.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["large", "medium", "shrunk"]);
+    assert!(largest[0].1 >= 1000 && largest[0].1 < 1100);
+    assert_eq!(largest[0].1, store.value_size("large").unwrap());
+    assert_eq!(store.largest_values(10).len(), 4);
+    assert!(store.largest_values(0).is_empty());
+
+    Ok(())
+}
+
+// Coalesced sets should be served before reaching the log file and survive a reopen
+#[test]
+fn coalesce_small_sets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        coalesce_bytes: Some(4096),
+        coalesce_window: Some(Duration::from_secs(3600)),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    let (file_index, header_len) = store.current_position();
+    let log_path = temp_dir.path().join(format!("{}.log", file_index));
+
+    for iter in 0..20 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+    assert_eq!(fs::metadata(&log_path)?.len(), header_len);
+    assert_eq!(store.stats().flushes, 0);
+    let snapshot = store.snapshot();
+    assert_eq!(snapshot.get("key3")?, Some("value3".to_owned()));
+
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.stats().flushes, 1);
+    assert_eq!(fs::metadata(&log_path)?.len(), store.current_position().1);
+
+    for iter in 0..200 {
+        store.set(format!("key{}", iter), format!("other{}", iter))?;
+    }
+    assert!(store.stats().flushes < 10);
+    store.flush()?;
+    drop(store);
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    assert_eq!(store.key_count(), 200);
+    assert_eq!(store.get("key199".to_owned())?, Some("other199".to_owned()));
+
+    Ok(())
+}
+
+// Sets should stay buffered past the 8 KiB a writer buffers by default, and every
+// write of the buffer should be counted
+#[test]
+fn coalesce_past_default_buffer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        coalesce_bytes: Some(64 * 1024),
+        coalesce_window: Some(Duration::from_secs(3600)),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let (file_index, header_len) = store.current_position();
+    let log_path = temp_dir.path().join(format!("{}.log", file_index));
+
+    let value = "v".repeat(300);
+    for iter in 0..100 {
+        store.set(format!("key{}", iter), value.to_owned())?;
+    }
+    assert!(store.current_position().1 - header_len > 30_000);
+    assert_eq!(fs::metadata(&log_path)?.len(), header_len);
+    assert_eq!(store.stats().flushes, 0);
+
+    // Filling the buffer up writes it out before buffering the next set.
+    for iter in 100..300 {
+        store.set(format!("key{}", iter), value.to_owned())?;
+    }
+    let flushes = store.stats().flushes;
+    assert!(flushes >= 1);
+    assert!(fs::metadata(&log_path)?.len() > header_len);
+
+    // A set larger than the buffer is written right away, after the buffered ones.
+    store.set("large", "v".repeat(100 * 1024))?;
+    assert!(store.stats().flushes > flushes);
+    assert_eq!(fs::metadata(&log_path)?.len(), store.current_position().1);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.key_count(), 301);
+    assert_eq!(store.get("key299")?, Some(value));
+
+    Ok(())
+}
+
+// Buffered sets should be written once their window elapsed by any operation, and
+// coalescing should be refused along with synced writes
+#[test]
+fn coalesce_window_checked_by_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        coalesce_bytes: Some(4096),
+        coalesce_window: Some(Duration::from_millis(20)),
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    let (file_index, header_len) = store.current_position();
+    let log_path = temp_dir.path().join(format!("{}.log", file_index));
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(fs::metadata(&log_path)?.len(), header_len);
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(fs::metadata(&log_path)?.len(), store.current_position().1);
+    drop(store);
+
+    let options = KvStoreOptions {
+        coalesce_bytes: Some(4096),
+        sync_writes: true,
+        ..KvStoreOptions::default()
+    };
+    match KvStore::open_with_options(temp_dir.path(), options) {
+        Err(KvsError::MessageError(_)) => (),
+        res => panic!("expected a message error, got {:?}", res.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// Auditing the index should report the keys whose offset holds another key's set
+#[test]
+fn audit_index_reports_drift() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for index in 1..=3 {
+        store.set(format!("key{}", index), format!("value{}", index))?;
+    }
+    store.set_int("count".to_owned(), 7)?;
+    assert_eq!(store.audit_index()?, vec![]);
+
+    // Swapping the names of two keys of the same length moves their sets around
+    // from under the index.
+    let log_path = temp_dir.path().join("1.log");
+    let contents = fs::read_to_string(&log_path)?;
+    let position = contents.find(r#"{"Set":{"key":"key1""#).unwrap() as u64;
+    let swapped = contents
+        .replace("key1", "tmp")
+        .replace("key2", "key1")
+        .replace("tmp", "key2");
+    fs::write(&log_path, swapped)?;
+
+    let discrepancies = store.audit_index()?;
+    assert_eq!(discrepancies.len(), 2);
+    assert_eq!(
+        discrepancies[0],
+        IndexDiscrepancy {
+            key: "key1".to_owned(),
+            file_index: 1,
+            position,
+            found: Some("key2".to_owned()),
+        }
+    );
+    assert_eq!(discrepancies[1].key, "key2");
+    assert_eq!(discrepancies[1].found, Some("key1".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.audit_index()?, vec![]);
+
+    Ok(())
+}
+
+// A verified compaction should fail on a corrupt live command, naming its key
+#[test]
+fn verified_compaction_rejects_corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        verify_compaction: true,
+        ..KvStoreOptions::default()
+    };
+    let mut store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for index in 1..=3 {
+        store.set(format!("key{}", index), format!("value{}", index))?;
+    }
+    store.compact()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // The compaction output sits right before the writer.
+    let (file_index, _) = store.current_position();
+    let corrupt_path = temp_dir.path().join(format!("{}.log", file_index - 1));
+    let contents = fs::read_to_string(&corrupt_path)?;
+    fs::write(&corrupt_path, contents.replace("key2", "kez2"))?;
+
+    match store.compact() {
+        Err(KvsError::CorruptRecord(key)) => assert_eq!(key, "key2"),
+        res => panic!("expected a corrupt record error, got {:?}", res),
+    }
+    // Nothing got compacted, the other keys are still served.
+    assert_eq!(store.current_position().0, file_index);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+
+    // Automatic compactions leave the operations triggering them alone.
+    let value = "v".repeat(100 * 1024);
+    for _ in 0..12 {
+        store.set("key5".to_owned(), value.to_owned())?;
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.current_position().0, file_index);
+
+    // Once the corrupt key is overwritten, compactions go through again.
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key5".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// A store manager should close the least recently used tenant once full, flushing it
+#[test]
+fn store_manager_evicts_least_recent_tenant() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Sets stay buffered until flushed, so only a clean close gets them to disk.
+    let mut manager = StoreManager::new(temp_dir.path(), 2).with_options(|| KvStoreOptions {
+        coalesce_bytes: Some(1 << 20),
+        coalesce_window: Some(Duration::from_secs(3600)),
+        ..KvStoreOptions::default()
+    });
+
+    for tenant in &["acme", "initech"] {
+        let store = manager.get_store(tenant)?;
+        store.set("owner".to_owned(), tenant.to_string())?;
+    }
+    // Using acme leaves initech as the least recently used tenant.
+    assert_eq!(
+        manager.get_store("acme")?.get("owner".to_owned())?,
+        Some("acme".to_owned())
+    );
+    manager
+        .get_store("umbrella")?
+        .set("owner".to_owned(), "umbrella".to_owned())?;
+    assert_eq!(manager.open_count(), 2);
+    assert!(!manager.is_open("initech"));
+    assert!(manager.is_open("acme") && manager.is_open("umbrella"));
+
+    let mut closed = KvStore::open(temp_dir.path().join("initech"))?;
+    assert_eq!(closed.get("owner".to_owned())?, Some("initech".to_owned()));
+    assert_eq!(closed.key_count(), 1);
+    drop(closed);
+
+    assert_eq!(
+        manager.get_store("initech")?.get("owner".to_owned())?,
+        Some("initech".to_owned())
+    );
+    assert!(!manager.is_open("acme"));
+    assert_eq!(
+        manager.get_store("acme")?.get("owner".to_owned())?,
+        Some("acme".to_owned())
+    );
+
+    match manager.get_store("../escape") {
+        Err(KvsError::MessageError(_)) => (),
+        res => panic!("expected a message error, got {:?}", res.map(|_| ())),
+    }
+    manager.close_all()?;
+    assert_eq!(manager.open_count(), 0);
+
+    Ok(())
+}
+
+// Keys should be usable as string slices, without an owned String
+#[test]
+fn borrowed_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let key: &str = "foo";
+
+    store.set(key, "bar")?;
+    assert_eq!(store.get(key)?, Some("bar".to_owned()));
+    assert_eq!(store.get("missing")?, None);
+    store.remove(key)?;
+    assert_eq!(store.get(key)?, None);
+    match store.remove(key) {
+        Err(KvsError::KeyNotFound) => (),
+        res => panic!("expected a key not found error, got {:?}", res),
+    }
+
+    // Owned strings keep working, through the engine trait as well.
+    store.set("foo".to_owned(), "baz".to_owned())?;
+    let key = "foo".to_owned();
+    assert_eq!(store.get(&key)?, Some("baz".to_owned()));
+    let engine: &mut dyn KvsEngine = &mut store;
+    assert_eq!(engine.get("foo".to_owned())?, Some("baz".to_owned()));
+
+    Ok(())
+}
+
+// Gets of missing keys should be answered by the bloom filters, without a file read
+#[test]
+fn bloom_filters_answer_missing_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+    assert_eq!(store.get("missing")?, None);
+    assert_eq!(store.bloom_rejections(), 1);
+    assert_eq!(store.disk_reads(), 0);
+    assert_eq!(store.stats().bytes_read, 0);
+    drop(store);
+
+    // The filters get built again while replaying the log files.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("missing")?, None);
+    assert_eq!(store.bloom_rejections(), 1);
+    assert_eq!(store.stats().bytes_read, 0);
+    assert_eq!(store.get("key42")?, Some("value42".to_owned()));
+
+    store.compact()?;
+    for iter in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", iter))?,
+            Some(format!("value{}", iter))
+        );
+    }
+    assert_eq!(store.get("missing")?, None);
+    assert_eq!(store.bloom_rejections(), 2);
+
+    Ok(())
+}
+
+// Values streamed from a reader should read back the same, even across reopens
+#[test]
+fn set_stream_large_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value_dir = TempDir::new().expect("unable to create temporary working directory");
+    // Multi-byte characters straddle the chunks read, quotes and newlines get escaped.
+    let value = "ünïcødé \"quoted\"\n".repeat(50_000);
+    let value_path = value_dir.path().join("value.txt");
+    fs::write(&value_path, &value)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_stream("large".to_owned(), fs::File::open(&value_path)?)?;
+    assert_eq!(store.get("large")?.as_ref(), Some(&value));
+
+    // Superseding the value is enough uncompacted bytes for a compaction.
+    store.set_stream("large".to_owned(), fs::File::open(&value_path)?)?;
+    assert_eq!(store.uncompacted_bytes(), 0);
+    assert_eq!(store.get("large")?.as_ref(), Some(&value));
+
+    // A value which isn't UTF-8 leaves the log file untouched.
+    let invalid: &[u8] = b"valid until \xff";
+    match store.set_stream("invalid".to_owned(), invalid) {
+        Err(KvsError::Io(ref e)) if e.kind() == io::ErrorKind::InvalidData => (),
+        res => panic!("expected an invalid data error, got {:?}", res),
+    }
+    store.set("small", "value")?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("large")?, Some(value));
+    assert_eq!(store.get("invalid")?, None);
+    assert_eq!(store.get("small")?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should compact once there are too many log files, with nothing stale to reclaim
+#[test]
+fn compacts_past_max_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_count = |dir: &TempDir| -> Result<usize> { Ok(log_entries(dir.path())?.len()) };
+    let options = || KvStoreOptions {
+        max_log_size: Some(256),
+        ..KvStoreOptions::default()
+    };
+
+    let mut store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for iter in 0..100 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+    drop(store);
+    let unbounded = log_count(&temp_dir)?;
+    assert!(unbounded > 10, "{} log files", unbounded);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_options(
+        temp_dir.path(),
+        KvStoreOptions {
+            max_log_files: Some(4),
+            ..options()
+        },
+    )?;
+    for iter in 0..100 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+    // Removing stale log files is left to a thread, which dropping the store waits for.
+    drop(store);
+    let bounded = log_count(&temp_dir)?;
+    assert!(bounded <= 4, "{} log files", bounded);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", iter))?,
+            Some(format!("value{}", iter))
+        );
+    }
+
+    Ok(())
+}
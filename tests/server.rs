@@ -0,0 +1,856 @@
+use kvs::test_util::{EngineCall, FlakyEngine, MemoryKvsEngine, RecordingEngine};
+use kvs::{
+    Health, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, Response, Result, ServerConfig,
+};
+use serde_json::{json, Deserializer, Value};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn spawn_server(temp_dir: &TempDir, addr: SocketAddr) {
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    thread::spawn(move || KvsServer::new(store).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+}
+
+// Requests carrying unknown fields should be rejected with an explicit error.
+#[test]
+fn server_rejects_unknown_fields() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4010".parse().unwrap();
+    spawn_server(&temp_dir, addr);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut responses = Deserializer::from_reader(stream.try_clone().unwrap()).into_iter::<Value>();
+
+    let request = json!({ "Set": { "key": "key1", "value": "value1", "ttl": 10 } });
+    serde_json::to_writer(&mut stream, &request).unwrap();
+    stream.flush().unwrap();
+
+    let response = responses.next().unwrap().unwrap();
+    let message = response["Err"]
+        .as_str()
+        .expect("expected an error response");
+    assert!(message.contains("unknown field `ttl`"));
+
+    // The connection is still usable after a rejected request.
+    let request = json!({ "Get": { "key": "key1" } });
+    serde_json::to_writer(&mut stream, &request).unwrap();
+    stream.flush().unwrap();
+
+    let response = responses.next().unwrap().unwrap();
+    assert_eq!(response, json!({ "Ok": null }));
+}
+
+fn write_frame(stream: &mut TcpStream, payload: &[u8]) {
+    stream
+        .write_all(&(payload.len() as u32).to_be_bytes())
+        .unwrap();
+    stream.write_all(payload).unwrap();
+    stream.flush().unwrap();
+}
+
+fn read_frame(stream: &mut TcpStream) -> Value {
+    let mut length = [0u8; 4];
+    stream.read_exact(&mut length).unwrap();
+    let mut payload = vec![0u8; u32::from_be_bytes(length) as usize];
+    stream.read_exact(&mut payload).unwrap();
+    serde_json::from_slice(&payload).unwrap()
+}
+
+// Once negotiated, a corrupt frame should be rejected without affecting the next ones.
+#[test]
+fn server_skips_corrupt_frames() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4011".parse().unwrap();
+    spawn_server(&temp_dir, addr);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let handshake = json!({ "Handshake": { "version": 2 } });
+    serde_json::to_writer(&mut stream, &handshake).unwrap();
+    stream.flush().unwrap();
+
+    let mut response = [0u8; 8];
+    stream.read_exact(&mut response).unwrap();
+    assert_eq!(&response, br#"{"Ok":2}"#);
+
+    write_frame(&mut stream, br#"{"Set":{"key":"k"#);
+    let response = read_frame(&mut stream);
+    assert!(response["Err"]
+        .as_str()
+        .unwrap()
+        .contains("Invalid request"));
+
+    write_frame(&mut stream, br#"{"Set":{"key":"key1","value":"value1"}}"#);
+    assert_eq!(read_frame(&mut stream), json!({ "Ok": null }));
+
+    write_frame(&mut stream, br#"{"Get":{"key":"key1"}}"#);
+    assert_eq!(read_frame(&mut stream), json!({ "Ok": "value1" }));
+}
+
+// The same key should hold independent values in different namespaces.
+#[test]
+fn server_isolates_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4012".parse().unwrap();
+    spawn_server(&temp_dir, addr);
+
+    // The server handles one connection at a time, so clients are dropped in between.
+    KvsClient::connect(addr)?.set("key1".to_owned(), "value0".to_owned())?;
+    KvsClient::connect(addr)?
+        .with_namespace("first")
+        .set("key1".to_owned(), "value1".to_owned())?;
+    KvsClient::connect(addr)?
+        .with_namespace("second")
+        .set("key1".to_owned(), "value2".to_owned())?;
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value0".to_owned()));
+    drop(client);
+
+    let mut client = KvsClient::connect(addr)?.with_namespace("first");
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    drop(client);
+
+    let mut client = KvsClient::connect(addr)?.with_namespace("second");
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// A remote compaction should reclaim the space taken by stale commands.
+#[test]
+fn server_compacts_on_demand() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4013".parse().unwrap();
+    spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    for iter in 0..1000 {
+        client.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+
+    let report = client.compact()?;
+    assert!(report.files_removed >= 1);
+    assert!(report.bytes_reclaimed() > 30_000);
+    assert_eq!(client.get("key1".to_owned())?, Some("value999".to_owned()));
+
+    // Stale log files are removed in the background, the lock file staying.
+    for _ in 0..50 {
+        if fs::read_dir(temp_dir.path())?.count() == 3 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), 3);
+
+    Ok(())
+}
+
+// A range request should only send back the requested slice of the value.
+#[test]
+fn server_gets_value_ranges() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4014".parse().unwrap();
+    spawn_server(&temp_dir, addr);
+
+    let value = "abcdefghijklmnopqrstuvwxyz".repeat(100);
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), value.to_owned())?;
+
+    assert_eq!(
+        client.get_range("key1".to_owned(), 1300, 1310)?,
+        Some(value[1300..1310].to_owned())
+    );
+    assert_eq!(client.get_range("key2".to_owned(), 0, 10)?, None);
+    assert!(client.get_range("key1".to_owned(), 10, 0).is_err());
+
+    Ok(())
+}
+
+// Info should report the crate version along with the state of the engine.
+#[test]
+fn server_reports_info() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4015".parse().unwrap();
+    spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    client.set("key2".to_owned(), "value1".to_owned())?;
+    drop(client);
+
+    let info = KvsClient::connect(addr)?.info()?;
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.key_count, 2);
+    assert!(info.uncompacted_bytes > 0);
+    assert_eq!(info.compaction_threshold, Some(1024 * 1024));
+    assert_eq!(info.connections, 2);
+
+    Ok(())
+}
+
+// The server should forward each request to the engine it wraps, whatever it is.
+#[test]
+fn server_forwards_calls_to_engine() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4016".parse().unwrap();
+    let engine = RecordingEngine::new();
+    let boxed: Box<dyn KvsEngine + Send> = Box::new(engine.clone());
+    thread::spawn(move || KvsServer::new(boxed).run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?.with_namespace("ns");
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+
+    assert_eq!(
+        engine.calls(),
+        vec![
+            EngineCall::Set {
+                key: "2:ns:key1".to_owned(),
+                value: "value1".to_owned()
+            },
+            EngineCall::Get {
+                key: "2:ns:key1".to_owned()
+            },
+            EngineCall::Get {
+                key: "2:ns:key2".to_owned()
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+// A shut down server should stop accepting connections and flush its engine, even
+// with a client still connected.
+#[test]
+fn server_flushes_engine_on_shutdown() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4018".parse().unwrap();
+    let engine = RecordingEngine::new();
+    let server = KvsServer::new(engine.clone());
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    // The client staying connected, idle, doesn't hold the shutdown up.
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    drop(client);
+
+    assert_eq!(engine.calls().last(), Some(&EngineCall::Flush));
+    assert!(TcpStream::connect(addr).is_err());
+
+    Ok(())
+}
+
+// Responses to requests sent without waiting should come back in order.
+#[test]
+fn server_answers_queued_requests_in_order() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4017".parse().unwrap();
+    spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    let set = client.send_set("key1".to_owned(), "value1".to_owned())?;
+    let get = client.send_get("key1".to_owned())?;
+    let remove = client.send_remove("key1".to_owned())?;
+
+    // Blocking requests can't interleave with pending responses.
+    assert!(client.get("key1".to_owned()).is_err());
+
+    assert_eq!(client.recv()?, Response::Set(set));
+    assert_eq!(
+        client.recv()?,
+        Response::Get(get, Some("value1".to_owned()))
+    );
+    assert_eq!(client.recv()?, Response::Remove(remove));
+    assert!(client.recv().is_err());
+
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Scanning keys in pages should list each of them exactly once, in order.
+#[test]
+fn server_scans_keys_in_pages() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4019".parse().unwrap();
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{:04}", i), format!("value{}", i))?;
+    }
+    store.set("2:ns".to_owned(), "value0".to_owned())?;
+    store.set("2:ns:key1".to_owned(), "value1".to_owned())?;
+    store.set("2:nt:key2".to_owned(), "value2".to_owned())?;
+    thread::spawn(move || KvsServer::new(store).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    let keys = client
+        .scan_keys(100)
+        .filter(|key| !key.as_ref().unwrap().contains(':'))
+        .collect::<Result<Vec<_>>>()?;
+    let expected: Vec<String> = (0..1000).map(|i| format!("key{:04}", i)).collect();
+    assert_eq!(keys, expected);
+
+    let page = client.scan_keys_page(Some("key0099".to_owned()), 2)?;
+    assert_eq!(page, vec!["key0100".to_owned(), "key0101".to_owned()]);
+    drop(client);
+
+    // Keys outside of the namespace are left out, even when sorting right next to it.
+    let mut client = KvsClient::connect(addr)?.with_namespace("ns");
+    let keys = client.scan_keys(1).collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, vec!["key1".to_owned()]);
+
+    Ok(())
+}
+
+// Info should report the server as degraded while compaction lags way behind.
+#[test]
+fn server_reports_degraded_health() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr: SocketAddr = "127.0.0.1:4020".parse().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::new(store).with_degraded_threshold(1000);
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.info()?.health, Health::Healthy);
+
+    for iter in 0..100 {
+        client.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    let info = client.info()?;
+    assert!(info.uncompacted_bytes > 1000);
+    assert_eq!(info.health, Health::Degraded);
+
+    client.compact()?;
+    assert_eq!(client.info()?.health, Health::Healthy);
+
+    Ok(())
+}
+
+// Namespaces whose naive concatenations with a key match should stay isolated.
+#[test]
+fn server_isolates_colliding_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4021".parse().unwrap();
+    spawn_server(&temp_dir, addr);
+
+    // Both would be stored as `a::b` with a plain separator.
+    KvsClient::connect(addr)?
+        .with_namespace("a:")
+        .set("b".to_owned(), "value1".to_owned())?;
+    KvsClient::connect(addr)?
+        .with_namespace("a")
+        .set(":b".to_owned(), "value2".to_owned())?;
+
+    let mut client = KvsClient::connect(addr)?.with_namespace("a:");
+    assert_eq!(client.get("b".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.scan_keys(10).collect::<Result<Vec<_>>>()?, vec!["b"]);
+    drop(client);
+
+    let mut client = KvsClient::connect(addr)?.with_namespace("a");
+    assert_eq!(client.get(":b".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        client.scan_keys(10).collect::<Result<Vec<_>>>()?,
+        vec![":b"]
+    );
+    drop(client);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("a::b".to_owned())?, None);
+
+    Ok(())
+}
+
+// A glob pattern should fetch exactly the keys it matches as a whole.
+#[test]
+fn server_gets_keys_matching_pattern() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4022".parse().unwrap();
+    spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("user:1".to_owned(), "value1".to_owned())?;
+    client.set("user:2".to_owned(), "value2".to_owned())?;
+    client.set("user:1:name".to_owned(), "name1".to_owned())?;
+    client.set("admin:1".to_owned(), "value3".to_owned())?;
+
+    assert_eq!(
+        client.get_pattern("user:*".to_owned())?,
+        vec![
+            ("user:1".to_owned(), "value1".to_owned()),
+            ("user:1:name".to_owned(), "name1".to_owned()),
+            ("user:2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    assert_eq!(
+        client.get_pattern("user:?".to_owned())?,
+        vec![
+            ("user:1".to_owned(), "value1".to_owned()),
+            ("user:2".to_owned(), "value2".to_owned()),
+        ]
+    );
+    assert_eq!(
+        client.get_pattern("*:*:name".to_owned())?,
+        vec![("user:1:name".to_owned(), "name1".to_owned())]
+    );
+    assert_eq!(client.get_pattern("user".to_owned())?, vec![]);
+
+    Ok(())
+}
+
+// A read-only server should refuse writes without reaching the engine.
+#[test]
+fn server_rejects_writes_when_read_only() -> Result<()> {
+    let mut engine = RecordingEngine::new();
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let recorder = engine.clone();
+    let addr: SocketAddr = "127.0.0.1:4023".parse().unwrap();
+    thread::spawn(move || {
+        KvsServer::new(engine)
+            .with_read_only(true)
+            .run(addr)
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    match client.set("key1".to_owned(), "value2".to_owned()) {
+        Err(KvsError::ReadOnly) => (),
+        res => panic!("expected a read-only error, got {:?}", res),
+    }
+    match client.remove("key1".to_owned()) {
+        Err(KvsError::ReadOnly) => (),
+        res => panic!("expected a read-only error, got {:?}", res),
+    }
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert_eq!(
+        recorder.calls(),
+        vec![
+            EngineCall::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+            },
+            EngineCall::Get {
+                key: "key1".to_owned(),
+            },
+        ]
+    );
+
+    Ok(())
+}
+
+// Errors of the engine should reach clients as the matching `KvsError` variant.
+#[test]
+fn server_errors_keep_their_kind() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4024".parse().unwrap();
+    spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    match client.remove("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => (),
+        res => panic!("expected a key not found error, got {:?}", res),
+    }
+
+    client.set("key1".to_owned(), "\u{e9}".to_owned())?;
+    match client.get_range("key1".to_owned(), 1, 2) {
+        Err(KvsError::InvalidRange) => (),
+        res => panic!("expected an invalid range error, got {:?}", res),
+    }
+
+    Ok(())
+}
+
+// A conditional remove should only go through while the value is the expected one.
+#[test]
+fn server_removes_if_value_matches() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4025".parse().unwrap();
+    spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert!(client.remove_if("key1".to_owned(), "value1".to_owned())?);
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    assert!(!client.remove_if("key2".to_owned(), "value1".to_owned())?);
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    assert!(!client.remove_if("key3".to_owned(), "value3".to_owned())?);
+
+    Ok(())
+}
+
+// A large value should be streamed back in chunks, reassembling into the original.
+#[test]
+fn server_streams_large_values() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4026".parse().unwrap();
+    spawn_server(&temp_dir, addr);
+
+    // Multi-byte characters end up straddling the bounds of the chunks.
+    let value = "value\u{e9}".repeat(100_000);
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), value.to_owned())?;
+
+    let mut streamed = Vec::new();
+    assert!(client.get_stream("key1".to_owned(), &mut streamed)?);
+    assert_eq!(streamed, value.as_bytes());
+
+    let mut streamed = Vec::new();
+    assert!(!client.get_stream("key2".to_owned(), &mut streamed)?);
+    assert!(streamed.is_empty());
+
+    // The connection stays in sync once a stream is over.
+    assert_eq!(
+        client.get("key1".to_owned())?.map(|v| v.len()),
+        Some(value.len())
+    );
+
+    Ok(())
+}
+
+// A server bound to several addresses should serve all of them from the same store.
+#[test]
+fn server_listens_on_multiple_addresses() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let first: SocketAddr = "127.0.0.1:4027".parse().unwrap();
+    let second: SocketAddr = "127.0.0.1:4028".parse().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::new(store);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run_multi(vec![first, second]));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(first)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    drop(client);
+
+    let mut client = KvsClient::connect(second)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    drop(client);
+
+    let mut client = KvsClient::connect(first)?;
+    assert_eq!(client.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(client);
+
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+    assert!(TcpStream::connect(first).is_err());
+    assert!(TcpStream::connect(second).is_err());
+
+    Ok(())
+}
+
+// A connection sending requests faster than the rate limit should get errors
+// until its budget refills.
+#[test]
+fn server_limits_request_rate() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4029".parse().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || KvsServer::new(store).with_rate_limit(5).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    for _ in 0..5 {
+        client.set("key1".to_owned(), "value1".to_owned())?;
+    }
+    let throttled = (0..20)
+        .filter(|_| match client.get("key1".to_owned()) {
+            Err(KvsError::RateLimited) => true,
+            Ok(_) => false,
+            Err(e) => panic!("expected a rate limited error, got {:?}", e),
+        })
+        .count();
+    assert!(throttled > 0);
+
+    thread::sleep(Duration::from_secs(1));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A server tied to an in-memory engine should serve requests without any directory.
+#[test]
+fn server_serves_memory_engine() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4030".parse().unwrap();
+    thread::spawn(move || KvsServer::new(MemoryKvsEngine::new()).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value2".to_owned()));
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    match client.remove("key1".to_owned()) {
+        Err(KvsError::KeyNotFound) => (),
+        res => panic!("expected a key not found error, got {:?}", res),
+    }
+
+    Ok(())
+}
+
+// An increment retried with the same operation id should only be applied once,
+// even from another connection.
+#[test]
+fn server_applies_repeated_operations_once() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4031".parse().unwrap();
+    spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.increment_once("key1".to_owned(), 5, 42)?, 5);
+    assert_eq!(client.increment_once("key1".to_owned(), 5, 42)?, 5);
+    drop(client);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.increment_once("key1".to_owned(), 5, 42)?, 5);
+    assert_eq!(client.increment_once("key1".to_owned(), 5, 43)?, 10);
+    assert_eq!(client.increment("key1".to_owned(), 1)?, 11);
+    assert_eq!(client.increment("key1".to_owned(), 1)?, 12);
+
+    Ok(())
+}
+
+// Len should report the length of values in bytes.
+#[test]
+fn server_reports_value_lengths() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4032".parse().unwrap();
+    spawn_server(&temp_dir, addr);
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "valu\u{e9}".to_owned())?;
+    assert_eq!(client.len("key1".to_owned())?, Some(6));
+    assert_eq!(client.len("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Values set before a sync should be found once the server is gone.
+#[test]
+fn server_syncs_writes_on_demand() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4033".parse().unwrap();
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?);
+    let shutdown = server.shutdown_handle();
+    let handle = thread::spawn(move || server.run(addr));
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    for index in 0..10 {
+        client.send_set(format!("key{}", index), format!("value{}", index))?;
+    }
+    for _ in 0..10 {
+        client.recv()?;
+    }
+    client.sync()?;
+    drop(client);
+
+    shutdown.shutdown();
+    handle.join().unwrap()?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for index in 0..10 {
+        assert_eq!(
+            store.get(format!("key{}", index))?,
+            Some(format!("value{}", index))
+        );
+    }
+
+    Ok(())
+}
+
+// A request larger than the limit should be answered with an error before the
+// connection gets closed, whatever the framing.
+#[test]
+fn server_rejects_oversized_requests() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4034".parse().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || {
+        KvsServer::new(store)
+            .with_max_request_bytes(1024)
+            .run(addr)
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let mut stream = TcpStream::connect(addr)?;
+    let value = "v".repeat(2048);
+    write!(stream, r#"{{"Set":{{"key":"key1","value":"{}"#, value)?;
+    let mut responses = Deserializer::from_reader(stream.try_clone()?).into_iter::<Value>();
+    let response = responses.next().unwrap().unwrap();
+    assert_eq!(response, json!({ "Err": { "RequestTooLarge": 1024 } }));
+    assert!(responses.next().is_none());
+    drop(responses);
+    drop(stream);
+
+    // A frame announcing a huge payload is refused upfront.
+    let mut stream = TcpStream::connect(addr)?;
+    let handshake = json!({ "Handshake": { "version": 2 } });
+    serde_json::to_writer(&mut stream, &handshake)?;
+    let mut response = [0u8; 8];
+    stream.read_exact(&mut response)?;
+    stream.write_all(&u32::MAX.to_be_bytes())?;
+    assert_eq!(
+        read_frame(&mut stream),
+        json!({ "Err": { "RequestTooLarge": 1024 } })
+    );
+    assert_eq!(stream.read(&mut response)?, 0);
+    drop(stream);
+
+    // Requests within the limit are served as usual.
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "v".repeat(512))?;
+    match client.set("key1".to_owned(), value) {
+        Err(KvsError::RequestTooLarge(1024)) => (),
+        res => panic!("expected a request too large error, got {:?}", res),
+    }
+
+    Ok(())
+}
+
+// Large values should travel deflated between peers enabling compression, and
+// plainly to clients which don't.
+#[test]
+fn server_compresses_large_messages() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4037".parse().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || {
+        KvsServer::new(store)
+            .with_compression(true)
+            .run(addr)
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(500));
+
+    let value: String = (0..100_000).map(|i| format!("{} ", i % 100)).collect();
+    let mut client = KvsClient::connect_compressed(addr)?;
+    client.set("large".to_owned(), value.to_owned())?;
+    assert_eq!(client.get("large".to_owned())?, Some(value.to_owned()));
+    client.set("small".to_owned(), "value".to_owned())?;
+    assert_eq!(client.get("small".to_owned())?, Some("value".to_owned()));
+    // Connections are served one at a time.
+    drop(client);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("large".to_owned())?, Some(value.to_owned()));
+    drop(client);
+
+    // On the wire, small payloads are flagged raw and large ones deflated.
+    let mut stream = TcpStream::connect(addr)?;
+    serde_json::to_writer(&mut stream, &json!({ "Handshake": { "version": 3 } }))?;
+    stream.flush()?;
+    let mut response = [0u8; 8];
+    stream.read_exact(&mut response)?;
+    assert_eq!(&response, br#"{"Ok":3}"#);
+
+    let mut request = vec![0];
+    request.extend_from_slice(br#"{"Get":{"key":"large"}}"#);
+    write_frame(&mut stream, &request);
+    let mut length = [0u8; 4];
+    stream.read_exact(&mut length)?;
+    let mut payload = vec![0u8; u32::from_be_bytes(length) as usize];
+    stream.read_exact(&mut payload)?;
+    assert_eq!(payload[0], 1);
+    assert!(payload.len() < value.len() / 4);
+
+    Ok(())
+}
+
+// A client asking for compression should fall back to plain messages with a server
+// which doesn't enable it.
+#[test]
+fn compressed_client_falls_back_to_plain_messages() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4038".parse().unwrap();
+    spawn_server(&temp_dir, addr);
+
+    let value = "v".repeat(100_000);
+    let mut client = KvsClient::connect_compressed(addr)?;
+    client.set("large".to_owned(), value.to_owned())?;
+    assert_eq!(client.get("large".to_owned())?, Some(value));
+
+    Ok(())
+}
+
+// Sets failing with a transient error should be retried, up to a bound.
+#[test]
+fn server_retries_transiently_failing_sets() -> Result<()> {
+    let addr: SocketAddr = "127.0.0.1:4039".parse().unwrap();
+    let engine = FlakyEngine::new(5);
+    let server = KvsServer::new(engine.clone()).with_write_retries(3);
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(client.set("key1".to_owned(), "value1".to_owned()).is_err());
+    assert_eq!(engine.failures_left(), 1);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.failures_left(), 0);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// The configuration answered should reflect how the server was set up.
+#[test]
+fn server_reports_config() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let addr: SocketAddr = "127.0.0.1:4042".parse().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::new(store)
+        .with_read_only(true)
+        .with_rate_limit(500)
+        .with_compression(true)
+        .with_max_request_bytes(4096)
+        .with_write_retries(1);
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let config = KvsClient::connect(addr)?.config()?;
+    assert_eq!(
+        config,
+        ServerConfig {
+            config_version: 1,
+            engine: "kvs".to_owned(),
+            threads: 1,
+            compaction_threshold: Some(1024 * 1024),
+            read_only: true,
+            protocol_version: 3,
+            rate_limit: Some(500),
+            max_request_bytes: 4096,
+            write_retries: 1,
+        }
+    );
+
+    let serialized = serde_json::to_string(&config)?;
+    assert_eq!(serde_json::from_str::<ServerConfig>(&serialized)?, config);
+
+    Ok(())
+}
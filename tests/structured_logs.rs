@@ -0,0 +1,131 @@
+#![cfg(feature = "structured-logs")]
+
+use kvs::test_util::MemoryKvsEngine;
+use kvs::{KvsClient, KvsServer, Result};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+
+/// A span created while serving, along with the span entered at the time.
+#[derive(Debug, Clone)]
+struct CapturedSpan {
+    name: &'static str,
+    fields: HashMap<String, String>,
+    parent: Option<u64>,
+}
+
+type Spans = Arc<Mutex<BTreeMap<u64, CapturedSpan>>>;
+
+/// Keeps every span created, by id.
+struct CapturingSubscriber {
+    next_id: AtomicU64,
+    spans: Spans,
+}
+
+thread_local! {
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+struct Fields<'a>(&'a mut HashMap<String, String>);
+
+impl Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_owned(), format!("{:?}", value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_owned(), value.to_owned());
+    }
+}
+
+impl Subscriber for CapturingSubscriber {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let mut fields = HashMap::new();
+        attrs.record(&mut Fields(&mut fields));
+        let parent = ENTERED.with(|entered| entered.borrow().last().cloned());
+        let span = CapturedSpan {
+            name: attrs.metadata().name(),
+            fields,
+            parent,
+        };
+        self.spans.lock().unwrap().insert(id, span);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(span) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            values.record(&mut Fields(&mut span.fields));
+        }
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, _: &Event<'_>) {}
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, _: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().pop());
+    }
+}
+
+// Each request served should be in a span of its command, within the span of its
+// connection and peer address.
+#[test]
+fn server_enters_spans_per_connection_and_request() -> Result<()> {
+    let spans = Spans::default();
+    let subscriber = CapturingSubscriber {
+        next_id: AtomicU64::new(1),
+        spans: Arc::clone(&spans),
+    };
+    tracing::subscriber::set_global_default(subscriber).unwrap();
+
+    let addr: SocketAddr = "127.0.0.1:4035".parse().unwrap();
+    thread::spawn(move || KvsServer::new(MemoryKvsEngine::new()).run(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(client);
+
+    let spans = spans.lock().unwrap();
+    let (connection_id, connection) = spans
+        .iter()
+        .find(|(_, span)| span.name == "connection")
+        .expect("no connection span");
+    assert_eq!(connection.fields["connection"], "1");
+    let peer_addr: SocketAddr = connection.fields["peer_addr"].parse().unwrap();
+    assert_eq!(peer_addr.ip(), addr.ip());
+
+    let requests: Vec<&CapturedSpan> = spans
+        .values()
+        .filter(|span| span.name == "request")
+        .collect();
+    let commands: Vec<&str> = requests
+        .iter()
+        .map(|span| span.fields["command"].as_str())
+        .collect();
+    assert!(commands.ends_with(&["set", "get"]), "{:?}", commands);
+    for request in requests {
+        assert_eq!(request.parent, Some(*connection_id));
+    }
+
+    Ok(())
+}